@@ -0,0 +1,11 @@
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    // Compile the Remote Execution API protocol for the REAPI caching backend.
+    tonic_build::configure().compile(
+        &[
+            "proto/build/bazel/remote/execution/v2/remote_execution.proto",
+            "proto/google/bytestream/bytestream.proto",
+        ],
+        &["proto"],
+    )?;
+    Ok(())
+}
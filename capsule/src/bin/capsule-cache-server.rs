@@ -0,0 +1,103 @@
+use anyhow::{anyhow, Context, Result};
+use capsule::server::CacheServer;
+use clap::{App, Arg};
+use log::warn;
+use std::collections::HashMap;
+use std::env;
+use std::fs;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    // Initialize logging. Default is INFO level, can be overridden in CAPSULE_LOG
+    env_logger::Builder::new()
+        .filter_level(log::LevelFilter::Info)
+        .parse_env("CAPSULE_LOG")
+        .init();
+
+    let matches = App::new("capsule-cache-server")
+        .version(env!("CARGO_PKG_VERSION"))
+        .about("Shared cache server for capsules, to be used with the 'http' backend")
+        .arg(
+            Arg::new("listen")
+                .long("listen")
+                .help("Address to listen on")
+                .takes_value(true)
+                .default_value("127.0.0.1:8080"),
+        )
+        .arg(
+            Arg::new("dir")
+                .long("dir")
+                .help("Directory to store the cache in")
+                .takes_value(true)
+                .required(true),
+        )
+        .arg(
+            Arg::new("token_file")
+                .long("token_file")
+                .help("File with accepted access tokens, separated by whitespace")
+                .takes_value(true)
+                .multiple_occurrences(true),
+        )
+        .arg(
+            Arg::new("quota")
+                .long("quota")
+                .help("Default quota of a namespace in bytes")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::new("namespace_quota")
+                .long("namespace_quota")
+                .help("Quota of a namespace in bytes, as NAMESPACE=BYTES")
+                .takes_value(true)
+                .multiple_occurrences(true),
+        )
+        .arg(
+            Arg::new("max_entry_size")
+                .long("max_entry_size")
+                .help("Maximum size of a cache entry in bytes")
+                .takes_value(true),
+        )
+        .get_matches();
+
+    let addr: SocketAddr = matches
+        .value_of("listen")
+        .unwrap()
+        .parse()
+        .context("Invalid listen address")?;
+    // Tokens aren't taken from the command line, where other users of the machine could see them.
+    let mut tokens: Vec<String> = env::var("CAPSULE_SERVER_TOKENS")
+        .map(|value| value.split_whitespace().map(String::from).collect())
+        .unwrap_or_default();
+    for path in matches.values_of("token_file").into_iter().flatten() {
+        let contents = fs::read_to_string(path).with_context(|| format!("Reading token file '{}'", path))?;
+        tokens.extend(contents.split_whitespace().map(String::from));
+    }
+    if tokens.is_empty() {
+        warn!("No access tokens specified, the cache is open to everyone");
+    }
+    let default_quota = match matches.value_of("quota") {
+        Some(value) => Some(value.parse().context("Invalid quota")?),
+        None => None,
+    };
+    let mut quotas = HashMap::new();
+    for value in matches.values_of("namespace_quota").into_iter().flatten() {
+        let (namespace, quota) = value
+            .split_once('=')
+            .ok_or_else(|| anyhow!("Invalid namespace_quota '{}', expected NAMESPACE=BYTES", value))?;
+        quotas.insert(namespace.to_string(), quota.parse().context("Invalid namespace_quota")?);
+    }
+
+    let mut server = CacheServer::new(
+        PathBuf::from(matches.value_of("dir").unwrap()),
+        tokens,
+        default_quota,
+        quotas,
+    )?;
+    if let Some(value) = matches.value_of("max_entry_size") {
+        server.max_entry_size = value.parse().context("Invalid max_entry_size")?;
+    }
+    Arc::new(server).serve(addr).await
+}
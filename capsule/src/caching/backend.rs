@@ -1,13 +1,25 @@
 use anyhow::Result;
 use async_trait::async_trait;
+use chrono::{DateTime, Utc};
 use std::fmt;
 use std::pin::Pin;
 use tokio::io::AsyncRead;
 
 use crate::iohashing::{InputHashBundle, InputOutputBundle, OutputHashBundle};
 
+/// A cache entry as found by listing the cache.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CacheEntry {
+    /// Hash of the inputs, which keys the entry.
+    pub hash: String,
+    /// When the entry was written (or last used, for caches that track it), if known.
+    pub last_modified: Option<DateTime<Utc>>,
+    /// Size of the stored entry in bytes.
+    pub size: u64,
+}
+
 #[async_trait]
-pub trait CachingBackend {
+pub trait CachingBackend: Sync {
     /// Return the name of this backend.
     fn name(&self) -> &'static str {
         "backend"
@@ -16,12 +28,25 @@ pub trait CachingBackend {
     /// Lookup the cache by the inputs hash, and return Some result if there's cache hit.
     async fn lookup(&self, inputs: &InputHashBundle) -> Result<Option<InputOutputBundle>>;
 
+    /// Read the cache entry of the inputs hash like `lookup`, but without touching or repairing the cache,
+    /// so that inspecting the cache doesn't change it.
+    async fn peek(&self, inputs: &InputHashBundle) -> Result<Option<InputOutputBundle>> {
+        self.lookup(inputs).await
+    }
+
     /// Write a cache entry keyed by input, containing hashes of outputs.
     async fn write(&self, inputs: &InputHashBundle, outputs: &OutputHashBundle, source: String) -> Result<()>;
 
+    /// Write a cache entry like `write`, but without making it the latest entry of the capsule, e.g. when copying
+    /// an older entry between cache tiers, so that cache misses are still explained against the last write.
+    async fn write_entry(&self, inputs: &InputHashBundle, outputs: &OutputHashBundle, source: String) -> Result<()>;
+
+    /// Return the cache entry written most recently for the capsule, so that cache misses can be explained.
+    async fn latest_entry(&self) -> Result<Option<InputOutputBundle>>;
+
     /// Download a file addressed by item_hash from the backend storage, and return an AsyncRead handle
     /// that allows the caller to keep asynchrnously fetching the content.
-    async fn download_object_file(&self, item_hash: &str) -> Result<Pin<Box<dyn AsyncRead>>>;
+    async fn download_object_file(&self, item_hash: &str) -> Result<Pin<Box<dyn AsyncRead + Send>>>;
 
     /// Upload a file addressed by item_hash to the backend storage. The file is represented by an
     /// AsyncRead handle that allows us to keep reading the file during the async upload.
@@ -32,6 +57,9 @@ pub trait CachingBackend {
         file: Pin<Box<dyn AsyncRead + Send>>,
         content_length: u64,
     ) -> Result<()>;
+
+    /// List all cache entries of the capsule, most recently modified first.
+    async fn list_entries(&self) -> Result<Vec<CacheEntry>>;
 }
 
 impl fmt::Debug for dyn CachingBackend {
@@ -1,4 +1,4 @@
-use crate::caching::backend::CachingBackend;
+use crate::caching::backend::{CacheEntry, CachingBackend};
 use anyhow::{anyhow, Result};
 use async_trait::async_trait;
 use log::info;
@@ -24,7 +24,7 @@ impl CachingBackend for DummyBackend {
         Ok(None)
     }
 
-    async fn download_object_file(&self, _item_hash: &str) -> Result<Pin<Box<dyn AsyncRead>>> {
+    async fn download_object_file(&self, _item_hash: &str) -> Result<Pin<Box<dyn AsyncRead + Send>>> {
         Err(anyhow!("downloading object file in the dummy backend"))
     }
 
@@ -49,4 +49,17 @@ impl CachingBackend for DummyBackend {
         }
         Ok(())
     }
+
+    async fn write_entry(&self, inputs: &InputHashBundle, outputs: &OutputHashBundle, source: String) -> Result<()> {
+        self.write(inputs, outputs, source).await
+    }
+
+    async fn latest_entry(&self) -> Result<Option<InputOutputBundle>> {
+        Ok(None)
+    }
+
+    async fn list_entries(&self) -> Result<Vec<CacheEntry>> {
+        // Nothing is ever stored.
+        Ok(Vec::new())
+    }
 }
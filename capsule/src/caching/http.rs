@@ -0,0 +1,349 @@
+use anyhow::{anyhow, bail, Context, Result};
+use async_trait::async_trait;
+use futures::{try_join, TryStreamExt};
+use log::info;
+use reqwest::{header, Method, RequestBuilder, StatusCode};
+use serde_json;
+use std::io;
+use std::pin::Pin;
+use tempfile::tempfile;
+use tokio::io::{AsyncRead, AsyncSeekExt};
+use tokio_util::{codec, io::StreamReader};
+
+use crate::caching::backend::{CacheEntry, CachingBackend};
+use crate::config::Config;
+use crate::iohashing::{string_hash, HashFunction, InputHashBundle, InputOutputBundle, OutputHashBundle};
+
+/// Key of the latest entry of a capsule. Entry keys are hashed, so it can't collide with an inputs hash.
+const LATEST_KEY: &str = "latest";
+
+/// Authentication for the HTTP cache server.
+pub enum HttpAuth {
+    Basic { user: String, password: Option<String> },
+    Bearer(String),
+}
+
+/// A backend for the plain HTTP cache protocol, as implemented by bazel-remote and similar servers.
+///
+/// Cache entries are stored under `/ac/<key>`, and objects under `/cas/<sha256>`. As those servers have
+/// no notion of capsule IDs, the entry key is the hash of the capsule ID together with the inputs hash.
+pub struct HttpBackend {
+    /// Base URL of the cache server.
+    pub url: String,
+
+    /// Optional authentication.
+    pub auth: Option<HttpAuth>,
+
+    /// HTTP client.
+    pub client: reqwest::Client,
+
+    /// Capsule ID
+    pub capsule_id: String,
+}
+
+impl HttpBackend {
+    pub fn from_config(config: &Config) -> Result<Self> {
+        // Objects are stored under their hashes in `/cas/`, which servers like bazel-remote verify as SHA256.
+        if config.hash_function() != HashFunction::Sha256 {
+            bail!("The HTTP backend only supports the sha256 hash function");
+        }
+        let auth = if let Some(ref token) = config.http_token {
+            Some(HttpAuth::Bearer(token.clone()))
+        } else {
+            config.http_user.as_ref().map(|user| HttpAuth::Basic {
+                user: user.clone(),
+                password: config.http_password.clone(),
+            })
+        };
+        Ok(Self {
+            url: config
+                .http_url
+                .clone()
+                .ok_or_else(|| anyhow!("HTTP cache URL not specified"))?,
+            auth,
+            client: reqwest::Client::new(),
+            capsule_id: config.capsule_id.as_deref().unwrap().to_string(),
+        })
+    }
+
+    fn normalize_key(&self, key: &str) -> String {
+        format!("ac/{}", string_hash(&format!("{}/{}", self.capsule_id, key)))
+    }
+
+    fn normalize_object_key(&self, key: &str) -> String {
+        format!("cas/{}", key)
+    }
+
+    fn request(&self, method: Method, path: &str) -> RequestBuilder {
+        let request = self
+            .client
+            .request(method, format!("{}/{}", self.url.trim_end_matches('/'), path));
+        match self.auth {
+            Some(HttpAuth::Basic { ref user, ref password }) => request.basic_auth(user, password.as_ref()),
+            Some(HttpAuth::Bearer(ref token)) => request.bearer_auth(token),
+            None => request,
+        }
+    }
+
+    async fn get_entry(&self, path: &str) -> Result<Option<InputOutputBundle>> {
+        let response = self.request(Method::GET, path).send().await?;
+        if response.status() == StatusCode::NOT_FOUND {
+            return Ok(None); // Cache miss
+        }
+        let body = response
+            .error_for_status()?
+            .bytes()
+            .await
+            .context("failed to read HTTP body")?;
+        let bundle = serde_json::from_slice(&body).context("Cannot deserialize output")?;
+        Ok(Some(bundle))
+    }
+
+    async fn put_entry(&self, path: &str, data: Vec<u8>) -> Result<()> {
+        self.request(Method::PUT, path)
+            .header(header::CONTENT_TYPE, "application/json")
+            .body(data)
+            .send()
+            .await?
+            .error_for_status()?;
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl CachingBackend for HttpBackend {
+    fn name(&self) -> &'static str {
+        "http"
+    }
+
+    /// Lookup inputs on the HTTP cache server.
+    async fn lookup(&self, inputs: &InputHashBundle) -> Result<Option<InputOutputBundle>> {
+        self.get_entry(&self.normalize_key(&inputs.hash)).await
+    }
+
+    /// Read a file object from the server, and return AsyncRead object for consuming by capsule.
+    async fn download_object_file(&self, item_hash: &str) -> Result<Pin<Box<dyn AsyncRead + Send>>> {
+        let response = self
+            .request(Method::GET, &self.normalize_object_key(item_hash))
+            .send()
+            .await?
+            .error_for_status()?;
+        let stream = response.bytes_stream().map_err(io::Error::other);
+        Ok(Box::pin(StreamReader::new(stream)))
+    }
+
+    async fn upload_object_file(
+        &self,
+        name: String,
+        item_hash: &str,
+        mut file: Pin<Box<dyn AsyncRead + Send>>,
+        _content_length: u64,
+    ) -> Result<()> {
+        let key = self.normalize_object_key(item_hash);
+
+        // Objects in the content addresable storage are "immutable", so duplicate uploads can be skipped.
+        let response = self.request(Method::HEAD, &key).send().await?;
+        if response.status().is_success() {
+            info!("Skipping upload for {} with hash '{}'", name, item_hash);
+            return Ok(());
+        } else {
+            info!("Uploading object {} to '{}'", name, item_hash);
+        }
+
+        // The request body has to be 'static and Sync, which the file handle is not.
+        // So we'll spool the file into a temporary file and upload that one.
+        let spool = tempfile()?;
+        let mut spool = tokio::fs::File::from_std(spool);
+        tokio::io::copy(&mut file, &mut spool).await?;
+        let content_length = spool.metadata().await?.len();
+        spool.seek(io::SeekFrom::Start(0)).await?;
+        let byte_stream = codec::FramedRead::new(spool, codec::BytesCodec::new());
+        self.request(Method::PUT, &key)
+            .header(header::CONTENT_LENGTH, content_length)
+            .header(header::CONTENT_TYPE, "application/octet-stream")
+            .body(reqwest::Body::wrap_stream(byte_stream))
+            .send()
+            .await?
+            .error_for_status()?;
+        Ok(())
+    }
+
+    /// Write hashes of inputs and outputs to the HTTP cache server, keyed by hashes of inputs.
+    async fn write(&self, inputs: &InputHashBundle, outputs: &OutputHashBundle, source: String) -> Result<()> {
+        let io_bundle = InputOutputBundle {
+            inputs: inputs.clone(),
+            outputs: outputs.clone(),
+            source,
+        };
+        let data = serde_json::to_vec(&io_bundle)?;
+        let key = self.normalize_key(&io_bundle.inputs.hash);
+        let latest_key = self.normalize_key(LATEST_KEY);
+        try_join!(self.put_entry(&key, data.clone()), self.put_entry(&latest_key, data))?;
+        Ok(())
+    }
+
+    async fn write_entry(&self, inputs: &InputHashBundle, outputs: &OutputHashBundle, source: String) -> Result<()> {
+        let io_bundle = InputOutputBundle {
+            inputs: inputs.clone(),
+            outputs: outputs.clone(),
+            source,
+        };
+        let data = serde_json::to_vec(&io_bundle)?;
+        self.put_entry(&self.normalize_key(&io_bundle.inputs.hash), data).await
+    }
+
+    /// Read the latest entry of the capsule from the HTTP cache server.
+    async fn latest_entry(&self) -> Result<Option<InputOutputBundle>> {
+        self.get_entry(&self.normalize_key(LATEST_KEY)).await
+    }
+
+    async fn list_entries(&self) -> Result<Vec<CacheEntry>> {
+        // The HTTP cache protocol has no way to enumerate the cache.
+        Err(anyhow!("Listing cache entries is not supported by the http backend"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::iohashing::{FileOutput, Output};
+    use hyper::service::{make_service_fn, service_fn};
+    use hyper::{Body, Request, Response, Server};
+    use std::collections::HashMap;
+    use std::convert::Infallible;
+    use std::net::SocketAddr;
+    use std::sync::{Arc, Mutex};
+    use tokio::io::AsyncReadExt;
+
+    type Store = Arc<Mutex<HashMap<String, Vec<u8>>>>;
+
+    // A stand-in for bazel-remote: a key/value store of request paths, guarded by a bearer token.
+    async fn serve(store: Store, req: Request<Body>) -> Result<Response<Body>, hyper::Error> {
+        let authorized =
+            matches!(req.headers().get(hyper::header::AUTHORIZATION), Some(value) if value == "Bearer secret");
+        if !authorized {
+            return Ok(Response::builder().status(401).body(Body::empty()).unwrap());
+        }
+        let path = req.uri().path().to_string();
+        let response = match *req.method() {
+            hyper::Method::PUT => {
+                let body = hyper::body::to_bytes(req.into_body()).await?;
+                store.lock().unwrap().insert(path, body.to_vec());
+                Response::new(Body::empty())
+            }
+            _ => match store.lock().unwrap().get(&path) {
+                Some(data) => Response::new(Body::from(data.clone())),
+                None => Response::builder().status(404).body(Body::empty()).unwrap(),
+            },
+        };
+        Ok(response)
+    }
+
+    fn start_server() -> (SocketAddr, Store) {
+        let store = Store::default();
+        let server_store = store.clone();
+        let make_service = make_service_fn(move |_| {
+            let store = server_store.clone();
+            async move { Ok::<_, Infallible>(service_fn(move |req| serve(store.clone(), req))) }
+        });
+        let server = Server::bind(&([127, 0, 0, 1], 0).into()).serve(make_service);
+        let addr = server.local_addr();
+        tokio::spawn(server);
+        (addr, store)
+    }
+
+    fn backend(addr: SocketAddr, token: &str) -> HttpBackend {
+        HttpBackend {
+            url: format!("http://{}/", addr),
+            auth: Some(HttpAuth::Bearer(token.to_string())),
+            client: reqwest::Client::new(),
+            capsule_id: "wtf".to_string(),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_http_roundtrip() {
+        let (addr, store) = start_server();
+        let backend = backend(addr, "secret");
+        let inputs = InputHashBundle {
+            hash: "1234".to_string(),
+            ..Default::default()
+        };
+        assert!(backend.lookup(&inputs).await.unwrap().is_none());
+
+        let contents = b"file contents".to_vec();
+        let object_hash = string_hash("file contents");
+        backend
+            .upload_object_file(
+                "out".to_string(),
+                &object_hash,
+                Box::pin(std::io::Cursor::new(contents.clone())),
+                contents.len() as u64,
+            )
+            .await
+            .unwrap();
+        let outputs = OutputHashBundle {
+            hash: "5678".to_string(),
+            hash_details: vec![(
+                Output::File(FileOutput {
+                    filename: "out".into(),
+                    present: true,
+                    mode: 0o644,
+                    symlink: None,
+                }),
+                object_hash.clone(),
+            )],
+            ..Default::default()
+        };
+        backend.write(&inputs, &outputs, "job".to_string()).await.unwrap();
+        assert!(store.lock().unwrap().contains_key(&format!("/cas/{}", object_hash)));
+
+        let bundle = backend.lookup(&inputs).await.unwrap().unwrap();
+        assert_eq!(bundle.outputs.hash, "5678");
+        assert_eq!(bundle.source, "job");
+        let mut downloaded = Vec::new();
+        backend
+            .download_object_file(&object_hash)
+            .await
+            .unwrap()
+            .read_to_end(&mut downloaded)
+            .await
+            .unwrap();
+        assert_eq!(downloaded, contents);
+    }
+
+    #[tokio::test]
+    async fn test_http_capsule_id() {
+        let (addr, _) = start_server();
+        let backend1 = backend(addr, "secret");
+        let mut backend2 = backend(addr, "secret");
+        backend2.capsule_id = "wtf2".to_string();
+        let inputs = InputHashBundle {
+            hash: "1234".to_string(),
+            ..Default::default()
+        };
+        backend1
+            .write(&inputs, &OutputHashBundle::default(), String::new())
+            .await
+            .unwrap();
+        assert!(backend1.lookup(&inputs).await.unwrap().is_some());
+        assert!(backend2.lookup(&inputs).await.unwrap().is_none());
+        assert_eq!(backend1.latest_entry().await.unwrap().unwrap().inputs.hash, "1234");
+        assert!(backend2.latest_entry().await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_http_unauthorized() {
+        let (addr, _) = start_server();
+        let backend = backend(addr, "wrong");
+        let inputs = InputHashBundle {
+            hash: "1234".to_string(),
+            ..Default::default()
+        };
+        assert!(backend.lookup(&inputs).await.is_err());
+        assert!(backend
+            .write(&inputs, &OutputHashBundle::default(), String::new())
+            .await
+            .is_err());
+    }
+}
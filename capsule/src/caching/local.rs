@@ -0,0 +1,639 @@
+use anyhow::{anyhow, Context, Result};
+use async_trait::async_trait;
+use filetime::FileTime;
+use log::{info, warn};
+use nix::fcntl::{flock, FlockArg};
+use serde_json;
+use std::cmp::Reverse;
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tempfile::NamedTempFile;
+use tokio::io::{AsyncRead, AsyncWriteExt};
+use tokio::task;
+
+use crate::caching::backend::{CacheEntry, CachingBackend};
+use crate::config::Config;
+use crate::iohashing::{InputHashBundle, InputOutputBundle, OutputHashBundle};
+
+/// Subdirectory of the cache root with the action entries.
+const ENTRIES_DIR: &str = "ac";
+
+/// Subdirectory of the cache root with the content addressed objects.
+const OBJECTS_DIR: &str = "cas";
+
+/// Subdirectory of the cache root with the latest entry of each capsule.
+const LATEST_DIR: &str = "latest";
+
+/// File in the cache root with the estimated total size of entries and objects, and the time of the last
+/// eviction scan, so that the cache isn't scanned on every write.
+const USAGE_FILE: &str = "usage";
+
+/// With a maximum age, the cache is scanned for expired entries at least this often, even if it's not full.
+const SCAN_INTERVAL: Duration = Duration::from_secs(3600);
+
+/// Objects and entries younger than this are never garbage collected for being dangling,
+/// as they may belong to a cache write which is still in flight in another capsule.
+const GRACE_PERIOD: Duration = Duration::from_secs(600);
+
+#[derive(Clone)]
+pub struct LocalBackend {
+    /// Root directory of the cache.
+    pub root: PathBuf,
+
+    /// Maximum total size of entries and objects in bytes.
+    pub max_size: Option<u64>,
+
+    /// Maximum time since the last use of an entry.
+    pub max_age: Option<Duration>,
+
+    /// Capsule ID
+    pub capsule_id: String,
+}
+
+/// An action entry found while scanning the cache for eviction.
+struct EntryInfo {
+    path: PathBuf,
+    size: u64,
+    last_used: SystemTime,
+    objects: Vec<String>,
+}
+
+/// Estimated usage of the cache, kept in the usage file.
+struct Usage {
+    size: u64,
+    scanned: SystemTime,
+}
+
+impl Usage {
+    fn parse(contents: &str) -> Option<Self> {
+        let (size, scanned) = contents.trim().split_once(' ')?;
+        Some(Self {
+            size: size.parse().ok()?,
+            scanned: UNIX_EPOCH + Duration::from_secs(scanned.parse().ok()?),
+        })
+    }
+}
+
+/// An object found while scanning the cache for eviction.
+struct ObjectInfo {
+    path: PathBuf,
+    size: u64,
+    modified: SystemTime,
+}
+
+// Capsule IDs are usually target paths, so they need escaping to become a single directory name.
+fn escape_capsule_id(capsule_id: &str) -> String {
+    capsule_id.replace('%', "%25").replace('/', "%2F")
+}
+
+// Recursively list all regular files below the given directory.
+fn walk_files(dir: &Path, files: &mut Vec<PathBuf>) -> Result<()> {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(err) => return Err(err.into()),
+    };
+    for entry in entries {
+        let entry = entry?;
+        let file_type = entry.file_type()?;
+        if file_type.is_dir() {
+            walk_files(&entry.path(), files)?;
+        } else if file_type.is_file() {
+            files.push(entry.path());
+        }
+    }
+    Ok(())
+}
+
+// Remove a file, ignoring the case when it has already been removed by a concurrent capsule.
+fn remove_file(path: &Path) -> Result<()> {
+    match fs::remove_file(path) {
+        Err(err) if err.kind() != io::ErrorKind::NotFound => {
+            Err(err).with_context(|| format!("Removing '{}' from the local cache", path.display()))
+        }
+        _ => Ok(()),
+    }
+}
+
+// Mark a file as recently used.
+fn touch(path: &Path) -> Result<()> {
+    filetime::set_file_mtime(path, FileTime::now())
+        .with_context(|| format!("Updating modification time of '{}'", path.display()))
+}
+
+// Write a file atomically, so that concurrent readers never see it partially written.
+async fn write_file(path: &Path, data: &[u8]) -> Result<()> {
+    let dir = path.parent().context("No parent directory")?;
+    tokio::fs::create_dir_all(dir).await?;
+    let (tmp_file, tmp_path) = NamedTempFile::new_in(dir)?.into_parts();
+    let mut tmp_file = tokio::fs::File::from_std(tmp_file);
+    tmp_file.write_all(data).await?;
+    tmp_file.flush().await?;
+    tmp_path.persist(path)?;
+    Ok(())
+}
+
+impl LocalBackend {
+    pub fn from_config(config: &Config) -> Result<Self> {
+        let root = match config.local_cache_dir {
+            Some(ref dir) => PathBuf::from(dir),
+            None => PathBuf::from(
+                std::env::var("HOME").map_err(|_| anyhow!("Local cache directory not specified and no HOME"))?,
+            )
+            .join(".cache")
+            .join("capsules"),
+        };
+        Ok(Self {
+            root,
+            max_size: config.local_cache_max_size,
+            max_age: config.local_cache_max_age.map(Duration::from_secs),
+            capsule_id: config.capsule_id.as_deref().unwrap().to_string(),
+        })
+    }
+
+    fn entry_path(&self, key: &str) -> PathBuf {
+        self.root
+            .join(ENTRIES_DIR)
+            .join(escape_capsule_id(&self.capsule_id))
+            .join(&key[0..2])
+            .join(key)
+    }
+
+    // The latest entries are kept apart from the regular ones, so that they are never evicted.
+    fn latest_path(&self) -> PathBuf {
+        self.root.join(LATEST_DIR).join(escape_capsule_id(&self.capsule_id))
+    }
+
+    fn object_path(&self, key: &str) -> PathBuf {
+        self.root.join(OBJECTS_DIR).join(&key[0..2]).join(key)
+    }
+
+    /// Run blocking file system work, such as waiting for the lock of the usage file, in a thread,
+    /// so that it doesn't stall the runtime and the downloads running on it.
+    async fn run_blocking<T, F>(&self, work: F) -> Result<T>
+    where
+        T: Send + 'static,
+        F: FnOnce(&Self) -> Result<T> + Send + 'static,
+    {
+        let backend = self.clone();
+        task::spawn_blocking(move || work(&backend)).await?
+    }
+
+    /// Write the serialized entry, and keep the cache within its limits, scanning the directory only when they
+    /// may have been crossed.
+    async fn store_entry(&self, key: &str, data: &[u8]) -> Result<()> {
+        write_file(&self.entry_path(key), data).await?;
+        if !self.has_limits() {
+            return Ok(());
+        }
+        let size = data.len() as u64;
+        self.run_blocking(move |backend| {
+            if backend.needs_eviction(&backend.add_usage(size)?) {
+                backend.evict()?;
+            }
+            Ok(())
+        })
+        .await
+    }
+
+    fn has_limits(&self) -> bool {
+        self.max_size.is_some() || self.max_age.is_some()
+    }
+
+    /// Update the usage file under an exclusive lock, as other capsules may be updating it concurrently.
+    /// A missing or unreadable file is passed as None.
+    fn update_usage<F: FnOnce(Option<Usage>) -> Usage>(&self, update: F) -> Result<Usage> {
+        fs::create_dir_all(&self.root)?;
+        let path = self.root.join(USAGE_FILE);
+        let mut file = fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&path)
+            .with_context(|| format!("Opening '{}'", path.display()))?;
+        flock(file.as_raw_fd(), FlockArg::LockExclusive)?;
+        let mut contents = String::new();
+        file.read_to_string(&mut contents)?;
+        let usage = update(Usage::parse(&contents));
+        let scanned = usage.scanned.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+        file.seek(SeekFrom::Start(0))?;
+        file.set_len(0)?;
+        write!(file, "{} {}", usage.size, scanned)?;
+        // The lock is released when the file is closed.
+        Ok(usage)
+    }
+
+    /// Add the size of a new entry or object to the estimated usage.
+    fn add_usage(&self, size: u64) -> Result<Usage> {
+        self.update_usage(|usage| match usage {
+            Some(usage) => Usage {
+                size: usage.size + size,
+                ..usage
+            },
+            // Without an estimate, the next write scans the cache.
+            None => Usage {
+                size,
+                scanned: UNIX_EPOCH,
+            },
+        })
+    }
+
+    /// Whether the cache has to be scanned, because it may be over its maximum size, or may have expired entries.
+    fn needs_eviction(&self, usage: &Usage) -> bool {
+        let scan_due = SystemTime::now().duration_since(usage.scanned).unwrap_or_default() > SCAN_INTERVAL;
+        matches!(self.max_size, Some(max_size) if usage.size > max_size) || (self.max_age.is_some() && scan_due)
+    }
+
+    /// Evict entries which are too old, or don't fit into the maximum size, least recently used first,
+    /// and record the remaining size as the estimated usage.
+    ///
+    /// Entries are the unit of eviction: an object is only removed once no remaining entry refers to
+    /// it, so that we never leave an entry whose objects are gone.
+    fn evict(&self) -> Result<()> {
+        if !self.has_limits() {
+            return Ok(());
+        }
+        let now = SystemTime::now();
+        let age = |time: SystemTime| now.duration_since(time).unwrap_or_default();
+
+        let mut objects = HashMap::new();
+        let mut object_files = Vec::new();
+        walk_files(&self.root.join(OBJECTS_DIR), &mut object_files)?;
+        for path in object_files {
+            // The file may be gone already, if another capsule is evicting concurrently.
+            if let Ok(metadata) = fs::metadata(&path) {
+                let hash = path.file_name().unwrap().to_string_lossy().to_string();
+                objects.insert(
+                    hash,
+                    ObjectInfo {
+                        path,
+                        size: metadata.len(),
+                        modified: metadata.modified()?,
+                    },
+                );
+            }
+        }
+
+        let mut entries = Vec::new();
+        let mut entry_files = Vec::new();
+        walk_files(&self.root.join(ENTRIES_DIR), &mut entry_files)?;
+        for path in entry_files {
+            let (metadata, data) = match (fs::metadata(&path), fs::read(&path)) {
+                (Ok(metadata), Ok(data)) => (metadata, data),
+                _ => continue,
+            };
+            match serde_json::from_slice::<InputOutputBundle>(&data) {
+                Ok(bundle) => entries.push(EntryInfo {
+                    path,
+                    size: metadata.len(),
+                    last_used: metadata.modified()?,
+                    objects: bundle.outputs.object_hashes().map(String::from).collect(),
+                }),
+                Err(err) => {
+                    warn!("Removing unreadable local cache entry '{}': {}", path.display(), err);
+                    remove_file(&path)?;
+                }
+            }
+        }
+
+        // Walk the entries from the most recently used, keeping them until we run out of space.
+        entries.sort_by_key(|entry| Reverse(entry.last_used));
+        let mut referenced = HashSet::new();
+        let mut total_size = 0;
+        let mut full = false;
+        for entry in entries {
+            let dangling = entry.objects.iter().any(|hash| !objects.contains_key(hash));
+            let expired = matches!(self.max_age, Some(max_age) if age(entry.last_used) > max_age);
+            let entry_size = entry.size
+                + entry
+                    .objects
+                    .iter()
+                    .filter(|hash| !referenced.contains(*hash))
+                    .filter_map(|hash| objects.get(hash))
+                    .map(|object| object.size)
+                    .sum::<u64>();
+            full = full || matches!(self.max_size, Some(max_size) if total_size + entry_size > max_size);
+            if full || expired || (dangling && age(entry.last_used) > GRACE_PERIOD) {
+                info!("Evicting local cache entry '{}'", entry.path.display());
+                remove_file(&entry.path)?;
+            } else {
+                total_size += entry_size;
+                referenced.extend(entry.objects);
+            }
+        }
+
+        // Now remove all objects no longer referenced by any entry.
+        for (hash, object) in objects {
+            if !referenced.contains(&hash) && age(object.modified) > GRACE_PERIOD {
+                info!("Evicting local cache object '{}'", hash);
+                remove_file(&object.path)?;
+            }
+        }
+        // Unreferenced objects in their grace period aren't counted, as they are removed by the next scan.
+        // Writes which happened during this scan may be missed, the next scan corrects that too.
+        self.update_usage(|_| Usage {
+            size: total_size,
+            scanned: now,
+        })?;
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl CachingBackend for LocalBackend {
+    fn name(&self) -> &'static str {
+        "local"
+    }
+
+    /// Lookup inputs in the local cache directory.
+    async fn lookup(&self, inputs: &InputHashBundle) -> Result<Option<InputOutputBundle>> {
+        let path = self.entry_path(&inputs.hash);
+        let bundle = match self.peek(inputs).await? {
+            Some(bundle) => bundle,
+            None => return Ok(None), // Cache miss
+        };
+        self.run_blocking(move |backend| {
+            // An entry is only usable with all of its objects, otherwise treat it as a miss.
+            for hash in bundle.outputs.object_hashes() {
+                if !backend.object_path(hash).is_file() {
+                    warn!("Object '{}' missing in the local cache, ignoring the entry", hash);
+                    remove_file(&path)?;
+                    return Ok(None);
+                }
+            }
+            touch(&path)?;
+            Ok(Some(bundle))
+        })
+        .await
+    }
+
+    /// Read the entry as it is, leaving its last use and any missing objects alone.
+    async fn peek(&self, inputs: &InputHashBundle) -> Result<Option<InputOutputBundle>> {
+        let data = match tokio::fs::read(self.entry_path(&inputs.hash)).await {
+            Ok(data) => data,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(err) => return Err(err.into()),
+        };
+        let bundle = serde_json::from_slice(&data).context("Cannot deserialize output")?;
+        Ok(Some(bundle))
+    }
+
+    async fn download_object_file(&self, item_hash: &str) -> Result<Pin<Box<dyn AsyncRead + Send>>> {
+        let path = self.object_path(item_hash);
+        let file = tokio::fs::File::open(&path)
+            .await
+            .with_context(|| format!("Opening object '{}' in the local cache", item_hash))?;
+        Ok(Box::pin(file))
+    }
+
+    async fn upload_object_file(
+        &self,
+        name: String,
+        item_hash: &str,
+        mut file: Pin<Box<dyn AsyncRead + Send>>,
+        _content_length: u64,
+    ) -> Result<()> {
+        let path = self.object_path(item_hash);
+        // Objects in the content addresable storage are "immutable", so duplicate uploads can be skipped.
+        if matches!(tokio::fs::metadata(&path).await, Ok(metadata) if metadata.is_file()) {
+            info!("Skipping upload for {} with hash '{}'", name, item_hash);
+            return self.run_blocking(move |_| touch(&path)).await;
+        } else {
+            info!("Storing object {} to '{}'", name, item_hash);
+        }
+        let dir = path.parent().context("No parent directory")?;
+        tokio::fs::create_dir_all(dir).await?;
+        // Write into a temporary file first, so that concurrent readers never see partial objects.
+        let (tmp_file, tmp_path) = NamedTempFile::new_in(dir)?.into_parts();
+        let mut tmp_file = tokio::fs::File::from_std(tmp_file);
+        let size = tokio::io::copy(&mut file, &mut tmp_file).await?;
+        tmp_file.flush().await?;
+        tmp_path.persist(&path)?;
+        if self.has_limits() {
+            self.run_blocking(move |backend| backend.add_usage(size)).await?;
+        }
+        Ok(())
+    }
+
+    /// Write hashes of inputs and outputs into the local cache, keyed by hashes of inputs.
+    async fn write(&self, inputs: &InputHashBundle, outputs: &OutputHashBundle, source: String) -> Result<()> {
+        let io_bundle = InputOutputBundle {
+            inputs: inputs.clone(),
+            outputs: outputs.clone(),
+            source,
+        };
+        let data = serde_json::to_vec(&io_bundle)?;
+        self.store_entry(&io_bundle.inputs.hash, &data).await?;
+        write_file(&self.latest_path(), &data).await
+    }
+
+    async fn write_entry(&self, inputs: &InputHashBundle, outputs: &OutputHashBundle, source: String) -> Result<()> {
+        let io_bundle = InputOutputBundle {
+            inputs: inputs.clone(),
+            outputs: outputs.clone(),
+            source,
+        };
+        self.store_entry(&io_bundle.inputs.hash, &serde_json::to_vec(&io_bundle)?)
+            .await
+    }
+
+    /// Read the copy of the latest entry of the capsule.
+    async fn latest_entry(&self) -> Result<Option<InputOutputBundle>> {
+        let data = match tokio::fs::read(self.latest_path()).await {
+            Ok(data) => data,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(err) => return Err(err.into()),
+        };
+        Ok(Some(
+            serde_json::from_slice(&data).context("Cannot deserialize the latest entry")?,
+        ))
+    }
+
+    /// List entries of the capsule in the local cache directory, most recently used first.
+    async fn list_entries(&self) -> Result<Vec<CacheEntry>> {
+        let mut files = Vec::new();
+        walk_files(
+            &self.root.join(ENTRIES_DIR).join(escape_capsule_id(&self.capsule_id)),
+            &mut files,
+        )?;
+        let mut entries = Vec::new();
+        for path in files {
+            // The entry may be gone already, if another capsule is evicting concurrently.
+            if let Ok(metadata) = fs::metadata(&path) {
+                entries.push(CacheEntry {
+                    hash: path.file_name().unwrap().to_string_lossy().to_string(),
+                    last_modified: Some(metadata.modified()?.into()),
+                    size: metadata.len(),
+                });
+            }
+        }
+        entries.sort_by_key(|entry| Reverse(entry.last_modified));
+        Ok(entries)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::iohashing::{FileOutput, Output};
+    use tempfile::TempDir;
+
+    fn backend(root: &Path, max_size: Option<u64>) -> LocalBackend {
+        LocalBackend {
+            root: root.to_owned(),
+            max_size,
+            max_age: None,
+            capsule_id: "wtf/capsule".to_string(),
+        }
+    }
+
+    fn bundles(hash: &str, object_hash: &str) -> (InputHashBundle, OutputHashBundle) {
+        let inputs = InputHashBundle {
+            hash: hash.to_string(),
+            ..Default::default()
+        };
+        let outputs = OutputHashBundle {
+            hash: hash.to_string(),
+            hash_details: vec![(
+                Output::File(FileOutput {
+                    filename: "out".into(),
+                    present: true,
+                    mode: 0o644,
+                    symlink: None,
+                }),
+                object_hash.to_string(),
+            )],
+            ..Default::default()
+        };
+        (inputs, outputs)
+    }
+
+    async fn store(backend: &LocalBackend, hash: &str, object_hash: &str, contents: &[u8]) {
+        let (inputs, outputs) = bundles(hash, object_hash);
+        backend
+            .upload_object_file(
+                "out".to_string(),
+                object_hash,
+                Box::pin(std::io::Cursor::new(contents.to_vec())),
+                contents.len() as u64,
+            )
+            .await
+            .unwrap();
+        backend.write(&inputs, &outputs, String::new()).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_local_roundtrip() {
+        let tmp_dir = TempDir::new().unwrap();
+        let backend = backend(tmp_dir.path(), None);
+        let (inputs, _) = bundles("aa11", "bb22");
+        assert!(backend.lookup(&inputs).await.unwrap().is_none());
+        store(&backend, "aa11", "bb22", b"contents").await;
+        let bundle = backend.lookup(&inputs).await.unwrap().unwrap();
+        assert_eq!(bundle.outputs.hash, "aa11");
+        let mut contents = Vec::new();
+        tokio::io::AsyncReadExt::read_to_end(&mut backend.download_object_file("bb22").await.unwrap(), &mut contents)
+            .await
+            .unwrap();
+        assert_eq!(contents, b"contents");
+    }
+
+    #[tokio::test]
+    async fn test_local_missing_object() {
+        let tmp_dir = TempDir::new().unwrap();
+        let backend = backend(tmp_dir.path(), None);
+        store(&backend, "aa11", "bb22", b"contents").await;
+        fs::remove_file(backend.object_path("bb22")).unwrap();
+        let (inputs, _) = bundles("aa11", "bb22");
+        // Peeking shows the entry as it is, and neither removes it nor marks it as used.
+        let past = FileTime::from_system_time(SystemTime::now() - Duration::from_secs(60));
+        filetime::set_file_mtime(backend.entry_path("aa11"), past).unwrap();
+        assert!(backend.peek(&inputs).await.unwrap().is_some());
+        let metadata = fs::metadata(backend.entry_path("aa11")).unwrap();
+        assert_eq!(FileTime::from_last_modification_time(&metadata), past);
+        assert!(backend.lookup(&inputs).await.unwrap().is_none());
+        assert!(!backend.entry_path("aa11").exists());
+    }
+
+    #[tokio::test]
+    async fn test_local_eviction() {
+        let tmp_dir = TempDir::new().unwrap();
+        // Enough space for about two entries with their objects.
+        let backend = backend(tmp_dir.path(), Some(1000));
+        store(&backend, "aa11", "bb11", &[1; 300]).await;
+        store(&backend, "aa22", "bb22", &[2; 300]).await;
+        // Use the first entry, so that the second one becomes the least recently used.
+        let past = FileTime::from_system_time(SystemTime::now() - Duration::from_secs(60));
+        filetime::set_file_mtime(backend.entry_path("aa11"), past).unwrap();
+        filetime::set_file_mtime(backend.entry_path("aa22"), past).unwrap();
+        backend.lookup(&bundles("aa11", "bb11").0).await.unwrap().unwrap();
+        store(&backend, "aa33", "bb33", &[3; 300]).await;
+
+        assert!(backend.entry_path("aa11").exists());
+        assert!(!backend.entry_path("aa22").exists());
+        assert!(backend.entry_path("aa33").exists());
+        // Objects of evicted entries stay around for the grace period.
+        assert!(backend.object_path("bb22").exists());
+    }
+
+    #[tokio::test]
+    async fn test_local_eviction_scans() {
+        let tmp_dir = TempDir::new().unwrap();
+        let backend = backend(tmp_dir.path(), Some(1000));
+        store(&backend, "aa11", "bb11", &[1; 100]).await;
+        // An unreferenced object past the grace period.
+        let stale = backend.object_path("cc33");
+        fs::create_dir_all(stale.parent().unwrap()).unwrap();
+        fs::write(&stale, [3; 100]).unwrap();
+        let past = FileTime::from_system_time(SystemTime::now() - GRACE_PERIOD * 2);
+        filetime::set_file_mtime(&stale, past).unwrap();
+
+        // Below the maximum size, writes don't scan the cache.
+        store(&backend, "aa22", "bb22", &[2; 100]).await;
+        assert!(stale.exists());
+        // Once the estimated size crosses it, they do, and the estimate is corrected.
+        store(&backend, "aa33", "bb33", &[3; 700]).await;
+        assert!(!stale.exists());
+        let usage = Usage::parse(&fs::read_to_string(tmp_dir.path().join(USAGE_FILE)).unwrap()).unwrap();
+        assert!(usage.size <= 1000);
+        assert!(SystemTime::now().duration_since(usage.scanned).unwrap() < SCAN_INTERVAL);
+    }
+
+    #[tokio::test]
+    async fn test_local_latest_entry() {
+        let tmp_dir = TempDir::new().unwrap();
+        let backend = backend(tmp_dir.path(), None);
+        assert!(backend.latest_entry().await.unwrap().is_none());
+        store(&backend, "aa11", "bb11", b"one").await;
+        store(&backend, "aa22", "bb22", b"two").await;
+        let latest = backend.latest_entry().await.unwrap().unwrap();
+        assert_eq!(latest.inputs.hash, "aa22");
+        // The latest entry is not a regular one.
+        assert_eq!(backend.list_entries().await.unwrap().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_local_list_entries() {
+        let tmp_dir = TempDir::new().unwrap();
+        let backend = backend(tmp_dir.path(), None);
+        assert!(backend.list_entries().await.unwrap().is_empty());
+        store(&backend, "aa11", "bb11", b"one").await;
+        store(&backend, "aa22", "bb22", b"two").await;
+        let past = FileTime::from_system_time(SystemTime::now() - Duration::from_secs(60));
+        filetime::set_file_mtime(backend.entry_path("aa11"), past).unwrap();
+        let hashes: Vec<String> = backend
+            .list_entries()
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|entry| entry.hash)
+            .collect();
+        assert_eq!(hashes, vec!["aa22", "aa11"]);
+    }
+}
@@ -1,4 +1,9 @@
 pub mod backend;
 pub mod dummy;
+pub mod http;
+pub mod local;
+pub mod reapi;
 pub mod s3;
+pub mod s3_gc;
+pub mod tiered;
 pub mod test;
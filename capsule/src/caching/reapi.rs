@@ -0,0 +1,854 @@
+use anyhow::{anyhow, bail, Context, Result};
+use async_trait::async_trait;
+use bytes::Bytes;
+use futures::channel::mpsc;
+use futures::{SinkExt, TryStreamExt};
+use log::info;
+use prost::Message;
+use serde_json;
+use std::collections::HashMap;
+use std::io;
+use std::path::PathBuf;
+use std::pin::Pin;
+use std::sync::Mutex;
+use std::time::SystemTime;
+use tokio::io::{AsyncRead, AsyncReadExt};
+use tokio_util::io::StreamReader;
+use tonic::transport::{Channel, Endpoint};
+use tonic::Code;
+
+use crate::caching::backend::{CacheEntry, CachingBackend};
+use crate::config::Config;
+use crate::iohashing::{
+    bytes_hash, string_hash, HashFunction, InputHashBundle, InputOutputBundle, Output, OutputHashBundle, TreeOutput,
+};
+
+/// Generated code of the Remote Execution API protocol.
+pub mod proto {
+    pub mod remote_execution {
+        tonic::include_proto!("build.bazel.remote.execution.v2");
+    }
+    pub mod bytestream {
+        tonic::include_proto!("google.bytestream");
+    }
+}
+
+use proto::bytestream::byte_stream_client::ByteStreamClient;
+use proto::bytestream::{ReadRequest, WriteRequest};
+use proto::remote_execution::action_cache_client::ActionCacheClient;
+use proto::remote_execution::content_addressable_storage_client::ContentAddressableStorageClient;
+use proto::remote_execution::{
+    command, Action, ActionResult, Command, Digest, ExecutedActionMetadata, FindMissingBlobsRequest,
+    GetActionResultRequest, OutputFile, OutputSymlink, UpdateActionResultRequest,
+};
+
+/// Type URL of the capsule cache entry, stored in the auxiliary metadata of action results.
+const BUNDLE_TYPE_URL: &str = "type.googleapis.com/capsule.InputOutputBundle";
+
+/// Environment variable of the action command holding the inputs hash.
+const INPUTS_HASH_VAR: &str = "CAPSULE_INPUTS_HASH";
+
+/// Path of the output file referencing the combined log, for which the ActionResult has no field of its own.
+/// Paths of the other output files are workspace paths starting with "//", so it can't clash with them.
+const COMBINED_LOG_PATH: &str = ".capsule/combined.log";
+
+/// Size of the chunks in ByteStream writes.
+const CHUNK_SIZE: usize = 64 * 1024;
+
+/// A backend for the Bazel Remote Execution API (REAPI) ActionCache, ContentAddressableStorage and
+/// ByteStream services, as implemented by buildbarn, buildgrid and others.
+///
+/// Each capsule invocation is represented by an Action without an input root, whose Command has the capsule ID
+/// as arguments and the inputs hash in its environment. The ActionResult lists the output files, including those in
+/// output directories, the captured streams and the exit code, and carries the whole capsule cache entry in its auxiliary metadata.
+pub struct ReapiBackend {
+    /// gRPC channel to the REAPI server.
+    pub channel: Channel,
+
+    /// REAPI instance name.
+    pub instance_name: String,
+
+    /// Workspace root, to find sizes of output files.
+    pub workspace_root: Option<String>,
+
+    /// Capsule ID
+    pub capsule_id: String,
+
+    /// Sizes of blobs seen in lookups and uploads, as REAPI addresses blobs by hash and size.
+    sizes: Mutex<HashMap<String, i64>>,
+}
+
+fn blob_digest(data: &[u8]) -> Digest {
+    Digest {
+        hash: bytes_hash(data),
+        size_bytes: data.len() as i64,
+    }
+}
+
+impl ReapiBackend {
+    pub fn new(channel: Channel, instance_name: String, workspace_root: Option<String>, capsule_id: String) -> Self {
+        Self {
+            channel,
+            instance_name,
+            workspace_root,
+            capsule_id,
+            sizes: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn from_config(config: &Config) -> Result<Self> {
+        // Objects are stored under their hashes as blob digests, which the server verifies.
+        if config.hash_function() != HashFunction::Sha256 {
+            bail!("The REAPI backend only supports the sha256 hash function");
+        }
+        let endpoint = config
+            .reapi_endpoint
+            .clone()
+            .ok_or_else(|| anyhow!("REAPI endpoint not specified"))?;
+        // grpc:// and grpcs:// are the usual notations in Bazel flags, tonic wants the plain HTTP scheme.
+        let endpoint = if let Some(rest) = endpoint.strip_prefix("grpc://") {
+            format!("http://{}", rest)
+        } else if let Some(rest) = endpoint.strip_prefix("grpcs://") {
+            format!("https://{}", rest)
+        } else {
+            endpoint
+        };
+        let channel = Endpoint::from_shared(endpoint)
+            .context("Invalid REAPI endpoint")?
+            .connect_lazy();
+        Ok(Self::new(
+            channel,
+            config.reapi_instance_name.clone().unwrap_or_default(),
+            config.workspace_root.clone(),
+            config.capsule_id.as_deref().unwrap().to_string(),
+        ))
+    }
+
+    /// Returns the serialized Command and Action corresponding to the given inputs, and the action digest.
+    fn action(&self, inputs: &InputHashBundle) -> (Vec<Vec<u8>>, Digest) {
+        self.encode_action(Some(&inputs.hash))
+    }
+
+    /// Returns the serialized Command and Action holding the latest entry of the capsule, and the action digest.
+    /// It is the only action of the capsule whose command has no inputs hash.
+    fn latest_action(&self) -> (Vec<Vec<u8>>, Digest) {
+        self.encode_action(None)
+    }
+
+    fn encode_action(&self, inputs_hash: Option<&str>) -> (Vec<Vec<u8>>, Digest) {
+        let command = Command {
+            arguments: vec![
+                "capsule".to_string(),
+                "--capsule_id".to_string(),
+                self.capsule_id.clone(),
+            ],
+            environment_variables: inputs_hash
+                .map(|hash| command::EnvironmentVariable {
+                    name: INPUTS_HASH_VAR.to_string(),
+                    value: hash.to_string(),
+                })
+                .into_iter()
+                .collect(),
+        }
+        .encode_to_vec();
+        let action = Action {
+            command_digest: Some(blob_digest(&command)),
+            ..Default::default()
+        }
+        .encode_to_vec();
+        let digest = blob_digest(&action);
+        (vec![command, action], digest)
+    }
+
+    fn resource_name(&self, name: &str) -> String {
+        if self.instance_name.is_empty() {
+            name.to_string()
+        } else {
+            format!("{}/{}", self.instance_name, name)
+        }
+    }
+
+    async fn blob_missing(&self, digest: &Digest) -> Result<bool> {
+        let response = ContentAddressableStorageClient::new(self.channel.clone())
+            .find_missing_blobs(FindMissingBlobsRequest {
+                instance_name: self.instance_name.clone(),
+                blob_digests: vec![digest.clone()],
+            })
+            .await?
+            .into_inner();
+        Ok(!response.missing_blob_digests.is_empty())
+    }
+
+    async fn write_blob(&self, digest: &Digest, file: Pin<Box<dyn AsyncRead + Send>>) -> Result<()> {
+        // Upload resource names have to be unique per upload.
+        let upload_id = string_hash(&format!(
+            "{}-{:?}-{}",
+            std::process::id(),
+            SystemTime::now(),
+            digest.hash
+        ));
+        let resource_name = self.resource_name(&format!(
+            "uploads/{}/blobs/{}/{}",
+            upload_id, digest.hash, digest.size_bytes
+        ));
+        // Feed the chunks of the file through a channel, so that the request stream doesn't borrow anything.
+        let (mut sender, requests) = mpsc::channel(1);
+        tokio::spawn(async move {
+            let mut file = file;
+            let mut offset = 0;
+            loop {
+                let mut data = vec![0; CHUNK_SIZE];
+                let mut len = 0;
+                while len < CHUNK_SIZE {
+                    match file.read(&mut data[len..]).await {
+                        Ok(0) => break,
+                        Ok(n) => len += n,
+                        // Ending the stream early makes the server reject the incomplete upload.
+                        Err(_) => return,
+                    }
+                }
+                data.truncate(len);
+                let request = WriteRequest {
+                    // Only the first request has to carry the resource name.
+                    resource_name: if offset == 0 {
+                        resource_name.clone()
+                    } else {
+                        String::new()
+                    },
+                    write_offset: offset,
+                    // The last request is the first short one, possibly empty.
+                    finish_write: len < CHUNK_SIZE,
+                    data,
+                };
+                offset += len as i64;
+                if sender.send(request).await.is_err() || len < CHUNK_SIZE {
+                    return;
+                }
+            }
+        });
+        let response = ByteStreamClient::new(self.channel.clone())
+            .write(requests)
+            .await?
+            .into_inner();
+        if response.committed_size != digest.size_bytes {
+            return Err(anyhow!(
+                "Uploaded {} bytes instead of {} for '{}'",
+                response.committed_size,
+                digest.size_bytes,
+                digest.hash
+            ));
+        }
+        Ok(())
+    }
+
+    /// Read the capsule cache entry stored in the action result of the given action.
+    async fn get_entry(&self, action_digest: Digest) -> Result<Option<InputOutputBundle>> {
+        let response = ActionCacheClient::new(self.channel.clone())
+            .get_action_result(GetActionResultRequest {
+                instance_name: self.instance_name.clone(),
+                action_digest: Some(action_digest),
+            })
+            .await;
+        let action_result = match response {
+            Ok(response) => response.into_inner(),
+            Err(status) if status.code() == Code::NotFound => return Ok(None), // Cache miss
+            Err(status) => return Err(status.into()),
+        };
+        // Remember the sizes, so that we can download the objects.
+        {
+            let mut sizes = self.sizes.lock().unwrap();
+            let digests = action_result
+                .output_files
+                .iter()
+                .filter_map(|output_file| output_file.digest.as_ref())
+                .chain(&action_result.stdout_digest)
+                .chain(&action_result.stderr_digest);
+            for digest in digests {
+                sizes.insert(digest.hash.clone(), digest.size_bytes);
+            }
+        }
+        let metadata = action_result.execution_metadata.unwrap_or_default();
+        let bundle = metadata
+            .auxiliary_metadata
+            .iter()
+            .find(|any| any.type_url == BUNDLE_TYPE_URL)
+            .context("Action result is not a capsule cache entry")?;
+        let bundle = serde_json::from_slice(&bundle.value).context("Cannot deserialize output")?;
+        Ok(Some(bundle))
+    }
+
+    /// Store the action result of the given action.
+    async fn put_entry(&self, blobs: Vec<Vec<u8>>, action_digest: Digest, action_result: ActionResult) -> Result<()> {
+        // Store the command and the action itself, so that the action cache entry is valid for servers which check it.
+        for blob in blobs {
+            let digest = blob_digest(&blob);
+            if self.blob_missing(&digest).await? {
+                self.write_blob(&digest, Box::pin(io::Cursor::new(blob))).await?;
+            }
+        }
+        ActionCacheClient::new(self.channel.clone())
+            .update_action_result(UpdateActionResultRequest {
+                instance_name: self.instance_name.clone(),
+                action_digest: Some(action_digest),
+                action_result: Some(action_result),
+            })
+            .await?;
+        Ok(())
+    }
+
+    // The digest of an object that's about to be referenced in an action result. Its size is known from the upload,
+    // or else read from the file it was uploaded from, if there is one.
+    fn object_digest(&self, hash: &str, path: Option<PathBuf>) -> Result<Digest> {
+        let known_size = self.sizes.lock().unwrap().get(hash).copied();
+        let size_bytes = match (known_size, path) {
+            (Some(size), _) => size,
+            (None, Some(path)) => std::fs::metadata(&path)
+                .with_context(|| format!("Reading size of '{}'", path.display()))?
+                .len() as i64,
+            (None, None) => bail!("Unknown size of object '{}'", hash),
+        };
+        Ok(Digest {
+            hash: hash.to_string(),
+            size_bytes,
+        })
+    }
+
+    /// The action result holding the entry, which references its outputs so that the server keeps them.
+    fn action_result(
+        &self,
+        inputs: &InputHashBundle,
+        outputs: &OutputHashBundle,
+        source: String,
+    ) -> Result<ActionResult> {
+        let io_bundle = InputOutputBundle {
+            inputs: inputs.clone(),
+            outputs: outputs.clone(),
+            source,
+        };
+        let (mut output_files, mut output_symlinks) = (Vec::new(), Vec::new());
+        let (mut stdout_digest, mut stderr_digest) = (None, None);
+        for (output, hash) in &outputs.hash_details {
+            match output {
+                Output::File(file_output) => {
+                    if let Some(ref target) = file_output.symlink {
+                        output_symlinks.push(OutputSymlink {
+                            path: file_output.filename.to_string(),
+                            target: target.clone(),
+                        });
+                    } else if file_output.present {
+                        let path = file_output.filename.to_path(&self.workspace_root)?;
+                        output_files.push(OutputFile {
+                            path: file_output.filename.to_string(),
+                            digest: Some(self.object_digest(hash, Some(path))?),
+                            is_executable: file_output.mode & 0o111 != 0,
+                        });
+                    }
+                }
+                Output::Stdout => stdout_digest = Some(self.object_digest(hash, None)?),
+                Output::Stderr => stderr_digest = Some(self.object_digest(hash, None)?),
+                Output::Combined => output_files.push(OutputFile {
+                    path: COMBINED_LOG_PATH.to_string(),
+                    digest: Some(self.object_digest(hash, None)?),
+                    is_executable: false,
+                }),
+                // Files of output directories are listed one by one rather than as a Tree, so that their sizes are
+                // known from the action result alone. The rest of the directory is in the manifest.
+                Output::Tree(TreeOutput {
+                    dirname,
+                    manifest: Some(manifest),
+                }) => {
+                    let dir = dirname.to_path(&self.workspace_root)?;
+                    for (path, hash, mode) in manifest.files() {
+                        output_files.push(OutputFile {
+                            path: format!("{}/{}", dirname, path.display()),
+                            digest: Some(self.object_digest(hash, Some(dir.join(&path)))?),
+                            is_executable: mode & 0o111 != 0,
+                        });
+                    }
+                }
+                _ => {}
+            }
+        }
+        Ok(ActionResult {
+            output_files,
+            output_symlinks,
+            stdout_digest,
+            stderr_digest,
+            exit_code: outputs.result_code().unwrap_or_default(),
+            execution_metadata: Some(ExecutedActionMetadata {
+                worker: io_bundle.source.clone(),
+                auxiliary_metadata: vec![prost_types::Any {
+                    type_url: BUNDLE_TYPE_URL.to_string(),
+                    value: serde_json::to_vec(&io_bundle)?,
+                }],
+            }),
+        })
+    }
+}
+
+#[async_trait]
+impl CachingBackend for ReapiBackend {
+    fn name(&self) -> &'static str {
+        "reapi"
+    }
+
+    /// Lookup inputs in the REAPI action cache.
+    async fn lookup(&self, inputs: &InputHashBundle) -> Result<Option<InputOutputBundle>> {
+        let (_, action_digest) = self.action(inputs);
+        self.get_entry(action_digest).await
+    }
+
+    /// Read a blob from the CAS via ByteStream, and return AsyncRead object for consuming by capsule.
+    async fn download_object_file(&self, item_hash: &str) -> Result<Pin<Box<dyn AsyncRead + Send>>> {
+        let size = *self
+            .sizes
+            .lock()
+            .unwrap()
+            .get(item_hash)
+            .ok_or_else(|| anyhow!("Unknown size of object '{}'", item_hash))?;
+        let stream = ByteStreamClient::new(self.channel.clone())
+            .read(ReadRequest {
+                resource_name: self.resource_name(&format!("blobs/{}/{}", item_hash, size)),
+                ..Default::default()
+            })
+            .await?
+            .into_inner();
+        let stream = stream
+            .map_ok(|response| Bytes::from(response.data))
+            .map_err(io::Error::other);
+        Ok(Box::pin(StreamReader::new(stream)))
+    }
+
+    async fn upload_object_file(
+        &self,
+        name: String,
+        item_hash: &str,
+        file: Pin<Box<dyn AsyncRead + Send>>,
+        content_length: u64,
+    ) -> Result<()> {
+        let digest = Digest {
+            hash: item_hash.to_string(),
+            size_bytes: content_length as i64,
+        };
+        self.sizes
+            .lock()
+            .unwrap()
+            .insert(digest.hash.clone(), digest.size_bytes);
+        // Objects in the content addresable storage are "immutable", so duplicate uploads can be skipped.
+        if !self.blob_missing(&digest).await? {
+            info!("Skipping upload for {} with hash '{}'", name, item_hash);
+            return Ok(());
+        } else {
+            info!("Uploading object {} to '{}'", name, item_hash);
+        }
+        self.write_blob(&digest, file).await
+    }
+
+    /// Write hashes of inputs and outputs into the REAPI action cache, keyed by the action digest.
+    async fn write(&self, inputs: &InputHashBundle, outputs: &OutputHashBundle, source: String) -> Result<()> {
+        let action_result = self.action_result(inputs, outputs, source)?;
+        let (blobs, action_digest) = self.action(inputs);
+        self.put_entry(blobs, action_digest, action_result.clone()).await?;
+        let (latest_blobs, latest_action_digest) = self.latest_action();
+        self.put_entry(latest_blobs, latest_action_digest, action_result).await
+    }
+
+    async fn write_entry(&self, inputs: &InputHashBundle, outputs: &OutputHashBundle, source: String) -> Result<()> {
+        let action_result = self.action_result(inputs, outputs, source)?;
+        let (blobs, action_digest) = self.action(inputs);
+        self.put_entry(blobs, action_digest, action_result).await
+    }
+
+    /// Read the latest entry of the capsule from the REAPI action cache.
+    async fn latest_entry(&self) -> Result<Option<InputOutputBundle>> {
+        let (_, action_digest) = self.latest_action();
+        self.get_entry(action_digest).await
+    }
+
+    async fn list_entries(&self) -> Result<Vec<CacheEntry>> {
+        // The Remote Execution API has no way to enumerate the action cache.
+        Err(anyhow!("Listing cache entries is not supported by the reapi backend"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::iohashing::FileOutput;
+    use crate::output_tree::{self, Directory};
+    use proto::bytestream::byte_stream_server::{ByteStream, ByteStreamServer};
+    use proto::bytestream::{ReadResponse, WriteResponse};
+    use proto::remote_execution::action_cache_server::{ActionCache, ActionCacheServer};
+    use proto::remote_execution::content_addressable_storage_server::{
+        ContentAddressableStorage, ContentAddressableStorageServer,
+    };
+    use proto::remote_execution::FindMissingBlobsResponse;
+    use std::collections::BTreeMap;
+    use std::sync::Arc;
+    use tokio::net::TcpListener;
+    use tokio_stream::wrappers::TcpListenerStream;
+    use tonic::{Request, Response, Status, Streaming};
+
+    // An in-process stand-in for a REAPI server, keeping everything in memory.
+    #[derive(Default, Clone)]
+    struct StandIn {
+        action_results: Arc<Mutex<HashMap<String, ActionResult>>>,
+        blobs: Arc<Mutex<HashMap<String, Vec<u8>>>>,
+        writes: Arc<Mutex<usize>>,
+    }
+
+    #[tonic::async_trait]
+    impl ActionCache for StandIn {
+        async fn get_action_result(
+            &self,
+            request: Request<GetActionResultRequest>,
+        ) -> Result<Response<ActionResult>, Status> {
+            let digest = request.into_inner().action_digest.unwrap();
+            match self.action_results.lock().unwrap().get(&digest.hash) {
+                Some(result) => Ok(Response::new(result.clone())),
+                None => Err(Status::not_found("no such action")),
+            }
+        }
+
+        async fn update_action_result(
+            &self,
+            request: Request<UpdateActionResultRequest>,
+        ) -> Result<Response<ActionResult>, Status> {
+            let request = request.into_inner();
+            let result = request.action_result.unwrap();
+            self.action_results
+                .lock()
+                .unwrap()
+                .insert(request.action_digest.unwrap().hash, result.clone());
+            Ok(Response::new(result))
+        }
+    }
+
+    #[tonic::async_trait]
+    impl ContentAddressableStorage for StandIn {
+        async fn find_missing_blobs(
+            &self,
+            request: Request<FindMissingBlobsRequest>,
+        ) -> Result<Response<FindMissingBlobsResponse>, Status> {
+            let blobs = self.blobs.lock().unwrap();
+            let missing_blob_digests = request
+                .into_inner()
+                .blob_digests
+                .into_iter()
+                .filter(|digest| !blobs.contains_key(&digest.hash))
+                .collect();
+            Ok(Response::new(FindMissingBlobsResponse { missing_blob_digests }))
+        }
+    }
+
+    #[tonic::async_trait]
+    impl ByteStream for StandIn {
+        type ReadStream = futures::stream::Iter<std::vec::IntoIter<Result<ReadResponse, Status>>>;
+
+        async fn read(&self, request: Request<ReadRequest>) -> Result<Response<Self::ReadStream>, Status> {
+            let resource_name = request.into_inner().resource_name;
+            let hash = resource_name.split('/').nth(1).unwrap_or_default();
+            let data = self
+                .blobs
+                .lock()
+                .unwrap()
+                .get(hash)
+                .cloned()
+                .ok_or_else(|| Status::not_found("no such blob"))?;
+            let chunks = data
+                .chunks(3)
+                .map(|chunk| ReadResponse { data: chunk.to_vec() })
+                .map(Ok)
+                .collect::<Vec<_>>();
+            Ok(Response::new(futures::stream::iter(chunks)))
+        }
+
+        async fn write(&self, request: Request<Streaming<WriteRequest>>) -> Result<Response<WriteResponse>, Status> {
+            let mut stream = request.into_inner();
+            let mut resource_name = String::new();
+            let mut data = Vec::new();
+            while let Some(request) = stream.message().await? {
+                if resource_name.is_empty() {
+                    resource_name = request.resource_name;
+                }
+                data.extend(request.data);
+            }
+            // Resource name is "uploads/<uuid>/blobs/<hash>/<size>".
+            let hash = resource_name.split('/').nth(3).unwrap_or_default();
+            if bytes_hash(&data) != hash {
+                return Err(Status::invalid_argument("hash mismatch"));
+            }
+            *self.writes.lock().unwrap() += 1;
+            let committed_size = data.len() as i64;
+            self.blobs.lock().unwrap().insert(hash.to_string(), data);
+            Ok(Response::new(WriteResponse { committed_size }))
+        }
+    }
+
+    async fn start_server() -> (Channel, StandIn) {
+        let stand_in = StandIn::default();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = tonic::transport::Server::builder()
+            .add_service(ActionCacheServer::new(stand_in.clone()))
+            .add_service(ContentAddressableStorageServer::new(stand_in.clone()))
+            .add_service(ByteStreamServer::new(stand_in.clone()))
+            .serve_with_incoming(TcpListenerStream::new(listener));
+        tokio::spawn(server);
+        let channel = Endpoint::from_shared(format!("http://{}", addr))
+            .unwrap()
+            .connect_lazy();
+        (channel, stand_in)
+    }
+
+    #[tokio::test]
+    async fn test_reapi_roundtrip() {
+        let (channel, stand_in) = start_server().await;
+        let backend = ReapiBackend::new(channel, String::new(), None, "wtf".to_string());
+        let inputs = InputHashBundle {
+            hash: "1234".to_string(),
+            ..Default::default()
+        };
+        assert!(backend.lookup(&inputs).await.unwrap().is_none());
+
+        let contents = vec![7u8; CHUNK_SIZE * 2 + 10];
+        let object_hash = bytes_hash(&contents);
+        for _ in 0..2 {
+            backend
+                .upload_object_file(
+                    "out".to_string(),
+                    &object_hash,
+                    Box::pin(io::Cursor::new(contents.clone())),
+                    contents.len() as u64,
+                )
+                .await
+                .unwrap();
+        }
+        // The second upload is skipped, as the blob is no longer missing.
+        assert_eq!(*stand_in.writes.lock().unwrap(), 1);
+
+        let outputs = OutputHashBundle {
+            hash: "5678".to_string(),
+            hash_details: vec![
+                (
+                    Output::File(FileOutput {
+                        filename: "out".into(),
+                        present: true,
+                        mode: 0o755,
+                        symlink: None,
+                    }),
+                    object_hash.clone(),
+                ),
+                (Output::ExitCode(0), string_hash("0")),
+            ],
+            ..Default::default()
+        };
+        backend.write(&inputs, &outputs, "job".to_string()).await.unwrap();
+
+        // A fresh backend learns the object sizes from the action result.
+        let backend = ReapiBackend::new(backend.channel.clone(), String::new(), None, "wtf".to_string());
+        let bundle = backend.lookup(&inputs).await.unwrap().unwrap();
+        assert_eq!(bundle.outputs.hash, "5678");
+        assert_eq!(bundle.source, "job");
+        let mut downloaded = Vec::new();
+        backend
+            .download_object_file(&object_hash)
+            .await
+            .unwrap()
+            .read_to_end(&mut downloaded)
+            .await
+            .unwrap();
+        assert_eq!(downloaded, contents);
+
+        let action_result = stand_in
+            .action_results
+            .lock()
+            .unwrap()
+            .values()
+            .next()
+            .cloned()
+            .unwrap();
+        assert_eq!(action_result.output_files[0].path, "out");
+        assert!(action_result.output_files[0].is_executable);
+
+        // The action and its command are stored in the CAS, with the inputs hash in the command.
+        let (_, action_digest) = backend.action(&inputs);
+        let blobs = stand_in.blobs.lock().unwrap();
+        let action = Action::decode(&blobs[&action_digest.hash][..]).unwrap();
+        assert!(action.input_root_digest.is_none());
+        let command = Command::decode(&blobs[&action.command_digest.unwrap().hash][..]).unwrap();
+        assert_eq!(command.arguments, ["capsule", "--capsule_id", "wtf"]);
+        assert_eq!(command.environment_variables[0].name, INPUTS_HASH_VAR);
+        assert_eq!(command.environment_variables[0].value, "1234");
+    }
+
+    #[tokio::test]
+    async fn test_reapi_captured_streams() {
+        let (channel, stand_in) = start_server().await;
+        let backend = ReapiBackend::new(channel, String::new(), None, "wtf".to_string());
+        let inputs = InputHashBundle {
+            hash: "1234".to_string(),
+            ..Default::default()
+        };
+        let streams = [
+            (Output::Stdout, b"out\n".to_vec()),
+            (Output::Stderr, b"error\n".to_vec()),
+            (Output::Combined, b"combined log\n".to_vec()),
+        ];
+        let mut hash_details = Vec::new();
+        for (output, contents) in &streams {
+            let hash = bytes_hash(contents);
+            backend
+                .upload_object_file(
+                    output.to_string(),
+                    &hash,
+                    Box::pin(io::Cursor::new(contents.clone())),
+                    contents.len() as u64,
+                )
+                .await
+                .unwrap();
+            hash_details.push((output.clone(), hash));
+        }
+        let outputs = OutputHashBundle {
+            hash_details,
+            ..Default::default()
+        };
+        backend.write(&inputs, &outputs, "job".to_string()).await.unwrap();
+
+        let action_result = stand_in
+            .action_results
+            .lock()
+            .unwrap()
+            .values()
+            .next()
+            .cloned()
+            .unwrap();
+        assert_eq!(action_result.stdout_digest, Some(blob_digest(b"out\n")));
+        assert_eq!(action_result.stderr_digest, Some(blob_digest(b"error\n")));
+        assert_eq!(action_result.output_files[0].path, COMBINED_LOG_PATH);
+
+        // A fresh backend learns the sizes of the streams from the action result, and can replay them.
+        let backend = ReapiBackend::new(backend.channel.clone(), String::new(), None, "wtf".to_string());
+        let bundle = backend.lookup(&inputs).await.unwrap().unwrap();
+        for (output, contents) in &streams {
+            let (_, hash) = bundle
+                .outputs
+                .hash_details
+                .iter()
+                .find(|(item, _)| item == output)
+                .unwrap();
+            let mut downloaded = Vec::new();
+            backend
+                .download_object_file(hash)
+                .await
+                .unwrap()
+                .read_to_end(&mut downloaded)
+                .await
+                .unwrap();
+            assert_eq!(&downloaded, contents);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_reapi_output_directory() {
+        let (channel, stand_in) = start_server().await;
+        let backend = ReapiBackend::new(channel, String::new(), None, "wtf".to_string());
+        let inputs = InputHashBundle {
+            hash: "1234".to_string(),
+            ..Default::default()
+        };
+        let (top, nested) = (b"top\n".to_vec(), b"nested\n".to_vec());
+        for contents in [&top, &nested] {
+            backend
+                .upload_object_file(
+                    "tree file".to_string(),
+                    &bytes_hash(contents),
+                    Box::pin(io::Cursor::new(contents.clone())),
+                    contents.len() as u64,
+                )
+                .await
+                .unwrap();
+        }
+        let file = |contents: &[u8], mode| output_tree::Entry::File {
+            hash: bytes_hash(contents),
+            mode,
+        };
+        let manifest = Directory {
+            mode: 0o755,
+            entries: BTreeMap::from([
+                ("top".to_string(), file(&top, 0o755)),
+                (
+                    "sub".to_string(),
+                    output_tree::Entry::Directory(Directory {
+                        mode: 0o755,
+                        entries: BTreeMap::from([("nested".to_string(), file(&nested, 0o644))]),
+                    }),
+                ),
+            ]),
+        };
+        let outputs = OutputHashBundle {
+            hash_details: vec![(
+                Output::Tree(TreeOutput {
+                    dirname: "out".into(),
+                    manifest: Some(manifest.clone()),
+                }),
+                manifest.hash(HashFunction::Sha256),
+            )],
+            ..Default::default()
+        };
+        backend.write(&inputs, &outputs, "job".to_string()).await.unwrap();
+
+        let action_result = stand_in
+            .action_results
+            .lock()
+            .unwrap()
+            .values()
+            .next()
+            .cloned()
+            .unwrap();
+        let files: Vec<_> = action_result
+            .output_files
+            .iter()
+            .map(|file| (file.path.as_str(), file.digest.clone().unwrap(), file.is_executable))
+            .collect();
+        assert_eq!(
+            files,
+            [
+                ("out/sub/nested", blob_digest(&nested), false),
+                ("out/top", blob_digest(&top), true),
+            ]
+        );
+
+        // A fresh backend learns the sizes of the files in the directory from the action result.
+        let backend = ReapiBackend::new(backend.channel.clone(), String::new(), None, "wtf".to_string());
+        backend.lookup(&inputs).await.unwrap().unwrap();
+        for contents in [top, nested] {
+            let mut downloaded = Vec::new();
+            backend
+                .download_object_file(&bytes_hash(&contents))
+                .await
+                .unwrap()
+                .read_to_end(&mut downloaded)
+                .await
+                .unwrap();
+            assert_eq!(downloaded, contents);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_reapi_capsule_id() {
+        let (channel, _) = start_server().await;
+        let backend1 = ReapiBackend::new(channel.clone(), String::new(), None, "wtf1".to_string());
+        let backend2 = ReapiBackend::new(channel, String::new(), None, "wtf2".to_string());
+        let inputs = InputHashBundle {
+            hash: "1234".to_string(),
+            ..Default::default()
+        };
+        backend1
+            .write(&inputs, &OutputHashBundle::default(), String::new())
+            .await
+            .unwrap();
+        assert!(backend1.lookup(&inputs).await.unwrap().is_some());
+        assert!(backend2.lookup(&inputs).await.unwrap().is_none());
+    }
+}
@@ -2,20 +2,26 @@ use anyhow::anyhow;
 use anyhow::{Context, Result};
 use async_compression::tokio::bufread::{GzipDecoder, GzipEncoder};
 use async_trait::async_trait;
-use futures::TryStreamExt;
+use futures::{try_join, TryStreamExt};
 use hyperx::header::CacheDirective;
 use log::{error, info};
 use rusoto_core::region::Region;
-use rusoto_s3::{GetObjectRequest, HeadObjectRequest, PutObjectRequest, S3Client, S3 as _};
+use rusoto_s3::{
+    GetObjectRequest, HeadObjectRequest, ListObjectsV2Request, Object, PutObjectRequest, S3Client, S3 as _,
+};
 use serde_json;
+use std::cmp::Reverse;
 use std::pin::Pin;
 use tempfile::tempfile;
 use tokio::io::{AsyncRead, AsyncReadExt, AsyncSeekExt, BufReader};
 use tokio_util::codec;
 
-use crate::caching::backend::CachingBackend;
+use crate::caching::backend::{CacheEntry, CachingBackend};
 use crate::config::Config;
-use crate::iohashing::{InputHashBundle, InputOutputBundle, OutputHashBundle};
+use crate::iohashing::{HashFunction, InputHashBundle, InputOutputBundle, OutputHashBundle};
+
+/// Key of the latest entry of a capsule, under the capsule ID.
+pub(crate) const LATEST_KEY: &str = "latest";
 
 pub struct S3Backend {
     /// S3 bucket for keys
@@ -35,6 +41,9 @@ pub struct S3Backend {
 
     /// Capsule ID
     pub capsule_id: String,
+
+    /// Hash function of the objects.
+    pub hash_function: HashFunction,
 }
 
 impl S3Backend {
@@ -96,6 +105,7 @@ impl S3Backend {
             client_uploads,
             client_downloads,
             capsule_id: config.capsule_id.as_deref().unwrap().to_string(),
+            hash_function: config.hash_function(),
         })
     }
 
@@ -103,8 +113,17 @@ impl S3Backend {
         format!("{}/{}/{}", &self.capsule_id, &key[0..2], key)
     }
 
+    // The latest entry doesn't have the two character directory, so it never looks like a regular entry.
+    fn latest_key(&self) -> String {
+        format!("{}/{}", &self.capsule_id, LATEST_KEY)
+    }
+
+    // Objects hashed with other functions than SHA256 are kept apart, under the name of the function.
     fn normalize_object_key(&self, key: &str) -> String {
-        format!("{}/{}", &key[0..2], key)
+        match self.hash_function {
+            HashFunction::Sha256 => format!("{}/{}", &key[0..2], key),
+            hash_function => format!("{}/{}/{}", hash_function, &key[0..2], key),
+        }
     }
 
     async fn object_exists(&self, request: HeadObjectRequest) -> Result<bool> {
@@ -123,30 +142,35 @@ impl S3Backend {
             }
         }
     }
-}
 
-#[async_trait]
-impl CachingBackend for S3Backend {
-    fn name(&self) -> &'static str {
-        "s3"
+    async fn put_entry(&self, key: String, data: Vec<u8>) -> Result<()> {
+        let data_len = data.len();
+        let request = PutObjectRequest {
+            bucket: self.bucket.clone(),
+            body: Some(data.into()),
+            cache_control: Some(CacheDirective::NoCache.to_string()),
+            content_length: Some(data_len as i64),
+            content_type: Some("application/json".to_owned()),
+            key,
+            ..Default::default()
+        };
+        self.client.put_object(request).await?;
+        Ok(())
     }
 
-    /// Lookup inputs in S3.
-    async fn lookup(&self, inputs: &InputHashBundle) -> Result<Option<InputOutputBundle>> {
-        let key = self.normalize_key(&inputs.hash);
+    /// Read the raw contents of a cache entry in the keys bucket, or None if there's no such entry.
+    pub(crate) async fn read_entry(&self, key: &str) -> Result<Option<Vec<u8>>> {
         let request = GetObjectRequest {
             bucket: self.bucket.clone(),
-            key,
+            key: key.to_string(),
             ..Default::default()
         };
         let response = self.client.get_object(request).await;
         match response {
-            Err(rusoto_core::RusotoError::Service(rusoto_s3::GetObjectError::NoSuchKey(_))) => {
-                Ok(None) // Cache miss
-            }
+            Err(rusoto_core::RusotoError::Service(rusoto_s3::GetObjectError::NoSuchKey(_))) => Ok(None),
             Err(rusoto_core::RusotoError::Unknown(resp)) if resp.status == 404 => {
                 // No such bucket
-                Ok(None) // Cache miss
+                Ok(None)
             }
             Err(e) => Err(e.into()),
             Ok(response) => {
@@ -157,14 +181,59 @@ impl CachingBackend for S3Backend {
                     .read_to_end(&mut body)
                     .await
                     .context("failed to read HTTP body")?;
+                Ok(Some(body))
+            }
+        }
+    }
+
+    /// List all keys in the bucket starting with the prefix, following the pagination of S3.
+    pub(crate) async fn list_keys(&self, bucket: &str, prefix: Option<String>) -> Result<Vec<Object>> {
+        let mut keys = Vec::new();
+        let mut continuation_token = None;
+        loop {
+            let request = ListObjectsV2Request {
+                bucket: bucket.to_string(),
+                prefix: prefix.clone(),
+                continuation_token: continuation_token.take(),
+                ..Default::default()
+            };
+            let response = match self.client.list_objects_v2(request).await {
+                Ok(response) => response,
+                Err(rusoto_core::RusotoError::Service(rusoto_s3::ListObjectsV2Error::NoSuchBucket(_))) => {
+                    return Ok(keys)
+                }
+                Err(rusoto_core::RusotoError::Unknown(resp)) if resp.status == 404 => return Ok(keys),
+                Err(e) => return Err(e.into()),
+            };
+            keys.extend(response.contents.unwrap_or_default());
+            match response.next_continuation_token {
+                Some(token) if response.is_truncated.unwrap_or(false) => continuation_token = Some(token),
+                _ => return Ok(keys),
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl CachingBackend for S3Backend {
+    fn name(&self) -> &'static str {
+        "s3"
+    }
+
+    /// Lookup inputs in S3.
+    async fn lookup(&self, inputs: &InputHashBundle) -> Result<Option<InputOutputBundle>> {
+        let key = self.normalize_key(&inputs.hash);
+        match self.read_entry(&key).await? {
+            Some(body) => {
                 let bundle = serde_json::from_slice(&body).context("Cannot deserialize output")?;
                 Ok(Some(bundle))
             }
+            None => Ok(None), // Cache miss
         }
     }
 
     /// Read a file object from the storage, and return AsyncRead object for consuming by capsule.
-    async fn download_object_file(&self, item_hash: &str) -> Result<Pin<Box<dyn AsyncRead>>> {
+    async fn download_object_file(&self, item_hash: &str) -> Result<Pin<Box<dyn AsyncRead + Send>>> {
         let key = self.normalize_object_key(item_hash);
         let request = GetObjectRequest {
             bucket: self.bucket_objects.clone(),
@@ -241,19 +310,59 @@ impl CachingBackend for S3Backend {
         let key = self.normalize_key(&io_bundle.inputs.hash);
         // Prepare data for S3 writing.
         let data = serde_json::to_vec(&io_bundle)?;
-        let data_len = data.len();
-        let request = PutObjectRequest {
-            bucket: self.bucket.clone(),
-            body: Some(data.into()),
-            cache_control: Some(CacheDirective::NoCache.to_string()),
-            content_length: Some(data_len as i64),
-            content_type: Some("application/json".to_owned()),
-            key,
-            ..Default::default()
-        };
 
-        // Write data to S3 (asynchronously).
-        self.client.put_object(request).await?;
+        // Write data to S3 (asynchronously), both as the entry itself and as the latest entry of the capsule.
+        try_join!(
+            self.put_entry(key, data.clone()),
+            self.put_entry(self.latest_key(), data)
+        )?;
         Ok(())
     }
+
+    async fn write_entry(&self, inputs: &InputHashBundle, outputs: &OutputHashBundle, source: String) -> Result<()> {
+        let io_bundle = InputOutputBundle {
+            inputs: inputs.clone(),
+            outputs: outputs.clone(),
+            source,
+        };
+        let data = serde_json::to_vec(&io_bundle)?;
+        self.put_entry(self.normalize_key(&io_bundle.inputs.hash), data).await
+    }
+
+    /// Read the latest entry of the capsule from S3.
+    async fn latest_entry(&self) -> Result<Option<InputOutputBundle>> {
+        match self.read_entry(&self.latest_key()).await? {
+            Some(body) => Ok(Some(
+                serde_json::from_slice(&body).context("Cannot deserialize the latest entry")?,
+            )),
+            None => Ok(None),
+        }
+    }
+
+    /// List entries of the capsule in the keys bucket.
+    async fn list_entries(&self) -> Result<Vec<CacheEntry>> {
+        let prefix = format!("{}/", self.capsule_id);
+        let objects = self.list_keys(&self.bucket, Some(prefix.clone())).await?;
+        let mut entries: Vec<CacheEntry> = objects
+            .into_iter()
+            .filter_map(|object| {
+                // Skip entries of other capsules, whose IDs merely start with ours.
+                let (dir, hash) = object.key.as_ref()?.strip_prefix(&prefix)?.split_once('/')?;
+                if dir.len() != 2 || !hash.starts_with(dir) || hash.contains('/') {
+                    return None;
+                }
+                Some(CacheEntry {
+                    hash: hash.to_string(),
+                    last_modified: object
+                        .last_modified
+                        .as_ref()
+                        .and_then(|value| chrono::DateTime::parse_from_rfc3339(value).ok())
+                        .map(Into::into),
+                    size: object.size.unwrap_or_default() as u64,
+                })
+            })
+            .collect();
+        entries.sort_by_key(|entry| Reverse(entry.last_modified));
+        Ok(entries)
+    }
 }
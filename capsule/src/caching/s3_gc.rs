@@ -0,0 +1,460 @@
+use anyhow::{anyhow, Result};
+use futures::StreamExt;
+use log::{info, warn};
+use rusoto_s3::{Delete, DeleteObjectsRequest, Object, ObjectIdentifier, S3 as _};
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, HashSet};
+use std::fmt;
+use std::time::{Duration, SystemTime};
+
+use crate::caching::s3::{S3Backend, LATEST_KEY};
+use crate::config::Config;
+use crate::iohashing::{HashFunction, InputOutputBundle};
+
+/// How many cache entries are read concurrently to find the objects they reference.
+const CONCURRENT_READS_MAX: usize = 32;
+
+/// S3 limit on the number of keys in a single DeleteObjects request.
+const DELETE_BATCH_MAX: usize = 1000;
+
+/// Which cache entries should be garbage collected.
+#[derive(Debug, Clone)]
+pub struct GcPolicy {
+    /// Delete entries written longer ago than this.
+    pub max_age: Option<Duration>,
+
+    /// Keep at most this many most recent entries per capsule ID.
+    pub keep: Option<usize>,
+
+    /// Never delete entries or objects written more recently than this, as they may belong to
+    /// a capsule which is still writing its results.
+    pub grace_period: Duration,
+
+    /// Only delete entries of this capsule ID. Objects are collected based on entries of all capsules.
+    pub capsule_id: Option<String>,
+
+    /// Only report what would be deleted.
+    pub dry_run: bool,
+}
+
+impl GcPolicy {
+    pub fn from_config(config: &Config) -> Result<Self> {
+        if config.gc_max_age.is_none() && config.gc_keep.is_none() {
+            return Err(anyhow!("Either max_age or keep has to be specified for gc"));
+        }
+        Ok(Self {
+            max_age: config.gc_max_age.map(Duration::from_secs),
+            keep: config.gc_keep,
+            grace_period: Duration::from_secs(config.gc_grace_period),
+            capsule_id: config.capsule_id.clone().filter(|id| id != "-"),
+            dry_run: config.gc_dry_run,
+        })
+    }
+}
+
+/// Summary of a garbage collection run.
+#[derive(Debug, Default)]
+pub struct GcReport {
+    pub dry_run: bool,
+    pub entries: usize,
+    pub entries_deleted: usize,
+    pub entries_bytes_deleted: u64,
+    pub objects: usize,
+    pub objects_deleted: usize,
+    pub objects_bytes_deleted: u64,
+}
+
+impl fmt::Display for GcReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let verb = if self.dry_run { "Would delete" } else { "Deleted" };
+        writeln!(
+            f,
+            "{} {} of {} cache entries ({} bytes)",
+            verb, self.entries_deleted, self.entries, self.entries_bytes_deleted
+        )?;
+        write!(
+            f,
+            "{} {} of {} objects ({} bytes)",
+            verb, self.objects_deleted, self.objects, self.objects_bytes_deleted
+        )
+    }
+}
+
+/// A cache entry in the keys bucket, stored as `<capsule_id>/<xx>/<hash>`, or the latest entry of a capsule,
+/// stored as `<capsule_id>/latest`.
+#[derive(Debug, Clone)]
+struct Entry {
+    key: String,
+    capsule_id: String,
+    last_modified: SystemTime,
+    size: u64,
+}
+
+/// An object in the objects bucket, stored as `<xx>/<hash>`, or `<hash function>/<xx>/<hash>`.
+#[derive(Debug, Clone)]
+struct StoredObject {
+    key: String,
+    hash: String,
+    last_modified: SystemTime,
+    size: u64,
+}
+
+fn last_modified(object: &Object) -> Option<SystemTime> {
+    let value = object.last_modified.as_ref()?;
+    chrono::DateTime::parse_from_rfc3339(value).ok().map(SystemTime::from)
+}
+
+// Keys which don't look like ours are never touched.
+fn parse_entry(object: &Object) -> Option<Entry> {
+    let key = object.key.as_ref()?;
+    // Capsule IDs may contain slashes, so split from the right.
+    let mut parts = key.rsplitn(3, '/');
+    let (hash, prefix, capsule_id) = (parts.next()?, parts.next()?, parts.next()?);
+    // A capsule ID ending in e.g. `/la` would make the latest entry look like a regular one.
+    if hash == LATEST_KEY {
+        return None;
+    }
+    if hash.len() < 2 || !hash.starts_with(prefix) || prefix.len() != 2 {
+        return None;
+    }
+    Some(Entry {
+        key: key.clone(),
+        capsule_id: capsule_id.to_string(),
+        last_modified: last_modified(object)?,
+        size: object.size.unwrap_or_default() as u64,
+    })
+}
+
+fn parse_latest(object: &Object) -> Option<Entry> {
+    let key = object.key.as_ref()?;
+    let capsule_id = key.strip_suffix(LATEST_KEY)?.strip_suffix('/')?;
+    if capsule_id.is_empty() {
+        return None;
+    }
+    Some(Entry {
+        key: key.clone(),
+        capsule_id: capsule_id.to_string(),
+        last_modified: last_modified(object)?,
+        size: object.size.unwrap_or_default() as u64,
+    })
+}
+
+fn parse_object(object: &Object) -> Option<StoredObject> {
+    let key = object.key.as_ref()?;
+    // Objects of other hash functions than SHA256 are under the name of the function.
+    let unprefixed = HashFunction::ALL
+        .iter()
+        .filter(|hash_function| **hash_function != HashFunction::Sha256)
+        .find_map(|hash_function| key.strip_prefix(&format!("{}/", hash_function)))
+        .unwrap_or(key);
+    let (prefix, hash) = unprefixed.split_once('/')?;
+    if hash.len() < 2 || !hash.starts_with(prefix) || prefix.len() != 2 || hash.contains('/') {
+        return None;
+    }
+    Some(StoredObject {
+        key: key.clone(),
+        hash: hash.to_string(),
+        last_modified: last_modified(object)?,
+        size: object.size.unwrap_or_default() as u64,
+    })
+}
+
+fn age(last_modified: SystemTime, now: SystemTime) -> Duration {
+    now.duration_since(last_modified).unwrap_or_default()
+}
+
+/// Split entries into the expired and the surviving ones.
+fn select_expired(entries: Vec<Entry>, policy: &GcPolicy, now: SystemTime) -> (Vec<Entry>, Vec<Entry>) {
+    let mut by_capsule: BTreeMap<String, Vec<Entry>> = BTreeMap::new();
+    for entry in entries {
+        by_capsule.entry(entry.capsule_id.clone()).or_default().push(entry);
+    }
+    let (mut expired, mut surviving) = (Vec::new(), Vec::new());
+    for (capsule_id, mut entries) in by_capsule {
+        if matches!(policy.capsule_id, Some(ref id) if *id != capsule_id) {
+            surviving.extend(entries);
+            continue;
+        }
+        // Most recent first.
+        entries.sort_by_key(|entry| Reverse(entry.last_modified));
+        for (rank, entry) in entries.into_iter().enumerate() {
+            let entry_age = age(entry.last_modified, now);
+            let too_old = matches!(policy.max_age, Some(max_age) if entry_age > max_age);
+            let too_many = matches!(policy.keep, Some(keep) if rank >= keep);
+            if (too_old || too_many) && entry_age > policy.grace_period {
+                expired.push(entry);
+            } else {
+                surviving.push(entry);
+            }
+        }
+    }
+    (expired, surviving)
+}
+
+/// Split latest entries into those of capsules without any surviving entries, and the rest.
+fn select_stale_latest(
+    latest: Vec<Entry>,
+    surviving: &[Entry],
+    policy: &GcPolicy,
+    now: SystemTime,
+) -> (Vec<Entry>, Vec<Entry>) {
+    let capsule_ids: HashSet<&str> = surviving.iter().map(|entry| entry.capsule_id.as_str()).collect();
+    latest.into_iter().partition(|entry| {
+        !capsule_ids.contains(entry.capsule_id.as_str())
+            && !matches!(policy.capsule_id, Some(ref id) if *id != entry.capsule_id)
+            && age(entry.last_modified, now) > policy.grace_period
+    })
+}
+
+/// Find objects which no surviving entry references.
+fn select_orphans(
+    objects: Vec<StoredObject>,
+    referenced: &HashSet<String>,
+    policy: &GcPolicy,
+    now: SystemTime,
+) -> Vec<StoredObject> {
+    objects
+        .into_iter()
+        .filter(|object| !referenced.contains(&object.hash) && age(object.last_modified, now) > policy.grace_period)
+        .collect()
+}
+
+impl S3Backend {
+    /// Delete cache entries according to the policy, and then all objects no remaining entry refers to.
+    /// The latest entry of a capsule is deleted along with the last of its entries, and otherwise keeps
+    /// the objects it refers to.
+    ///
+    /// Objects uploaded within the grace period are kept, as their entries may not have been written yet.
+    /// Entries written while the collection is running are picked up by a second listing of the keys
+    /// bucket before objects are deleted. A capsule that skipped uploading an object because it was present,
+    /// and writes its entry only after that second listing, could still lose the object; capsules treat
+    /// such a broken entry as a miss, and upload the object again.
+    pub async fn gc(&self, policy: &GcPolicy) -> Result<GcReport> {
+        let mut report = GcReport {
+            dry_run: policy.dry_run,
+            ..Default::default()
+        };
+        let started = SystemTime::now();
+
+        let listing = self.list_keys(&self.bucket, None).await?;
+        let entries: Vec<Entry> = listing.iter().filter_map(parse_entry).collect();
+        let latest: Vec<Entry> = listing.iter().filter_map(parse_latest).collect();
+        report.entries = entries.len() + latest.len();
+        let known_keys: HashSet<String> = entries.iter().chain(&latest).map(|entry| entry.key.clone()).collect();
+        let (mut expired, mut surviving) = select_expired(entries, policy, started);
+        let (stale_latest, surviving_latest) = select_stale_latest(latest, &surviving, policy, started);
+        expired.extend(stale_latest);
+        surviving.extend(surviving_latest);
+        for entry in &expired {
+            info!("Expired cache entry '{}'", entry.key);
+        }
+        report.entries_deleted = expired.len();
+        report.entries_bytes_deleted = expired.iter().map(|entry| entry.size).sum();
+        let mut referenced = self.referenced_objects(&surviving).await?;
+        if !policy.dry_run {
+            let bucket = self.bucket.clone();
+            self.delete_keys(&bucket, expired.into_iter().map(|entry| entry.key).collect())
+                .await?;
+        }
+
+        let objects: Vec<StoredObject> = self
+            .list_keys(&self.bucket_objects, None)
+            .await?
+            .iter()
+            .filter_map(parse_object)
+            .collect();
+        report.objects = objects.len();
+
+        // Entries written since the start, whose objects have to survive as well.
+        let fresh: Vec<Entry> = self
+            .list_keys(&self.bucket, None)
+            .await?
+            .iter()
+            .filter_map(|object| parse_entry(object).or_else(|| parse_latest(object)))
+            .filter(|entry| !known_keys.contains(&entry.key) || entry.last_modified >= started)
+            .collect();
+        referenced.extend(self.referenced_objects(&fresh).await?);
+
+        let orphans = select_orphans(objects, &referenced, policy, SystemTime::now());
+        for object in &orphans {
+            info!("Unreferenced object '{}'", object.key);
+        }
+        report.objects_deleted = orphans.len();
+        report.objects_bytes_deleted = orphans.iter().map(|object| object.size).sum();
+        if !policy.dry_run {
+            let bucket = self.bucket_objects.clone();
+            self.delete_keys(&bucket, orphans.into_iter().map(|object| object.key).collect())
+                .await?;
+        }
+        Ok(report)
+    }
+
+    /// Read the given entries, and collect hashes of all objects they reference.
+    async fn referenced_objects(&self, entries: &[Entry]) -> Result<HashSet<String>> {
+        let reads = entries
+            .iter()
+            .map(|entry| async move { (entry, self.read_entry(&entry.key).await) });
+        let mut results = futures::stream::iter(reads).buffer_unordered(CONCURRENT_READS_MAX);
+        let mut referenced = HashSet::new();
+        while let Some((entry, result)) = results.next().await {
+            // An entry that can't be read might reference anything, so bail out rather than delete its objects.
+            let body = match result? {
+                Some(body) => body,
+                None => continue, // Deleted in the meantime.
+            };
+            // An entry that can't be parsed is unusable for capsules anyway.
+            match serde_json::from_slice::<InputOutputBundle>(&body) {
+                Ok(bundle) => referenced.extend(bundle.outputs.object_hashes().map(String::from)),
+                Err(err) => warn!("Cannot parse cache entry '{}': {}", entry.key, err),
+            }
+        }
+        Ok(referenced)
+    }
+
+    async fn delete_keys(&self, bucket: &str, keys: Vec<String>) -> Result<()> {
+        for batch in keys.chunks(DELETE_BATCH_MAX) {
+            let request = DeleteObjectsRequest {
+                bucket: bucket.to_string(),
+                delete: Delete {
+                    objects: batch
+                        .iter()
+                        .map(|key| ObjectIdentifier {
+                            key: key.clone(),
+                            ..Default::default()
+                        })
+                        .collect(),
+                    quiet: Some(true),
+                },
+                ..Default::default()
+            };
+            let response = self.client.delete_objects(request).await?;
+            if let Some(error) = response.errors.unwrap_or_default().first() {
+                return Err(anyhow!(
+                    "Failed to delete '{}' from bucket '{}': {}",
+                    error.key.as_deref().unwrap_or_default(),
+                    bucket,
+                    error.message.as_deref().unwrap_or_default()
+                ));
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DAY: Duration = Duration::from_secs(86400);
+
+    fn object(key: &str, modified: SystemTime) -> Object {
+        Object {
+            key: Some(key.to_string()),
+            last_modified: Some(chrono::DateTime::<chrono::Utc>::from(modified).to_rfc3339()),
+            size: Some(10),
+            ..Default::default()
+        }
+    }
+
+    fn policy(max_age: Option<Duration>, keep: Option<usize>) -> GcPolicy {
+        GcPolicy {
+            max_age,
+            keep,
+            grace_period: DAY,
+            capsule_id: None,
+            dry_run: false,
+        }
+    }
+
+    fn keys<'a, I: IntoIterator<Item = &'a Entry>>(entries: I) -> Vec<&'a str> {
+        let mut keys: Vec<&str> = entries.into_iter().map(|entry| entry.key.as_str()).collect();
+        keys.sort_unstable();
+        keys
+    }
+
+    #[test]
+    fn test_parse_keys() {
+        let now = SystemTime::UNIX_EPOCH + 1000 * DAY;
+        let entry = parse_entry(&object("//ic/rs:build/ab/abcd", now)).unwrap();
+        assert_eq!(entry.capsule_id, "//ic/rs:build");
+        assert_eq!(entry.last_modified, now);
+        assert!(parse_entry(&object("wtf/xy/abcd", now)).is_none());
+        assert!(parse_entry(&object("abcd", now)).is_none());
+        assert!(parse_entry(&object("//ic/rs:build/latest", now)).is_none());
+        assert!(parse_entry(&object("//ic/la/latest", now)).is_none());
+        assert_eq!(
+            parse_latest(&object("//ic/la/latest", now)).unwrap().capsule_id,
+            "//ic/la"
+        );
+        assert!(parse_latest(&object("latest", now)).is_none());
+        assert!(parse_latest(&object("//ic/ab/abcd", now)).is_none());
+
+        let stored = parse_object(&object("ab/abcd", now)).unwrap();
+        assert_eq!(stored.hash, "abcd");
+        assert!(parse_object(&object("foo", now)).is_none());
+        assert!(parse_object(&object("ab/cd/abcd", now)).is_none());
+        let stored = parse_object(&object("blake3/ab/abcd", now)).unwrap();
+        assert_eq!(stored.hash, "abcd");
+        assert_eq!(stored.key, "blake3/ab/abcd");
+        assert!(parse_object(&object("sha256/ab/abcd", now)).is_none());
+    }
+
+    #[test]
+    fn test_select_expired() {
+        let now = SystemTime::UNIX_EPOCH + 1000 * DAY;
+        let entries: Vec<Entry> = [("a/11/1111", 0), ("a/22/2222", 3), ("a/33/3333", 10), ("b/44/4444", 20)]
+            .iter()
+            .map(|(key, days)| parse_entry(&object(key, now - *days * DAY - Duration::from_secs(1))).unwrap())
+            .collect();
+
+        let (expired, _) = select_expired(entries.clone(), &policy(Some(7 * DAY), None), now);
+        assert_eq!(keys(&expired), vec!["a/33/3333", "b/44/4444"]);
+
+        // Only the most recent entry of each capsule survives.
+        let (expired, surviving) = select_expired(entries.clone(), &policy(None, Some(1)), now);
+        assert_eq!(keys(&expired), vec!["a/22/2222", "a/33/3333"]);
+        assert_eq!(keys(&surviving), vec!["a/11/1111", "b/44/4444"]);
+
+        // Nothing within the grace period is deleted.
+        let (expired, _) = select_expired(entries.clone(), &policy(None, Some(0)), now);
+        assert_eq!(keys(&expired), vec!["a/22/2222", "a/33/3333", "b/44/4444"]);
+
+        let mut only_b = policy(Some(7 * DAY), None);
+        only_b.capsule_id = Some("b".to_string());
+        let (expired, _) = select_expired(entries, &only_b, now);
+        assert_eq!(keys(&expired), vec!["b/44/4444"]);
+    }
+
+    #[test]
+    fn test_select_stale_latest() {
+        let now = SystemTime::UNIX_EPOCH + 1000 * DAY;
+        let surviving = vec![parse_entry(&object("a/11/1111", now)).unwrap()];
+        let latest: Vec<Entry> = [("a/latest", 10), ("b/latest", 10), ("c/latest", 0)]
+            .iter()
+            .map(|(key, days)| parse_latest(&object(key, now - *days * DAY)).unwrap())
+            .collect();
+
+        // Only capsules without entries lose their latest entry, unless it was just written.
+        let (stale, kept) = select_stale_latest(latest.clone(), &surviving, &policy(None, Some(1)), now);
+        assert_eq!(keys(&stale), vec!["b/latest"]);
+        assert_eq!(keys(&kept), vec!["a/latest", "c/latest"]);
+
+        let mut only_c = policy(None, Some(1));
+        only_c.capsule_id = Some("c".to_string());
+        let (stale, _) = select_stale_latest(latest, &surviving, &only_c, now);
+        assert!(stale.is_empty());
+    }
+
+    #[test]
+    fn test_select_orphans() {
+        let now = SystemTime::UNIX_EPOCH + 1000 * DAY;
+        let objects: Vec<StoredObject> = [("11/1111", 10), ("22/2222", 10), ("33/3333", 0)]
+            .iter()
+            .map(|(key, days)| parse_object(&object(key, now - *days * DAY)).unwrap())
+            .collect();
+        let referenced: HashSet<String> = ["1111".to_string()].into_iter().collect();
+        let orphans = select_orphans(objects, &referenced, &policy(None, Some(1)), now);
+        let orphans: Vec<&str> = orphans.iter().map(|object| object.key.as_str()).collect();
+        assert_eq!(orphans, vec!["22/2222"]);
+    }
+}
@@ -1,4 +1,4 @@
-use crate::caching::backend::CachingBackend;
+use crate::caching::backend::{CacheEntry, CachingBackend};
 use anyhow::anyhow;
 use anyhow::Result;
 use async_trait::async_trait;
@@ -29,6 +29,7 @@ pub struct TestBackendConfig {
 #[derive(Default)]
 pub struct TestBackend {
     keys: Arc<RwLock<HashMap<String, InputOutputBundle>>>,
+    latest: Arc<RwLock<Option<InputOutputBundle>>>,
     objects: Arc<RwLock<HashMap<String, Vec<u8>>>>,
     test_config: TestBackendConfig,
     capsule_id: String,
@@ -50,6 +51,35 @@ impl TestBackend {
     fn normalize_key(&self, key: &str) -> String {
         format!("{}/{}", self.capsule_id, key)
     }
+
+    /// Write the entry, and make it the latest one of the capsule if asked to.
+    async fn store(
+        &self,
+        inputs: &InputHashBundle,
+        outputs: &OutputHashBundle,
+        source: String,
+        latest: bool,
+    ) -> Result<()> {
+        if self.test_config.write_timeout {
+            time::sleep(Duration::from_millis(500)).await;
+        }
+        if self.test_config.failing_write {
+            Err(anyhow!("Failed to write key"))
+        } else {
+            let key = self.normalize_key(&inputs.hash);
+            let bundle = InputOutputBundle {
+                inputs: inputs.clone(),
+                outputs: outputs.clone(),
+                source,
+            };
+            if latest {
+                *self.latest.write().unwrap() = Some(bundle.clone());
+            }
+            let mut hashmap = self.keys.write().unwrap();
+            hashmap.insert(key, bundle);
+            Ok(())
+        }
+    }
 }
 
 #[async_trait]
@@ -72,27 +102,14 @@ impl CachingBackend for TestBackend {
     }
 
     async fn write(&self, inputs: &InputHashBundle, outputs: &OutputHashBundle, source: String) -> Result<()> {
-        if self.test_config.write_timeout {
-            time::sleep(Duration::from_millis(500)).await;
-        }
-        if self.test_config.failing_write {
-            Err(anyhow!("Failed to write key"))
-        } else {
-            let key = self.normalize_key(&inputs.hash);
-            let mut hashmap = self.keys.write().unwrap();
-            hashmap.insert(
-                key,
-                InputOutputBundle {
-                    inputs: inputs.clone(),
-                    outputs: outputs.clone(),
-                    source,
-                },
-            );
-            Ok(())
-        }
+        self.store(inputs, outputs, source, true).await
+    }
+
+    async fn write_entry(&self, inputs: &InputHashBundle, outputs: &OutputHashBundle, source: String) -> Result<()> {
+        self.store(inputs, outputs, source, false).await
     }
 
-    async fn download_object_file(&self, item_hash: &str) -> Result<Pin<Box<dyn AsyncRead>>> {
+    async fn download_object_file(&self, item_hash: &str) -> Result<Pin<Box<dyn AsyncRead + Send>>> {
         if self.test_config.download_timeout {
             time::sleep(Duration::from_millis(500)).await;
         }
@@ -125,4 +142,29 @@ impl CachingBackend for TestBackend {
             Ok(())
         }
     }
+
+    async fn latest_entry(&self) -> Result<Option<InputOutputBundle>> {
+        if self.test_config.failing_lookup {
+            Err(anyhow!("Failed to read the latest entry"))
+        } else {
+            Ok(self.latest.read().unwrap().clone())
+        }
+    }
+
+    async fn list_entries(&self) -> Result<Vec<CacheEntry>> {
+        let prefix = self.normalize_key("");
+        let hashmap = self.keys.read().unwrap();
+        let mut entries: Vec<CacheEntry> = hashmap
+            .iter()
+            .filter_map(|(key, bundle)| {
+                Some(CacheEntry {
+                    hash: key.strip_prefix(&prefix)?.to_string(),
+                    last_modified: None,
+                    size: serde_json::to_vec(bundle).ok()?.len() as u64,
+                })
+            })
+            .collect();
+        entries.sort_by(|a, b| a.hash.cmp(&b.hash));
+        Ok(entries)
+    }
 }
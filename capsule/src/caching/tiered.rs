@@ -0,0 +1,561 @@
+use anyhow::{anyhow, bail, Context, Result};
+use async_trait::async_trait;
+use futures::future::join_all;
+use log::{info, warn};
+use std::collections::HashSet;
+use std::pin::Pin;
+use std::sync::Mutex;
+use tempfile::NamedTempFile;
+use tokio::io::{AsyncRead, AsyncWriteExt};
+use tokio::task;
+
+use crate::caching::backend::{CacheEntry, CachingBackend};
+use crate::caching::{http, local, reapi, s3};
+use crate::config::Config;
+use crate::iohashing::{HashFunction, InputHashBundle, InputOutputBundle, OutputHashBundle};
+
+pub type Tier = Box<dyn CachingBackend + Send + Sync>;
+
+/// A cache entry found in a slower tier, which will be copied into the faster tiers
+/// once all of its objects have been copied there.
+struct PendingFill {
+    tier: usize,
+    bundle: InputOutputBundle,
+    missing: HashSet<String>,
+}
+
+/// A backend stacking several backends, ordered from the fastest to the slowest, e.g. local disk in front of S3.
+///
+/// Lookups and downloads try the tiers in order, and fill the faster tiers with what was found in a slower one.
+/// Writes and uploads go to every tier.
+pub struct TieredBackend {
+    /// Caching backends, the fastest first.
+    pub tiers: Vec<Tier>,
+
+    /// Whether failures to write into the tiers other than the first one are only logged.
+    pub ignore_write_errors: bool,
+
+    /// Hash function of the objects, to verify them before they are copied into the faster tiers.
+    pub hash_function: HashFunction,
+
+    /// Entries waiting for their objects to be copied into the faster tiers.
+    pending: Mutex<Vec<PendingFill>>,
+}
+
+impl TieredBackend {
+    pub fn new(tiers: Vec<Tier>, ignore_write_errors: bool) -> Self {
+        Self {
+            tiers,
+            ignore_write_errors,
+            hash_function: HashFunction::default(),
+            pending: Mutex::new(Vec::new()),
+        }
+    }
+
+    pub fn from_config(config: &Config) -> Result<Self> {
+        if config.tiers.is_empty() {
+            bail!("No tiers specified for the tiered backend");
+        }
+        let tiers = config
+            .tiers
+            .iter()
+            .map(|tier| -> Result<Tier> {
+                Ok(match tier.as_str() {
+                    "s3" => Box::new(s3::S3Backend::from_config(config)?),
+                    "local" => Box::new(local::LocalBackend::from_config(config)?),
+                    "http" => Box::new(http::HttpBackend::from_config(config)?),
+                    "reapi" => Box::new(reapi::ReapiBackend::from_config(config)?),
+                    _ => bail!("Unsupported cache tier '{}'", tier),
+                })
+            })
+            .collect::<Result<_>>()?;
+        let mut backend = Self::new(tiers, config.tier_ignore_write_errors);
+        backend.hash_function = config.hash_function();
+        Ok(backend)
+    }
+
+    /// Combine results of an operation on every tier, according to the error policy.
+    fn check_write_results(&self, what: &str, results: Vec<Result<()>>) -> Result<()> {
+        for (index, result) in results.into_iter().enumerate() {
+            if let Err(err) = result {
+                let tier = self.tiers[index].name();
+                if index > 0 && self.ignore_write_errors {
+                    warn!("Failed to {} in the {} cache tier: {:#}", what, tier, err);
+                } else {
+                    return Err(err.context(format!("Failed to {} in the {} cache tier", what, tier)));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Write the entry into the tiers faster than the given one. Failures are only logged, as the
+    /// entry is still available in the slower tier. The entry was written by an earlier run, so it
+    /// doesn't become the latest entry of the capsule there.
+    async fn fill_entry(&self, tier: usize, bundle: &InputOutputBundle) {
+        let writes = self.tiers[..tier]
+            .iter()
+            .map(|faster| faster.write_entry(&bundle.inputs, &bundle.outputs, bundle.source.clone()));
+        for (faster, result) in self.tiers[..tier].iter().zip(join_all(writes).await) {
+            if let Err(err) = result {
+                warn!(
+                    "Failed to fill the {} cache tier with an entry: {:#}",
+                    faster.name(),
+                    err
+                );
+            }
+        }
+    }
+
+    /// Spool the file into a temporary file, so that it could be read several times.
+    async fn spool(file: &mut Pin<Box<dyn AsyncRead + Send>>) -> Result<(NamedTempFile, u64)> {
+        let spool = NamedTempFile::new()?;
+        let mut writer = tokio::fs::File::from_std(spool.reopen()?);
+        let content_length = tokio::io::copy(file, &mut writer).await?;
+        writer.flush().await?;
+        Ok((spool, content_length))
+    }
+
+    /// Upload the spooled file to each of the given tiers, concurrently.
+    async fn upload_spooled(
+        tiers: &[Tier],
+        name: &str,
+        item_hash: &str,
+        spool: &NamedTempFile,
+        content_length: u64,
+    ) -> Result<Vec<Result<()>>> {
+        let mut uploads = Vec::new();
+        for tier in tiers {
+            let file = tokio::fs::File::from_std(spool.reopen()?);
+            uploads.push(tier.upload_object_file(name.to_string(), item_hash, Box::pin(file), content_length));
+        }
+        Ok(join_all(uploads).await)
+    }
+
+    /// Note that the object is in every tier up to and including the given one, and write the pending entries all
+    /// of whose objects are now in the tiers faster than theirs into those tiers, wherever the objects came from.
+    async fn object_available(&self, tier: usize, item_hash: &str) {
+        let ready: Vec<PendingFill> = {
+            let mut pending = self.pending.lock().unwrap();
+            for entry in pending.iter_mut().filter(|entry| entry.tier <= tier + 1) {
+                entry.missing.remove(item_hash);
+            }
+            let (ready, rest) = pending.drain(..).partition(|entry| entry.missing.is_empty());
+            *pending = rest;
+            ready
+        };
+        for entry in ready {
+            self.fill_entry(entry.tier, &entry.bundle).await;
+        }
+    }
+
+    /// Copy the object found in a slower tier into the faster ones, and return a reader of its contents.
+    async fn fill_object(
+        &self,
+        tier: usize,
+        item_hash: &str,
+        mut file: Pin<Box<dyn AsyncRead + Send>>,
+    ) -> Result<Pin<Box<dyn AsyncRead + Send>>> {
+        let (spool, content_length) = Self::spool(&mut file).await?;
+        // Don't let a corrupted object into the faster tiers.
+        let path = spool.path().to_path_buf();
+        let hash_function = self.hash_function;
+        let received_hash = task::spawn_blocking(move || hash_function.file_hash(&path)).await??;
+        if received_hash != item_hash {
+            return Err(anyhow!("Mismatch of the hash of object '{}'", item_hash));
+        }
+
+        let results =
+            Self::upload_spooled(&self.tiers[..tier], "cache fill", item_hash, &spool, content_length).await?;
+        let mut filled = true;
+        for (faster, result) in self.tiers[..tier].iter().zip(results) {
+            if let Err(err) = result {
+                warn!(
+                    "Failed to fill the {} cache tier with object '{}': {:#}",
+                    faster.name(),
+                    item_hash,
+                    err
+                );
+                filled = false;
+            }
+        }
+        if filled {
+            self.object_available(tier, item_hash).await;
+        }
+
+        // The reopened spool reads from the start, and stays around after the temporary file is removed.
+        Ok(Box::pin(tokio::fs::File::from_std(spool.reopen()?)))
+    }
+}
+
+#[async_trait]
+impl CachingBackend for TieredBackend {
+    fn name(&self) -> &'static str {
+        "tiered"
+    }
+
+    /// Lookup inputs in each tier in order, until there's a cache hit.
+    async fn lookup(&self, inputs: &InputHashBundle) -> Result<Option<InputOutputBundle>> {
+        let mut last_error = None;
+        let mut answered = false;
+        for (index, tier) in self.tiers.iter().enumerate() {
+            match tier.lookup(inputs).await {
+                Ok(Some(bundle)) => {
+                    if index > 0 {
+                        info!("Cache hit in the {} cache tier, filling the faster tiers", tier.name());
+                        let missing: HashSet<String> = bundle.outputs.object_hashes().map(String::from).collect();
+                        if missing.is_empty() {
+                            self.fill_entry(index, &bundle).await;
+                        } else {
+                            self.pending.lock().unwrap().push(PendingFill {
+                                tier: index,
+                                bundle: bundle.clone(),
+                                missing,
+                            });
+                        }
+                    }
+                    return Ok(Some(bundle));
+                }
+                Ok(None) => answered = true,
+                Err(err) => {
+                    warn!("Failed to lookup in the {} cache tier: {:#}", tier.name(), err);
+                    last_error = Some(err);
+                }
+            }
+        }
+        // A miss is only an error if none of the tiers could tell.
+        match last_error {
+            Some(err) if !answered => Err(err),
+            _ => Ok(None),
+        }
+    }
+
+    /// Read the entry from the first tier that has it, without filling the faster tiers.
+    async fn peek(&self, inputs: &InputHashBundle) -> Result<Option<InputOutputBundle>> {
+        let mut last_error = None;
+        for tier in &self.tiers {
+            match tier.peek(inputs).await {
+                Ok(Some(bundle)) => return Ok(Some(bundle)),
+                Ok(None) => {}
+                Err(err) => last_error = Some(err),
+            }
+        }
+        match last_error {
+            Some(err) => Err(err),
+            None => Ok(None),
+        }
+    }
+
+    /// Download the object from the first tier that has it, copying it into the faster tiers.
+    async fn download_object_file(&self, item_hash: &str) -> Result<Pin<Box<dyn AsyncRead + Send>>> {
+        let mut last_error = None;
+        for (index, tier) in self.tiers.iter().enumerate() {
+            match tier.download_object_file(item_hash).await {
+                Ok(file) if index == 0 => {
+                    self.object_available(index, item_hash).await;
+                    return Ok(file);
+                }
+                Ok(file) => return self.fill_object(index, item_hash, file).await,
+                Err(err) => last_error = Some(err),
+            }
+        }
+        Err(last_error.unwrap_or_else(|| anyhow!("No cache tiers")))
+            .with_context(|| format!("Object '{}' not found in any cache tier", item_hash))
+    }
+
+    /// Upload the object into every tier.
+    async fn upload_object_file(
+        &self,
+        name: String,
+        item_hash: &str,
+        mut file: Pin<Box<dyn AsyncRead + Send>>,
+        _content_length: u64,
+    ) -> Result<()> {
+        let (spool, content_length) = Self::spool(&mut file).await?;
+        let results = Self::upload_spooled(&self.tiers, &name, item_hash, &spool, content_length).await?;
+        self.check_write_results("upload object", results)
+    }
+
+    /// Write the cache entry into every tier.
+    async fn write(&self, inputs: &InputHashBundle, outputs: &OutputHashBundle, source: String) -> Result<()> {
+        let writes = self
+            .tiers
+            .iter()
+            .map(|tier| tier.write(inputs, outputs, source.clone()));
+        let results = join_all(writes).await;
+        self.check_write_results("write entry", results)
+    }
+
+    async fn write_entry(&self, inputs: &InputHashBundle, outputs: &OutputHashBundle, source: String) -> Result<()> {
+        let writes = self
+            .tiers
+            .iter()
+            .map(|tier| tier.write_entry(inputs, outputs, source.clone()));
+        let results = join_all(writes).await;
+        self.check_write_results("write entry", results)
+    }
+
+    /// Return the latest entry from the first tier that has one.
+    async fn latest_entry(&self) -> Result<Option<InputOutputBundle>> {
+        let mut last_error = None;
+        for tier in &self.tiers {
+            match tier.latest_entry().await {
+                Ok(Some(bundle)) => return Ok(Some(bundle)),
+                Ok(None) => {}
+                Err(err) => last_error = Some(err),
+            }
+        }
+        match last_error {
+            Some(err) => Err(err),
+            None => Ok(None),
+        }
+    }
+
+    /// List entries of the slowest tier that supports listing, as it is usually the most complete one.
+    async fn list_entries(&self) -> Result<Vec<CacheEntry>> {
+        let mut last_error = None;
+        for tier in self.tiers.iter().rev() {
+            match tier.list_entries().await {
+                Ok(entries) => return Ok(entries),
+                Err(err) => last_error = Some(err),
+            }
+        }
+        Err(last_error.unwrap_or_else(|| anyhow!("No cache tiers")))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::caching::test::{TestBackend, TestBackendConfig};
+    use crate::iohashing::{string_hash, FileOutput, Output};
+    use std::sync::Arc;
+    use tokio::io::AsyncReadExt;
+
+    // Lets the test keep a handle to a tier owned by the tiered backend.
+    struct Shared(Arc<TestBackend>);
+
+    #[async_trait]
+    impl CachingBackend for Shared {
+        async fn lookup(&self, inputs: &InputHashBundle) -> Result<Option<InputOutputBundle>> {
+            self.0.lookup(inputs).await
+        }
+
+        async fn write(&self, inputs: &InputHashBundle, outputs: &OutputHashBundle, source: String) -> Result<()> {
+            self.0.write(inputs, outputs, source).await
+        }
+
+        async fn write_entry(
+            &self,
+            inputs: &InputHashBundle,
+            outputs: &OutputHashBundle,
+            source: String,
+        ) -> Result<()> {
+            self.0.write_entry(inputs, outputs, source).await
+        }
+
+        async fn download_object_file(&self, item_hash: &str) -> Result<Pin<Box<dyn AsyncRead + Send>>> {
+            self.0.download_object_file(item_hash).await
+        }
+
+        async fn upload_object_file(
+            &self,
+            name: String,
+            item_hash: &str,
+            file: Pin<Box<dyn AsyncRead + Send>>,
+            content_length: u64,
+        ) -> Result<()> {
+            self.0.upload_object_file(name, item_hash, file, content_length).await
+        }
+
+        async fn latest_entry(&self) -> Result<Option<InputOutputBundle>> {
+            self.0.latest_entry().await
+        }
+
+        async fn list_entries(&self) -> Result<Vec<CacheEntry>> {
+            self.0.list_entries().await
+        }
+    }
+
+    fn tier(test_config: TestBackendConfig) -> (Tier, Arc<TestBackend>) {
+        let backend = Arc::new(TestBackend::new("wtf", test_config));
+        (Box::new(Shared(backend.clone())), backend)
+    }
+
+    fn inputs() -> InputHashBundle {
+        InputHashBundle {
+            hash: "1234".to_string(),
+            ..Default::default()
+        }
+    }
+
+    fn outputs(object_hash: &str) -> OutputHashBundle {
+        OutputHashBundle {
+            hash: "5678".to_string(),
+            hash_details: vec![(
+                Output::File(FileOutput {
+                    filename: "out".into(),
+                    present: true,
+                    mode: 0o644,
+                    symlink: None,
+                }),
+                object_hash.to_string(),
+            )],
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_tiered_fill() {
+        let (fast_tier, fast) = tier(TestBackendConfig::default());
+        let (slow_tier, slow) = tier(TestBackendConfig::default());
+        let contents = b"file contents".to_vec();
+        let object_hash = string_hash("file contents");
+        slow.upload_object_file(
+            "out".to_string(),
+            &object_hash,
+            Box::pin(std::io::Cursor::new(contents.clone())),
+            contents.len() as u64,
+        )
+        .await
+        .unwrap();
+        slow.write(&inputs(), &outputs(&object_hash), "job".to_string())
+            .await
+            .unwrap();
+
+        let backend = TieredBackend::new(vec![fast_tier, slow_tier], false);
+        let bundle = backend.lookup(&inputs()).await.unwrap().unwrap();
+        assert_eq!(bundle.source, "job");
+        // The entry only gets into the fast tier together with its objects.
+        assert!(fast.lookup(&inputs()).await.unwrap().is_none());
+
+        let mut downloaded = Vec::new();
+        backend
+            .download_object_file(&object_hash)
+            .await
+            .unwrap()
+            .read_to_end(&mut downloaded)
+            .await
+            .unwrap();
+        assert_eq!(downloaded, contents);
+        assert!(fast.lookup(&inputs()).await.unwrap().is_some());
+        // The filled entry was written by an earlier run, so misses are still explained against the latest one.
+        assert!(fast.latest_entry().await.unwrap().is_none());
+        let mut filled = Vec::new();
+        fast.download_object_file(&object_hash)
+            .await
+            .unwrap()
+            .read_to_end(&mut filled)
+            .await
+            .unwrap();
+        assert_eq!(filled, contents);
+    }
+
+    async fn read_object(backend: &dyn CachingBackend, object_hash: &str) -> Vec<u8> {
+        let mut contents = Vec::new();
+        backend
+            .download_object_file(object_hash)
+            .await
+            .unwrap()
+            .read_to_end(&mut contents)
+            .await
+            .unwrap();
+        contents
+    }
+
+    #[tokio::test]
+    async fn test_tiered_fill_objects_elsewhere() {
+        let contents = b"file contents".to_vec();
+        let object_hash = string_hash("file contents");
+        let upload = |backend: Arc<TestBackend>| {
+            let contents = contents.clone();
+            let object_hash = object_hash.clone();
+            async move {
+                backend
+                    .upload_object_file(
+                        "out".to_string(),
+                        &object_hash,
+                        Box::pin(std::io::Cursor::new(contents.clone())),
+                        contents.len() as u64,
+                    )
+                    .await
+                    .unwrap()
+            }
+        };
+
+        // The object of the entry is already in the fast tier.
+        let (fast_tier, fast) = tier(TestBackendConfig::default());
+        let (slow_tier, slow) = tier(TestBackendConfig::default());
+        upload(fast.clone()).await;
+        upload(slow.clone()).await;
+        slow.write(&inputs(), &outputs(&object_hash), "job".to_string())
+            .await
+            .unwrap();
+        let backend = TieredBackend::new(vec![fast_tier, slow_tier], false);
+        assert!(backend.lookup(&inputs()).await.unwrap().is_some());
+        assert_eq!(read_object(&backend, &object_hash).await, contents);
+        assert!(fast.lookup(&inputs()).await.unwrap().is_some());
+
+        // The object of an entry in the middle tier is only in the slowest one.
+        let (fast_tier, fast) = tier(TestBackendConfig::default());
+        let (middle_tier, middle) = tier(TestBackendConfig::default());
+        let (slow_tier, slow) = tier(TestBackendConfig::default());
+        upload(slow.clone()).await;
+        middle
+            .write(&inputs(), &outputs(&object_hash), "job".to_string())
+            .await
+            .unwrap();
+        let backend = TieredBackend::new(vec![fast_tier, middle_tier, slow_tier], false);
+        assert!(backend.lookup(&inputs()).await.unwrap().is_some());
+        assert_eq!(read_object(&backend, &object_hash).await, contents);
+        assert!(fast.lookup(&inputs()).await.unwrap().is_some());
+        assert_eq!(read_object(fast.as_ref(), &object_hash).await, contents);
+    }
+
+    #[tokio::test]
+    async fn test_tiered_write() {
+        let (fast_tier, fast) = tier(TestBackendConfig::default());
+        let (slow_tier, slow) = tier(TestBackendConfig::default());
+        let backend = TieredBackend::new(vec![fast_tier, slow_tier], false);
+        backend
+            .write(&inputs(), &OutputHashBundle::default(), "job".to_string())
+            .await
+            .unwrap();
+        assert!(fast.lookup(&inputs()).await.unwrap().is_some());
+        assert!(slow.lookup(&inputs()).await.unwrap().is_some());
+    }
+
+    #[tokio::test]
+    async fn test_tiered_write_errors() {
+        let failing = || TestBackendConfig {
+            failing_write: true,
+            failing_lookup: true,
+            ..Default::default()
+        };
+        let (fast_tier, _) = tier(TestBackendConfig::default());
+        let (slow_tier, _) = tier(failing());
+        let backend = TieredBackend::new(vec![fast_tier, slow_tier], false);
+        assert!(backend
+            .write(&inputs(), &OutputHashBundle::default(), String::new())
+            .await
+            .is_err());
+        // Lookup still works, since the fast tier has answered.
+        assert!(backend.lookup(&inputs()).await.unwrap().is_some());
+
+        let (fast_tier, _) = tier(TestBackendConfig::default());
+        let (slow_tier, _) = tier(failing());
+        let backend = TieredBackend::new(vec![fast_tier, slow_tier], true);
+        assert!(backend
+            .write(&inputs(), &OutputHashBundle::default(), String::new())
+            .await
+            .is_ok());
+
+        let (fast_tier, _) = tier(failing());
+        let (slow_tier, _) = tier(TestBackendConfig::default());
+        let backend = TieredBackend::new(vec![fast_tier, slow_tier], true);
+        assert!(backend
+            .write(&inputs(), &OutputHashBundle::default(), String::new())
+            .await
+            .is_err());
+    }
+}
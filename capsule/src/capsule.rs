@@ -5,20 +5,34 @@ use futures::join;
 use futures::stream::{StreamExt, TryStreamExt};
 use glob::glob;
 use indoc::indoc;
-use log::{error, info};
+use log::{error, info, warn};
+use std::collections::{BTreeSet, HashSet};
+use std::fs::File;
+use std::future::Future;
+use std::io::{self, Seek, SeekFrom};
 use std::os::unix::fs::PermissionsExt;
-use std::process::ExitStatus;
+use std::os::unix::io::{AsFd, AsRawFd};
+use std::path::{Path, PathBuf};
+use std::process::{ExitStatus, Stdio};
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::time::Duration;
 use tempfile::NamedTempFile;
-use tokio::io::AsyncWriteExt;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 use tokio::process::Command;
 use tokio::{task, time};
 
 use crate::caching::backend::CachingBackend;
-use crate::config::{Config, Milestone};
+use crate::combined_log::{CombinedLog, Stream};
+use crate::config::{Config, Milestone, SymlinkPolicy};
+use crate::depfile::read_depfile;
+use crate::git_files::{tracked_files, GIT_INPUT_PREFIX};
+use crate::hash_cache::HashCache;
+use crate::input_filter::InputFilter;
 use crate::iohashing::*;
 use crate::observability::logger::Logger;
+use crate::output_tree::{prepare_tree, read_tree, remove_tree, set_modes};
+use crate::platform::platform_tags;
+use crate::tool_fingerprint::{binary_tool_tag, command_tool_tag};
 use crate::workspace_path::WorkspacePath;
 
 static USAGE: &str = "Usage: capsule <capsule arguments ...> -- command [<arguments>]";
@@ -42,6 +56,126 @@ mod timeouts {
     pub(super) const TIMEOUT_DOWNLOAD_MILLIS: u64 = 200;
 }
 
+/// Read a list of input files and patterns, separated by NUL characters if there are any, and by newlines otherwise.
+fn read_input_list(path: &Path) -> Result<Vec<WorkspacePath>> {
+    let contents = std::fs::read(path).with_context(|| format!("Reading input list '{}'", path.to_string_lossy()))?;
+    let separator = if contents.contains(&0) { b'\0' } else { b'\n' };
+    contents
+        .split(|&b| b == separator)
+        .map(|entry| entry.strip_suffix(b"\r").unwrap_or(entry))
+        .filter(|entry| !entry.is_empty())
+        .map(|entry| {
+            let entry = std::str::from_utf8(entry)
+                .with_context(|| format!("Invalid entry in input list '{}'", path.to_string_lossy()))?;
+            Ok(entry.into())
+        })
+        .collect()
+}
+
+/// Read the target of the symlink, which has to be valid UTF-8 to be recorded in a cache entry.
+fn read_symlink(path: &Path) -> Result<String> {
+    let target = std::fs::read_link(path).with_context(|| format!("Reading symlink '{}'", path.to_string_lossy()))?;
+    target
+        .into_os_string()
+        .into_string()
+        .map_err(|_| anyhow!("Target of symlink '{}' isn't valid UTF-8", path.to_string_lossy()))
+}
+
+/// Expand the glob pattern. A literal path which is a dangling symlink doesn't match in some versions of the glob
+/// crate, as they follow symlinks when checking whether the path exists, so it's returned on its own then.
+fn expand_glob(pattern: &Path) -> Result<Vec<PathBuf>> {
+    let glob_pattern = pattern.to_str().ok_or(anyhow!("can't convert path to string"))?;
+    let files = glob(glob_pattern)?.collect::<Result<Vec<_>, _>>()?;
+    if files.is_empty() && matches!(pattern.symlink_metadata(), Ok(metadata) if metadata.is_symlink()) {
+        return Ok(vec![pattern.to_owned()]);
+    }
+    Ok(files)
+}
+
+/// Make the standard input seekable, so that it can be hashed and then still read by the wrapped command.
+///
+/// Unless it's a regular file already, the standard input is copied into an anonymous temporary file,
+/// which then replaces it. The wrapped command inherits it, and so does the fallback exec.
+fn spool_stdin() -> Result<()> {
+    let stdin = io::stdin();
+    if File::from(stdin.as_fd().try_clone_to_owned()?).metadata()?.is_file() {
+        return Ok(());
+    }
+    let mut spool = tempfile::tempfile().context("Creating a file to spool stdin")?;
+    io::copy(&mut stdin.lock(), &mut spool).context("Spooling stdin")?;
+    spool.seek(SeekFrom::Start(0))?;
+    nix::unistd::dup2(spool.as_raw_fd(), stdin.as_raw_fd()).context("Replacing stdin with its spool")?;
+    Ok(())
+}
+
+/// Copy a stream of the wrapped command to our own stream as it goes, passing every chunk to `capture` too.
+async fn tee<R, W, F>(mut from: R, mut to: W, mut capture: F) -> Result<()>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+    F: FnMut(&[u8]),
+{
+    const BUFSIZE: usize = 8192;
+    let mut forward = true;
+    let mut buf: [u8; BUFSIZE] = [0; BUFSIZE];
+    loop {
+        let rd = from.read(&mut buf).await?;
+        if rd == 0 {
+            break;
+        }
+        // If our own stream is gone, keep draining the command's one, so that it doesn't block.
+        if forward {
+            let mut result = to.write_all(&buf[..rd]).await;
+            if result.is_ok() {
+                result = to.flush().await;
+            }
+            if let Err(err) = result {
+                warn!("Failed to pass on the output of the command: {}", err);
+                forward = false;
+            }
+        }
+        capture(&buf[..rd]);
+    }
+    Ok(())
+}
+
+/// Standard output and error of the wrapped command, captured as it ran.
+#[derive(Default)]
+pub struct CapturedStreams {
+    pub stdout: Option<Vec<u8>>,
+    pub stderr: Option<Vec<u8>>,
+    /// Both streams, interleaved as the command wrote them.
+    pub combined: Option<CombinedLog>,
+    /// Whether any of the captures exceeded the size limit, so that the run can't be replayed.
+    pub too_large: bool,
+}
+
+impl CapturedStreams {
+    /// Record a chunk of output into all the captures of its stream, unless they grow over `max_size` bytes.
+    fn record(&mut self, stream: Stream, chunk: &[u8], max_size: usize) {
+        let buffer = match stream {
+            Stream::Stdout => self.stdout.as_mut(),
+            Stream::Stderr => self.stderr.as_mut(),
+        };
+        let buffer_size = buffer.as_ref().map_or(0, |buffer| buffer.len() + chunk.len());
+        let log_size = self.combined.as_ref().map_or(0, |log| log.size_with(chunk.len()));
+        if buffer_size > max_size || log_size > max_size {
+            // The run won't be cached anyway, so there's no point in keeping anything.
+            *self = Self {
+                too_large: true,
+                ..Default::default()
+            };
+            return;
+        }
+        if let Some(buffer) = buffer {
+            buffer.extend_from_slice(chunk);
+        }
+        if let Some(log) = self.combined.as_mut() {
+            log.record(stream, chunk);
+        }
+    }
+}
+
 pub struct Capsule<'a> {
     config: &'a Config,
     caching_backend: &'a dyn CachingBackend,
@@ -65,17 +199,126 @@ impl<'a> Capsule<'a> {
         self.config.capsule_job.as_ref().cloned().unwrap_or_default()
     }
 
+    /// An empty input set, hashing files the configured way.
+    fn input_set(&self) -> InputSet {
+        InputSet {
+            hash_paths: self.config.hash_input_paths,
+            hash_cache: self.config.hash_cache_dir.as_ref().map(HashCache::new),
+            concurrent_hash_max: self.config.concurrent_hash_max,
+            hash_function: self.config.hash_function(),
+            ..Default::default()
+        }
+    }
+
+    fn input_filter(&self) -> Result<InputFilter> {
+        InputFilter::new(
+            &self.config.exclude_patterns,
+            self.config.use_ignore_files,
+            &self.config.workspace_root,
+        )
+    }
+
+    /// Returns the files listed in the depfile, if there is one, which are not among the known inputs yet.
+    fn depfile_inputs<'b, I>(&self, filter: &mut InputFilter, known: I) -> Result<Vec<WorkspacePath>>
+    where
+        I: Iterator<Item = &'b Input>,
+    {
+        let root = &self.config.workspace_root;
+        let depfile = match self.config.depfile {
+            Some(ref depfile) => depfile.to_path(root)?,
+            None => return Ok(Vec::new()),
+        };
+        let prerequisites = match read_depfile(&depfile)? {
+            Some(prerequisites) => prerequisites,
+            None => return Ok(Vec::new()),
+        };
+        let cwd = std::env::current_dir().context("Reading the current directory")?;
+        let mut known: HashSet<PathBuf> = known
+            .filter_map(|input| match input {
+                Input::File(filename) => filename.to_path(root).ok().map(|path| cwd.join(path)),
+                _ => None,
+            })
+            .collect();
+        let mut files = Vec::new();
+        for prerequisite in prerequisites {
+            let path = cwd.join(prerequisite);
+            // Files which are gone, e.g. temporary ones, can't be inputs of the next run.
+            if path.is_file() && !filter.is_excluded(&path)? && known.insert(path.clone()) {
+                files.push(WorkspacePath::from_full_path(&path, root));
+            }
+        }
+        Ok(files)
+    }
+
+    /// Adds the inputs listed in the depfile the command wrote to the inputs of its cache entry.
+    fn discover_inputs(&self, inputs: &InputHashBundle) -> Result<InputHashBundle> {
+        let mut filter = self.input_filter()?;
+        let mut discovered = self.input_set();
+        for file in self.depfile_inputs(&mut filter, inputs.hash_details.iter().map(|(input, _)| input))? {
+            discovered.add_input(Input::File(file));
+        }
+        let mut inputs = inputs.clone();
+        inputs.discovered_inputs = discovered.hash_bundle(&self.config.workspace_root)?.hash_details;
+        Ok(inputs)
+    }
+
+    /// Returns an input discovered in the run of the cached entry which has changed since, if any.
+    fn changed_discovered_input(&self, cached: &InputHashBundle) -> Result<Option<Input>> {
+        let mut current = self.input_set();
+        for (input, _) in &cached.discovered_inputs {
+            current.add_input(input.clone());
+        }
+        let current = current.hash_bundle(&self.config.workspace_root)?;
+        let cached = InputHashBundle {
+            hash_details: cached.discovered_inputs.clone(),
+            ..Default::default()
+        };
+        Ok(current.diff(&cached).changed.into_iter().next())
+    }
+
     pub fn read_inputs(&self) -> Result<InputHashBundle> {
-        let mut inputs = InputSet::default();
-        for file_pattern in &self.config.input_files {
+        let mut inputs = self.input_set();
+        let mut filter = self.input_filter()?;
+        let mut input_patterns = self.config.input_files.clone();
+        for input_list in &self.config.input_lists {
+            input_patterns.extend(read_input_list(&input_list.to_path(&self.config.workspace_root)?)?);
+        }
+        for input_pattern in &input_patterns {
             let mut file_count = 0;
-            let fp = file_pattern.to_path(&self.config.workspace_root)?;
-            let glob_pattern = fp.to_str().ok_or(anyhow!("can't convert path to string"))?;
-            for file in glob(glob_pattern)? {
-                let file = file?;
-                if file.is_file() {
+            let pattern_str = input_pattern.to_string();
+            let (file_pattern, files) = match pattern_str.strip_prefix(GIT_INPUT_PREFIX) {
+                Some(path) => {
+                    let file_pattern = WorkspacePath::from(path);
+                    let tracked = tracked_files(&file_pattern.to_path(&self.config.workspace_root)?)
+                        .with_context(|| format!("Listing the files of '{}'", input_pattern))?;
+                    let mut files = Vec::new();
+                    for file in tracked {
+                        if let Some(hash) = file.blob_hash.filter(|_| self.config.git_blob_hashes) {
+                            // Blob hashes aren't hashes of the contents, so they mustn't collide with them.
+                            inputs
+                                .file_hashes
+                                .insert(file.path.clone(), format!("git-blob:{}", hash));
+                        }
+                        files.push(file.path);
+                    }
+                    (file_pattern, files)
+                }
+                None => {
+                    let files = expand_glob(&input_pattern.to_path(&self.config.workspace_root)?)?;
+                    (input_pattern.clone(), files)
+                }
+            };
+            for file in files {
+                // Symlinks hashed by their targets are inputs even if they dangle.
+                let symlink = self.config.input_symlinks == SymlinkPolicy::Target && file.is_symlink();
+                // Tracked files deleted from the work tree are left out, and so are submodules.
+                if (file.is_file() || symlink) && !filter.is_excluded(&file)? {
+                    if symlink {
+                        let hash = self.config.hash_function().symlink_hash(&read_symlink(&file)?);
+                        inputs.file_hashes.insert(file.clone(), hash);
+                    }
                     // Convert workspace relative patterns to workspace relative expansions.
-                    let expansion_file_name = match *file_pattern {
+                    let expansion_file_name = match file_pattern {
                         WorkspacePath::NonWorkspace(_) => WorkspacePath::NonWorkspace(file),
                         WorkspacePath::Workspace(_) => WorkspacePath::Workspace(file),
                     };
@@ -84,45 +327,110 @@ impl<'a> Capsule<'a> {
                 }
             }
             if file_count == 0 {
-                return Err(anyhow!("Pattern '{}' didn't match any files", file_pattern));
+                return Err(anyhow!("Pattern '{}' didn't match any files", input_pattern));
             }
         }
 
         for tool_tag in &self.config.tool_tags {
             inputs.add_input(Input::ToolTag(tool_tag.clone()));
         }
+
+        let (hash_function, hash_cache) = (self.config.hash_function(), inputs.hash_cache.clone());
+        let mut tool_binaries: Vec<&str> = self.config.tool_binaries.iter().map(String::as_str).collect();
+        if self.config.fingerprint_command {
+            if let Some(program) = self.config.command_to_run.first() {
+                tool_binaries.push(program);
+            }
+        }
+        for tool_binary in tool_binaries {
+            let tool_tag = binary_tool_tag(
+                tool_binary,
+                &self.config.workspace_root,
+                hash_function,
+                hash_cache.as_ref(),
+            )?;
+            inputs.add_input(Input::ToolTag(tool_tag));
+        }
+        for tool_command in &self.config.tool_commands {
+            inputs.add_input(Input::ToolTag(command_tool_tag(tool_command, hash_function)?));
+        }
+        if self.config.platform_tag {
+            for tool_tag in platform_tags() {
+                inputs.add_input(Input::ToolTag(tool_tag));
+            }
+        }
+
+        for name in &self.config.env_inputs {
+            let value = std::env::var_os(name).map(|value| value.to_string_lossy().into_owned());
+            inputs.add_input(Input::EnvVar(name.clone(), value));
+        }
+
+        if !self.config.ignore_command {
+            let cwd = std::env::current_dir().context("Reading the current directory")?;
+            let cwd = WorkspacePath::from_full_path(&cwd, &self.config.workspace_root);
+            if let (WorkspacePath::NonWorkspace(_), Some(root)) = (&cwd, &self.config.workspace_root) {
+                warn!(
+                    "The current directory '{}' is outside of the workspace root '{}', and is hashed as an absolute path",
+                    cwd, root
+                );
+            }
+            inputs.add_input(Input::Command {
+                args: self.config.command_to_run.clone(),
+                cwd,
+            });
+        }
+
+        if self.config.stdin_input {
+            // Spooling consumes the original stdin, so it's done after everything else that may fail.
+            spool_stdin()?;
+            inputs.add_input(Input::Stdin);
+        }
         let capsule_id = self.capsule_id();
         inputs
             .hash_bundle(&self.config.workspace_root)
             .with_context(|| format!("Hashing inputs of capsule '{}'", capsule_id))
     }
 
-    pub fn read_outputs(&self, exit_code: Option<i32>) -> Result<OutputHashBundle> {
+    pub fn read_outputs(&self, exit_code: Option<i32>, captured: &CapturedStreams) -> Result<OutputHashBundle> {
         let mut outputs = OutputSet::default();
+        outputs.concurrent_hash_max = self.config.concurrent_hash_max;
+        outputs.hash_function = self.config.hash_function();
         if let Some(exit_code) = exit_code {
             outputs.add_output(Output::ExitCode(exit_code));
         }
+        if let Some(ref data) = captured.stdout {
+            outputs.add_captured(Output::Stdout, data);
+        }
+        if let Some(ref data) = captured.stderr {
+            outputs.add_captured(Output::Stderr, data);
+        }
+        if let Some(ref log) = captured.combined {
+            outputs.add_captured(Output::Combined, log.as_bytes());
+        }
         for file_pattern in &self.config.output_files {
-            let fp = file_pattern.to_path(&self.config.workspace_root)?;
-            let glob_pattern = fp.to_str().ok_or(anyhow!("can't convert path to string"))?;
             let mut present = false;
-            for file in glob(glob_pattern)? {
-                let file = file?;
+            for file in expand_glob(&file_pattern.to_path(&self.config.workspace_root)?)? {
                 if file.is_dir() {
                     continue;
                 }
-                if file.is_file() {
-                    // Convert workspace relative patterns to workspace relative expansions.
-                    let mode = file.metadata()?.permissions().mode();
-                    let expansion_file_name =
-                        WorkspacePath::from_full_path(file.as_path(), &self.config.workspace_root);
-                    outputs.add_output(Output::File(FileOutput {
-                        filename: expansion_file_name,
-                        present: true,
-                        mode,
-                    }));
-                    present = true;
-                }
+                let metadata = file.symlink_metadata()?;
+                // Symlinks are recorded as such, dangling ones included, rather than as the files they point to.
+                let symlink = if metadata.file_type().is_symlink() {
+                    Some(read_symlink(&file)?)
+                } else if metadata.is_file() {
+                    None
+                } else {
+                    continue;
+                };
+                // Convert workspace relative patterns to workspace relative expansions.
+                let expansion_file_name = WorkspacePath::from_full_path(file.as_path(), &self.config.workspace_root);
+                outputs.add_output(Output::File(FileOutput {
+                    filename: expansion_file_name,
+                    present: true,
+                    mode: metadata.permissions().mode(),
+                    symlink,
+                }));
+                present = true;
             }
             if !present {
                 // This seems to be a file that hasn't matched.
@@ -130,9 +438,18 @@ impl<'a> Capsule<'a> {
                     filename: file_pattern.clone(),
                     present: false,
                     mode: 0o644, // Default permissions just in case.
+                    symlink: None,
                 }));
             }
         }
+        for dirname in &self.config.output_dirs {
+            let dir = dirname.to_path(&self.config.workspace_root)?;
+            let manifest = read_tree(&dir, self.config.hash_function(), self.config.concurrent_hash_max)?;
+            outputs.add_output(Output::Tree(TreeOutput {
+                dirname: dirname.clone(),
+                manifest,
+            }));
+        }
         let capsule_id = self.capsule_id();
         outputs
             .hash_bundle(&self.config.workspace_root)
@@ -143,21 +460,107 @@ impl<'a> Capsule<'a> {
         left.hash == right.hash
     }
 
-    async fn execute_command(&self, inputs: &InputHashBundle, program_run: &mut AtomicBool) -> Result<ExitStatus> {
+    /// Whether the configuration asks to capture the standard output, the standard error,
+    /// and both of them combined.
+    fn capture_streams(&self) -> (bool, bool, bool) {
+        (
+            self.config.capture_stdout.unwrap_or(false),
+            self.config.capture_stderr.unwrap_or(false),
+            self.config.capture_combined,
+        )
+    }
+
+    async fn execute_command(
+        &self,
+        inputs: &InputHashBundle,
+        capture: bool,
+        program_run: &mut AtomicBool,
+    ) -> Result<(ExitStatus, CapturedStreams)> {
         info!("Executing command: {:?}", self.config.command_to_run);
         if self.config.command_to_run.is_empty() {
             Err(anyhow!(USAGE))
         } else {
-            let mut child = Command::new(&self.config.command_to_run[0])
+            let (capture_stdout, capture_stderr, capture_combined) = if capture {
+                self.capture_streams()
+            } else {
+                (false, false, false)
+            };
+            let mut command = Command::new(&self.config.command_to_run[0]);
+            command
                 .args(&self.config.command_to_run[1..])
-                .env(&self.config.inputs_hash_var, &inputs.hash)
-                .spawn()
-                .with_context(|| "Spawning command")?;
+                .env(&self.config.inputs_hash_var, &inputs.hash);
+            if capture_stdout || capture_combined {
+                command.stdout(Stdio::piped());
+            }
+            if capture_stderr || capture_combined {
+                command.stderr(Stdio::piped());
+            }
+            let captured = std::sync::Mutex::new(CapturedStreams {
+                stdout: capture_stdout.then(Vec::new),
+                stderr: capture_stderr.then(Vec::new),
+                combined: capture_combined.then(CombinedLog::default),
+                too_large: false,
+            });
+            let mut child = command.spawn().with_context(|| "Spawning command")?;
             // Having executed the command, just need to tell our caller whether we succeeded in
             // running the program.  this happens as soon as we have a child program.
             program_run.store(true, Ordering::SeqCst);
-            let exit_status = child.wait().await?;
-            Ok(exit_status)
+            let max_size = self.config.capture_max_size;
+            let stdout = child.stdout.take();
+            let stderr = child.stderr.take();
+            // Both streams are read concurrently, so chunks go into the combined log in about the order
+            // the command wrote them.
+            let record = |stream| {
+                let captured = &captured;
+                move |chunk: &[u8]| captured.lock().unwrap().record(stream, chunk, max_size)
+            };
+            let stdout_fut = async {
+                match stdout {
+                    Some(stdout) => tee(stdout, tokio::io::stdout(), record(Stream::Stdout)).await,
+                    None => Ok(()),
+                }
+            };
+            let stderr_fut = async {
+                match stderr {
+                    Some(stderr) => tee(stderr, tokio::io::stderr(), record(Stream::Stderr)).await,
+                    None => Ok(()),
+                }
+            };
+            let (exit_status, _, _) = futures::try_join!(
+                async { child.wait().await.map_err(anyhow::Error::from) },
+                stdout_fut,
+                stderr_fut
+            )?;
+            Ok((exit_status, captured.into_inner().unwrap()))
+        }
+    }
+
+    /// Explain a cache miss by comparing the inputs against the latest cache entry of the capsule.
+    /// Failing to do so is not an error, as it's just a diagnostic.
+    async fn explain_miss(&self, inputs: &InputHashBundle) -> Option<InputsDiff> {
+        let latest = time::timeout(
+            Duration::from_millis(timeouts::TIMEOUT_LOOKUP_MILLIS),
+            self.caching_backend.latest_entry(),
+        )
+        .await;
+        match latest {
+            Ok(Ok(Some(latest))) => {
+                let diff = inputs.diff(&latest.inputs);
+                info!("Cache miss on {}: {}", self.capsule_id(), diff);
+                Some(diff)
+            }
+            Ok(Ok(None)) => {
+                info!("Cache miss on {}: no previous cache entry", self.capsule_id());
+                None
+            }
+            Ok(Err(err)) => {
+                warn!("Failed to read the latest cache entry: {:#}", err);
+                None
+            }
+            Err(_) => {
+                warn!("Time out reading the latest cache entry");
+                None
+            }
         }
     }
 
@@ -167,13 +570,18 @@ impl<'a> Capsule<'a> {
         lookup_result: &Option<InputOutputBundle>,
         program_run: &mut AtomicBool,
     ) -> Result<ExitStatus> {
-        let exit_status = self
-            .execute_command(inputs, program_run)
-            .await
-            .with_context(|| "Waiting for child")?;
+        // If it's a cache miss, try to tell why while the command runs.
+        let explain_fut = async {
+            match lookup_result {
+                None => self.explain_miss(inputs).await,
+                Some(_) => None,
+            }
+        };
+        let (execution, miss_explanation) = join!(self.execute_command(inputs, true, program_run), explain_fut);
+        let (exit_status, captured) = execution.with_context(|| "Waiting for child")?;
         // Now that we got the exit code, we try hard to pass it back to exit.
         // If we fail along the way, we should complain, but still continue.
-        match self.read_outputs(exit_status.code()) {
+        match self.read_outputs(exit_status.code(), &captured) {
             Ok(outputs) => {
                 let non_determinism = lookup_result.as_ref().map_or(false, |lookup_result| {
                     !Self::equal_outputs(&lookup_result.outputs, &outputs)
@@ -191,20 +599,51 @@ impl<'a> Capsule<'a> {
                     );
                 }
 
+                // A run whose output can't be replayed in full is not cached.
+                let mut cacheable = !captured.too_large;
+                if captured.too_large {
+                    warn!(
+                        "Captured output exceeds {} bytes, not writing it to cache",
+                        self.config.capture_max_size
+                    );
+                }
+                // Neither is a run whose inputs can't be verified on a cache hit.
+                let inputs = self.discover_inputs(inputs).unwrap_or_else(|err| {
+                    error!(
+                        "Failed to read inputs from the depfile, not writing to cache: {:#}",
+                        err
+                    );
+                    cacheable = false;
+                    inputs.clone()
+                });
+
                 // Concurrently write the log, cache entry and cache objects (files).
                 // The larger of each of the timeouts is applied to the combined branch.
                 let logger_fut = time::timeout(
                     Duration::from_millis(timeouts::TIMEOUT_LOGGING_MILLIS),
-                    self.logger.log(inputs, &outputs, false, non_determinism),
-                );
-                let cache_write_fut = time::timeout(
-                    Duration::from_millis(timeouts::TIMEOUT_CACHE_WRITE_MILLIS),
-                    self.caching_backend.write(inputs, &outputs, self.capsule_job()),
-                );
-                let upload_fut = time::timeout(
-                    Duration::from_millis(timeouts::TIMEOUT_UPLOAD_MILLIS),
-                    self.upload_files(&outputs),
+                    self.logger
+                        .log(&inputs, &outputs, false, non_determinism, miss_explanation.as_ref()),
                 );
+                let cache_write_fut = async {
+                    if !cacheable {
+                        return Ok(Ok(()));
+                    }
+                    time::timeout(
+                        Duration::from_millis(timeouts::TIMEOUT_CACHE_WRITE_MILLIS),
+                        self.caching_backend.write(&inputs, &outputs, self.capsule_job()),
+                    )
+                    .await
+                };
+                let upload_fut = async {
+                    if !cacheable {
+                        return Ok(Ok(()));
+                    }
+                    time::timeout(
+                        Duration::from_millis(timeouts::TIMEOUT_UPLOAD_MILLIS),
+                        self.upload_files(&outputs, &captured),
+                    )
+                    .await
+                };
                 let (logger_result, cache_result, upload_result) = join!(logger_fut, cache_write_fut, upload_fut);
 
                 // If any of the above failed, we should just complain in the output, no need
@@ -245,51 +684,184 @@ impl<'a> Capsule<'a> {
     async fn download_files(&self, outputs: &OutputHashBundle) -> Result<()> {
         // Now download all files that should be present.
         let mut all_files_futures = Vec::new();
+        let mut trees = Vec::new();
         // This loop generates futures for all downloadable files, and places them
         // into all_files_futures.
         for (item, item_hash) in &outputs.hash_details {
             if let Output::File(ref fileoutput) = item {
-                if fileoutput.present {
-                    info!("Downloading file '{}' hash '{}'", fileoutput.filename, item_hash);
+                if let Some(ref target) = fileoutput.symlink {
+                    info!("Restoring symlink '{}' -> '{}'", fileoutput.filename, target);
                     let filename = fileoutput.filename.to_path(&self.config.workspace_root)?;
                     let dir = filename.parent().context("No parent directory")?;
                     std::fs::create_dir_all(dir)?;
-                    let file = NamedTempFile::new_in(dir)?;
-                    let (file, path) = file.into_parts();
-                    let mut file_stream = tokio::fs::File::from_std(file);
-                    let download_file_fut = async move {
-                        let mut file_body_reader = self.caching_backend.download_object_file(item_hash).await?;
-                        tokio::io::copy(&mut file_body_reader, &mut file_stream).await?;
-                        file_stream.flush().await?;
-                        info!("File {} downloaded, verifying hash", fileoutput.filename);
-                        // Calculating the SHA256 is a long CPU bound op, better do in a thread.
-                        let tmp_path = path.to_path_buf();
-                        let received_hash = task::spawn_blocking(move || file_hash(&tmp_path)).await??;
-                        if received_hash != *item_hash {
-                            return Err(anyhow!("Mismatch of the downloaded file hash"));
-                        }
-                        path.persist(&filename)?;
-                        std::fs::set_permissions(&filename, std::fs::Permissions::from_mode(fileoutput.mode))?;
-                        Ok::<(), anyhow::Error>(())
-                    };
-                    all_files_futures.push(download_file_fut);
+                    // Like downloaded files, the symlink is created aside and then renamed over the destination.
+                    let path = NamedTempFile::new_in(dir)?.into_temp_path();
+                    std::fs::remove_file(&path)?;
+                    std::os::unix::fs::symlink(target, &path)?;
+                    path.persist(&filename)?;
+                } else if fileoutput.present {
+                    let filename = fileoutput.filename.to_path(&self.config.workspace_root)?;
+                    all_files_futures.push(self.download_file(
+                        fileoutput.filename.to_string(),
+                        item_hash,
+                        filename,
+                        fileoutput.mode,
+                        outputs.hash_function,
+                    )?);
                 }
             }
+            if let Output::Tree(TreeOutput {
+                ref dirname,
+                manifest: Some(ref manifest),
+            }) = item
+            {
+                info!("Restoring tree '{}' hash '{}'", dirname, item_hash);
+                let dir = dirname.to_path(&self.config.workspace_root)?;
+                prepare_tree(&dir, manifest)?;
+                for (path, hash, mode) in manifest.files() {
+                    all_files_futures.push(self.download_file(
+                        format!("{}/{}", dirname, path.to_string_lossy()),
+                        hash,
+                        dir.join(&path),
+                        mode,
+                        outputs.hash_function,
+                    )?);
+                }
+                trees.push((dir, manifest));
+            }
+            if let Output::Tree(TreeOutput {
+                ref dirname,
+                manifest: None,
+            }) = item
+            {
+                // The command didn't create the directory, so one left by an earlier run is stale.
+                remove_tree(&dirname.to_path(&self.config.workspace_root)?)?;
+            }
         }
         // Limit concurrency to max configured download threads.
         futures::stream::iter(all_files_futures.into_iter())
             .buffer_unordered(self.config.concurrent_download_max)
             .try_collect()
             .await?;
+        // Directories may be read-only, so their modes are set once all of their files are in place.
+        for (dir, manifest) in trees {
+            set_modes(&dir, manifest)?;
+        }
         Ok(())
     }
 
-    /// Upload output files into S3, keyed by their hash (content addressed).
-    async fn upload_files(&self, outputs: &OutputHashBundle) -> Result<()> {
+    /// Create a temporary file next to the destination, and return the future downloading the object into it,
+    /// verifying its hash, and moving it into place.
+    fn download_file<'b>(
+        &'b self,
+        name: String,
+        item_hash: &'b str,
+        filename: PathBuf,
+        mode: u32,
+        hash_function: HashFunction,
+    ) -> Result<impl Future<Output = Result<()>> + 'b> {
+        info!("Downloading file '{}' hash '{}'", name, item_hash);
+        let dir = filename.parent().context("No parent directory")?;
+        std::fs::create_dir_all(dir)?;
+        let file = NamedTempFile::new_in(dir)?;
+        let (file, path) = file.into_parts();
+        let mut file_stream = tokio::fs::File::from_std(file);
+        Ok(async move {
+            let mut file_body_reader = self.caching_backend.download_object_file(item_hash).await?;
+            tokio::io::copy(&mut file_body_reader, &mut file_stream).await?;
+            file_stream.flush().await?;
+            info!("File {} downloaded, verifying hash", name);
+            // Calculating the hash is a long CPU bound op, better do in a thread.
+            let tmp_path = path.to_path_buf();
+            let received_hash = task::spawn_blocking(move || hash_function.file_hash(&tmp_path)).await??;
+            if received_hash != *item_hash {
+                return Err(anyhow!("Mismatch of the downloaded file hash"));
+            }
+            path.persist(&filename)?;
+            std::fs::set_permissions(&filename, std::fs::Permissions::from_mode(mode))?;
+            Ok::<(), anyhow::Error>(())
+        })
+    }
+
+    /// Download the captured streams of the command from the caching backend.
+    async fn download_streams(&self, outputs: &OutputHashBundle) -> Result<Vec<(Output, Vec<u8>)>> {
+        let mut streams = Vec::new();
+        for (item, item_hash) in &outputs.hash_details {
+            if let Output::Stdout | Output::Stderr | Output::Combined = item {
+                info!("Downloading {} hash '{}'", item, item_hash);
+                let mut data = Vec::new();
+                self.caching_backend
+                    .download_object_file(item_hash)
+                    .await?
+                    .read_to_end(&mut data)
+                    .await?;
+                if outputs.hash_function.bytes_hash(&data) != *item_hash {
+                    return Err(anyhow!("Mismatch of the downloaded {} hash", item));
+                }
+                streams.push((item.clone(), data));
+            }
+        }
+        // Replay the standard output first, regardless of the hashes.
+        streams.sort();
+        Ok(streams)
+    }
+
+    /// Write the captured streams to our own ones, as if the command had run.
+    ///
+    /// The combined log, if present, is preferred over the separate streams, as it keeps their interleaving.
+    async fn replay_streams(streams: &[(Output, Vec<u8>)]) -> Result<()> {
+        async fn write(stream: Stream, data: &[u8]) -> Result<()> {
+            match stream {
+                Stream::Stdout => {
+                    let mut stdout = tokio::io::stdout();
+                    stdout.write_all(data).await?;
+                    stdout.flush().await?;
+                }
+                Stream::Stderr => {
+                    let mut stderr = tokio::io::stderr();
+                    stderr.write_all(data).await?;
+                    stderr.flush().await?;
+                }
+            }
+            Ok(())
+        }
+        if let Some((_, log)) = streams.iter().find(|(item, _)| *item == Output::Combined) {
+            for record in CombinedLog::parse(log)? {
+                write(record.stream, record.data).await?;
+            }
+            return Ok(());
+        }
+        for (item, data) in streams {
+            match item {
+                Output::Stdout => write(Stream::Stdout, data).await?,
+                Output::Stderr => write(Stream::Stderr, data).await?,
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+
+    /// Upload output files and captured streams into S3, keyed by their hash (content addressed).
+    async fn upload_files(&self, outputs: &OutputHashBundle, captured: &CapturedStreams) -> Result<()> {
         let mut all_files_futures = Vec::new();
+        let mut tree_objects = HashSet::new();
         for (item, item_hash) in &outputs.hash_details {
+            let data = match item {
+                Output::Stdout => captured.stdout.as_deref(),
+                Output::Stderr => captured.stderr.as_deref(),
+                Output::Combined => captured.combined.as_ref().map(CombinedLog::as_bytes),
+                _ => None,
+            };
+            if let Some(data) = data {
+                all_files_futures.push(self.caching_backend.upload_object_file(
+                    item.to_string(),
+                    item_hash,
+                    Box::pin(std::io::Cursor::new(data.to_vec())),
+                    data.len() as u64,
+                ));
+            }
             if let Output::File(ref fileoutput) = item {
-                if fileoutput.present {
+                if fileoutput.present && fileoutput.symlink.is_none() {
                     let object_name = fileoutput.filename.to_string();
                     let file_name = fileoutput.filename.to_path(&self.config.workspace_root)?;
                     let tokio_file = tokio::fs::File::open(&file_name).await?;
@@ -302,6 +874,27 @@ impl<'a> Capsule<'a> {
                     ));
                 }
             }
+            if let Output::Tree(TreeOutput {
+                ref dirname,
+                manifest: Some(ref manifest),
+            }) = item
+            {
+                let dir = dirname.to_path(&self.config.workspace_root)?;
+                for (path, hash, _) in manifest.files() {
+                    // Files with the same contents are one object.
+                    if !tree_objects.insert(hash) {
+                        continue;
+                    }
+                    let tokio_file = tokio::fs::File::open(dir.join(&path)).await?;
+                    let content_length = tokio_file.metadata().await?.len();
+                    all_files_futures.push(self.caching_backend.upload_object_file(
+                        format!("{}/{}", dirname, path.to_string_lossy()),
+                        hash,
+                        Box::pin(tokio_file),
+                        content_length,
+                    ));
+                }
+            }
         }
         // Limit concurrency to max configured upload threads.
         futures::stream::iter(all_files_futures.into_iter())
@@ -328,10 +921,10 @@ impl<'a> Capsule<'a> {
         // CAPSULE_INPUTS_HASH with data about the capsule inputs.
         if self.config.passive {
             return self
-                .execute_command(&inputs, program_run)
+                .execute_command(&inputs, false, program_run)
                 .await
                 .with_context(|| "Waiting for child")
-                .map(|exit_status| exit_status.code().unwrap_or(Self::DEFAULT_EXIT_CODE));
+                .map(|(exit_status, _)| exit_status.code().unwrap_or(Self::DEFAULT_EXIT_CODE));
         }
 
         let lookup_result = time::timeout(
@@ -358,7 +951,12 @@ impl<'a> Capsule<'a> {
                 log_cache_hit("ignoring and proceeding with execution");
                 use_cache = false
             } else {
-                if !self.config.cache_failure {
+                // The inputs hash differs between hash functions anyway, this only guards against a collision.
+                if lookup_result.outputs.hash_function != self.config.hash_function() {
+                    log_cache_hit("entry of a different hash function, proceeding with execution");
+                    use_cache = false;
+                }
+                if use_cache && !self.config.cache_failure {
                     // If result code from the command is not 0
                     if lookup_result.outputs.result_code().unwrap_or(1) != 0 {
                         log_cache_hit("cached failure, proceeding with execution");
@@ -384,21 +982,76 @@ impl<'a> Capsule<'a> {
                         use_cache = false;
                     }
                 }
+                // Output directories are recorded as declared, so they have to be the same ones.
+                if use_cache {
+                    let cached_dirs: BTreeSet<&WorkspacePath> = lookup_result
+                        .outputs
+                        .hash_details
+                        .iter()
+                        .filter_map(|(output, _)| match output {
+                            Output::Tree(tree_output) => Some(&tree_output.dirname),
+                            _ => None,
+                        })
+                        .collect();
+                    if cached_dirs != self.config.output_dirs.iter().collect() {
+                        log_cache_hit("mismatch in output directories, proceeding with execution");
+                        use_cache = false;
+                    }
+                }
+                // Inputs the cached run read, as listed in its depfile, have to be the same as now.
+                if use_cache && !lookup_result.inputs.discovered_inputs.is_empty() {
+                    match self.changed_discovered_input(&lookup_result.inputs) {
+                        Ok(None) => {}
+                        Ok(Some(input)) => {
+                            log_cache_hit(&format!(
+                                "discovered input {} changed, proceeding with execution",
+                                input
+                            ));
+                            use_cache = false;
+                        }
+                        Err(err) => {
+                            log_cache_hit(&format!(
+                                "failed to hash discovered inputs: {:#}, proceeding with execution",
+                                err
+                            ));
+                            use_cache = false;
+                        }
+                    }
+                }
+                // Streams that should be captured have to be in the cache entry, to be replayed.
+                if use_cache {
+                    let cached = |stream: Output| lookup_result.outputs.hash_details.iter().any(|(o, _)| *o == stream);
+                    let (capture_stdout, capture_stderr, capture_combined) = self.capture_streams();
+                    if (capture_stdout && !cached(Output::Stdout))
+                        || (capture_stderr && !cached(Output::Stderr))
+                        || (capture_combined && !cached(Output::Combined))
+                    {
+                        log_cache_hit("captured output is missing, proceeding with execution");
+                        use_cache = false;
+                    }
+                }
             }
 
             if use_cache {
-                if let Ok(result) = time::timeout(
-                    Duration::from_millis(timeouts::TIMEOUT_DOWNLOAD_MILLIS),
-                    self.download_files(&lookup_result.outputs),
-                )
-                .await
+                let download_fut = async {
+                    let (_, streams) = futures::try_join!(
+                        self.download_files(&lookup_result.outputs),
+                        self.download_streams(&lookup_result.outputs)
+                    )?;
+                    Ok::<_, anyhow::Error>(streams)
+                };
+                if let Ok(result) =
+                    time::timeout(Duration::from_millis(timeouts::TIMEOUT_DOWNLOAD_MILLIS), download_fut).await
                 {
                     match result {
-                        Ok(_) => {
+                        Ok(streams) => {
+                            Self::replay_streams(&streams).await.unwrap_or_else(|err| {
+                                error!("Failed to replay captured output: {}", err);
+                            });
                             log_cache_hit("success");
                             // Log successful cached results.
                             self.logger
-                                .log(&inputs, &lookup_result.outputs, true, false)
+                                .log(&inputs, &lookup_result.outputs, true, false, None)
                                 .await
                                 .unwrap_or_else(|err| {
                                     error!("Failed to log results for observability: {}", err);
@@ -437,15 +1090,219 @@ mod tests {
     use serial_test::serial;
     use tempfile::TempDir;
 
-    const EMPTY_SHA256: &'static str = "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855";
+    // Hash of the inputs without any files, tool tags or command.
+    const EMPTY_INPUTS_HASH: &str = "7cbf3ddc614c5f8b49cdd6731df70fad030a6899f87e4af747b264344fe0fb9c";
 
     #[test]
     #[serial]
     fn test_empty_capsule() {
         let backend = dummy::DummyBackend::default();
-        let config = Config::new(["capsule", "-c", "wtf", "--", "/bin/echo"].iter(), None).unwrap();
+        let config = Config::new(
+            ["capsule", "-c", "wtf", "--ignore_command", "--", "/bin/echo"].iter(),
+            None,
+        )
+        .unwrap();
+        let capsule = Capsule::new(&config, &backend, &Dummy);
+        assert_eq!(capsule.read_inputs().unwrap().hash, EMPTY_INPUTS_HASH);
+    }
+
+    #[test]
+    #[serial]
+    fn test_command_input() {
+        let backend = dummy::DummyBackend::default();
+        let hash = |args: &[&str]| {
+            let config = Config::new(args.iter(), None).unwrap();
+            Capsule::new(&config, &backend, &Dummy).read_inputs().unwrap()
+        };
+        let inputs = hash(&["capsule", "-c", "wtf", "--", "gcc", "-O0", "a.c"]);
+        let cwd = std::env::current_dir().unwrap();
+        assert_eq!(
+            inputs.hash_details[0].0,
+            Input::Command {
+                args: vec!["gcc".to_string(), "-O0".to_string(), "a.c".to_string()],
+                cwd: cwd.as_path().into(),
+            }
+        );
+        assert_ne!(
+            inputs.hash,
+            hash(&["capsule", "-c", "wtf", "--", "gcc", "-O3", "a.c"]).hash
+        );
+        // The opt-out makes the command line irrelevant.
+        assert_eq!(
+            hash(&["capsule", "-c", "wtf", "--ignore_command", "--", "gcc", "-O0", "a.c"]).hash,
+            hash(&["capsule", "-c", "wtf", "--ignore_command", "--", "gcc", "-O3", "a.c"]).hash
+        );
+    }
+
+    #[test]
+    #[serial]
+    fn test_env_input() {
+        let backend = dummy::DummyBackend::default();
+        let config = Config::new(
+            [
+                "capsule",
+                "-c",
+                "wtf",
+                "--env_input",
+                "CAPSULE_TEST_CC",
+                "--",
+                "/bin/echo",
+            ]
+            .iter(),
+            None,
+        )
+        .unwrap();
         let capsule = Capsule::new(&config, &backend, &Dummy);
-        assert_eq!(capsule.read_inputs().unwrap().hash, EMPTY_SHA256);
+        std::env::remove_var("CAPSULE_TEST_CC");
+        let unset = capsule.read_inputs().unwrap();
+        assert_eq!(
+            unset.hash_details[0].0,
+            Input::EnvVar("CAPSULE_TEST_CC".to_string(), None)
+        );
+        std::env::set_var("CAPSULE_TEST_CC", "");
+        let empty = capsule.read_inputs().unwrap();
+        std::env::set_var("CAPSULE_TEST_CC", "clang");
+        let clang = capsule.read_inputs().unwrap();
+        std::env::remove_var("CAPSULE_TEST_CC");
+        assert_ne!(unset.hash, empty.hash);
+        assert_ne!(empty.hash, clang.hash);
+        assert_eq!(
+            clang.hash_details[0].0,
+            Input::EnvVar("CAPSULE_TEST_CC".to_string(), Some("clang".to_string()))
+        );
+    }
+
+    #[test]
+    #[serial]
+    fn test_tool_fingerprints() {
+        let tmp_dir = TempDir::new().unwrap();
+        let tool = tmp_dir.path().join("tool");
+        fs::write(&tool, "#!/bin/sh\necho v1\n").unwrap();
+        fs::set_permissions(&tool, fs::Permissions::from_mode(0o755)).unwrap();
+        let tool = tool.to_str().unwrap();
+        let backend = dummy::DummyBackend::default();
+        let read_inputs = |args: &[&str]| {
+            let mut all_args = vec!["capsule", "-c", "wtf", "--ignore_command"];
+            all_args.extend(args);
+            all_args.extend(["--", tool]);
+            let config = Config::new(all_args, None).unwrap();
+            Capsule::new(&config, &backend, &Dummy).read_inputs().unwrap()
+        };
+        // Hash details are ordered by the hashes, which depend on the path of the tool.
+        let tool_tags = |inputs: &InputHashBundle| -> Vec<String> {
+            let mut tool_tags: Vec<String> = inputs
+                .hash_details
+                .iter()
+                .filter_map(|(input, _)| match input {
+                    Input::ToolTag(tool_tag) => Some(tool_tag.clone()),
+                    _ => None,
+                })
+                .collect();
+            tool_tags.sort();
+            tool_tags
+        };
+        let v1 = read_inputs(&["--tool_binary", tool, "--tool_cmd", &format!("{} --version", tool)]);
+        assert_eq!(
+            tool_tags(&v1),
+            vec![
+                format!("binary tool: {}", file_hash(Path::new(tool)).unwrap()),
+                format!("command {} --version: {}", tool, string_hash("v1\n")),
+            ]
+        );
+        assert_eq!(
+            tool_tags(&read_inputs(&["--fingerprint_command"])),
+            vec![format!("binary tool: {}", file_hash(Path::new(tool)).unwrap())]
+        );
+        // A new version of the tool changes both fingerprints.
+        fs::write(tool, "#!/bin/sh\necho v2\n").unwrap();
+        let v2 = read_inputs(&["--tool_binary", tool, "--tool_cmd", &format!("{} --version", tool)]);
+        assert_ne!(v1.hash, v2.hash);
+        assert!(tool_tags(&v1).iter().zip(tool_tags(&v2)).all(|(a, b)| *a != b));
+
+        let mut expected = platform_tags();
+        expected.sort();
+        assert_eq!(tool_tags(&read_inputs(&["--platform_tag"])), expected);
+    }
+
+    #[test]
+    #[serial]
+    fn test_hash_input_paths() {
+        let tmp_dir = TempDir::new().unwrap();
+        let backend = dummy::DummyBackend::default();
+        let pattern = format!("{}/*", tmp_dir.path().to_str().unwrap());
+        let hash = |hash_paths: bool| {
+            let mut args = vec!["capsule", "-c", "wtf", "--ignore_command", "-i", &pattern];
+            if hash_paths {
+                args.push("--hash_input_paths");
+            }
+            args.extend(["--", "/bin/echo"]);
+            let config = Config::new(args, None).unwrap();
+            Capsule::new(&config, &backend, &Dummy).read_inputs().unwrap().hash
+        };
+        std::fs::write(tmp_dir.path().join("a.c"), "int main;").unwrap();
+        let before = (hash(false), hash(true));
+        std::fs::rename(tmp_dir.path().join("a.c"), tmp_dir.path().join("b.c")).unwrap();
+        let after = (hash(false), hash(true));
+        // Only the path sensitive mode notices the rename.
+        assert_eq!(before.0, after.0);
+        assert_ne!(before.1, after.1);
+    }
+
+    #[test]
+    #[serial]
+    fn test_input_symlinks() {
+        let tmp_dir = TempDir::new().unwrap();
+        let backend = dummy::DummyBackend::default();
+        let pattern = format!("{}/*", tmp_dir.path().to_str().unwrap());
+        let read_inputs = |policy: &str, pattern: &str| {
+            let args = vec![
+                "capsule",
+                "-c",
+                "wtf",
+                "--ignore_command",
+                "--input_symlinks",
+                policy,
+                "-i",
+                pattern,
+                "--",
+                "/bin/echo",
+            ];
+            let config = Config::new(args, None).unwrap();
+            Capsule::new(&config, &backend, &Dummy).read_inputs()
+        };
+        std::fs::write(tmp_dir.path().join("libfoo.so.1"), "v1").unwrap();
+        std::fs::write(tmp_dir.path().join("libfoo.so.2"), "v1").unwrap();
+        std::os::unix::fs::symlink("libfoo.so.1", tmp_dir.path().join("libfoo.so")).unwrap();
+        let before = (
+            read_inputs("follow", &pattern).unwrap(),
+            read_inputs("target", &pattern).unwrap(),
+        );
+        let link_hash = |inputs: &InputHashBundle| {
+            let link = Input::File(tmp_dir.path().join("libfoo.so").into());
+            let (_, hash) = inputs.hash_details.iter().find(|(input, _)| *input == link).unwrap();
+            hash.clone()
+        };
+        assert_eq!(link_hash(&before.1), HashFunction::Sha256.symlink_hash("libfoo.so.1"));
+
+        // Retargeting to a file with the same contents is only noticed when hashing the targets.
+        std::fs::remove_file(tmp_dir.path().join("libfoo.so")).unwrap();
+        std::os::unix::fs::symlink("libfoo.so.2", tmp_dir.path().join("libfoo.so")).unwrap();
+        let after = (
+            read_inputs("follow", &pattern).unwrap(),
+            read_inputs("target", &pattern).unwrap(),
+        );
+        assert_eq!(before.0.hash, after.0.hash);
+        assert_ne!(before.1.hash, after.1.hash);
+
+        // Dangling symlinks are inputs only when hashing the targets.
+        std::os::unix::fs::symlink("nonexistent", tmp_dir.path().join("dangling")).unwrap();
+        assert_eq!(read_inputs("follow", &pattern).unwrap().hash_details.len(), 3);
+        assert_eq!(read_inputs("target", &pattern).unwrap().hash_details.len(), 4);
+        // So are dangling symlinks given by their path.
+        let dangling = tmp_dir.path().join("dangling");
+        let dangling = dangling.to_str().unwrap();
+        assert!(read_inputs("follow", dangling).is_err());
+        assert_eq!(read_inputs("target", dangling).unwrap().hash_details.len(), 1);
     }
 
     #[tokio::test]
@@ -459,6 +1316,7 @@ mod tests {
                 "capsule",
                 "-c",
                 "wtf",
+                "--ignore_command",
                 "--",
                 "/bin/bash",
                 "-c",
@@ -469,11 +1327,11 @@ mod tests {
         )
         .unwrap();
         let capsule = Capsule::new(&config, &backend, &Dummy);
-        assert_eq!(capsule.read_inputs().unwrap().hash, EMPTY_SHA256);
+        assert_eq!(capsule.read_inputs().unwrap().hash, EMPTY_INPUTS_HASH);
         let mut program_run = AtomicBool::new(false);
         let _ = capsule.run_capsule(&mut program_run).await.unwrap();
         let out_file_contents = std::fs::read_to_string(out_file).unwrap();
-        assert_eq!(out_file_contents, EMPTY_SHA256);
+        assert_eq!(out_file_contents, EMPTY_INPUTS_HASH);
     }
 
     #[test]
@@ -494,7 +1352,17 @@ mod tests {
     fn test_ok_glob() {
         let backend = dummy::DummyBackend::default();
         let config = Config::new(
-            ["capsule", "-c", "wtf", "-i", "/bin/echo", "--", "/bin/echo"].iter(),
+            [
+                "capsule",
+                "-c",
+                "wtf",
+                "--ignore_command",
+                "-i",
+                "/bin/echo",
+                "--",
+                "/bin/echo",
+            ]
+            .iter(),
             None,
         )
         .unwrap();
@@ -529,6 +1397,73 @@ mod tests {
         root
     }
 
+    /// Reads the inputs of a capsule in the workspace `root` with the given arguments, and returns the input files,
+    /// relative to the root, with their hashes, sorted.
+    fn input_file_hashes(root: &Path, args: &[&str]) -> Result<Vec<(String, String)>> {
+        let mut all_args = vec!["capsule", "-c", "wtf", "--ignore_command", "-w", root.to_str().unwrap()];
+        all_args.extend(args);
+        all_args.extend(["--", "/bin/echo"]);
+        let config = Config::new(all_args, None).unwrap();
+        let backend = dummy::DummyBackend::default();
+        let mut files: Vec<(String, String)> = Capsule::new(&config, &backend, &Dummy)
+            .read_inputs()?
+            .hash_details
+            .into_iter()
+            .filter_map(|(input, hash)| match input {
+                Input::File(WorkspacePath::Workspace(path)) => {
+                    Some((path.strip_prefix(root).unwrap().display().to_string(), hash))
+                }
+                _ => None,
+            })
+            .collect();
+        files.sort();
+        Ok(files)
+    }
+
+    /// Like `input_file_hashes`, without the hashes.
+    fn input_files(root: &Path, args: &[&str]) -> Result<Vec<String>> {
+        Ok(input_file_hashes(root, args)?
+            .into_iter()
+            .map(|(file, _)| file)
+            .collect())
+    }
+
+    #[test]
+    #[serial]
+    fn test_concurrent_hash_max() {
+        let tmp_dir = TempDir::new().unwrap();
+        for i in 0..20 {
+            fs::write(tmp_dir.path().join(format!("{}.c", i)), i.to_string().repeat(i)).unwrap();
+        }
+        let pattern = format!("{}/*.c", tmp_dir.path().display());
+        let backend = dummy::DummyBackend::default();
+        let hashes = |concurrent_hash_max: &str| {
+            let config = Config::new(
+                vec![
+                    "capsule",
+                    "-c",
+                    "wtf",
+                    "-i",
+                    &pattern,
+                    "-o",
+                    &pattern,
+                    "--concurrent_hash_max",
+                    concurrent_hash_max,
+                    "--",
+                    "/bin/echo",
+                ],
+                None,
+            )
+            .unwrap();
+            let capsule = Capsule::new(&config, &backend, &Dummy);
+            let inputs = capsule.read_inputs().unwrap();
+            let outputs = capsule.read_outputs(Some(0), &CapturedStreams::default()).unwrap();
+            assert_eq!(inputs.hash_details.len(), 21);
+            (inputs.hash, inputs.hash_details, outputs.hash, outputs.hash_details)
+        };
+        assert_eq!(hashes("1"), hashes("4"));
+    }
+
     #[test]
     #[serial]
     fn test_recursive_glob() {
@@ -540,6 +1475,7 @@ mod tests {
                 "capsule",
                 "-c",
                 "wtf",
+                "--ignore_command",
                 "-i",
                 &format!("{}/**/111", root.to_str().unwrap()),
                 "--",
@@ -563,6 +1499,87 @@ mod tests {
         );
     }
 
+    #[test]
+    #[serial]
+    fn test_exclude_inputs() {
+        let tmp_dir = TempDir::new().unwrap();
+        let root = create_file_tree(tmp_dir.path());
+        fs::write(root.join(".capsuleignore"), "111\n").unwrap();
+        let read_inputs = |args: &[&str]| input_files(&root, &[&["-i", "//**/*"], args].concat());
+        assert_eq!(
+            read_inputs(&["--exclude", "//dir2", "--exclude", "//**/222"]).unwrap(),
+            vec![".capsuleignore", "123", "dir1/111"]
+        );
+        assert_eq!(
+            read_inputs(&["--use_ignore_files", "--exclude", "//.capsuleignore"]).unwrap(),
+            vec!["123", "dir1/222", "dir2/subdir2/222"]
+        );
+        // A pattern with only excluded matches is an error, like one without any.
+        assert!(read_inputs(&["--exclude", "//**"]).is_err());
+    }
+
+    #[test]
+    #[serial]
+    fn test_input_list() {
+        let tmp_dir = TempDir::new().unwrap();
+        let root = create_file_tree(tmp_dir.path());
+        let read_inputs = |list: &str| {
+            fs::write(root.join("inputs.txt"), list).unwrap();
+            input_files(&root, &["-i", "//123", "--input_list", "//inputs.txt"])
+        };
+        // Entries are paths or patterns, separated by newlines, or by NULs if there are any.
+        assert_eq!(
+            read_inputs("//dir1/111\r\n\n//dir2/**/*\n").unwrap(),
+            vec!["123", "dir1/111", "dir2/subdir2/111", "dir2/subdir2/222"]
+        );
+        assert!(read_inputs("//dir1/111\0//dir1/2 2\n\0").is_err());
+        fs::write(root.join("dir1").join("2 2\n"), "").unwrap();
+        assert_eq!(
+            read_inputs("//dir1/111\0//dir1/2 2\n\0").unwrap(),
+            vec!["123", "dir1/111", "dir1/2 2\n"]
+        );
+        assert!(read_inputs("//nonexistent\n").is_err());
+    }
+
+    #[test]
+    #[serial]
+    fn test_git_inputs() {
+        let tmp_dir = TempDir::new().unwrap();
+        let root = create_file_tree(tmp_dir.path());
+        let git = |args: &[&str]| {
+            let status = std::process::Command::new("git")
+                .arg("-C")
+                .arg(&root)
+                .args(args)
+                .status()
+                .unwrap();
+            assert!(status.success());
+        };
+        git(&["init", "-q"]);
+        git(&["add", "123", "dir1"]);
+        fs::write(root.join("dir1").join("222"), "modified").unwrap();
+        let read_inputs = |args: &[&str]| input_file_hashes(&root, args);
+        let empty = string_hash("");
+        let modified = string_hash("modified");
+        // The untracked files of dir2 are left out.
+        assert_eq!(
+            read_inputs(&["-i", "git:////"]).unwrap(),
+            vec![
+                ("123".to_string(), empty.clone()),
+                ("dir1/111".to_string(), empty.clone()),
+                ("dir1/222".to_string(), modified.clone()),
+            ]
+        );
+        // Only the modified file is read, the others have the hash of the empty blob.
+        let empty_blob = "git-blob:e69de29bb2d1d6434b8b29ae775ad8c2e48c5391".to_string();
+        assert_eq!(
+            read_inputs(&["-i", "git:////dir1", "--git_blob_hashes"]).unwrap(),
+            vec![("dir1/111".to_string(), empty_blob), ("dir1/222".to_string(), modified),]
+        );
+        assert!(read_inputs(&["-i", "git:////dir2"]).is_err());
+        assert!(read_inputs(&["-i", &format!("git://{}", tmp_dir.path().display())]).is_err());
+    }
+
     #[test]
     #[serial]
     fn test_single_glob() {
@@ -574,6 +1591,7 @@ mod tests {
                 "capsule",
                 "-c",
                 "wtf",
+                "--ignore_command",
                 "-i",
                 &format!("{}/*/111", root.to_str().unwrap()),
                 "--",
@@ -603,6 +1621,7 @@ mod tests {
                 "capsule",
                 "-c",
                 "wtf",
+                "--ignore_command",
                 "-i",
                 &format!("{}/**/*", root.to_str().unwrap()),
                 "--",
@@ -674,6 +1693,120 @@ mod tests {
         assert!(out_file_1.is_file());
     }
 
+    #[tokio::test]
+    #[serial]
+    async fn test_capture_streams() {
+        let backend = TestBackend::new("wtf", TestBackendConfig::default());
+        let config = Config::new(
+            [
+                "capsule",
+                "-c",
+                "wtf",
+                "--capture_stdout",
+                "--capture_stderr",
+                "--",
+                "/bin/bash",
+                "-c",
+                "echo out; echo err >&2",
+            ]
+            .iter(),
+            None,
+        )
+        .unwrap();
+        let capsule = Capsule::new(&config, &backend, &Dummy);
+        let mut program_run = AtomicBool::new(false);
+        let code = capsule.run_capsule(&mut program_run).await.unwrap();
+        assert_eq!(code, 0);
+        assert!(program_run.load(Ordering::SeqCst));
+
+        let inputs = capsule.read_inputs().unwrap();
+        let outputs = backend.lookup(&inputs).await.unwrap().unwrap().outputs;
+        assert!(outputs.hash_details.contains(&(Output::Stdout, bytes_hash(b"out\n"))));
+        assert!(outputs.hash_details.contains(&(Output::Stderr, bytes_hash(b"err\n"))));
+
+        // The 2nd time the streams are replayed from the cache.
+        let capsule = Capsule::new(&config, &backend, &Dummy);
+        let mut program_run = AtomicBool::new(false);
+        let code = capsule.run_capsule(&mut program_run).await.unwrap();
+        assert_eq!(code, 0);
+        assert!(!program_run.load(Ordering::SeqCst));
+        let streams = capsule.download_streams(&outputs).await.unwrap();
+        assert_eq!(
+            streams,
+            vec![(Output::Stdout, b"out\n".to_vec()), (Output::Stderr, b"err\n".to_vec())]
+        );
+    }
+
+    #[tokio::test]
+    #[serial]
+    async fn test_capture_streams_limits() {
+        let backend = TestBackend::new("wtf", TestBackendConfig::default());
+        let run = |args: &[&str]| {
+            let mut args: Vec<&str> = args.to_vec();
+            args.extend(["--", "/bin/bash", "-c", "echo output"]);
+            let config = Config::new(args, None).unwrap();
+            let backend = &backend;
+            async move {
+                let capsule = Capsule::new(&config, backend, &Dummy);
+                let mut program_run = AtomicBool::new(false);
+                capsule.run_capsule(&mut program_run).await.unwrap();
+                program_run.load(Ordering::SeqCst)
+            }
+        };
+        // Output over the limit is not cached.
+        assert!(run(&["capsule", "-c", "wtf", "--capture_stdout", "--capture_max_size", "5"]).await);
+        assert!(run(&["capsule", "-c", "wtf", "--capture_stdout", "--capture_max_size", "5"]).await);
+        // Records of the combined log count towards the limit.
+        assert!(run(&["capsule", "-c", "wtf", "--capture_combined", "--capture_max_size", "19"]).await);
+        assert!(run(&["capsule", "-c", "wtf", "--capture_combined", "--capture_max_size", "19"]).await);
+        // A cache entry without the captured output can't be replayed.
+        assert!(run(&["capsule", "-c", "wtf"]).await);
+        assert!(!run(&["capsule", "-c", "wtf"]).await);
+        assert!(run(&["capsule", "-c", "wtf", "--capture_stdout"]).await);
+        assert!(!run(&["capsule", "-c", "wtf", "--capture_stdout"]).await);
+        assert!(run(&["capsule", "-c", "wtf", "--capture_combined"]).await);
+        assert!(!run(&["capsule", "-c", "wtf", "--capture_combined"]).await);
+    }
+
+    #[tokio::test]
+    #[serial]
+    async fn test_capture_combined() {
+        let backend = TestBackend::new("wtf", TestBackendConfig::default());
+        let config = Config::new(
+            [
+                "capsule",
+                "-c",
+                "wtf",
+                "--capture_combined",
+                "--",
+                "/bin/bash",
+                "-c",
+                "echo out; sleep 0.1; echo err >&2",
+            ]
+            .iter(),
+            None,
+        )
+        .unwrap();
+        let capsule = Capsule::new(&config, &backend, &Dummy);
+        let mut program_run = AtomicBool::new(false);
+        capsule.run_capsule(&mut program_run).await.unwrap();
+
+        let inputs = capsule.read_inputs().unwrap();
+        let outputs = backend.lookup(&inputs).await.unwrap().unwrap().outputs;
+        let streams = capsule.download_streams(&outputs).await.unwrap();
+        assert_eq!(streams.len(), 1);
+        assert_eq!(streams[0].0, Output::Combined);
+        let records: Vec<_> = CombinedLog::parse(&streams[0].1)
+            .unwrap()
+            .into_iter()
+            .map(|record| (record.stream, record.data))
+            .collect();
+        assert_eq!(
+            records,
+            vec![(Stream::Stdout, &b"out\n"[..]), (Stream::Stderr, &b"err\n"[..])]
+        );
+    }
+
     #[tokio::test]
     #[serial]
     async fn test_cache_hit_job_id() {
@@ -756,6 +1889,104 @@ mod tests {
         assert!(out_file_1.is_file());
     }
 
+    #[tokio::test]
+    #[serial]
+    async fn test_depfile_inputs() {
+        let tmp_dir = TempDir::new().unwrap();
+        let backend = TestBackend::new("wtf", TestBackendConfig::default());
+        let path = |name: &str| tmp_dir.path().join(name).to_str().unwrap().to_string();
+        fs::write(path("main.c"), "main").unwrap();
+        fs::write(path("header.h"), "header").unwrap();
+        let config = Config::new(
+            [
+                "capsule",
+                "-c",
+                "wtf",
+                "-i",
+                &path("main.c"),
+                "-o",
+                &path("main.o"),
+                "-o",
+                &path("main.d"),
+                "--depfile",
+                &path("main.d"),
+                "--",
+                "/bin/bash",
+                "-c",
+                &format!(
+                    "cat {0}/main.c {0}/header.h > {0}/main.o && echo '{0}/main.o: {0}/main.c {0}/header.h' > {0}/main.d",
+                    tmp_dir.path().display()
+                ),
+            ]
+            .iter(),
+            None,
+        )
+        .unwrap();
+        let run = || async {
+            let capsule = Capsule::new(&config, &backend, &Dummy);
+            let mut program_run = AtomicBool::new(false);
+            assert_eq!(capsule.run_capsule(&mut program_run).await.unwrap(), 0);
+            program_run.load(Ordering::SeqCst)
+        };
+        // The header is recorded in the entry, but the depfile doesn't change the key, so the entry is used.
+        assert!(run().await);
+        let header = Input::File(path("header.h").into());
+        let capsule = Capsule::new(&config, &backend, &Dummy);
+        assert!(!capsule
+            .read_inputs()
+            .unwrap()
+            .hash_details
+            .iter()
+            .any(|(input, _)| *input == header));
+        assert!(Path::new(&path("main.d")).is_file());
+        assert!(!run().await);
+
+        // The entry is used without the depfile too (e.g. in a clean checkout), while the header is the same.
+        fs::remove_file(path("main.d")).unwrap();
+        assert!(!run().await);
+        assert!(Path::new(&path("main.d")).is_file());
+        fs::write(path("header.h"), "changed").unwrap();
+        assert!(run().await);
+        assert_eq!(fs::read_to_string(path("main.o")).unwrap(), "mainchanged");
+        assert!(!run().await);
+    }
+
+    #[tokio::test]
+    #[serial]
+    async fn test_cache_miss_explanation() {
+        let tmp_dir = TempDir::new().unwrap();
+        let backend = TestBackend::new("wtf", TestBackendConfig::default());
+        let in_file = tmp_dir.path().join("in");
+        std::fs::write(&in_file, "123").unwrap();
+        let config = Config::new(
+            [
+                "capsule",
+                "-c",
+                "wtf",
+                "-i",
+                in_file.to_str().unwrap(),
+                "--",
+                "/bin/echo",
+            ]
+            .iter(),
+            None,
+        )
+        .unwrap();
+        let capsule = Capsule::new(&config, &backend, &Dummy);
+        // Nothing to compare against for the very first run.
+        assert!(capsule.explain_miss(&capsule.read_inputs().unwrap()).await.is_none());
+        let mut program_run = AtomicBool::new(false);
+        capsule.run_capsule(&mut program_run).await.unwrap();
+        let previous_hash = capsule.read_inputs().unwrap().hash;
+
+        std::fs::write(&in_file, "456").unwrap();
+        let diff = capsule.explain_miss(&capsule.read_inputs().unwrap()).await.unwrap();
+        assert_eq!(diff.previous_hash, previous_hash);
+        assert_eq!(diff.changed, vec![Input::File(in_file.as_path().into())]);
+        assert!(diff.added.is_empty());
+        assert!(diff.removed.is_empty());
+    }
+
     #[tokio::test]
     #[serial]
     async fn test_cache_miss_capsule_id() {
@@ -927,6 +2158,162 @@ mod tests {
         assert_eq!(out_file.metadata().unwrap().permissions().mode() & 0o777, 0o755);
     }
 
+    #[tokio::test]
+    #[serial]
+    async fn test_cache_hit_symlinks() {
+        let tmp_dir = TempDir::new().unwrap();
+        let backend = TestBackend::new("wtf", TestBackendConfig::default());
+        let path = |name: &str| tmp_dir.path().join(name).to_string_lossy().into_owned();
+        let config = Config::new(
+            [
+                "capsule",
+                "-c",
+                "wtf",
+                "-i",
+                "/bin/echo",
+                "-o",
+                &path("libfoo.so*"),
+                "-o",
+                &path("dangling"),
+                "--",
+                "/bin/bash",
+                "-c",
+                &format!(
+                    "echo '123' > {}; ln -s libfoo.so.1 {}; ln -s nonexistent {}",
+                    path("libfoo.so.1"),
+                    path("libfoo.so"),
+                    path("dangling")
+                ),
+            ]
+            .iter(),
+            None,
+        )
+        .unwrap();
+        let capsule = Capsule::new(&config, &backend, &Dummy);
+        let mut program_run = AtomicBool::new(false);
+        let code = capsule.run_capsule(&mut program_run).await.unwrap();
+        assert_eq!(code, 0);
+        assert!(program_run.load(Ordering::SeqCst));
+
+        for name in ["libfoo.so.1", "libfoo.so", "dangling"] {
+            std::fs::remove_file(path(name)).unwrap();
+        }
+
+        let capsule = Capsule::new(&config, &backend, &Dummy);
+        let mut program_run = AtomicBool::new(false);
+        let code = capsule.run_capsule(&mut program_run).await.unwrap();
+        assert_eq!(code, 0);
+        // The 2nd time the program should NOT run.
+        assert!(!program_run.load(Ordering::SeqCst));
+        assert!(Path::new(&path("libfoo.so.1")).symlink_metadata().unwrap().is_file());
+        assert_eq!(std::fs::read_link(path("libfoo.so")).unwrap(), Path::new("libfoo.so.1"));
+        assert_eq!(std::fs::read_to_string(path("libfoo.so")).unwrap(), "123\n");
+        assert_eq!(std::fs::read_link(path("dangling")).unwrap(), Path::new("nonexistent"));
+    }
+
+    #[tokio::test]
+    #[serial]
+    async fn test_cache_hit_tree() {
+        let tmp_dir = TempDir::new().unwrap();
+        let backend = TestBackend::new("wtf", TestBackendConfig::default());
+        let out_dir = tmp_dir.path().join("out");
+        let out_dir_name = out_dir.to_string_lossy();
+        let config = Config::new(
+            [
+                "capsule",
+                "-c",
+                "wtf",
+                "-i",
+                "/bin/echo",
+                "--output_dir",
+                &out_dir_name,
+                "--",
+                "/bin/bash",
+                "-c",
+                &format!(
+                    "mkdir -p {0}/sub/empty; echo a > {0}/a; echo a > {0}/sub/a; chmod 700 {0}/sub; ln -s a {0}/link",
+                    out_dir_name
+                ),
+            ]
+            .iter(),
+            None,
+        )
+        .unwrap();
+        let capsule = Capsule::new(&config, &backend, &Dummy);
+        let mut program_run = AtomicBool::new(false);
+        let code = capsule.run_capsule(&mut program_run).await.unwrap();
+        assert_eq!(code, 0);
+        assert!(program_run.load(Ordering::SeqCst));
+        let manifest = read_tree(&out_dir, HashFunction::Sha256, 1).unwrap();
+
+        // Stale files are removed, and missing ones restored.
+        std::fs::remove_dir_all(out_dir.join("sub")).unwrap();
+        std::fs::write(out_dir.join("stale"), "stale").unwrap();
+
+        let capsule = Capsule::new(&config, &backend, &Dummy);
+        let mut program_run = AtomicBool::new(false);
+        let code = capsule.run_capsule(&mut program_run).await.unwrap();
+        assert_eq!(code, 0);
+        // The 2nd time the program should NOT run.
+        assert!(!program_run.load(Ordering::SeqCst));
+        assert_eq!(read_tree(&out_dir, HashFunction::Sha256, 1).unwrap(), manifest);
+        assert!(out_dir.join("sub/empty").is_dir());
+        assert_eq!(
+            out_dir.join("sub").metadata().unwrap().permissions().mode() & 0o777,
+            0o700
+        );
+        assert!(!out_dir.join("stale").exists());
+
+        // Entries without the output directory aren't used.
+        let config = Config::new(
+            ["capsule", "-c", "wtf", "-i", "/bin/echo", "--", "/bin/true"].iter(),
+            None,
+        )
+        .unwrap();
+        let capsule = Capsule::new(&config, &backend, &Dummy);
+        let mut program_run = AtomicBool::new(false);
+        capsule.run_capsule(&mut program_run).await.unwrap();
+        assert!(program_run.load(Ordering::SeqCst));
+    }
+
+    #[tokio::test]
+    #[serial]
+    async fn test_cache_hit_absent_tree() {
+        let tmp_dir = TempDir::new().unwrap();
+        let backend = TestBackend::new("wtf", TestBackendConfig::default());
+        let out_dir = tmp_dir.path().join("out");
+        let config = Config::new(
+            [
+                "capsule",
+                "-c",
+                "wtf",
+                "-i",
+                "/bin/echo",
+                "--output_dir",
+                out_dir.to_str().unwrap(),
+                "--",
+                "/bin/true",
+            ]
+            .iter(),
+            None,
+        )
+        .unwrap();
+        let capsule = Capsule::new(&config, &backend, &Dummy);
+        let mut program_run = AtomicBool::new(false);
+        assert_eq!(capsule.run_capsule(&mut program_run).await.unwrap(), 0);
+        assert!(program_run.load(Ordering::SeqCst));
+
+        // A directory left by an earlier run is removed, as the command doesn't create it.
+        fs::create_dir_all(out_dir.join("read_only")).unwrap();
+        fs::write(out_dir.join("read_only/stale"), "stale").unwrap();
+        fs::set_permissions(out_dir.join("read_only"), fs::Permissions::from_mode(0o500)).unwrap();
+        let capsule = Capsule::new(&config, &backend, &Dummy);
+        let mut program_run = AtomicBool::new(false);
+        assert_eq!(capsule.run_capsule(&mut program_run).await.unwrap(), 0);
+        assert!(!program_run.load(Ordering::SeqCst));
+        assert!(!out_dir.exists());
+    }
+
     #[tokio::test]
     #[serial]
     // Here the logic changed. OutputBundles contain the information about whether an output file
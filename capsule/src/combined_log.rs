@@ -0,0 +1,126 @@
+//! A log of both the standard output and error of a command, which preserves their interleaving.
+//!
+//! The log is a sequence of records, one per chunk of output, as it was read from the command:
+//! a stream tag byte (`o` or `e`), milliseconds since the start of the command as a big endian u64,
+//! the length of the chunk as a big endian u32, and then the chunk itself.
+use anyhow::{bail, Result};
+use std::convert::TryInto;
+use std::time::{Duration, Instant};
+
+/// Stream a chunk of output was written to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Stream {
+    Stdout,
+    Stderr,
+}
+
+const HEADER_SIZE: usize = 1 + 8 + 4;
+
+/// A chunk of output of the command.
+#[derive(Debug, PartialEq)]
+pub struct Record<'a> {
+    pub stream: Stream,
+    /// Time since the start of the command.
+    pub elapsed: Duration,
+    pub data: &'a [u8],
+}
+
+pub struct CombinedLog {
+    start: Instant,
+    data: Vec<u8>,
+}
+
+impl Default for CombinedLog {
+    fn default() -> Self {
+        Self {
+            start: Instant::now(),
+            data: Vec::new(),
+        }
+    }
+}
+
+impl CombinedLog {
+    /// Size of the log after recording a chunk of the given size.
+    pub fn size_with(&self, chunk_len: usize) -> usize {
+        self.data.len() + HEADER_SIZE + chunk_len
+    }
+
+    pub fn record(&mut self, stream: Stream, chunk: &[u8]) {
+        self.data.push(match stream {
+            Stream::Stdout => b'o',
+            Stream::Stderr => b'e',
+        });
+        let elapsed = self.start.elapsed().as_millis() as u64;
+        self.data.extend_from_slice(&elapsed.to_be_bytes());
+        self.data.extend_from_slice(&(chunk.len() as u32).to_be_bytes());
+        self.data.extend_from_slice(chunk);
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.data
+    }
+
+    /// Parse a log back into records, in the order they were written.
+    pub fn parse(mut data: &[u8]) -> Result<Vec<Record<'_>>> {
+        let mut records = Vec::new();
+        while !data.is_empty() {
+            if data.len() < HEADER_SIZE {
+                bail!("Truncated combined log record header");
+            }
+            let stream = match data[0] {
+                b'o' => Stream::Stdout,
+                b'e' => Stream::Stderr,
+                tag => bail!("Unknown stream tag {} in combined log", tag),
+            };
+            let elapsed = u64::from_be_bytes(data[1..9].try_into()?);
+            let len = u32::from_be_bytes(data[9..HEADER_SIZE].try_into()?) as usize;
+            if data.len() < HEADER_SIZE + len {
+                bail!("Truncated combined log record");
+            }
+            records.push(Record {
+                stream,
+                elapsed: Duration::from_millis(elapsed),
+                data: &data[HEADER_SIZE..HEADER_SIZE + len],
+            });
+            data = &data[HEADER_SIZE + len..];
+        }
+        Ok(records)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_roundtrip() {
+        let mut log = CombinedLog::default();
+        log.record(Stream::Stdout, b"out 1\n");
+        log.record(Stream::Stderr, b"err\n");
+        log.record(Stream::Stdout, b"");
+        log.record(Stream::Stdout, b"out 2\n");
+        let records = CombinedLog::parse(log.as_bytes()).unwrap();
+        let chunks: Vec<_> = records.iter().map(|r| (r.stream, r.data)).collect();
+        assert_eq!(
+            chunks,
+            vec![
+                (Stream::Stdout, &b"out 1\n"[..]),
+                (Stream::Stderr, &b"err\n"[..]),
+                (Stream::Stdout, &b""[..]),
+                (Stream::Stdout, &b"out 2\n"[..]),
+            ]
+        );
+        assert!(records.windows(2).all(|w| w[0].elapsed <= w[1].elapsed));
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        let mut log = CombinedLog::default();
+        log.record(Stream::Stdout, b"out\n");
+        let data = log.as_bytes();
+        assert!(CombinedLog::parse(&data[..data.len() - 1]).is_err());
+        assert!(CombinedLog::parse(&data[..HEADER_SIZE - 1]).is_err());
+        assert!(CombinedLog::parse(b"x").is_err());
+        assert!(CombinedLog::parse(b"").unwrap().is_empty());
+    }
+}
@@ -12,6 +12,7 @@ use std::str::FromStr;
 use std::{env, ffi::OsString};
 use toml;
 
+use crate::iohashing::HashFunction;
 use crate::workspace_path::WorkspacePath;
 
 #[derive(Debug, Derivative, PartialEq)]
@@ -30,6 +31,32 @@ pub enum Backend {
     #[derivative(Default)]
     Dummy, // No backend means dummy.
     S3,
+    Local,
+    Http,
+    Reapi,
+    Tiered,
+}
+
+/// What capsule is asked to do.
+#[derive(Debug, Derivative, PartialEq)]
+#[derivative(Default)]
+pub enum Command {
+    #[derivative(Default)]
+    Run, // Run the wrapped command, using the cache.
+    Gc,                   // Garbage collect the cache.
+    Ls,                   // List cache entries of the capsule.
+    Show(String),         // Show the cache entry with the given inputs hash.
+    Diff(String, String), // Compare two cache entries given by inputs hashes.
+}
+
+/// How input files which are symlinks are hashed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Derivative, Deserialize)]
+#[derivative(Default)]
+#[serde(rename_all = "lowercase")]
+pub enum SymlinkPolicy {
+    #[derivative(Default)]
+    Follow, // Hash the contents of the file the symlink points to.
+    Target, // Hash the target of the symlink, including dangling ones.
 }
 
 #[derive(Debug, Deserialize, Derivative)]
@@ -50,9 +77,27 @@ pub struct Config {
     #[serde(default)]
     pub cache_failure: bool,
 
+    #[serde(default)]
+    pub ignore_command: bool, // Don't make the command line and working directory a part of the inputs hash.
+
+    #[serde(default)]
+    pub hash_input_paths: bool, // Hash workspace paths of input files together with their contents.
+
+    #[serde(default)]
+    pub hash_cache_dir: Option<String>, // Directory of the persistent memo of input file hashes.
+
+    #[serde(default)]
+    pub hash_function: Option<HashFunction>, // SHA256 unless specified.
+
+    #[serde(default)]
+    pub stdin_input: bool, // Make the standard input a part of the inputs hash.
+
     #[serde(skip)]
     pub backend: Backend,
 
+    #[serde(skip)]
+    pub command: Command,
+
     #[serde(default)]
     pub capsule_id: Option<String>,
 
@@ -63,20 +108,69 @@ pub struct Config {
     #[serde(rename = "input")]
     pub input_files: Vec<WorkspacePath>,
 
+    #[serde(default)]
+    #[serde(rename = "input_list")]
+    pub input_lists: Vec<WorkspacePath>, // Files listing input files and patterns, one per line.
+
+    #[serde(default)]
+    #[serde(rename = "exclude")]
+    pub exclude_patterns: Vec<WorkspacePath>,
+
+    #[serde(default)]
+    pub use_ignore_files: bool, // Exclude inputs ignored by .gitignore and .capsuleignore files.
+
+    #[serde(default)]
+    pub depfile: Option<WorkspacePath>, // Makefile style list of the files the command read, written by it.
+
+    #[serde(default)]
+    pub git_blob_hashes: bool, // Use git's blob hashes for the unmodified files of git:// inputs.
+
+    #[serde(default)]
+    pub input_symlinks: SymlinkPolicy,
+
     #[serde(default)]
     #[serde(rename = "tool_tag")]
     pub tool_tags: Vec<String>,
 
+    #[serde(default)]
+    #[serde(rename = "tool_binary")]
+    pub tool_binaries: Vec<String>, // Executables whose contents are hashed into tool tags.
+
+    #[serde(default)]
+    #[serde(rename = "tool_cmd")]
+    pub tool_commands: Vec<String>, // Commands (e.g. "rustc -vV") whose output is hashed into tool tags.
+
+    #[serde(default)]
+    pub fingerprint_command: bool, // Hash the executable of the wrapped command into a tool tag.
+
+    #[serde(default)]
+    pub platform_tag: bool, // Add tool tags describing the host platform (architecture, glibc etc.)
+
+    #[serde(default)]
+    #[serde(rename = "env_input")]
+    pub env_inputs: Vec<String>,
+
     #[serde(default)]
     #[serde(rename = "output")]
     pub output_files: Vec<WorkspacePath>,
 
+    #[serde(default)]
+    #[serde(rename = "output_dir")]
+    pub output_dirs: Vec<WorkspacePath>, // Directories restored as a whole, with empty subdirectories and modes.
+
     #[serde(default)]
     pub capture_stdout: Option<bool>,
 
     #[serde(default)]
     pub capture_stderr: Option<bool>,
 
+    #[serde(default)]
+    pub capture_combined: bool, // Capture stdout and stderr together, preserving their interleaving.
+
+    #[serde(default = "default_capture_max_size")]
+    #[derivative(Default(value = "default_capture_max_size()"))]
+    pub capture_max_size: usize, // Streams larger than that are not cached.
+
     #[serde(default)]
     pub command_to_run: Vec<String>,
 
@@ -120,6 +214,53 @@ pub struct Config {
     #[serde(default)]
     pub s3_downloads_region: Option<String>,
 
+    #[serde(default)]
+    pub http_url: Option<String>,
+
+    #[serde(default)]
+    pub http_user: Option<String>,
+
+    #[serde(default)]
+    pub http_password: Option<String>,
+
+    #[serde(default)]
+    pub http_token: Option<String>,
+
+    #[serde(default)]
+    pub reapi_endpoint: Option<String>,
+
+    #[serde(default)]
+    pub reapi_instance_name: Option<String>,
+
+    #[serde(default)]
+    pub local_cache_dir: Option<String>,
+
+    #[serde(default)]
+    pub local_cache_max_size: Option<u64>,
+
+    #[serde(default)]
+    pub local_cache_max_age: Option<u64>,
+
+    #[serde(default)]
+    #[serde(rename = "tier")]
+    pub tiers: Vec<String>,
+
+    #[serde(default)]
+    pub tier_ignore_write_errors: bool,
+
+    #[serde(default)]
+    pub gc_max_age: Option<u64>,
+
+    #[serde(default)]
+    pub gc_keep: Option<usize>,
+
+    #[serde(default = "default_gc_grace_period")]
+    #[derivative(Default(value = "default_gc_grace_period()"))]
+    pub gc_grace_period: u64,
+
+    #[serde(default)]
+    pub gc_dry_run: bool,
+
     #[serde(default)]
     pub inputs_hash_var: String,
 
@@ -133,6 +274,10 @@ pub struct Config {
     #[serde(default = "default_concurrent_upload_max")]
     #[derivative(Default(value = "default_concurrent_upload_max()"))]
     pub concurrent_upload_max: usize,
+
+    #[serde(default = "default_concurrent_hash_max")]
+    #[derivative(Default(value = "default_concurrent_hash_max()"))]
+    pub concurrent_hash_max: usize,
 }
 
 // Ugliness until serde supports normal default parameters.
@@ -143,6 +288,15 @@ fn default_concurrent_download_max() -> usize {
 fn default_concurrent_upload_max() -> usize {
     3
 }
+fn default_concurrent_hash_max() -> usize {
+    std::thread::available_parallelism().map_or(4, |n| n.get())
+}
+fn default_gc_grace_period() -> u64 {
+    86400
+}
+fn default_capture_max_size() -> usize {
+    16 * 1024 * 1024
+}
 
 impl Config {
     // Merge one config (e.g. Capsule.toml) into another (~/.capsules.toml)
@@ -155,10 +309,58 @@ impl Config {
             self.verbose = true;
         }
         self.input_files.append(&mut config.input_files);
+        self.input_lists.append(&mut config.input_lists);
+        self.exclude_patterns.append(&mut config.exclude_patterns);
+        if config.use_ignore_files {
+            self.use_ignore_files = true;
+        }
+        if config.git_blob_hashes {
+            self.git_blob_hashes = true;
+        }
+        if config.input_symlinks != SymlinkPolicy::default() {
+            self.input_symlinks = config.input_symlinks;
+        }
+        if self.depfile.is_none() {
+            self.depfile = config.depfile.take();
+        }
         self.output_files.append(&mut config.output_files);
+        self.output_dirs.append(&mut config.output_dirs);
         self.tool_tags.append(&mut config.tool_tags);
+        self.tool_binaries.append(&mut config.tool_binaries);
+        self.tool_commands.append(&mut config.tool_commands);
+        if config.fingerprint_command {
+            self.fingerprint_command = true;
+        }
+        if config.platform_tag {
+            self.platform_tag = true;
+        }
+        self.env_inputs.append(&mut config.env_inputs);
+        if config.ignore_command {
+            self.ignore_command = true;
+        }
+        if config.hash_input_paths {
+            self.hash_input_paths = true;
+        }
+        if self.hash_cache_dir.is_none() {
+            self.hash_cache_dir = config.hash_cache_dir.take();
+        }
+        if config.concurrent_hash_max != default_concurrent_hash_max() {
+            self.concurrent_hash_max = config.concurrent_hash_max;
+        }
+        if config.hash_function.is_some() {
+            self.hash_function = config.hash_function;
+        }
+        if config.stdin_input {
+            self.stdin_input = true;
+        }
         self.capture_stdout = config.capture_stdout;
         self.capture_stderr = config.capture_stderr;
+        if config.capture_combined {
+            self.capture_combined = true;
+        }
+        if config.capture_max_size != default_capture_max_size() {
+            self.capture_max_size = config.capture_max_size;
+        }
         if self.honeycomb_dataset.is_none() {
             self.honeycomb_dataset = config.honeycomb_dataset.take();
         }
@@ -167,6 +369,11 @@ impl Config {
         }
     }
 
+    /// Hash function of the inputs, outputs and cache keys.
+    pub fn hash_function(&self) -> HashFunction {
+        self.hash_function.unwrap_or_default()
+    }
+
     pub fn new<I, T>(cmdline_args: I, default_toml: Option<&Path>) -> Result<Self>
     where
         I: IntoIterator<Item = T>,
@@ -191,7 +398,8 @@ impl Config {
                     .short('c')
                     .long("capsule_id")
                     .takes_value(true)
-                    .multiple_occurrences(false),
+                    .multiple_occurrences(false)
+                    .global(true),
             )
             .arg(
                 Arg::new("file")
@@ -199,7 +407,8 @@ impl Config {
                     .short('f')
                     .long("file")
                     .takes_value(true)
-                    .multiple_occurrences(false),
+                    .multiple_occurrences(false)
+                    .global(true),
             )
             .arg(
                 Arg::new("workspace_root")
@@ -207,7 +416,8 @@ impl Config {
                     .short('w')
                     .long("workspace_root")
                     .takes_value(true)
-                    .multiple_occurrences(false),
+                    .multiple_occurrences(false)
+                    .global(true),
             )
             .arg(
                 Arg::new("capsule_job")
@@ -215,7 +425,8 @@ impl Config {
                     .short('j')
                     .long("capsule_job")
                     .takes_value(true)
-                    .multiple_occurrences(false),
+                    .multiple_occurrences(false)
+                    .global(true),
             )
             .arg(
                 Arg::new("input")
@@ -223,7 +434,51 @@ impl Config {
                     .short('i')
                     .long("input")
                     .takes_value(true)
-                    .multiple_occurrences(true),
+                    .multiple_occurrences(true)
+                    .global(true),
+            )
+            .arg(
+                Arg::new("input_list")
+                    .help("File listing input files, separated by newlines or NUL characters")
+                    .long("input_list")
+                    .takes_value(true)
+                    .multiple_occurrences(true)
+                    .global(true),
+            )
+            .arg(
+                Arg::new("exclude")
+                    .help("Exclude input files matching the pattern, or in a directory matching it")
+                    .long("exclude")
+                    .takes_value(true)
+                    .multiple_occurrences(true)
+                    .global(true),
+            )
+            .arg(
+                Arg::new("use_ignore_files")
+                    .help("Exclude input files ignored by .gitignore and .capsuleignore files")
+                    .long("use_ignore_files")
+                    .global(true),
+            )
+            .arg(
+                Arg::new("depfile")
+                    .help("Depfile written by the command, whose prerequisites are recorded in the cache entry")
+                    .long("depfile")
+                    .takes_value(true)
+                    .global(true),
+            )
+            .arg(
+                Arg::new("git_blob_hashes")
+                    .help("Use git's blob hashes for the files of git:// inputs not modified in the work tree")
+                    .long("git_blob_hashes")
+                    .global(true),
+            )
+            .arg(
+                Arg::new("input_symlinks")
+                    .help("Whether input symlinks are hashed by the contents they point to, or by their target paths")
+                    .long("input_symlinks")
+                    .takes_value(true)
+                    .possible_values(["follow", "target"])
+                    .global(true),
             )
             .arg(
                 Arg::new("tool_tag")
@@ -231,7 +486,44 @@ impl Config {
                     .short('t')
                     .long("tool_tag")
                     .takes_value(true)
-                    .multiple_occurrences(true),
+                    .multiple_occurrences(true)
+                    .global(true),
+            )
+            .arg(
+                Arg::new("tool_binary")
+                    .help("Tool executable, looked up in PATH, whose hash is added as a tool tag")
+                    .long("tool_binary")
+                    .takes_value(true)
+                    .multiple_occurrences(true)
+                    .global(true),
+            )
+            .arg(
+                Arg::new("tool_cmd")
+                    .help("Command printing the tool version (e.g. 'rustc -vV'), whose output is added as a tool tag")
+                    .long("tool_cmd")
+                    .takes_value(true)
+                    .multiple_occurrences(true)
+                    .global(true),
+            )
+            .arg(
+                Arg::new("fingerprint_command")
+                    .help("Add the hash of the executable of the wrapped command as a tool tag")
+                    .long("fingerprint_command")
+                    .global(true),
+            )
+            .arg(
+                Arg::new("platform_tag")
+                    .help("Add tool tags describing the architecture, kernel, glibc, CPU level and container image")
+                    .long("platform_tag")
+                    .global(true),
+            )
+            .arg(
+                Arg::new("env_input")
+                    .help("Environment variable whose value is an input (RUSTFLAGS, CC, etc.)")
+                    .long("env_input")
+                    .takes_value(true)
+                    .multiple_occurrences(true)
+                    .global(true),
             )
             .arg(
                 Arg::new("output")
@@ -239,145 +531,339 @@ impl Config {
                     .short('o')
                     .long("output")
                     .takes_value(true)
-                    .multiple_occurrences(true),
+                    .multiple_occurrences(true)
+                    .global(true),
+            )
+            .arg(
+                Arg::new("output_dir")
+                    .help("Output directory, restored exactly, including empty subdirectories and modes")
+                    .long("output_dir")
+                    .takes_value(true)
+                    .multiple_occurrences(true)
+                    .global(true),
             )
             .arg(
                 Arg::new("capture_stdout")
                     .help("Capture stdout with the cached bundle")
                     .long("capture_stdout")
-                    .takes_value(false),
+                    .takes_value(false)
+                    .global(true),
             )
             .arg(
                 Arg::new("capture_stderr")
                     .help("Capture stderr with the cached bundle")
                     .long("capture_stderr")
-                    .takes_value(false),
+                    .takes_value(false)
+                    .global(true),
+            )
+            .arg(
+                Arg::new("capture_combined")
+                    .help("Capture stdout and stderr together, replaying them in the original order")
+                    .long("capture_combined")
+                    .takes_value(false)
+                    .global(true),
+            )
+            .arg(
+                Arg::new("capture_max_size")
+                    .help("Maximum size of a captured stream in bytes, larger outputs are not cached")
+                    .long("capture_max_size")
+                    .takes_value(true)
+                    .global(true),
             )
             .arg(
                 Arg::new("verbose")
                     .help("Verbose output")
                     .short('v')
                     .long("verbose")
-                    .takes_value(false),
+                    .takes_value(false)
+                    .global(true),
             )
             .arg(
                 Arg::new("placebo")
                     .help("Placebo mode")
                     .short('p')
                     .long("placebo")
-                    .takes_value(false),
+                    .takes_value(false)
+                    .global(true),
             )
             .arg(
                 Arg::new("passive")
                     .help("Passive mode - just execute the wrapped command, no lookups, no caching etc.")
                     .long("passive")
-                    .takes_value(false),
+                    .takes_value(false)
+                    .global(true),
             )
             .arg(
                 Arg::new("cache_failure")
                     .help("Use cached failures")
-                    .long("cache_failure"),
+                    .long("cache_failure")
+                    .global(true),
+            )
+            .arg(
+                Arg::new("ignore_command")
+                    .help("Don't include the command line and working directory in the inputs hash")
+                    .long("ignore_command")
+                    .global(true),
+            )
+            .arg(
+                Arg::new("hash_input_paths")
+                    .help("Include the paths of input files in the inputs hash, not only their contents")
+                    .long("hash_input_paths")
+                    .global(true),
+            )
+            .arg(
+                Arg::new("hash_function")
+                    .help("Hash function of the inputs, outputs and cache keys")
+                    .long("hash_function")
+                    .takes_value(true)
+                    .possible_values(["sha256", "blake3"])
+                    .global(true),
+            )
+            .arg(
+                Arg::new("hash_cache_dir")
+                    .help("Directory where hashes of input files are memoized across capsule invocations")
+                    .long("hash_cache_dir")
+                    .takes_value(true)
+                    .global(true),
+            )
+            .arg(
+                Arg::new("concurrent_hash_max")
+                    .help("Maximum number of files hashed at the same time")
+                    .long("concurrent_hash_max")
+                    .takes_value(true)
+                    .global(true),
+            )
+            .arg(
+                Arg::new("stdin_input")
+                    .help("Include the standard input in the inputs hash, spooling it for the command")
+                    .long("stdin_input")
+                    .global(true),
             )
             .arg(
                 Arg::new("backend")
                     .short('b')
                     .long("backend")
                     .help("which backend to use")
-                    .possible_values(&["dummy", "s3"]),
+                    .possible_values(&["dummy", "s3", "local", "http", "reapi", "tiered"])
+                    .global(true),
             )
             .arg(
                 Arg::new("honeycomb_dataset")
                     .long("honeycomb_dataset")
                     .help("Honeycomb Dataset")
-                    .takes_value(true),
+                    .takes_value(true)
+                    .global(true),
             )
             .arg(
                 Arg::new("honeycomb_token")
                     .long("honeycomb_token")
                     .help("Honeycomb Access Token")
-                    .takes_value(true),
+                    .takes_value(true)
+                    .global(true),
             )
             .arg(
                 Arg::new("honeycomb_trace_id")
                     .long("honeycomb_trace_id")
                     .help("Honeycomb Trace ID")
-                    .takes_value(true),
+                    .takes_value(true)
+                    .global(true),
             )
             .arg(
                 Arg::new("honeycomb_parent_id")
                     .long("honeycomb_parent_id")
                     .help("Honeycomb trace span parent ID")
-                    .takes_value(true),
+                    .takes_value(true)
+                    .global(true),
             )
             .arg(
                 Arg::new("honeycomb_kv")
                     .long("honeycomb_kv")
                     .help("Honeycomb Extra Key-Value")
                     .takes_value(true)
-                    .multiple_occurrences(true),
+                    .multiple_occurrences(true)
+                    .global(true),
             )
             .arg(
                 Arg::new("s3_bucket")
                     .long("s3_bucket")
                     .help("S3 bucket name")
-                    .takes_value(true),
+                    .takes_value(true)
+                    .global(true),
             )
             .arg(
                 Arg::new("s3_bucket_objects")
                     .long("s3_bucket_objects")
                     .help("S3 bucket for objects name")
-                    .takes_value(true),
+                    .takes_value(true)
+                    .global(true),
             )
             .arg(
                 Arg::new("s3_endpoint")
                     .long("s3_endpoint")
                     .help("S3 endpoint")
-                    .takes_value(true),
+                    .takes_value(true)
+                    .global(true),
             )
             .arg(
                 Arg::new("s3_region")
                     .long("s3_region")
                     .help("S3 region")
-                    .takes_value(true),
+                    .takes_value(true)
+                    .global(true),
             )
             .arg(
                 Arg::new("s3_uploads_endpoint")
                     .long("s3_uploads_endpoint")
                     .help("S3 uploads endpoint")
-                    .takes_value(true),
+                    .takes_value(true)
+                    .global(true),
             )
             .arg(
                 Arg::new("s3_uploads_region")
                     .long("s3_uploads_region")
                     .help("S3 uploads region")
-                    .takes_value(true),
+                    .takes_value(true)
+                    .global(true),
             )
             .arg(
                 Arg::new("s3_downloads_endpoint")
                     .long("s3_downloads_endpoint")
                     .help("S3 downloads endpoint")
-                    .takes_value(true),
+                    .takes_value(true)
+                    .global(true),
             )
             .arg(
                 Arg::new("s3_downloads_region")
                     .long("s3_downloads_region")
                     .help("S3 downloads region")
-                    .takes_value(true),
+                    .takes_value(true)
+                    .global(true),
+            )
+            .arg(
+                Arg::new("http_url")
+                    .long("http_url")
+                    .help("HTTP cache server URL")
+                    .takes_value(true)
+                    .global(true),
+            )
+            .arg(
+                Arg::new("http_user")
+                    .long("http_user")
+                    .help("HTTP cache basic auth user")
+                    .takes_value(true)
+                    .global(true),
+            )
+            .arg(
+                Arg::new("reapi_endpoint")
+                    .long("reapi_endpoint")
+                    .help("Remote Execution API endpoint")
+                    .takes_value(true)
+                    .global(true),
+            )
+            .arg(
+                Arg::new("reapi_instance_name")
+                    .long("reapi_instance_name")
+                    .help("Remote Execution API instance name")
+                    .takes_value(true)
+                    .global(true),
+            )
+            .arg(
+                Arg::new("local_cache_dir")
+                    .long("local_cache_dir")
+                    .help("Directory of the local cache")
+                    .takes_value(true)
+                    .global(true),
+            )
+            .arg(
+                Arg::new("local_cache_max_size")
+                    .long("local_cache_max_size")
+                    .help("Maximum size of the local cache in bytes")
+                    .takes_value(true)
+                    .global(true),
+            )
+            .arg(
+                Arg::new("local_cache_max_age")
+                    .long("local_cache_max_age")
+                    .help("Maximum age of unused local cache entries in seconds")
+                    .takes_value(true)
+                    .global(true),
+            )
+            .arg(
+                Arg::new("tier")
+                    .long("tier")
+                    .help("Backend of a cache tier for the tiered backend, the fastest first")
+                    .takes_value(true)
+                    .multiple_occurrences(true)
+                    .possible_values(&["s3", "local", "http", "reapi"])
+                    .global(true),
+            )
+            .arg(
+                Arg::new("tier_ignore_write_errors")
+                    .long("tier_ignore_write_errors")
+                    .help("Only log failures to write into the tiers after the first one")
+                    .takes_value(false)
+                    .global(true),
             )
             .arg(
                 Arg::new("inputs_hash_var")
                     .long("inputs_hash_var")
                     .help("Variable in which the hash of inputs values stored")
                     .takes_value(true)
-                    .default_value("CAPSULE_INPUTS_HASH"),
+                    .default_value("CAPSULE_INPUTS_HASH")
+                    .global(true),
             )
             .arg(
                 Arg::new("inputs_hash")
                     .long("inputs_hash")
                     .help("Output the hash value to stdout, no cache lookup, storage, or execution")
-                    .takes_value(false),
+                    .takes_value(false)
+                    .global(true),
+            )
+            .arg(Arg::new("command_to_run").last(true))
+            .subcommand(
+                App::new("gc")
+                    .about("Garbage collect the S3 cache instead of running a command")
+                    .arg(
+                        Arg::new("max_age")
+                            .long("max_age")
+                            .help("Delete cache entries written more than this many seconds ago")
+                            .takes_value(true),
+                    )
+                    .arg(
+                        Arg::new("keep")
+                            .long("keep")
+                            .help("Keep at most this many most recent cache entries per capsule ID")
+                            .takes_value(true),
+                    )
+                    .arg(
+                        Arg::new("grace_period")
+                            .long("grace_period")
+                            .help("Never delete anything written less than this many seconds ago")
+                            .takes_value(true),
+                    )
+                    .arg(
+                        Arg::new("dry_run")
+                            .long("dry_run")
+                            .help("Only report what would be deleted")
+                            .takes_value(false),
+                    ),
             )
-            .arg(Arg::new("command_to_run").last(true));
+            .subcommand(App::new("ls").about("List cache entries of the capsule"))
+            .subcommand(
+                App::new("show")
+                    .about("Show the cache entry with the given inputs hash")
+                    .arg(Arg::new("hash").help("Inputs hash of the entry").required(true)),
+            )
+            .subcommand(
+                App::new("diff")
+                    .about("Compare inputs and outputs of two cache entries")
+                    .arg(Arg::new("hash_a").help("Inputs hash of the first entry").required(true))
+                    .arg(
+                        Arg::new("hash_b")
+                            .help("Inputs hash of the second entry")
+                            .required(true),
+                    ),
+            );
 
         // Look at the first element of command line, to find and remember argv[0].
 
@@ -433,9 +919,12 @@ impl Config {
             }
             if let Some(capsule_id) = matches.value_of("capsule_id") {
                 config.capsule_id = Some(capsule_id.to_owned());
-            } else if matches.is_present("inputs_hash") || matches.is_present("passive") {
-                // For --inputs_hash, or --passive, capsule_id doesn't matter, so let's just silence
-                // the check below.
+            } else if matches.is_present("inputs_hash")
+                || matches.is_present("passive")
+                || matches.subcommand_name() == Some("gc")
+            {
+                // For --inputs_hash, --passive, or gc of all capsules, capsule_id doesn't matter,
+                // so let's just silence the check below.
                 config.capsule_id = Some("-".to_owned());
             }
         }
@@ -452,9 +941,12 @@ impl Config {
         for matches in &match_sources {
             if let Some(capsule_id) = matches.value_of("capsule_id") {
                 config.capsule_id = Some(capsule_id.to_owned());
-            } else if matches.is_present("inputs_hash") || matches.is_present("passive") {
-                // For --inputs_hash, or --passive, capsule_id doesn't matter, so let's just silence
-                // the check below.
+            } else if matches.is_present("inputs_hash")
+                || matches.is_present("passive")
+                || matches.subcommand_name() == Some("gc")
+            {
+                // For --inputs_hash, --passive, or gc of all capsules, capsule_id doesn't matter,
+                // so let's just silence the check below.
                 config.capsule_id = Some("-".to_owned());
             }
         }
@@ -506,18 +998,64 @@ impl Config {
             if let Some(inputs) = matches.values_of("input") {
                 config.input_files.extend(inputs.map(Into::into));
             }
+            if let Some(input_lists) = matches.values_of("input_list") {
+                config.input_lists.extend(input_lists.map(Into::into));
+            }
+            if let Some(excludes) = matches.values_of("exclude") {
+                config.exclude_patterns.extend(excludes.map(Into::into));
+            }
+            if matches.is_present("use_ignore_files") {
+                config.use_ignore_files = true;
+            }
+            if matches.is_present("git_blob_hashes") {
+                config.git_blob_hashes = true;
+            }
+            if let Some(value) = matches.value_of("input_symlinks") {
+                match value {
+                    "follow" => config.input_symlinks = SymlinkPolicy::Follow,
+                    "target" => config.input_symlinks = SymlinkPolicy::Target,
+                    _ => {}
+                }
+            }
+            if let Some(depfile) = matches.value_of("depfile") {
+                config.depfile = Some(depfile.into());
+            }
             if let Some(tool_tags) = matches.values_of("tool_tag") {
                 config.tool_tags.extend(tool_tags.map(|x| x.to_owned()));
             }
+            if let Some(tool_binaries) = matches.values_of("tool_binary") {
+                config.tool_binaries.extend(tool_binaries.map(|x| x.to_owned()));
+            }
+            if let Some(tool_commands) = matches.values_of("tool_cmd") {
+                config.tool_commands.extend(tool_commands.map(|x| x.to_owned()));
+            }
+            if matches.is_present("fingerprint_command") {
+                config.fingerprint_command = true;
+            }
+            if matches.is_present("platform_tag") {
+                config.platform_tag = true;
+            }
+            if let Some(env_inputs) = matches.values_of("env_input") {
+                config.env_inputs.extend(env_inputs.map(|x| x.to_owned()));
+            }
             if let Some(outputs) = matches.values_of("output") {
                 config.output_files.extend(outputs.map(Into::into));
             }
+            if let Some(output_dirs) = matches.values_of("output_dir") {
+                config.output_dirs.extend(output_dirs.map(Into::into));
+            }
             if matches.is_present("capture_stdout") {
                 config.capture_stdout = Some(true);
             }
             if matches.is_present("capture_stderr") {
                 config.capture_stderr = Some(true);
             }
+            if matches.is_present("capture_combined") {
+                config.capture_combined = true;
+            }
+            if let Some(value) = matches.value_of("capture_max_size") {
+                config.capture_max_size = value.parse().context("Invalid capture_max_size")?;
+            }
             if matches.is_present("verbose") {
                 config.verbose = true;
             }
@@ -533,6 +1071,24 @@ impl Config {
             if matches.is_present("cache_failure") {
                 config.cache_failure = true;
             }
+            if matches.is_present("ignore_command") {
+                config.ignore_command = true;
+            }
+            if matches.is_present("hash_input_paths") {
+                config.hash_input_paths = true;
+            }
+            if let Some(value) = matches.value_of("hash_function") {
+                config.hash_function = Some(value.parse()?);
+            }
+            if let Some(value) = matches.value_of("hash_cache_dir") {
+                config.hash_cache_dir = Some(value.into());
+            }
+            if let Some(value) = matches.value_of("concurrent_hash_max") {
+                config.concurrent_hash_max = value.parse().context("Invalid concurrent_hash_max")?;
+            }
+            if matches.is_present("stdin_input") {
+                config.stdin_input = true;
+            }
             if let Some(capsule_job) = matches.value_of("capsule_job") {
                 config.capsule_job = Some(capsule_job.to_owned());
             }
@@ -540,8 +1096,13 @@ impl Config {
                 config.command_to_run = command.map(|x| x.to_owned()).collect();
             }
             if let Some(backend) = matches.value_of("backend") {
-                if backend == "s3" {
-                    config.backend = Backend::S3;
+                match backend {
+                    "s3" => config.backend = Backend::S3,
+                    "local" => config.backend = Backend::Local,
+                    "http" => config.backend = Backend::Http,
+                    "reapi" => config.backend = Backend::Reapi,
+                    "tiered" => config.backend = Backend::Tiered,
+                    _ => {}
                 }
             }
             if let Some(value) = matches.value_of("honeycomb_dataset") {
@@ -583,12 +1144,75 @@ impl Config {
             if let Some(value) = matches.value_of("s3_downloads_endpoint") {
                 config.s3_downloads_endpoint = Some(value.into());
             }
+            if let Some(value) = matches.value_of("http_url") {
+                config.http_url = Some(value.into());
+            }
+            if let Some(value) = matches.value_of("http_user") {
+                config.http_user = Some(value.into());
+            }
+            if let Some(value) = matches.value_of("reapi_endpoint") {
+                config.reapi_endpoint = Some(value.into());
+            }
+            if let Some(value) = matches.value_of("reapi_instance_name") {
+                config.reapi_instance_name = Some(value.into());
+            }
+            if let Some(value) = matches.value_of("local_cache_dir") {
+                config.local_cache_dir = Some(value.into());
+            }
+            if let Some(value) = matches.value_of("local_cache_max_size") {
+                config.local_cache_max_size = Some(value.parse().context("Invalid local_cache_max_size")?);
+            }
+            if let Some(value) = matches.value_of("local_cache_max_age") {
+                config.local_cache_max_age = Some(value.parse().context("Invalid local_cache_max_age")?);
+            }
+            if let Some(("gc", gc_matches)) = matches.subcommand() {
+                config.command = Command::Gc;
+                if let Some(value) = gc_matches.value_of("max_age") {
+                    config.gc_max_age = Some(value.parse().context("Invalid max_age")?);
+                }
+                if let Some(value) = gc_matches.value_of("keep") {
+                    config.gc_keep = Some(value.parse().context("Invalid keep")?);
+                }
+                if let Some(value) = gc_matches.value_of("grace_period") {
+                    config.gc_grace_period = value.parse().context("Invalid grace_period")?;
+                }
+                if gc_matches.is_present("dry_run") {
+                    config.gc_dry_run = true;
+                }
+            }
+            match matches.subcommand() {
+                Some(("ls", _)) => config.command = Command::Ls,
+                Some(("show", show_matches)) => {
+                    config.command = Command::Show(show_matches.value_of("hash").unwrap().to_owned());
+                }
+                Some(("diff", diff_matches)) => {
+                    config.command = Command::Diff(
+                        diff_matches.value_of("hash_a").unwrap().to_owned(),
+                        diff_matches.value_of("hash_b").unwrap().to_owned(),
+                    );
+                }
+                _ => {}
+            }
+            if let Some(values) = matches.values_of("tier") {
+                config.tiers.extend(values.map(|x| x.to_owned()));
+            }
+            if matches.is_present("tier_ignore_write_errors") {
+                config.tier_ignore_write_errors = true;
+            }
             if let Some(value) = matches.value_of("inputs_hash_var") {
                 config.inputs_hash_var = value.to_string();
             }
         }
 
-        if config.command_to_run.is_empty() && !config.inputs_hash_output {
+        // Secrets are not taken from the command line, where other users of the machine can see them.
+        if let Ok(value) = env::var("CAPSULE_HTTP_PASSWORD") {
+            config.http_password = Some(value);
+        }
+        if let Ok(value) = env::var("CAPSULE_HTTP_TOKEN") {
+            config.http_token = Some(value);
+        }
+
+        if config.command_to_run.is_empty() && !config.inputs_hash_output && config.command == Command::Run {
             bail!("The command to run was not specified");
         }
 
@@ -693,6 +1317,23 @@ mod tests {
         assert_eq!(config.capsule_id.unwrap(), "my other capsule id");
     }
 
+    #[test]
+    #[serial]
+    fn test_http_secrets_from_env() {
+        env::set_var("CAPSULE_HTTP_PASSWORD", "secret");
+        env::set_var("CAPSULE_HTTP_TOKEN", "token");
+        let config = Config::new(
+            vec!["capsule", "-c", "my_capsule", "--http_user", "me", "--", "/bin/echo"],
+            None,
+        );
+        env::remove_var("CAPSULE_HTTP_PASSWORD");
+        env::remove_var("CAPSULE_HTTP_TOKEN");
+        let config = config.unwrap();
+        assert_eq!(config.http_user.as_deref(), Some("me"));
+        assert_eq!(config.http_password.as_deref(), Some("secret"));
+        assert_eq!(config.http_token.as_deref(), Some("token"));
+    }
+
     #[test]
     #[serial]
     fn test_command_line_2() {
@@ -743,26 +1384,483 @@ mod tests {
 
     #[test]
     #[serial]
-    fn test_toml_defaults() {
+    fn test_ignore_command() {
+        let config = Config::new(vec!["capsule", "-c", "wtf", "--", "/bin/echo"], None).unwrap();
+        assert!(!config.ignore_command);
+
         let mut config_file = NamedTempFile::new().unwrap();
-        let config_contents: &'static str = indoc! {r#"
-           capture_stdout = true
-           tool_tag = ["docker-ABCDEF"]
-        "#};
-        println!("Config file:\n{}", config_contents);
-        config_file.write(config_contents.as_bytes()).unwrap();
+        config_file.write_all(b"[my_capsule]\nignore_command = true\n").unwrap();
         config_file.flush().unwrap();
-
         let config = Config::new(
-            vec!["placebo", "-c", "my_capsule", "--", "/bin/echo"],
-            Some(config_file.path()),
-        )
-        .unwrap();
-        assert_eq!(config.capture_stdout, Some(true));
-        assert!(config.capture_stderr.is_none());
-    }
-
-    #[test]
+            vec![
+                "capsule",
+                "-c",
+                "my_capsule",
+                "-f",
+                config_file.path().to_str().unwrap(),
+                "--",
+                "/bin/echo",
+            ],
+            None,
+        )
+        .unwrap();
+        assert!(config.ignore_command);
+
+        let config = Config::new(
+            vec!["capsule", "-c", "wtf", "--ignore_command", "--", "/bin/echo"],
+            None,
+        )
+        .unwrap();
+        assert!(config.ignore_command);
+    }
+
+    #[test]
+    #[serial]
+    fn test_hash_input_paths() {
+        let config = Config::new(vec!["capsule", "-c", "wtf", "--", "/bin/echo"], None).unwrap();
+        assert!(!config.hash_input_paths);
+
+        let mut config_file = NamedTempFile::new().unwrap();
+        config_file
+            .write_all(b"[my_capsule]\nhash_input_paths = true\n")
+            .unwrap();
+        config_file.flush().unwrap();
+        let config = Config::new(
+            vec![
+                "capsule",
+                "-c",
+                "my_capsule",
+                "-f",
+                config_file.path().to_str().unwrap(),
+                "--",
+                "/bin/echo",
+            ],
+            None,
+        )
+        .unwrap();
+        assert!(config.hash_input_paths);
+
+        let config = Config::new(
+            vec!["capsule", "-c", "wtf", "--hash_input_paths", "--", "/bin/echo"],
+            None,
+        )
+        .unwrap();
+        assert!(config.hash_input_paths);
+    }
+
+    #[test]
+    #[serial]
+    fn test_exclude() {
+        let mut config_file = NamedTempFile::new().unwrap();
+        config_file
+            .write_all(b"[my_capsule]\nexclude = [\"//target\"]\nuse_ignore_files = true\n")
+            .unwrap();
+        config_file.flush().unwrap();
+        let config = Config::new(
+            vec![
+                "capsule",
+                "-c",
+                "my_capsule",
+                "-f",
+                config_file.path().to_str().unwrap(),
+                "--exclude",
+                "**/*.swp",
+                "--",
+                "/bin/echo",
+            ],
+            None,
+        )
+        .unwrap();
+        assert_eq!(
+            config.exclude_patterns,
+            vec![WorkspacePath::from("//target"), WorkspacePath::from("**/*.swp")]
+        );
+        assert!(config.use_ignore_files);
+
+        let config = Config::new(vec!["capsule", "-c", "wtf", "--", "/bin/echo"], None).unwrap();
+        assert!(config.exclude_patterns.is_empty());
+        assert!(!config.use_ignore_files);
+    }
+
+    #[test]
+    #[serial]
+    fn test_tool_fingerprints() {
+        let mut config_file = NamedTempFile::new().unwrap();
+        config_file
+            .write_all(b"[my_capsule]\ntool_binary = [\"gcc\"]\ntool_cmd = [\"gcc --version\"]\n")
+            .unwrap();
+        config_file.flush().unwrap();
+        let config = Config::new(
+            vec![
+                "capsule",
+                "-c",
+                "my_capsule",
+                "-f",
+                config_file.path().to_str().unwrap(),
+                "--tool_binary",
+                "/usr/bin/ld",
+                "--tool_cmd",
+                "ld -v",
+                "--fingerprint_command",
+                "--",
+                "/bin/echo",
+            ],
+            None,
+        )
+        .unwrap();
+        assert_eq!(config.tool_binaries, vec!["gcc", "/usr/bin/ld"]);
+        assert_eq!(config.tool_commands, vec!["gcc --version", "ld -v"]);
+        assert!(config.fingerprint_command);
+
+        let config = Config::new(vec!["capsule", "-c", "wtf", "--", "/bin/echo"], None).unwrap();
+        assert!(config.tool_binaries.is_empty());
+        assert!(config.tool_commands.is_empty());
+        assert!(!config.fingerprint_command);
+    }
+
+    #[test]
+    #[serial]
+    fn test_platform_tag() {
+        let config = Config::new(vec!["capsule", "-c", "wtf", "--", "/bin/echo"], None).unwrap();
+        assert!(!config.platform_tag);
+        let config = Config::new(vec!["capsule", "-c", "wtf", "--platform_tag", "--", "/bin/echo"], None).unwrap();
+        assert!(config.platform_tag);
+    }
+
+    #[test]
+    #[serial]
+    fn test_input_list() {
+        let mut config_file = NamedTempFile::new().unwrap();
+        config_file
+            .write_all(b"[my_capsule]\ninput_list = [\"//inputs.txt\"]\n")
+            .unwrap();
+        config_file.flush().unwrap();
+        let config = Config::new(
+            vec![
+                "capsule",
+                "-c",
+                "my_capsule",
+                "-f",
+                config_file.path().to_str().unwrap(),
+                "--input_list",
+                "/tmp/more_inputs.txt",
+                "--",
+                "/bin/echo",
+            ],
+            None,
+        )
+        .unwrap();
+        assert_eq!(
+            config.input_lists,
+            vec![
+                WorkspacePath::from("//inputs.txt"),
+                WorkspacePath::from("/tmp/more_inputs.txt")
+            ]
+        );
+    }
+
+    #[test]
+    #[serial]
+    fn test_depfile() {
+        let config = Config::new(vec!["capsule", "-c", "wtf", "--", "/bin/echo"], None).unwrap();
+        assert_eq!(config.depfile, None);
+        let mut config_file = NamedTempFile::new().unwrap();
+        config_file
+            .write_all(b"[my_capsule]\ndepfile = \"//out/main.d\"\n")
+            .unwrap();
+        config_file.flush().unwrap();
+        let config_args = |args: &[&str]| {
+            let mut all_args = vec![
+                "capsule",
+                "-c",
+                "my_capsule",
+                "-f",
+                config_file.path().to_str().unwrap(),
+            ];
+            all_args.extend(args);
+            all_args.extend(["--", "/bin/echo"]);
+            Config::new(all_args, None).unwrap()
+        };
+        assert_eq!(config_args(&[]).depfile, Some("//out/main.d".into()));
+        // The command line overrides the config file.
+        assert_eq!(config_args(&["--depfile", "main.d"]).depfile, Some("main.d".into()));
+    }
+
+    #[test]
+    #[serial]
+    fn test_git_blob_hashes() {
+        let config = Config::new(vec!["capsule", "-c", "wtf", "--", "/bin/echo"], None).unwrap();
+        assert!(!config.git_blob_hashes);
+        let config = Config::new(
+            vec![
+                "capsule",
+                "-c",
+                "wtf",
+                "-i",
+                "git://src",
+                "--git_blob_hashes",
+                "--",
+                "/bin/echo",
+            ],
+            None,
+        )
+        .unwrap();
+        assert!(config.git_blob_hashes);
+        assert_eq!(config.input_files, vec![WorkspacePath::from("git://src")]);
+    }
+
+    #[test]
+    #[serial]
+    fn test_output_dir() {
+        let mut config_file = NamedTempFile::new().unwrap();
+        config_file
+            .write_all(b"[my_capsule]\noutput_dir = [\"//out/docs\"]\n")
+            .unwrap();
+        config_file.flush().unwrap();
+        let config = Config::new(
+            vec![
+                "capsule",
+                "-c",
+                "my_capsule",
+                "-f",
+                config_file.path().to_str().unwrap(),
+                "--output_dir",
+                "target/html",
+                "--",
+                "/bin/echo",
+            ],
+            None,
+        )
+        .unwrap();
+        assert_eq!(
+            config.output_dirs,
+            vec![WorkspacePath::from("//out/docs"), WorkspacePath::from("target/html")]
+        );
+        assert!(config.output_files.is_empty());
+    }
+
+    #[test]
+    #[serial]
+    fn test_input_symlinks() {
+        let config = Config::new(vec!["capsule", "-c", "wtf", "--", "/bin/echo"], None).unwrap();
+        assert_eq!(config.input_symlinks, SymlinkPolicy::Follow);
+
+        let mut config_file = NamedTempFile::new().unwrap();
+        config_file
+            .write_all(b"[my_capsule]\ninput_symlinks = \"target\"\n")
+            .unwrap();
+        config_file.flush().unwrap();
+        let config = Config::new(
+            vec![
+                "capsule",
+                "-c",
+                "my_capsule",
+                "-f",
+                config_file.path().to_str().unwrap(),
+                "--",
+                "/bin/echo",
+            ],
+            None,
+        )
+        .unwrap();
+        assert_eq!(config.input_symlinks, SymlinkPolicy::Target);
+
+        let config = Config::new(
+            vec!["capsule", "-c", "wtf", "--input_symlinks", "target", "--", "/bin/echo"],
+            None,
+        )
+        .unwrap();
+        assert_eq!(config.input_symlinks, SymlinkPolicy::Target);
+    }
+
+    #[test]
+    #[serial]
+    fn test_hash_cache_dir() {
+        let config = Config::new(vec!["capsule", "-c", "wtf", "--", "/bin/echo"], None).unwrap();
+        assert_eq!(config.hash_cache_dir, None);
+
+        let config = Config::new(
+            vec![
+                "capsule",
+                "-c",
+                "wtf",
+                "--hash_cache_dir",
+                "/tmp/hashes",
+                "--",
+                "/bin/echo",
+            ],
+            None,
+        )
+        .unwrap();
+        assert_eq!(config.hash_cache_dir.as_deref(), Some("/tmp/hashes"));
+    }
+
+    #[test]
+    #[serial]
+    fn test_hash_function() {
+        let config = Config::new(vec!["capsule", "-c", "wtf", "--", "/bin/echo"], None).unwrap();
+        assert_eq!(config.hash_function(), HashFunction::Sha256);
+
+        let mut config_file = NamedTempFile::new().unwrap();
+        config_file
+            .write_all(b"[my_capsule]\nhash_function = \"blake3\"\n")
+            .unwrap();
+        config_file.flush().unwrap();
+        let config = Config::new(
+            vec![
+                "capsule",
+                "-c",
+                "my_capsule",
+                "-f",
+                config_file.path().to_str().unwrap(),
+                "--",
+                "/bin/echo",
+            ],
+            None,
+        )
+        .unwrap();
+        assert_eq!(config.hash_function(), HashFunction::Blake3);
+
+        let config = Config::new(
+            vec!["capsule", "-c", "wtf", "--hash_function", "blake3", "--", "/bin/echo"],
+            None,
+        )
+        .unwrap();
+        assert_eq!(config.hash_function(), HashFunction::Blake3);
+
+        // Capsules.toml can switch back to the default of another config.
+        let mut default_config_file = NamedTempFile::new().unwrap();
+        default_config_file.write_all(b"hash_function = \"blake3\"\n").unwrap();
+        default_config_file.flush().unwrap();
+        let mut current_config_file = NamedTempFile::new().unwrap();
+        current_config_file
+            .write_all(b"[my_capsule]\nhash_function = \"sha256\"\n")
+            .unwrap();
+        current_config_file.flush().unwrap();
+        let args = |file: &NamedTempFile| {
+            vec![
+                "capsule".to_string(),
+                "-c".to_string(),
+                "my_capsule".to_string(),
+                "-f".to_string(),
+                file.path().display().to_string(),
+                "--".to_string(),
+                "/bin/echo".to_string(),
+            ]
+        };
+        let config = Config::new(args(&current_config_file), Some(default_config_file.path())).unwrap();
+        assert_eq!(config.hash_function(), HashFunction::Sha256);
+        let mut other_config_file = NamedTempFile::new().unwrap();
+        other_config_file.write_all(b"[my_capsule]\n").unwrap();
+        other_config_file.flush().unwrap();
+        let config = Config::new(args(&other_config_file), Some(default_config_file.path())).unwrap();
+        assert_eq!(config.hash_function(), HashFunction::Blake3);
+    }
+
+    #[test]
+    #[serial]
+    fn test_capture_max_size() {
+        let config = Config::new(vec!["capsule", "-c", "wtf", "--", "/bin/echo"], None).unwrap();
+        assert_eq!(config.capture_max_size, 16 * 1024 * 1024);
+        let config = Config::new(
+            vec!["capsule", "-c", "wtf", "--capture_max_size", "1000", "--", "/bin/echo"],
+            None,
+        )
+        .unwrap();
+        assert_eq!(config.capture_max_size, 1000);
+
+        // Capsules.toml overrides the default config, and the command line overrides both.
+        let mut default_config_file = NamedTempFile::new().unwrap();
+        default_config_file.write_all(b"capture_max_size = 1000\n").unwrap();
+        default_config_file.flush().unwrap();
+        let mut current_config_file = NamedTempFile::new().unwrap();
+        current_config_file
+            .write_all(b"[my_capsule]\ncapture_max_size = 2000\n[other_capsule]\ncapture_stdout = true\n")
+            .unwrap();
+        current_config_file.flush().unwrap();
+        let config_with = |capsule_id: &str, args: &[&str]| {
+            let file = format!("{}:{}", current_config_file.path().display(), capsule_id);
+            let mut cmdline = vec!["capsule", "-c", capsule_id, "-f", &file];
+            cmdline.extend(args);
+            cmdline.extend(["--", "/bin/echo"]);
+            Config::new(cmdline, Some(default_config_file.path())).unwrap()
+        };
+        assert_eq!(config_with("my_capsule", &[]).capture_max_size, 2000);
+        assert_eq!(config_with("other_capsule", &[]).capture_max_size, 1000);
+        assert_eq!(
+            config_with("my_capsule", &["--capture_max_size", "3000"]).capture_max_size,
+            3000
+        );
+    }
+
+    #[test]
+    #[serial]
+    fn test_toml_defaults() {
+        let mut config_file = NamedTempFile::new().unwrap();
+        let config_contents: &'static str = indoc! {r#"
+           capture_stdout = true
+           tool_tag = ["docker-ABCDEF"]
+        "#};
+        println!("Config file:\n{}", config_contents);
+        config_file.write(config_contents.as_bytes()).unwrap();
+        config_file.flush().unwrap();
+
+        let config = Config::new(
+            vec!["placebo", "-c", "my_capsule", "--", "/bin/echo"],
+            Some(config_file.path()),
+        )
+        .unwrap();
+        assert_eq!(config.capture_stdout, Some(true));
+        assert!(config.capture_stderr.is_none());
+    }
+
+    #[test]
+    #[serial]
+    fn test_concurrent_hash_max() {
+        let config = Config::new(vec!["placebo", "-c", "my_capsule", "--", "/bin/echo"], None).unwrap();
+        assert!(config.concurrent_hash_max >= 1);
+
+        let mut config_file = NamedTempFile::new().unwrap();
+        config_file.write_all(b"concurrent_hash_max = 2\n").unwrap();
+        config_file.flush().unwrap();
+        let config = Config::new(
+            vec!["placebo", "-c", "my_capsule", "--", "/bin/echo"],
+            Some(config_file.path()),
+        )
+        .unwrap();
+        assert_eq!(config.concurrent_hash_max, 2);
+
+        let mut current_config_file = NamedTempFile::new().unwrap();
+        current_config_file
+            .write_all(b"[my_capsule]\nconcurrent_hash_max = 3\n")
+            .unwrap();
+        current_config_file.flush().unwrap();
+        let file = current_config_file.path().to_str().unwrap();
+        let config = Config::new(
+            vec!["placebo", "-c", "my_capsule", "-f", file, "--", "/bin/echo"],
+            Some(config_file.path()),
+        )
+        .unwrap();
+        assert_eq!(config.concurrent_hash_max, 3);
+        let config = Config::new(
+            vec![
+                "placebo",
+                "-c",
+                "my_capsule",
+                "-f",
+                file,
+                "--concurrent_hash_max",
+                "5",
+                "--",
+                "/bin/echo",
+            ],
+            Some(config_file.path()),
+        )
+        .unwrap();
+        assert_eq!(config.concurrent_hash_max, 5);
+    }
+
+    #[test]
     #[serial]
     fn test_toml_precedence() {
         let mut default_config_file = NamedTempFile::new().unwrap();
@@ -781,6 +1879,7 @@ mod tests {
            output = ["compiled_binary"]
            input = ["/etc/passwd", "/nonexistent"]
            tool_tag = ["docker-1234"]
+           env_input = ["CC"]
         "#};
         current_config_file.write(config_contents.as_bytes()).unwrap();
         current_config_file.flush().unwrap();
@@ -792,6 +1891,8 @@ mod tests {
                 "my_capsule",
                 "-f",
                 &format!("{}:my_capsule", current_config_file.path().display()),
+                "--env_input",
+                "RUSTFLAGS",
                 "--",
                 "/bin/echo",
             ],
@@ -801,6 +1902,7 @@ mod tests {
         assert_eq!(config.capture_stdout, Some(false));
         assert!(config.capture_stderr.is_none());
         assert_eq!(config.tool_tags, vec!["docker-ABCDEF", "docker-1234"]);
+        assert_eq!(config.env_inputs, vec!["CC", "RUSTFLAGS"]);
     }
 
     #[test]
@@ -993,6 +2095,100 @@ mod tests {
         assert!(!config.outputs_match(vec![].into_iter()).unwrap());
     }
 
+    #[test]
+    #[serial]
+    fn test_local_backend() {
+        let config = Config::new(
+            vec![
+                "placebo",
+                "-c",
+                "my_capsule",
+                "-b",
+                "local",
+                "--local_cache_dir",
+                "/tmp/capsules",
+                "--local_cache_max_size",
+                "1000000",
+                "--",
+                "/bin/echo",
+            ],
+            None,
+        )
+        .unwrap();
+        assert!(matches!(config.backend, Backend::Local));
+        assert_eq!(config.local_cache_dir.as_deref(), Some("/tmp/capsules"));
+        assert_eq!(config.local_cache_max_size, Some(1_000_000));
+        assert_eq!(config.local_cache_max_age, None);
+    }
+
+    #[test]
+    #[serial]
+    fn test_tiered_backend() {
+        let config = Config::new(
+            vec![
+                "placebo",
+                "-c",
+                "my_capsule",
+                "-b",
+                "tiered",
+                "--tier",
+                "local",
+                "--tier",
+                "s3",
+                "--tier_ignore_write_errors",
+                "--",
+                "/bin/echo",
+            ],
+            None,
+        )
+        .unwrap();
+        assert!(matches!(config.backend, Backend::Tiered));
+        assert_eq!(config.tiers, vec!["local", "s3"]);
+        assert!(config.tier_ignore_write_errors);
+    }
+
+    #[test]
+    #[serial]
+    fn test_gc() {
+        let config = Config::new(
+            vec![
+                "capsule",
+                "gc",
+                "-b",
+                "s3",
+                "--s3_bucket",
+                "capsules",
+                "--keep",
+                "5",
+                "--dry_run",
+            ],
+            None,
+        )
+        .unwrap();
+        assert_eq!(config.command, Command::Gc);
+        assert!(matches!(config.backend, Backend::S3));
+        assert_eq!(config.s3_bucket.as_deref(), Some("capsules"));
+        assert_eq!(config.capsule_id.as_deref(), Some("-"));
+        assert_eq!(config.gc_keep, Some(5));
+        assert_eq!(config.gc_max_age, None);
+        assert_eq!(config.gc_grace_period, 86400);
+        assert!(config.gc_dry_run);
+    }
+
+    #[test]
+    #[serial]
+    fn test_inspect_commands() {
+        let config = Config::new(vec!["capsule", "ls", "-c", "wtf", "-b", "s3"], None).unwrap();
+        assert_eq!(config.command, Command::Ls);
+        assert_eq!(config.capsule_id.as_deref(), Some("wtf"));
+        let config = Config::new(vec!["capsule", "-c", "wtf", "show", "aa11"], None).unwrap();
+        assert_eq!(config.command, Command::Show("aa11".to_string()));
+        let config = Config::new(vec!["capsule", "-c", "wtf", "diff", "aa11", "bb22"], None).unwrap();
+        assert_eq!(config.command, Command::Diff("aa11".to_string(), "bb22".to_string()));
+        // Entries are stored per capsule, so the capsule ID is required.
+        assert!(Config::new(vec!["capsule", "ls"], None).is_err());
+    }
+
     #[test]
     #[serial]
     fn test_workspace_root() {
@@ -0,0 +1,99 @@
+//! Parsing of depfiles, the Makefile fragments listing the files a command read, as written by
+//! `gcc -MD`, `clang -MD` or `rustc --emit dep-info`.
+use anyhow::{Context, Result};
+use std::collections::HashSet;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// Returns the prerequisites of all the rules in the depfile, without duplicates, in the order they are listed.
+pub fn parse(contents: &str) -> Vec<PathBuf> {
+    let contents = contents.replace("\\\r\n", " ").replace("\\\n", " ");
+    let mut seen = HashSet::new();
+    let mut prerequisites = Vec::new();
+    let mut add = |token: &mut String| {
+        if !token.is_empty() && seen.insert(token.clone()) {
+            prerequisites.push(PathBuf::from(&token));
+        }
+        token.clear();
+    };
+    for line in contents.lines() {
+        // rustc writes the environment variables it read as comments.
+        if line.trim_start().starts_with('#') {
+            continue;
+        }
+        let mut in_prerequisites = false;
+        let mut token = String::new();
+        let mut chars = line.chars().peekable();
+        while let Some(c) = chars.next() {
+            match c {
+                '\\' if matches!(chars.peek(), Some(' ' | '#' | ':')) => token.push(chars.next().unwrap()),
+                '$' if chars.peek() == Some(&'$') => token.push(chars.next().unwrap()),
+                // The colon ending the targets is followed by a space, unlike the one of a drive letter.
+                ':' if !in_prerequisites && chars.peek().copied().unwrap_or(' ').is_whitespace() => {
+                    token.clear();
+                    in_prerequisites = true;
+                }
+                c if c.is_whitespace() => {
+                    if in_prerequisites {
+                        add(&mut token);
+                    }
+                    token.clear();
+                }
+                c => token.push(c),
+            }
+        }
+        if in_prerequisites {
+            add(&mut token);
+        }
+    }
+    prerequisites
+}
+
+/// Reads the depfile, returning None if it doesn't exist (e.g. before the first run of the command).
+pub fn read_depfile(path: &Path) -> Result<Option<Vec<PathBuf>>> {
+    match fs::read_to_string(path) {
+        Ok(contents) => Ok(Some(parse(&contents))),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e).with_context(|| format!("Reading depfile '{}'", path.to_string_lossy())),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn paths(paths: &[&str]) -> Vec<PathBuf> {
+        paths.iter().map(PathBuf::from).collect()
+    }
+
+    #[test]
+    fn test_parse() {
+        // gcc -MD -MP, with escaped spaces and dollars, and continuation lines.
+        let gcc = "main.o: src/main.c include/my\\ header.h \\\n /usr/include/stdio.h \\\n  cost$$.h\n\ninclude/my\\ header.h:\n\n/usr/include/stdio.h:\n";
+        assert_eq!(
+            parse(gcc),
+            paths(&["src/main.c", "include/my header.h", "/usr/include/stdio.h", "cost$.h"])
+        );
+        // rustc --emit dep-info, with one rule per target and comments.
+        let rustc = "/out/main.d: src/main.rs src/lib.rs\n\n/out/main: src/main.rs src/lib.rs\n\nsrc/main.rs:\nsrc/lib.rs:\n\n# env-dep:CARGO_PKG_NAME=capsule\n";
+        assert_eq!(parse(rustc), paths(&["src/main.rs", "src/lib.rs"]));
+        // Several targets, a drive letter, and CRLF line endings.
+        assert_eq!(
+            parse("a.o b.o : C:\\src\\a.c \\\r\n b.h\r\n"),
+            paths(&["C:\\src\\a.c", "b.h"])
+        );
+        assert!(parse("").is_empty());
+        assert!(parse("main.o:\n").is_empty());
+    }
+
+    #[test]
+    fn test_read_depfile() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("main.d");
+        assert_eq!(read_depfile(&path)?, None);
+        fs::write(&path, "main.o: main.c\n")?;
+        assert_eq!(read_depfile(&path)?, Some(paths(&["main.c"])));
+        assert!(read_depfile(dir.path()).is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,159 @@
+//! Enumeration of the files tracked by git, for `git://` inputs.
+use anyhow::{bail, Context, Result};
+use std::collections::HashSet;
+use std::ffi::OsStr;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+/// Prefix of inputs which stand for all files tracked by git under a path.
+pub const GIT_INPUT_PREFIX: &str = "git://";
+
+/// Modes of regular files in the git index, the only ones whose blob hash is the hash of their contents.
+const REGULAR_FILE_MODES: [&str; 2] = ["100644", "100755"];
+
+#[derive(Debug, PartialEq)]
+pub struct TrackedFile {
+    pub path: PathBuf,
+    /// Hash of the blob in the git index, if the file is a regular file not modified in the work tree.
+    pub blob_hash: Option<String>,
+}
+
+fn git(dir: &Path, args: &[&str]) -> Result<Vec<u8>> {
+    let output = Command::new("git")
+        .arg("-C")
+        .arg(dir)
+        .args(args)
+        .output()
+        .context("Running git")?;
+    if !output.status.success() {
+        bail!(
+            "git {} in '{}' failed: {}",
+            args.join(" "),
+            dir.to_string_lossy(),
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+    Ok(output.stdout)
+}
+
+/// Splits NUL-terminated git output, keeping the raw bytes since paths need not be valid UTF-8.
+fn split_nul(output: &[u8]) -> impl Iterator<Item = &[u8]> {
+    output.split(|&b| b == 0).filter(|s| !s.is_empty())
+}
+
+/// Returns the files tracked by git under the given directory, or the file itself if it is tracked.
+pub fn tracked_files(path: &Path) -> Result<Vec<TrackedFile>> {
+    let (dir, pathspec) = if path.is_dir() {
+        (path, ".".as_ref())
+    } else {
+        let file_name = path
+            .file_name()
+            .with_context(|| format!("Invalid git input '{}'", path.to_string_lossy()))?;
+        (path.parent().unwrap_or_else(|| Path::new(".")), file_name)
+    };
+    let dir = if dir.as_os_str().is_empty() {
+        Path::new(".")
+    } else {
+        dir
+    };
+    let pathspec = pathspec.to_str().context("can't convert path to string")?;
+    // Files whose stat info changed, e.g. by a fresh checkout or a touch, would otherwise be listed as modified.
+    // The refresh fails when files are modified, which diff-files reports below, and when the index can't be
+    // written, so its status is ignored.
+    let _ = Command::new("git")
+        .arg("-C")
+        .arg(dir)
+        .args(["update-index", "-q", "--refresh"])
+        .output();
+    // Files differing from the index in their contents or mode are read like other inputs.
+    let diff = git(dir, &["diff-files", "--name-only", "--relative", "-z", "--", pathspec])?;
+    let modified: HashSet<&[u8]> = split_nul(&diff).collect();
+    let listing = git(dir, &["ls-files", "--stage", "-z", "--", pathspec])?;
+    let mut files = Vec::new();
+    for entry in split_nul(&listing) {
+        // Entries are "<mode> <hash> <stage>\t<path>".
+        let unexpected = || format!("Unexpected git ls-files output '{}'", String::from_utf8_lossy(entry));
+        let tab = entry.iter().position(|&b| b == b'\t').with_context(unexpected)?;
+        let (info, file) = (&entry[..tab], &entry[tab + 1..]);
+        let mut info = std::str::from_utf8(info).ok().with_context(unexpected)?.split(' ');
+        let (mode, hash, stage) = (info.next(), info.next(), info.next());
+        let clean = REGULAR_FILE_MODES.iter().any(|regular| mode == Some(regular))
+            && stage == Some("0")
+            && !modified.contains(file);
+        let tracked = TrackedFile {
+            path: dir.join(OsStr::from_bytes(file)),
+            blob_hash: hash.filter(|_| clean).map(String::from),
+        };
+        // Files with merge conflicts are listed once per stage.
+        if files.last().map(|last: &TrackedFile| &last.path) != Some(&tracked.path) {
+            files.push(tracked);
+        }
+    }
+    Ok(files)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    #[test]
+    fn test_tracked_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let repo = dir.path();
+        git(repo, &["init", "-q"]).unwrap();
+        fs::create_dir_all(repo.join("sub")).unwrap();
+        fs::write(repo.join("sub/clean"), "clean\n").unwrap();
+        fs::write(repo.join("sub/modified"), "original\n").unwrap();
+        fs::write(repo.join("top"), "top\n").unwrap();
+        fs::write(repo.join(".gitignore"), "ignored\n").unwrap();
+        git(repo, &["add", "."]).unwrap();
+        fs::write(repo.join("sub/modified"), "modified\n").unwrap();
+        fs::write(repo.join("sub/untracked"), "untracked\n").unwrap();
+        fs::write(repo.join("sub/ignored"), "ignored\n").unwrap();
+
+        // Untracked and ignored files are left out, and only the unmodified ones have a blob hash.
+        let blob = String::from_utf8(git(repo, &["hash-object", "sub/clean"]).unwrap()).unwrap();
+        assert_eq!(
+            tracked_files(&repo.join("sub")).unwrap(),
+            vec![
+                TrackedFile {
+                    path: repo.join("sub").join("clean"),
+                    blob_hash: Some(blob.trim().to_string()),
+                },
+                TrackedFile {
+                    path: repo.join("sub").join("modified"),
+                    blob_hash: None,
+                },
+            ]
+        );
+
+        // Files whose contents didn't change keep their blob hash, even with new timestamps.
+        let mtime = filetime::FileTime::from_unix_time(1_000_000_000, 0);
+        filetime::set_file_mtime(repo.join("sub/clean"), mtime).unwrap();
+        assert_eq!(
+            tracked_files(&repo.join("sub/clean")).unwrap()[0].blob_hash,
+            Some(blob.trim().to_string())
+        );
+
+        let files = tracked_files(&repo.join("top")).unwrap();
+        assert_eq!(files.len(), 1);
+        assert_eq!(files[0].path, repo.join("top"));
+        assert!(tracked_files(&repo.join("sub/untracked")).unwrap().is_empty());
+        assert_eq!(tracked_files(repo).unwrap().len(), 4);
+
+        // Paths which aren't valid UTF-8 are kept as they are.
+        let non_utf8 = repo.join(OsStr::from_bytes(b"caf\xe9"));
+        fs::write(&non_utf8, "non-utf8\n").unwrap();
+        git(repo, &["add", "."]).unwrap();
+        let files = tracked_files(repo).unwrap();
+        assert_eq!(files.len(), 6);
+        assert!(files
+            .iter()
+            .any(|file| file.path == non_utf8 && file.blob_hash.is_some()));
+
+        let not_a_repo = tempfile::tempdir().unwrap();
+        assert!(tracked_files(not_a_repo.path()).is_err());
+    }
+}
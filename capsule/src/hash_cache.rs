@@ -0,0 +1,181 @@
+//! Persistent memo of file hashes, keyed by the metadata of the files.
+//!
+//! Many capsules of one build often share large inputs (toolchains, generated sources etc.), and
+//! without the memo each of them reads the files again. An entry maps the device, inode, size, mtime
+//! and ctime of a file to the hash of its contents. Writing to a file, or replacing it, changes its
+//! ctime, which can't be set back, so a changed file is never looked up under a stale entry.
+//!
+//! Each entry is a separate small file, which is written to a temporary file and renamed in place,
+//! so concurrent capsules can read and write the memo without any locking.
+use anyhow::{Context, Result};
+use log::warn;
+use std::fs;
+use std::io::Write;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tempfile::NamedTempFile;
+
+use crate::iohashing::{string_hash, HashFunction};
+
+/// Files modified more recently than this are always hashed, and their hashes aren't memoized.
+///
+/// The timestamps have a limited granularity, so a file can be changed again within the same tick
+/// without changing its metadata.
+const MIN_AGE: Duration = Duration::from_secs(1);
+
+#[derive(Debug, Clone)]
+pub struct HashCache {
+    /// Directory with the memo entries.
+    dir: PathBuf,
+    min_age: Duration,
+}
+
+impl HashCache {
+    pub fn new<P: Into<PathBuf>>(dir: P) -> Self {
+        Self {
+            dir: dir.into(),
+            min_age: MIN_AGE,
+        }
+    }
+
+    /// Returns the hash of the given file, reading it only if its hash isn't memoized yet.
+    pub fn file_hash(&self, filename: &Path, hash_function: HashFunction) -> Result<String> {
+        let metadata =
+            fs::metadata(filename).with_context(|| format!("Reading input file '{}'", filename.to_string_lossy()))?;
+        let key = match self.key(&metadata, hash_function) {
+            Some(key) => key,
+            None => return hash_function.file_hash(filename),
+        };
+        let entry = self.dir.join(&key[..2]).join(&key);
+        if let Ok(hash) = fs::read_to_string(&entry) {
+            if is_hash(&hash) {
+                return Ok(hash);
+            }
+        }
+        let hash = hash_function.file_hash(filename)?;
+        // Don't memoize the hash if the file changed while it was being read.
+        if fs::metadata(filename)
+            .ok()
+            .and_then(|metadata| self.key(&metadata, hash_function))
+            == Some(key)
+        {
+            if let Err(e) = write_entry(&entry, &hash) {
+                warn!(
+                    "Failed to memoize the hash of '{}': {:#}",
+                    filename.to_string_lossy(),
+                    e
+                );
+            }
+        }
+        Ok(hash)
+    }
+
+    /// Returns the memo key for a file, or None if its metadata can't be relied upon.
+    fn key(&self, metadata: &fs::Metadata, hash_function: HashFunction) -> Option<String> {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).ok()?;
+        // Timestamps in the future are just as unreliable as the recent ones.
+        let age = |secs: i64, nsecs: i64| now.checked_sub(Duration::new(u64::try_from(secs).ok()?, nsecs as u32));
+        if age(metadata.mtime(), metadata.mtime_nsec())? < self.min_age
+            || age(metadata.ctime(), metadata.ctime_nsec())? < self.min_age
+        {
+            return None;
+        }
+        Some(string_hash(&format!(
+            "{} {} {} {} {}.{:09} {}.{:09}",
+            hash_function,
+            metadata.dev(),
+            metadata.ino(),
+            metadata.size(),
+            metadata.mtime(),
+            metadata.mtime_nsec(),
+            metadata.ctime(),
+            metadata.ctime_nsec(),
+        )))
+    }
+}
+
+fn is_hash(s: &str) -> bool {
+    s.len() == 64 && s.bytes().all(|b| b.is_ascii_hexdigit())
+}
+
+/// Atomically writes a memo entry, so that concurrent readers never see a partial one.
+fn write_entry(entry: &Path, hash: &str) -> Result<()> {
+    let dir = entry.parent().unwrap();
+    fs::create_dir_all(dir)?;
+    let mut tmp = NamedTempFile::new_in(dir)?;
+    tmp.write_all(hash.as_bytes())?;
+    tmp.persist(entry)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::iohashing::file_hash;
+    use filetime::FileTime;
+    use tempfile::tempdir;
+
+    fn entries(dir: &Path) -> Vec<PathBuf> {
+        let mut entries = Vec::new();
+        for subdir in fs::read_dir(dir).unwrap() {
+            for entry in fs::read_dir(subdir.unwrap().path()).unwrap() {
+                entries.push(entry.unwrap().path());
+            }
+        }
+        entries
+    }
+
+    #[test]
+    fn test_memoized() -> Result<()> {
+        let memo = tempdir()?;
+        let files = tempdir()?;
+        let cache = HashCache {
+            dir: memo.path().to_owned(),
+            min_age: Duration::ZERO,
+        };
+        let file = files.path().join("input");
+        fs::write(&file, "contents")?;
+        let hash = cache.file_hash(&file, HashFunction::Sha256)?;
+        assert_eq!(hash, file_hash(&file)?);
+        let memoized = entries(memo.path());
+        assert_eq!(memoized.len(), 1);
+
+        // The memoized hash is used, without reading the file.
+        let fake = "ab".repeat(32);
+        fs::write(&memoized[0], &fake)?;
+        assert_eq!(cache.file_hash(&file, HashFunction::Sha256)?, fake);
+        // Hashes of other functions are memoized separately.
+        let blake3 = HashFunction::Blake3.file_hash(&file)?;
+        assert_eq!(cache.file_hash(&file, HashFunction::Blake3)?, blake3);
+        assert_eq!(cache.file_hash(&file, HashFunction::Blake3)?, blake3);
+
+        // A corrupt entry is ignored and replaced.
+        fs::write(&memoized[0], "garbage")?;
+        assert_eq!(cache.file_hash(&file, HashFunction::Sha256)?, hash);
+        assert_eq!(fs::read_to_string(&memoized[0])?, hash);
+
+        // Restoring the mtime doesn't hide the change, as the ctime changes anyway.
+        let mtime = FileTime::from_last_modification_time(&fs::metadata(&file)?);
+        fs::write(&memoized[0], &fake)?;
+        fs::write(&file, "other")?;
+        filetime::set_file_mtime(&file, mtime)?;
+        assert_eq!(cache.file_hash(&file, HashFunction::Sha256)?, file_hash(&file)?);
+        Ok(())
+    }
+
+    #[test]
+    fn test_recent_files_not_memoized() -> Result<()> {
+        let memo = tempdir()?;
+        let files = tempdir()?;
+        let cache = HashCache::new(memo.path());
+        let file = files.path().join("input");
+        fs::write(&file, "contents")?;
+        assert_eq!(cache.file_hash(&file, HashFunction::Sha256)?, file_hash(&file)?);
+        assert!(entries(memo.path()).is_empty());
+        assert!(cache
+            .file_hash(&files.path().join("nonexistent"), HashFunction::Sha256)
+            .is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,248 @@
+//! Exclusion of files matched by the input globs, either by exclude patterns, or by ignore files.
+use anyhow::{anyhow, Context, Result};
+use glob::{MatchOptions, Pattern};
+use ignore::gitignore::{Gitignore, GitignoreBuilder};
+use log::warn;
+use std::collections::HashMap;
+use std::path::{Component, Path, PathBuf};
+
+use crate::workspace_path::WorkspacePath;
+
+/// Names of the ignore files, with the later ones taking precedence in the same directory.
+const IGNORE_FILES: [&str; 2] = [".gitignore", ".capsuleignore"];
+
+/// Exclude patterns match like the input globs, so `*` doesn't cross directories.
+const MATCH_OPTIONS: MatchOptions = MatchOptions {
+    case_sensitive: true,
+    require_literal_separator: true,
+    require_literal_leading_dot: false,
+};
+
+pub struct InputFilter {
+    /// Directory relative paths and patterns are resolved against.
+    cwd: PathBuf,
+    excludes: Vec<Pattern>,
+    /// Whether `.gitignore` and `.capsuleignore` files are honoured.
+    use_ignore_files: bool,
+    /// Ignore files above the workspace root, or the current directory without one, don't apply.
+    boundary: PathBuf,
+    /// Ignore files of each directory seen so far, None if it has none.
+    ignore_files: HashMap<PathBuf, Option<Gitignore>>,
+    /// Whether each directory seen so far is the root of a git repository.
+    repo_roots: HashMap<PathBuf, bool>,
+}
+
+/// Resolve `.` and `..` in the path without touching the file system, so that paths and patterns
+/// spelled differently can still be compared.
+fn normalize(path: &Path) -> PathBuf {
+    let mut normalized = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::CurDir => {}
+            Component::ParentDir => {
+                normalized.pop();
+            }
+            component => normalized.push(component),
+        }
+    }
+    normalized
+}
+
+impl InputFilter {
+    pub fn new(excludes: &[WorkspacePath], use_ignore_files: bool, workspace_root: &Option<String>) -> Result<Self> {
+        let cwd = std::env::current_dir().context("Reading the current directory")?;
+        let excludes = excludes
+            .iter()
+            .map(|exclude| {
+                let path = normalize(&cwd.join(exclude.to_path(workspace_root)?));
+                let pattern = path.to_str().ok_or_else(|| anyhow!("can't convert path to string"))?;
+                Pattern::new(pattern).with_context(|| format!("Invalid exclude pattern '{}'", exclude))
+            })
+            .collect::<Result<_>>()?;
+        Ok(Self {
+            boundary: normalize(&cwd.join(workspace_root.as_deref().unwrap_or_default())),
+            cwd,
+            excludes,
+            use_ignore_files,
+            ignore_files: HashMap::new(),
+            repo_roots: HashMap::new(),
+        })
+    }
+
+    /// Whether the file is excluded from the inputs, as it or one of its directories matches an exclude
+    /// pattern, or is ignored by an ignore file.
+    pub fn is_excluded(&mut self, file: &Path) -> Result<bool> {
+        let path = normalize(&self.cwd.join(file));
+        let excluded = path.ancestors().any(|ancestor| {
+            self.excludes
+                .iter()
+                .any(|pattern| pattern.matches_path_with(ancestor, MATCH_OPTIONS))
+        });
+        if excluded {
+            return Ok(true);
+        }
+        if self.use_ignore_files {
+            return self.is_ignored(&path);
+        }
+        Ok(false)
+    }
+
+    /// Whether the file is ignored, looking for ignore files from its directory up to the root of the git
+    /// repository or the workspace, but never above the workspace root, or the current directory without one.
+    /// The deepest ignore file with a matching pattern decides.
+    fn is_ignored(&mut self, path: &Path) -> Result<bool> {
+        // Git never tracks its own directory.
+        if path.components().any(|component| component.as_os_str() == ".git") {
+            return Ok(true);
+        }
+        let mut dir = path.parent();
+        while let Some(current) = dir {
+            // For files outside of the boundary, stop where their path joins it.
+            if current != self.boundary && self.boundary.starts_with(current) {
+                break;
+            }
+            let matched = self.ignore_files(current)?.map(|ignore| {
+                let matched = ignore.matched_path_or_any_parents(path, false);
+                (matched.is_ignore(), matched.is_whitelist())
+            });
+            match matched {
+                Some((true, _)) => return Ok(true),
+                Some((_, true)) => return Ok(false),
+                _ => {}
+            }
+            if current == self.boundary || self.is_repo_root(current) {
+                break;
+            }
+            dir = current.parent();
+        }
+        Ok(false)
+    }
+
+    fn is_repo_root(&mut self, dir: &Path) -> bool {
+        *self
+            .repo_roots
+            .entry(dir.to_owned())
+            .or_insert_with(|| dir.join(".git").exists())
+    }
+
+    /// Returns the ignore files of the directory, reading them the first time.
+    fn ignore_files(&mut self, dir: &Path) -> Result<Option<&Gitignore>> {
+        if !self.ignore_files.contains_key(dir) {
+            let mut builder = GitignoreBuilder::new(dir);
+            let mut found = false;
+            for name in IGNORE_FILES {
+                let file = dir.join(name);
+                if file.is_file() {
+                    // Like git, skip the invalid lines rather than fail.
+                    if let Some(err) = builder.add(&file) {
+                        warn!("Invalid ignore file '{}': {}", file.to_string_lossy(), err);
+                    }
+                    found = true;
+                }
+            }
+            let ignore = if found {
+                Some(builder.build().context("Reading ignore files")?)
+            } else {
+                None
+            };
+            self.ignore_files.insert(dir.to_owned(), ignore);
+        }
+        Ok(self.ignore_files[dir].as_ref())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    fn create_files(root: &Path, files: &[&str]) {
+        for file in files {
+            let path = root.join(file);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, "").unwrap();
+        }
+    }
+
+    fn excluded(filter: &mut InputFilter, root: &Path, files: &[&str]) -> Vec<String> {
+        files
+            .iter()
+            .filter(|file| filter.is_excluded(&root.join(file)).unwrap())
+            .map(|file| file.to_string())
+            .collect()
+    }
+
+    #[test]
+    fn test_exclude_patterns() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path();
+        let files = [
+            "src/main.rs",
+            "src/.main.rs.swp",
+            "target/debug/main",
+            "docs/target/index.md",
+        ];
+        create_files(root, &files);
+        let patterns: Vec<WorkspacePath> = vec![
+            format!("{}/target", root.display()).into(),
+            "//**/*.swp".into(),
+            format!("{}/src/../docs/*.md", root.display()).into(),
+        ];
+        let workspace_root = Some(root.to_str().unwrap().to_string());
+        let mut filter = InputFilter::new(&patterns, false, &workspace_root).unwrap();
+        // Directories are excluded with everything in them, and `*` doesn't cross directories.
+        assert_eq!(
+            excluded(&mut filter, root, &files),
+            vec!["src/.main.rs.swp", "target/debug/main"]
+        );
+        assert!(filter.is_excluded(&root.join("docs/index.md")).unwrap());
+
+        assert!(InputFilter::new(&["***".into()], false, &None).is_err());
+    }
+
+    #[test]
+    fn test_ignore_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().join("repo");
+        let files = [
+            ".git/config",
+            "src/main.rs",
+            "src/main.o",
+            "src/keep.o",
+            "target/main",
+            "vendor/lib.rs",
+            "vendor/lib.o",
+        ];
+        create_files(&root, &files);
+        fs::write(root.join(".gitignore"), "*.o\n/target/\n").unwrap();
+        // The deeper ignore file wins, and .capsuleignore overrides .gitignore in the same directory.
+        fs::write(root.join("src/.gitignore"), "!keep.o\n").unwrap();
+        fs::write(root.join("vendor/.gitignore"), "!*.o\n").unwrap();
+        fs::write(root.join("vendor/.capsuleignore"), "lib.rs\n").unwrap();
+        // Ignore files above the repository don't apply.
+        fs::write(dir.path().join(".gitignore"), "*.rs\n").unwrap();
+
+        let mut filter = InputFilter::new(&[], true, &None).unwrap();
+        assert_eq!(
+            excluded(&mut filter, &root, &files),
+            vec![".git/config", "src/main.o", "target/main", "vendor/lib.rs"]
+        );
+        let mut filter = InputFilter::new(&[], false, &None).unwrap();
+        assert!(excluded(&mut filter, &root, &files).is_empty());
+
+        // Without a repository, the workspace root is the boundary.
+        fs::remove_dir_all(root.join(".git")).unwrap();
+        let workspace_root = Some(root.to_str().unwrap().to_string());
+        let mut filter = InputFilter::new(&[], true, &workspace_root).unwrap();
+        assert!(!filter.is_excluded(&root.join("src/main.rs")).unwrap());
+        let mut filter = InputFilter::new(&[], true, &None).unwrap();
+        assert!(filter.is_excluded(&root.join("src/main.rs")).unwrap());
+        // Nor is anything above the workspace root, even for files outside of it.
+        let workspace_root = Some(root.join("src").to_str().unwrap().to_string());
+        let mut filter = InputFilter::new(&[], true, &workspace_root).unwrap();
+        assert!(!filter.is_excluded(&root.join("src/main.o")).unwrap());
+        assert!(!filter.is_excluded(&root.join("target/main")).unwrap());
+        assert!(filter.is_excluded(&root.join("vendor/lib.rs")).unwrap());
+        assert!(filter.repo_roots.keys().all(|dir| dir.starts_with(&root)));
+    }
+}
@@ -0,0 +1,196 @@
+//! Inspection of the cache contents, without running anything.
+use anyhow::{anyhow, bail, Result};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt::{Display, Write};
+
+use crate::caching::backend::CachingBackend;
+use crate::iohashing::{InputHashBundle, InputOutputBundle};
+
+/// Fetch the cache entry keyed by the given inputs hash.
+async fn read_entry(backend: &dyn CachingBackend, hash: &str) -> Result<InputOutputBundle> {
+    // Backends use the hash as a part of their paths and keys, so don't let anything odd through.
+    if hash.len() < 2 || !hash.chars().all(|c| c.is_ascii_hexdigit()) {
+        bail!("Invalid inputs hash '{}'", hash);
+    }
+    let inputs = InputHashBundle {
+        hash: hash.to_string(),
+        ..Default::default()
+    };
+    backend
+        .peek(&inputs)
+        .await?
+        .ok_or_else(|| anyhow!("No cache entry with inputs hash '{}'", hash))
+}
+
+/// List cache entries of the capsule, most recent first, one per line.
+pub async fn list(backend: &dyn CachingBackend) -> Result<String> {
+    let mut out = String::new();
+    for entry in backend.list_entries().await? {
+        let last_modified = entry
+            .last_modified
+            .map(|time| time.format("%Y-%m-%d %H:%M:%S").to_string())
+            .unwrap_or_else(|| "-".to_string());
+        writeln!(out, "{}  {:>19}  {:>8}", entry.hash, last_modified, entry.size)?;
+    }
+    Ok(out)
+}
+
+/// Describe the cache entry with all of its inputs and outputs.
+pub async fn show(backend: &dyn CachingBackend, hash: &str) -> Result<String> {
+    let bundle = read_entry(backend, hash).await?;
+    let mut out = String::new();
+    writeln!(out, "Inputs hash:  {}", bundle.inputs.hash)?;
+    writeln!(out, "Outputs hash: {}", bundle.outputs.hash)?;
+    writeln!(out, "Source:       {}", bundle.source)?;
+    match bundle.outputs.result_code() {
+        Some(code) => writeln!(out, "Exit code:    {}", code)?,
+        None => writeln!(out, "Exit code:    unknown")?,
+    }
+    writeln!(out, "Inputs:")?;
+    for (input, hash) in &bundle.inputs.hash_details {
+        writeln!(out, "  {}  {}", hash, input)?;
+    }
+    if !bundle.inputs.discovered_inputs.is_empty() {
+        writeln!(out, "Discovered inputs:")?;
+        for (input, hash) in &bundle.inputs.discovered_inputs {
+            writeln!(out, "  {}  {}", hash, input)?;
+        }
+    }
+    writeln!(out, "Outputs:")?;
+    for (output, hash) in &bundle.outputs.hash_details {
+        writeln!(out, "  {}  {}", hash, output)?;
+    }
+    Ok(out)
+}
+
+/// Write the differences between two lists of hash details, as lines prefixed with '-' for items only
+/// in the first list, '+' for items only in the second one, and '~' for items with different hashes.
+fn diff_details<T: Ord + Display>(out: &mut String, a: &[(T, String)], b: &[(T, String)]) -> Result<()> {
+    let a: BTreeMap<&T, &str> = a.iter().map(|(item, hash)| (item, hash.as_str())).collect();
+    let b: BTreeMap<&T, &str> = b.iter().map(|(item, hash)| (item, hash.as_str())).collect();
+    let items: BTreeSet<&T> = a.keys().chain(b.keys()).copied().collect();
+    let mut same = true;
+    for item in items {
+        match (a.get(item), b.get(item)) {
+            (Some(hash_a), Some(hash_b)) if hash_a == hash_b => continue,
+            (Some(hash_a), Some(hash_b)) => writeln!(out, "~ {}: {} -> {}", item, hash_a, hash_b)?,
+            (Some(hash_a), None) => writeln!(out, "- {}: {}", item, hash_a)?,
+            (None, Some(hash_b)) => writeln!(out, "+ {}: {}", item, hash_b)?,
+            (None, None) => unreachable!(),
+        }
+        same = false;
+    }
+    if same {
+        writeln!(out, "  (identical)")?;
+    }
+    Ok(())
+}
+
+/// Compare inputs and outputs of two cache entries.
+pub async fn diff(backend: &dyn CachingBackend, hash_a: &str, hash_b: &str) -> Result<String> {
+    let a = read_entry(backend, hash_a).await?;
+    let b = read_entry(backend, hash_b).await?;
+    let mut out = String::new();
+    writeln!(out, "--- {} ({})", a.inputs.hash, a.source)?;
+    writeln!(out, "+++ {} ({})", b.inputs.hash, b.source)?;
+    writeln!(out, "Inputs:")?;
+    diff_details(&mut out, &a.inputs.hash_details, &b.inputs.hash_details)?;
+    writeln!(out, "Outputs:")?;
+    diff_details(&mut out, &a.outputs.hash_details, &b.outputs.hash_details)?;
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::caching::test::{TestBackend, TestBackendConfig};
+    use crate::iohashing::{FileOutput, Input, Output, OutputHashBundle};
+
+    async fn backend() -> TestBackend {
+        let backend = TestBackend::new("wtf", TestBackendConfig::default());
+        let entries = [
+            ("aa11", "1111", "2222", 0, "host1"),
+            ("bb22", "1111", "3333", 1, "host2"),
+        ];
+        for (hash, tool_hash, file_hash, code, source) in entries {
+            let inputs = InputHashBundle {
+                hash: hash.to_string(),
+                hash_details: vec![
+                    (Input::ToolTag("gcc".to_string()), tool_hash.to_string()),
+                    (Input::File("src/main.c".into()), file_hash.to_string()),
+                ],
+                ..Default::default()
+            };
+            let outputs = OutputHashBundle {
+                hash: format!("{}{}", hash, hash),
+                hash_details: vec![
+                    (
+                        Output::File(FileOutput {
+                            filename: "main.o".into(),
+                            present: true,
+                            mode: 0o644,
+                            symlink: None,
+                        }),
+                        "4444".to_string(),
+                    ),
+                    (Output::ExitCode(code), code.to_string()),
+                ],
+                ..Default::default()
+            };
+            backend.write(&inputs, &outputs, source.to_string()).await.unwrap();
+        }
+        backend
+    }
+
+    #[tokio::test]
+    async fn test_list() {
+        let backend = backend().await;
+        let listing = list(&backend).await.unwrap();
+        let hashes: Vec<&str> = listing
+            .lines()
+            .map(|line| line.split_whitespace().next().unwrap())
+            .collect();
+        assert_eq!(hashes, vec!["aa11", "bb22"]);
+    }
+
+    #[tokio::test]
+    async fn test_show() {
+        let backend = backend().await;
+        let output = show(&backend, "bb22").await.unwrap();
+        assert_eq!(
+            output,
+            "Inputs hash:  bb22\n\
+             Outputs hash: bb22bb22\n\
+             Source:       host2\n\
+             Exit code:    1\n\
+             Inputs:\n  \
+               1111  tool tag 'gcc'\n  \
+               3333  file 'src/main.c'\n\
+             Outputs:\n  \
+               4444  file 'main.o' (mode 644)\n  \
+               1  exit code 1\n"
+        );
+        assert!(show(&backend, "cc33").await.is_err());
+        assert!(show(&backend, "../etc").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_diff() {
+        let backend = backend().await;
+        let output = diff(&backend, "aa11", "bb22").await.unwrap();
+        assert_eq!(
+            output,
+            "--- aa11 (host1)\n\
+             +++ bb22 (host2)\n\
+             Inputs:\n\
+             ~ file 'src/main.c': 2222 -> 3333\n\
+             Outputs:\n\
+             - exit code 0: 0\n\
+             + exit code 1: 1\n"
+        );
+        assert!(diff(&backend, "aa11", "aa11")
+            .await
+            .unwrap()
+            .ends_with("Outputs:\n  (identical)\n"));
+    }
+}
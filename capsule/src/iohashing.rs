@@ -1,26 +1,60 @@
 use anyhow;
-use anyhow::{Context, Result};
+use anyhow::{bail, Context, Result};
+use derivative::Derivative;
 use serde::{Deserialize, Serialize};
 use sha2::{Digest, Sha256};
-use std::cmp::Ordering;
+use std::collections::BTreeMap;
+use std::fmt;
 use std::fs::File;
-use std::io::Read;
-use std::path::Path;
+use std::io::{self, Read, Seek, SeekFrom};
+use std::os::unix::io::AsFd;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
 
+use crate::hash_cache::HashCache;
+use crate::output_tree::Directory;
 use crate::workspace_path::WorkspacePath;
 
+/// Hash function of the contents of inputs and outputs, and of the cache keys.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Derivative, Serialize, Deserialize)]
+#[derivative(Default)]
+#[serde(rename_all = "lowercase")]
+pub enum HashFunction {
+    #[derivative(Default)]
+    Sha256,
+    Blake3,
+}
+
 #[derive(PartialOrd, Ord, PartialEq, Eq, Debug, Clone, Serialize, Deserialize)]
 pub enum Input {
     /// string uniquely defining the tool version (could be even the hash of its binary).    
     ToolTag(String),
     /// Input file.
     File(WorkspacePath),
+    /// The wrapped command line, and the directory it runs in.
+    Command { args: Vec<String>, cwd: WorkspacePath },
+    /// Environment variable with its value, or None if it is not set.
+    EnvVar(String, Option<String>),
+    /// Standard input of the command. It has to be seekable (e.g. spooled to a file) to be hashed.
+    Stdin,
 }
 
 /// Input set is the set of all inputs to the build step.
 #[derive(Default, Debug, Clone)]
 pub struct InputSet {
     pub inputs: Vec<Input>,
+    /// Whether the workspace paths of input files are hashed together with their contents.
+    pub hash_paths: bool,
+    /// Persistent memo of file hashes, if enabled.
+    pub hash_cache: Option<HashCache>,
+    /// Maximum number of files hashed at the same time.
+    pub concurrent_hash_max: usize,
+    /// Hash function of the file contents and of the whole set.
+    pub hash_function: HashFunction,
+    /// Hashes of input files known without reading them (e.g. git blob hashes), by their full path.
+    pub file_hashes: BTreeMap<PathBuf, String>,
 }
 
 #[derive(PartialOrd, Ord, PartialEq, Eq, Debug, Clone, Serialize, Deserialize)]
@@ -28,26 +62,112 @@ pub struct FileOutput {
     pub filename: WorkspacePath,
     pub present: bool,
     pub mode: u32,
+    /// Target of the symlink, if the output is one. Symlinks aren't objects in the content addressable storage,
+    /// they are recreated from the target on a cache hit.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub symlink: Option<String>,
+}
+
+/// Directory declared as an output, with all of its contents.
+#[derive(PartialOrd, Ord, PartialEq, Eq, Debug, Clone, Serialize, Deserialize)]
+pub struct TreeOutput {
+    pub dirname: WorkspacePath,
+    /// Manifest of the directory, None if the command didn't create it. It's embedded in the cache entry rather
+    /// than stored as an object of its own, since garbage collection, eviction and copying entries between tiers
+    /// find the objects an entry refers to in the entry itself, and they'd miss the files listed in the manifest.
+    pub manifest: Option<Directory>,
 }
 
 #[derive(PartialOrd, Ord, PartialEq, Eq, Debug, Clone, Serialize, Deserialize)]
 pub enum Output {
     File(FileOutput),
     ExitCode(i32),
-    Stdout(Vec<u8>),
-    Stderr(Vec<u8>),
+    /// Captured standard output. Its contents are an object in the content addressable storage.
+    Stdout,
+    /// Captured standard error, stored just like the standard output.
+    Stderr,
+    /// Captured standard output and error, interleaved in a combined log (see `combined_log`).
+    Combined,
+    /// Directory, whose files are objects in the content addressable storage, while the rest is in the manifest.
+    Tree(TreeOutput),
+}
+
+impl fmt::Display for Input {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Input::ToolTag(tool_tag) => write!(f, "tool tag '{}'", tool_tag),
+            Input::File(filename) => write!(f, "file '{}'", filename),
+            Input::Command { args, cwd } => write!(f, "command '{}' in '{}'", shell_words::join(args), cwd),
+            Input::EnvVar(name, Some(value)) => write!(f, "environment variable {}='{}'", name, value),
+            Input::EnvVar(name, None) => write!(f, "environment variable {} (unset)", name),
+            Input::Stdin => write!(f, "standard input"),
+        }
+    }
+}
+
+impl fmt::Display for HashFunction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            HashFunction::Sha256 => write!(f, "sha256"),
+            HashFunction::Blake3 => write!(f, "blake3"),
+        }
+    }
+}
+
+impl FromStr for HashFunction {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        HashFunction::ALL
+            .into_iter()
+            .find(|hash_function| hash_function.to_string() == s)
+            .ok_or_else(|| anyhow::anyhow!("Unknown hash function '{}'", s))
+    }
+}
+
+impl fmt::Display for Output {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Output::File(FileOutput {
+                filename,
+                symlink: Some(target),
+                ..
+            }) => write!(f, "symlink '{}' -> '{}'", filename, target),
+            Output::File(file_output) if file_output.present => {
+                write!(f, "file '{}' (mode {:o})", file_output.filename, file_output.mode)
+            }
+            Output::File(file_output) => write!(f, "file '{}' (absent)", file_output.filename),
+            Output::ExitCode(code) => write!(f, "exit code {}", code),
+            Output::Stdout => write!(f, "stdout"),
+            Output::Stderr => write!(f, "stderr"),
+            Output::Combined => write!(f, "combined stdout and stderr"),
+            Output::Tree(TreeOutput {
+                dirname,
+                manifest: Some(manifest),
+            }) => write!(f, "tree '{}' ({} files)", dirname, manifest.files().len()),
+            Output::Tree(tree_output) => write!(f, "tree '{}' (absent)", tree_output.dirname),
+        }
+    }
 }
 
 #[derive(Debug, Default, Serialize, Deserialize, Clone)]
 pub struct InputHashBundle {
     pub hash: String,
     pub hash_details: Vec<(Input, String)>,
+    /// Input files the command turned out to read, listed in its depfile, with their hashes. They aren't a part of
+    /// the hash, as they are only known after the run, but a cache hit requires them to be unchanged.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub discovered_inputs: Vec<(Input, String)>,
 }
 
 #[derive(Debug, Default, Serialize, Deserialize, Clone)]
 pub struct OutputHashBundle {
     pub hash: String,
     pub hash_details: Vec<(Output, String)>,
+    /// Hash function of the whole bundle and of the outputs, which are verified with it on download.
+    /// Entries written before it was recorded were all hashed with SHA256.
+    #[serde(default)]
+    pub hash_function: HashFunction,
 }
 
 impl OutputHashBundle {
@@ -60,6 +180,19 @@ impl OutputHashBundle {
         }
         None
     }
+
+    // Hashes of all objects that this bundle references in the content addressable storage.
+    pub fn object_hashes(&self) -> impl Iterator<Item = &str> {
+        self.hash_details.iter().flat_map(|(output, hash)| match output {
+            Output::File(file_output) if file_output.present && file_output.symlink.is_none() => vec![hash.as_str()],
+            Output::Stdout | Output::Stderr | Output::Combined => vec![hash.as_str()],
+            Output::Tree(TreeOutput {
+                manifest: Some(manifest),
+                ..
+            }) => manifest.files().into_iter().map(|(_, hash, _)| hash).collect(),
+            _ => vec![],
+        })
+    }
 }
 
 #[derive(Serialize, Deserialize, Clone)]
@@ -69,52 +202,239 @@ pub struct InputOutputBundle {
     pub source: String,
 }
 
+/// Differences between inputs of a capsule and inputs of its previous run, to explain a cache miss.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct InputsDiff {
+    /// Inputs hash of the previous run.
+    pub previous_hash: String,
+    /// Inputs present in both runs, but with different hashes.
+    pub changed: Vec<Input>,
+    /// Inputs which were not present in the previous run.
+    pub added: Vec<Input>,
+    /// Inputs of the previous run which are not present anymore.
+    pub removed: Vec<Input>,
+}
+
+impl InputsDiff {
+    pub fn is_empty(&self) -> bool {
+        self.changed.is_empty() && self.added.is_empty() && self.removed.is_empty()
+    }
+}
+
+impl fmt::Display for InputsDiff {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.is_empty() {
+            // Same inputs mean the same hash, so the previous entry must have been evicted.
+            return write!(
+                f,
+                "inputs are the same as in the previous cache entry {}, which is gone from the cache",
+                self.previous_hash
+            );
+        }
+        write!(f, "inputs differ from the previous cache entry {}:", self.previous_hash)?;
+        for input in &self.changed {
+            write!(f, "\n  changed {}", input)?;
+        }
+        for input in &self.added {
+            write!(f, "\n  added {}", input)?;
+        }
+        for input in &self.removed {
+            write!(f, "\n  removed {}", input)?;
+        }
+        Ok(())
+    }
+}
+
+impl InputHashBundle {
+    /// Compare these inputs against the inputs of a previous run.
+    pub fn diff(&self, previous: &InputHashBundle) -> InputsDiff {
+        let current: BTreeMap<&Input, &str> = self.hash_details.iter().map(|(i, h)| (i, h.as_str())).collect();
+        let previous_map: BTreeMap<&Input, &str> = previous.hash_details.iter().map(|(i, h)| (i, h.as_str())).collect();
+        let mut diff = InputsDiff {
+            previous_hash: previous.hash.clone(),
+            ..Default::default()
+        };
+        for (input, hash) in &current {
+            match previous_map.get(input) {
+                Some(previous_hash) if previous_hash != hash => diff.changed.push((*input).clone()),
+                Some(_) => {}
+                None => diff.added.push((*input).clone()),
+            }
+        }
+        diff.removed = previous_map
+            .keys()
+            .filter(|input| !current.contains_key(*input))
+            .map(|input| (*input).clone())
+            .collect();
+        diff
+    }
+}
+
 /// Output set is the set of all process outputs.
 #[derive(Default)]
 pub struct OutputSet {
     pub outputs: Vec<Output>,
+    /// Maximum number of files hashed at the same time.
+    pub concurrent_hash_max: usize,
+    /// Hash function of the file contents and of the whole set.
+    pub hash_function: HashFunction,
+    /// Captured streams with the hashes of their contents.
+    captured: Vec<(Output, String)>,
 }
 
-/// Returns the hash of the given file.
-///
-/// TODO(valeryz): Maybe cache these in a parent process' memory by the
-/// output of stat(2), except atime, so that we don't have to read
-/// them twice during a single build process.
-pub fn file_hash(filename: &Path) -> Result<String> {
-    const BUFSIZE: usize = 4096;
-    let mut acc = Sha256::new();
-    let mut f = File::open(filename).with_context(|| format!("Reading input file '{}'", filename.to_string_lossy()))?;
-    let mut buf: [u8; BUFSIZE] = [0; BUFSIZE];
-    loop {
-        let rd = f.read(&mut buf)?;
-        if rd == 0 {
-            break;
+/// Incremental hashing with one of the supported hash functions.
+pub enum Hasher {
+    Sha256(Sha256),
+    Blake3(Box<blake3::Hasher>),
+}
+
+impl Hasher {
+    pub fn update(&mut self, data: &[u8]) {
+        match self {
+            Hasher::Sha256(acc) => acc.update(data),
+            Hasher::Blake3(acc) => {
+                acc.update(data);
+            }
+        }
+    }
+
+    /// Returns the HEX string of the hash.
+    pub fn finalize(self) -> String {
+        match self {
+            Hasher::Sha256(acc) => format!("{:x}", acc.finalize()),
+            Hasher::Blake3(acc) => acc.finalize().to_hex().to_string(),
         }
-        acc.update(&buf[..rd]);
     }
-    Ok(format!("{:x}", acc.finalize()))
 }
 
-fn string_hash(s: &str) -> String {
-    let mut acc = Sha256::new();
-    acc.update(s.as_bytes());
-    format!("{:x}", acc.finalize())
+impl HashFunction {
+    pub const ALL: [HashFunction; 2] = [HashFunction::Sha256, HashFunction::Blake3];
+
+    pub fn hasher(self) -> Hasher {
+        match self {
+            HashFunction::Sha256 => Hasher::Sha256(Sha256::new()),
+            HashFunction::Blake3 => Hasher::Blake3(Box::new(blake3::Hasher::new())),
+        }
+    }
+
+    /// Returns the hash of the given file.
+    pub fn file_hash(self, filename: &Path) -> Result<String> {
+        let f = File::open(filename).with_context(|| format!("Reading input file '{}'", filename.to_string_lossy()))?;
+        self.reader_hash(f)
+    }
+
+    /// Returns the hash of everything the reader yields.
+    pub fn reader_hash<R: Read>(self, mut f: R) -> Result<String> {
+        const BUFSIZE: usize = 64 * 1024;
+        let mut acc = self.hasher();
+        let mut buf = vec![0; BUFSIZE];
+        loop {
+            let rd = f.read(&mut buf)?;
+            if rd == 0 {
+                break;
+            }
+            acc.update(&buf[..rd]);
+        }
+        Ok(acc.finalize())
+    }
+
+    pub fn string_hash(self, s: &str) -> String {
+        self.bytes_hash(s.as_bytes())
+    }
+
+    pub fn bytes_hash(self, s: &[u8]) -> String {
+        let mut acc = self.hasher();
+        acc.update(s);
+        acc.finalize()
+    }
+
+    /// Returns the hash of a symlink with the given target, which differs from the hash of a file with the
+    /// target as its contents.
+    pub fn symlink_hash(self, target: &str) -> String {
+        self.string_hash(&format!("symlink\0{}", target))
+    }
+}
+
+/// Returns the SHA256 of the given file.
+pub fn file_hash(filename: &Path) -> Result<String> {
+    HashFunction::Sha256.file_hash(filename)
 }
 
-fn bytes_hash(s: &[u8]) -> String {
-    let mut acc = Sha256::new();
-    acc.update(s);
-    format!("{:x}", acc.finalize())
+/// Returns the hash of the standard input from its current position, leaving the position intact,
+/// so that the wrapped command still reads all of it.
+pub fn stdin_hash(hash_function: HashFunction) -> Result<String> {
+    // A duplicate of the descriptor shares the position with the original one.
+    let mut stdin = File::from(io::stdin().as_fd().try_clone_to_owned()?);
+    let position = stdin
+        .stream_position()
+        .context("Standard input has to be spooled to be hashed")?;
+    let hash = hash_function.reader_hash(&mut stdin)?;
+    stdin.seek(SeekFrom::Start(position))?;
+    Ok(hash)
 }
 
+/// Hashes the files on up to `concurrency` threads, and returns their hashes in the same order.
+pub(crate) fn hash_files<F>(paths: &[PathBuf], concurrency: usize, hash: F) -> Result<Vec<String>>
+where
+    F: Fn(&Path) -> Result<String> + Sync,
+{
+    let next = AtomicUsize::new(0);
+    let worker = || {
+        let mut hashes = Vec::new();
+        loop {
+            let i = next.fetch_add(1, Ordering::Relaxed);
+            if i >= paths.len() {
+                break;
+            }
+            let result = hash(&paths[i]);
+            let failed = result.is_err();
+            hashes.push((i, result));
+            if failed {
+                // Make the other workers stop too.
+                next.store(paths.len(), Ordering::Relaxed);
+                break;
+            }
+        }
+        hashes
+    };
+    let threads = concurrency.clamp(1, paths.len().max(1));
+    let mut hashes = if threads == 1 {
+        worker()
+    } else {
+        thread::scope(|scope| {
+            let handles: Vec<_> = (0..threads).map(|_| scope.spawn(worker)).collect();
+            handles
+                .into_iter()
+                .flat_map(|handle| handle.join().expect("Hashing thread panicked"))
+                .collect()
+        })
+    };
+    hashes.sort_by_key(|(i, _)| *i);
+    hashes.into_iter().map(|(_, hash)| hash).collect()
+}
+
+/// Returns the SHA256 of the string, e.g. for keys which don't depend on the configured hash function.
+pub fn string_hash(s: &str) -> String {
+    HashFunction::Sha256.string_hash(s)
+}
+
+/// Returns the SHA256 of the bytes.
+pub fn bytes_hash(s: &[u8]) -> String {
+    HashFunction::Sha256.bytes_hash(s)
+}
+
+/// Version of the inputs hash. It has to be bumped whenever the way inputs are hashed changes,
+/// so that cache entries written by older capsules are never mistaken for the new ones.
+pub const CACHE_KEY_VERSION: &str = "3";
+
 /// Helper function for both input and output hash finalization.
-fn bundle_hash<'a, I: Iterator<Item = (&'a str, &'a str)>>(hash_details: I) -> String {
-    let mut acc: Sha256 = Sha256::new();
+fn bundle_hash<'a, I: Iterator<Item = (&'a str, &'a str)>>(hash_function: HashFunction, hash_details: I) -> String {
+    let mut acc = hash_function.hasher();
     for (tag, hash) in hash_details {
-        acc.update(tag);
-        acc.update(hash);
+        acc.update(tag.as_bytes());
+        acc.update(hash.as_bytes());
     }
-    format!("{:x}", acc.finalize())
+    acc.finalize()
 }
 
 impl InputSet {
@@ -129,41 +449,96 @@ impl InputSet {
     ///
     /// It does this by calculating a SHA256 hash of all SHA256 hashes of inputs (being either file
     /// or tool tag) sorted by the values of the hashes themselves.
+    ///
+    /// If `hash_paths` is set, each file contributes the hash of its normalized workspace path together
+    /// with its contents, so renaming or moving a file changes the total hash. The per-input hashes in the
+    /// bundle are always the hashes of the contents.
     pub fn hash_bundle(self, root: &Option<String>) -> Result<InputHashBundle> {
         // Calculate the hash of the input set independently of the order.
         let mut hash_bundle = InputHashBundle::default();
+        let mut path_hashes = Vec::new();
+        let paths = self
+            .inputs
+            .iter()
+            .filter_map(|input| match input {
+                Input::File(filename) => Some(filename.to_path(root)),
+                _ => None,
+            })
+            .collect::<Result<Vec<_>>>()?;
+        let (hash_cache, hash_function) = (self.hash_cache.as_ref(), self.hash_function);
+        let known_hashes = &self.file_hashes;
+        // Input files are hashed through the `HashCache` when it is enabled, so that capsules of one build
+        // don't read the same files again.
+        let hashes = hash_files(&paths, self.concurrent_hash_max, |path| {
+            match (known_hashes.get(path), hash_cache) {
+                (Some(hash), _) => Ok(hash.clone()),
+                (None, Some(cache)) => cache.file_hash(path, hash_function),
+                (None, None) => hash_function.file_hash(path),
+            }
+        })?;
+        let mut files = paths.into_iter().zip(hashes);
         for input in self.inputs {
             let hash = match input {
-                Input::File(ref filename) => {
-                    let path = filename.to_path(root)?;
-                    file_hash(&path)?
+                Input::File(_) => {
+                    // There is exactly one path and hash for each file input, in the same order.
+                    let (path, hash) = files.next().unwrap();
+                    if self.hash_paths {
+                        let normalized = WorkspacePath::from_full_path(&path, root);
+                        path_hashes.push(hash_function.string_hash(&format!("{}\0{}", normalized, hash)));
+                    }
+                    hash
+                }
+                Input::ToolTag(ref s) => hash_function.string_hash(s),
+                Input::Command { ref args, ref cwd } => {
+                    hash_function.string_hash(&serde_json::to_string(&(cwd, args))?)
+                }
+                // Unset is serialized as null, so it hashes differently from an empty value.
+                Input::EnvVar(ref name, ref value) => {
+                    hash_function.string_hash(&serde_json::to_string(&(name, value))?)
                 }
-                Input::ToolTag(ref s) => string_hash(s),
+                Input::Stdin => stdin_hash(hash_function)?,
             };
             hash_bundle.hash_details.push((input, hash));
         }
-        // Sort inputs hashes by the hash value, but so that tool_tags come first.
-        // This is needed so that when we cap our JSON, we could still see tool_tags.
-        hash_bundle.hash_details.sort_by(|a, b| {
-            if let Input::ToolTag(_) = a.0 {
-                if let Input::ToolTag(_) = b.0 {
-                    a.1.cmp(&b.1)
-                } else {
-                    Ordering::Less
-                }
-            } else {
-                a.1.cmp(&b.1)
-            }
-        });
-        hash_bundle.hash = bundle_hash(hash_bundle.hash_details.iter().map(|(inp, hash)| {
-            (
-                match inp {
-                    Input::File(_) => "File",
-                    Input::ToolTag(_) => "ToolTag",
-                },
-                &hash[..],
+        // Sort inputs hashes by the hash value, but so that tool_tags, environment and the command come first.
+        // This is needed so that when we cap our JSON, we could still see them.
+        let rank = |input: &Input| match input {
+            Input::ToolTag(_) => 0,
+            Input::EnvVar(..) => 1,
+            Input::Command { .. } => 2,
+            Input::Stdin => 3,
+            Input::File(_) => 4,
+        };
+        hash_bundle
+            .hash_details
+            .sort_by(|a, b| rank(&a.0).cmp(&rank(&b.0)).then_with(|| a.1.cmp(&b.1)));
+        let tag = |inp: &Input| match inp {
+            Input::File(_) => "File",
+            Input::ToolTag(_) => "ToolTag",
+            Input::Command { .. } => "Command",
+            Input::EnvVar(..) => "EnvVar",
+            Input::Stdin => "Stdin",
+        };
+        let version = std::iter::once(("Version", CACHE_KEY_VERSION));
+        hash_bundle.hash = if self.hash_paths {
+            // Files with equal contents sort arbitrarily among themselves, so the path hashes are
+            // sorted separately to keep the total hash independent of the order of the inputs.
+            path_hashes.sort();
+            let others = hash_bundle
+                .hash_details
+                .iter()
+                .filter(|(inp, _)| !matches!(inp, Input::File(_)))
+                .map(|(inp, hash)| (tag(inp), &hash[..]));
+            let files = path_hashes.iter().map(|hash| ("PathFile", &hash[..]));
+            // A separate tag keeps the keys of both modes apart, even for capsules without files.
+            bundle_hash(
+                hash_function,
+                version.chain(std::iter::once(("Paths", ""))).chain(others).chain(files),
             )
-        }));
+        } else {
+            let details = hash_bundle.hash_details.iter().map(|(inp, hash)| (tag(inp), &hash[..]));
+            bundle_hash(hash_function, version.chain(details))
+        };
         Ok(hash_bundle)
     }
 
@@ -187,41 +562,70 @@ impl OutputSet {
     pub fn hash_bundle(self, root: &Option<String>) -> Result<OutputHashBundle> {
         // Calculate the hash of the input set independently of the order.
         let mut hash_bundle = OutputHashBundle::default();
+        let paths = self
+            .outputs
+            .iter()
+            .filter_map(|output| match output {
+                Output::File(file_output) if file_output.present && file_output.symlink.is_none() => {
+                    Some(file_output.filename.to_path(root))
+                }
+                _ => None,
+            })
+            .collect::<Result<Vec<_>>>()?;
+        let hash_function = self.hash_function;
+        let mut hashes =
+            hash_files(&paths, self.concurrent_hash_max, |path| hash_function.file_hash(path))?.into_iter();
         for output in self.outputs {
             let hash = match output {
-                Output::File(ref file_output) => {
-                    if file_output.present {
-                        let path = file_output.filename.to_path(root)?;
-                        file_hash(&path)?
-                    } else {
-                        "".to_string()
-                    }
+                Output::File(ref file_output) => match file_output.symlink {
+                    Some(ref target) => hash_function.symlink_hash(target),
+                    // There is exactly one hash for each present file, in the same order.
+                    None if file_output.present => hashes.next().unwrap(),
+                    None => "".to_string(),
+                },
+                Output::ExitCode(code) => hash_function.string_hash(&code.to_string()),
+                Output::Tree(ref tree_output) => match tree_output.manifest {
+                    Some(ref manifest) => manifest.hash(hash_function),
+                    None => "".to_string(),
+                },
+                Output::Stdout | Output::Stderr | Output::Combined => {
+                    bail!("Captured {} has to be added with its contents", output);
                 }
-                Output::ExitCode(code) => string_hash(&code.to_string()),
-                Output::Stdout(ref buffer) => bytes_hash(buffer),
-                Output::Stderr(ref buffer) => bytes_hash(buffer),
             };
             hash_bundle.hash_details.push((output, hash));
         }
+        hash_bundle.hash_details.extend(self.captured);
         // Sort inputs hashes by the hash value.
         hash_bundle.hash_details.sort_by(|a, b| a.1.cmp(&b.1));
-        hash_bundle.hash = bundle_hash(hash_bundle.hash_details.iter().map(|(inp, hash)| {
-            (
-                match inp {
-                    Output::File(_) => "File",
-                    Output::ExitCode(_) => "ExitCode",
-                    Output::Stdout(_) => "StdOut",
-                    Output::Stderr(_) => "StdErr",
-                },
-                &hash[..],
-            )
-        }));
+        hash_bundle.hash_function = hash_function;
+        hash_bundle.hash = bundle_hash(
+            hash_function,
+            hash_bundle.hash_details.iter().map(|(inp, hash)| {
+                (
+                    match inp {
+                        Output::File(_) => "File",
+                        Output::ExitCode(_) => "ExitCode",
+                        Output::Stdout => "StdOut",
+                        Output::Stderr => "StdErr",
+                        Output::Combined => "Combined",
+                        Output::Tree(_) => "Tree",
+                    },
+                    &hash[..],
+                )
+            }),
+        );
         Ok(hash_bundle)
     }
 
     pub fn add_output(&mut self, output: Output) {
         self.outputs.push(output)
     }
+
+    /// Add a captured stream (`Output::Stdout`, `Output::Stderr` or `Output::Combined`) with its contents.
+    /// The contents are hashed right away, so `hash_function` has to be set before.
+    pub fn add_captured(&mut self, output: Output, data: &[u8]) {
+        self.captured.push((output, self.hash_function.bytes_hash(data)))
+    }
 }
 
 #[cfg(test)]
@@ -231,6 +635,8 @@ mod tests {
     use tempfile::NamedTempFile;
 
     const EMPTY_SHA256: &'static str = "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855";
+    // Sha256 of the cache key version tag alone, "Version" followed by CACHE_KEY_VERSION.
+    const EMPTY_INPUTS_HASH: &str = "7cbf3ddc614c5f8b49cdd6731df70fad030a6899f87e4af747b264344fe0fb9c";
 
     #[test]
     fn file_hash_test() -> Result<()> {
@@ -249,7 +655,7 @@ mod tests {
     #[test]
     fn test_input_set_empty() {
         let input_set = InputSet::default();
-        assert_eq!(input_set.hash(&None).unwrap(), EMPTY_SHA256);
+        assert_eq!(input_set.hash(&None).unwrap(), EMPTY_INPUTS_HASH);
     }
 
     #[test]
@@ -258,7 +664,7 @@ mod tests {
         let tool_tag = String::from("some tool_tag");
         input_set.add_input(Input::ToolTag(tool_tag));
         let hash1 = input_set.hash(&None).unwrap();
-        assert_ne!(hash1, EMPTY_SHA256);
+        assert_ne!(hash1, EMPTY_INPUTS_HASH);
     }
 
     #[test]
@@ -290,6 +696,95 @@ mod tests {
         assert_eq!(bundle1.hash_details, bundle2.hash_details);
     }
 
+    #[test]
+    fn test_input_set_command() {
+        let command = |args: &[&str], cwd: &str| Input::Command {
+            args: args.iter().map(|x| x.to_string()).collect(),
+            cwd: cwd.into(),
+        };
+        let hash = |input: Input| {
+            let mut input_set = InputSet::default();
+            input_set.add_input(Input::ToolTag("gcc".to_string()));
+            input_set.add_input(input);
+            input_set.hash(&None).unwrap()
+        };
+        let hash_o0 = hash(command(&["gcc", "-O0", "a.c"], "//src"));
+        assert_ne!(hash_o0, hash(command(&["gcc", "-O3", "a.c"], "//src")));
+        assert_ne!(hash_o0, hash(command(&["gcc", "-O0", "a.c"], "//lib")));
+        // Arguments are not simply concatenated.
+        assert_ne!(
+            hash(command(&["echo", "a b"], "//")),
+            hash(command(&["echo", "a", "b"], "//"))
+        );
+        assert_eq!(hash_o0, hash(command(&["gcc", "-O0", "a.c"], "//src")));
+    }
+
+    #[test]
+    fn test_input_set_env_var() {
+        let hash = |name: &str, value: Option<&str>| {
+            let mut input_set = InputSet::default();
+            input_set.add_input(Input::EnvVar(name.to_string(), value.map(String::from)));
+            input_set.hash_bundle(&None).unwrap()
+        };
+        let bundle = hash("CC", Some("gcc"));
+        assert_eq!(
+            bundle.hash_details[0].0,
+            Input::EnvVar("CC".to_string(), Some("gcc".to_string()))
+        );
+        assert_ne!(bundle.hash, hash("CC", Some("clang")).hash);
+        assert_ne!(bundle.hash, hash("CXX", Some("gcc")).hash);
+        // Unset and empty are different.
+        assert_ne!(hash("CC", None).hash, hash("CC", Some("")).hash);
+        assert_ne!(hash("CC", None).hash, EMPTY_INPUTS_HASH);
+        assert_eq!(bundle.hash, hash("CC", Some("gcc")).hash);
+    }
+
+    #[test]
+    fn test_inputs_diff() {
+        let bundle = |hash: &str, details: &[(Input, &str)]| InputHashBundle {
+            hash: hash.to_string(),
+            hash_details: details.iter().map(|(i, h)| (i.clone(), h.to_string())).collect(),
+            ..Default::default()
+        };
+        let previous = bundle(
+            "1111",
+            &[
+                (Input::ToolTag("gcc-9".to_string()), "aa"),
+                (Input::File("a.c".into()), "bb"),
+                (Input::File("b.c".into()), "cc"),
+            ],
+        );
+        let current = bundle(
+            "2222",
+            &[
+                (Input::ToolTag("gcc-10".to_string()), "dd"),
+                (Input::File("a.c".into()), "ee"),
+                (Input::File("c.c".into()), "ff"),
+            ],
+        );
+        let diff = current.diff(&previous);
+        assert_eq!(diff.previous_hash, "1111");
+        assert_eq!(diff.changed, vec![Input::File("a.c".into())]);
+        assert_eq!(
+            diff.added,
+            vec![Input::ToolTag("gcc-10".to_string()), Input::File("c.c".into())]
+        );
+        assert_eq!(
+            diff.removed,
+            vec![Input::ToolTag("gcc-9".to_string()), Input::File("b.c".into())]
+        );
+        assert_eq!(
+            diff.to_string(),
+            "inputs differ from the previous cache entry 1111:\n  \
+             changed file 'a.c'\n  \
+             added tool tag 'gcc-10'\n  \
+             added file 'c.c'\n  \
+             removed tool tag 'gcc-9'\n  \
+             removed file 'b.c'"
+        );
+        assert!(current.diff(&current).is_empty());
+    }
+
     #[test]
     fn test_input_set_file() {
         let mut file1 = NamedTempFile::new().unwrap();
@@ -303,12 +798,241 @@ mod tests {
         // These hashes were obtained by manual manipulation files and `openssl sha256`
         assert_eq!(
             input_set.clone().hash(&None).unwrap(),
-            "f409e4c7ae76997e69556daae6139bee1f02e4f618d3da8deea10bb35b6c0ebd"
+            "b4d890b80aeba8c39c28d8cd2d4646db8408256786c067f98f5c773fd8a23569"
         );
         input_set.add_input(Input::File(file2.path().into()));
         assert_eq!(
             input_set.hash(&None).unwrap(),
-            "a282f3da61a4bc322a8d31da6d30a0e924017962acbef2f6996b81709de8cdc3"
+            "ded46d378f9cb9ddc453e1d43defc6d4e49a1bcc20adf27633bffab3e08f34d7"
         );
     }
+
+    #[test]
+    fn test_input_set_paths() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = Some(dir.path().to_str().unwrap().to_string());
+        std::fs::write(dir.path().join("a.c"), "aaa").unwrap();
+        std::fs::write(dir.path().join("b.c"), "bbb").unwrap();
+        std::fs::write(dir.path().join("c.c"), "aaa").unwrap();
+        let hash = |files: &[&str], hash_paths: bool| {
+            let mut input_set = InputSet {
+                hash_paths,
+                ..Default::default()
+            };
+            for file in files {
+                input_set.add_input(Input::File(format!("//{}", file).into()));
+            }
+            input_set.hash(&root).unwrap()
+        };
+        // Without paths, a renamed file or swapped contents give the same hash.
+        assert_eq!(hash(&["a.c", "b.c"], false), hash(&["c.c", "b.c"], false));
+        // With paths, they don't.
+        let hash_ab = hash(&["a.c", "b.c"], true);
+        assert_ne!(hash_ab, hash(&["c.c", "b.c"], true));
+        std::fs::write(dir.path().join("a.c"), "bbb").unwrap();
+        std::fs::write(dir.path().join("b.c"), "aaa").unwrap();
+        assert_ne!(hash_ab, hash(&["a.c", "b.c"], true));
+        // The order of the inputs still doesn't matter, even for files with equal contents.
+        assert_eq!(hash(&["a.c", "b.c", "c.c"], true), hash(&["c.c", "b.c", "a.c"], true));
+        // Paths are normalized relative to the workspace root.
+        let absolute = dir.path().join("a.c");
+        let mut input_set = InputSet {
+            hash_paths: true,
+            ..Default::default()
+        };
+        input_set.add_input(Input::File(absolute.as_path().into()));
+        assert_eq!(input_set.hash(&root).unwrap(), hash(&["a.c"], true));
+        // Keys of both modes never collide.
+        assert_ne!(hash(&[], false), hash(&[], true));
+    }
+
+    #[test]
+    fn test_concurrent_hashing() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = Some(dir.path().to_str().unwrap().to_string());
+        for i in 0..50 {
+            std::fs::write(dir.path().join(format!("{}.c", i)), i.to_string().repeat(i)).unwrap();
+        }
+        let inputs = |concurrent_hash_max: usize, files: usize| {
+            let mut input_set = InputSet {
+                hash_paths: true,
+                concurrent_hash_max,
+                ..Default::default()
+            };
+            input_set.add_input(Input::ToolTag("tag".into()));
+            for i in 0..files {
+                input_set.add_input(Input::File(format!("//{}.c", i).into()));
+            }
+            input_set
+        };
+        let sequential = inputs(1, 50).hash_bundle(&root).unwrap();
+        let concurrent = inputs(8, 50).hash_bundle(&root).unwrap();
+        assert_eq!(sequential.hash, concurrent.hash);
+        assert_eq!(sequential.hash_details, concurrent.hash_details);
+        // More threads than files, and no files at all.
+        assert_eq!(inputs(1, 3).hash(&root).unwrap(), inputs(8, 3).hash(&root).unwrap());
+        assert_eq!(inputs(1, 0).hash(&root).unwrap(), inputs(8, 0).hash(&root).unwrap());
+        // A missing file fails the whole set.
+        let mut input_set = inputs(8, 50);
+        input_set.add_input(Input::File("//nonexistent.c".into()));
+        assert!(input_set.hash(&root).is_err());
+
+        let outputs = |concurrent_hash_max: usize| {
+            let mut output_set = OutputSet {
+                concurrent_hash_max,
+                ..Default::default()
+            };
+            for i in 0..50 {
+                output_set.add_output(Output::File(FileOutput {
+                    filename: format!("//{}.c", i).into(),
+                    present: i % 3 != 0,
+                    mode: 0o644,
+                    symlink: None,
+                }));
+            }
+            output_set.hash_bundle(&root).unwrap()
+        };
+        let sequential = outputs(1);
+        let concurrent = outputs(8);
+        assert_eq!(sequential.hash, concurrent.hash);
+        assert_eq!(sequential.hash_details, concurrent.hash_details);
+    }
+
+    #[test]
+    fn test_known_file_hashes() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = Some(dir.path().to_str().unwrap().to_string());
+        std::fs::write(dir.path().join("read.c"), "read").unwrap();
+        let mut input_set = InputSet::default();
+        input_set.add_input(Input::File("//read.c".into()));
+        // Files with a known hash aren't read at all.
+        input_set.add_input(Input::File("//known.c".into()));
+        input_set
+            .file_hashes
+            .insert(dir.path().join("known.c"), "git-blob:1234".into());
+        let bundle = input_set.hash_bundle(&root).unwrap();
+        assert_eq!(
+            bundle.hash_details,
+            vec![
+                (Input::File("//read.c".into()), string_hash("read")),
+                (Input::File("//known.c".into()), "git-blob:1234".into()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_symlink_outputs() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = Some(dir.path().to_str().unwrap().to_string());
+        std::fs::write(dir.path().join("libfoo.so.1"), "libfoo.so.1").unwrap();
+        let file = FileOutput {
+            filename: "//libfoo.so.1".into(),
+            present: true,
+            mode: 0o755,
+            symlink: None,
+        };
+        let mut output_set = OutputSet::default();
+        output_set.add_output(Output::File(file.clone()));
+        // Symlinks aren't read, even if dangling.
+        let link = FileOutput {
+            filename: "//libfoo.so".into(),
+            present: true,
+            mode: 0o777,
+            symlink: Some("libfoo.so.1".into()),
+        };
+        output_set.add_output(Output::File(link.clone()));
+        let bundle = output_set.hash_bundle(&root).unwrap();
+        let link_hash = HashFunction::Sha256.symlink_hash("libfoo.so.1");
+        // A symlink doesn't hash the same as a file with its target as the contents.
+        assert_ne!(link_hash, string_hash("libfoo.so.1"));
+        assert!(bundle.hash_details.contains(&(Output::File(link.clone()), link_hash)));
+        assert_eq!(
+            bundle.object_hashes().collect::<Vec<_>>(),
+            vec![string_hash("libfoo.so.1").as_str()]
+        );
+        assert_eq!(Output::File(link).to_string(), "symlink '//libfoo.so' -> 'libfoo.so.1'");
+        // Entries without symlinks are the same as before symlinks were recorded.
+        assert!(!serde_json::to_string(&file).unwrap().contains("symlink"));
+    }
+
+    #[test]
+    fn test_tree_outputs() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::create_dir_all(dir.path().join("out/empty")).unwrap();
+        std::fs::write(dir.path().join("out/a.txt"), "a").unwrap();
+        let manifest = crate::output_tree::read_tree(&dir.path().join("out"), HashFunction::Sha256, 1).unwrap();
+        let mut output_set = OutputSet::default();
+        output_set.add_output(Output::Tree(TreeOutput {
+            dirname: "//out".into(),
+            manifest: manifest.clone(),
+        }));
+        output_set.add_output(Output::Tree(TreeOutput {
+            dirname: "//absent".into(),
+            manifest: None,
+        }));
+        let bundle = output_set.hash_bundle(&None).unwrap();
+        let tree_hash = manifest.as_ref().unwrap().hash(HashFunction::Sha256);
+        assert!(bundle.hash_details.iter().any(|(_, hash)| *hash == tree_hash));
+        // The files of the tree are objects, the tree itself isn't.
+        assert_eq!(
+            bundle.object_hashes().collect::<Vec<_>>(),
+            vec![string_hash("a").as_str()]
+        );
+        let display: Vec<String> = bundle
+            .hash_details
+            .iter()
+            .map(|(output, _)| output.to_string())
+            .collect();
+        assert!(display.contains(&"tree '//out' (1 files)".to_string()));
+        assert!(display.contains(&"tree '//absent' (absent)".to_string()));
+    }
+
+    #[test]
+    fn test_hash_function() {
+        let mut file = NamedTempFile::new().unwrap();
+        file.write_all(b"contents").unwrap();
+        file.flush().unwrap();
+        assert_eq!(
+            HashFunction::Blake3.bytes_hash(b""),
+            "af1349b9f5f9a1a6a0404dea36dcc9499bcb25c9adc112b7cc9a93cae41f3262"
+        );
+        assert_eq!(HashFunction::Sha256.bytes_hash(b""), EMPTY_SHA256);
+        assert_eq!(
+            HashFunction::Blake3.file_hash(file.path()).unwrap(),
+            HashFunction::Blake3.bytes_hash(b"contents")
+        );
+
+        let inputs = |hash_function: HashFunction| {
+            let mut input_set = InputSet {
+                hash_function,
+                ..Default::default()
+            };
+            input_set.add_input(Input::File(file.path().into()));
+            input_set.add_input(Input::ToolTag("tag".into()));
+            input_set.hash_bundle(&None).unwrap()
+        };
+        let (sha256, blake3) = (inputs(HashFunction::Sha256), inputs(HashFunction::Blake3));
+        assert_ne!(sha256.hash, blake3.hash);
+        assert!(blake3.hash_details.contains(&(
+            Input::File(file.path().into()),
+            HashFunction::Blake3.bytes_hash(b"contents")
+        )));
+
+        let mut output_set = OutputSet {
+            hash_function: HashFunction::Blake3,
+            ..Default::default()
+        };
+        output_set.add_output(Output::ExitCode(0));
+        output_set.add_captured(Output::Stdout, b"out");
+        let outputs = output_set.hash_bundle(&None).unwrap();
+        assert_eq!(outputs.hash_function, HashFunction::Blake3);
+        assert!(outputs
+            .hash_details
+            .contains(&(Output::Stdout, HashFunction::Blake3.bytes_hash(b"out"))));
+        // Entries written before the hash function was recorded were hashed with SHA256.
+        let entry: OutputHashBundle = serde_json::from_str(r#"{"hash": "", "hash_details": []}"#).unwrap();
+        assert_eq!(entry.hash_function, HashFunction::Sha256);
+        let json = serde_json::to_string(&outputs).unwrap();
+        assert!(json.contains(r#""hash_function":"blake3""#));
+    }
 }
@@ -1,7 +1,17 @@
 pub mod caching;
 pub mod capsule;
+pub mod combined_log;
 pub mod config;
+pub mod depfile;
+pub mod git_files;
+pub mod hash_cache;
+pub mod input_filter;
+pub mod inspect;
 pub mod iohashing;
 pub mod observability;
+pub mod output_tree;
+pub mod platform;
+pub mod server;
+pub mod tool_fingerprint;
 pub mod workspace_path;
 pub mod wrapper;
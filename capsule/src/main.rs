@@ -1,9 +1,15 @@
-use anyhow::Result;
+use anyhow::{bail, Result};
 use capsule::caching::backend::CachingBackend;
 use capsule::caching::dummy;
+use capsule::caching::http;
+use capsule::caching::local;
+use capsule::caching::reapi;
 use capsule::caching::s3;
+use capsule::caching::s3_gc::GcPolicy;
+use capsule::caching::tiered;
 use capsule::capsule::Capsule;
-use capsule::config::{Backend, Config};
+use capsule::config::{Backend, Command, Config};
+use capsule::inspect;
 use capsule::observability::dummy::Dummy as DummyLogger;
 use capsule::observability::honeycomb;
 use capsule::observability::logger::Logger;
@@ -34,14 +40,42 @@ async fn main() -> Result<()> {
             env::args(),
             default_toml.as_ref().map(Path::new),
         )?;
-        // First, instantiate our caching backend (S3, Dummy, or possibly other in the future).
+        // Garbage collection and cache inspection don't wrap any program, so there's nothing to fall back to.
+        if config.command != Command::Run {
+            program_run_ref.store(true, Ordering::SeqCst);
+        }
+        if config.command == Command::Gc {
+            if !matches!(config.backend, Backend::S3) {
+                bail!("Garbage collection is only supported for the S3 backend");
+            }
+            let report = s3::S3Backend::from_config(&config)?
+                .gc(&GcPolicy::from_config(&config)?)
+                .await?;
+            println!("{}", report);
+            return Ok(0);
+        }
+        // First, instantiate our caching backend (S3, local, HTTP, REAPI, tiered, Dummy, or possibly other in the future).
         let backend: Box<dyn CachingBackend> = match config.backend {
             Backend::Dummy => Box::new(dummy::DummyBackend {
                 verbose_output: config.verbose,
                 capsule_id: config.capsule_id.as_ref().cloned().unwrap(),
             }),
             Backend::S3 => Box::new(s3::S3Backend::from_config(&config)?),
+            Backend::Local => Box::new(local::LocalBackend::from_config(&config)?),
+            Backend::Http => Box::new(http::HttpBackend::from_config(&config)?),
+            Backend::Reapi => Box::new(reapi::ReapiBackend::from_config(&config)?),
+            Backend::Tiered => Box::new(tiered::TieredBackend::from_config(&config)?),
         };
+        let inspection = match config.command {
+            Command::Ls => Some(inspect::list(backend.as_ref()).await?),
+            Command::Show(ref hash) => Some(inspect::show(backend.as_ref(), hash).await?),
+            Command::Diff(ref hash_a, ref hash_b) => Some(inspect::diff(backend.as_ref(), hash_a, hash_b).await?),
+            _ => None,
+        };
+        if let Some(inspection) = inspection {
+            print!("{}", inspection);
+            return Ok(0);
+        }
         // Instantiate our logger (for observability)
         let logger: Box<dyn Logger> = if config.honeycomb_dataset.is_some() {
             Box::new(honeycomb::Honeycomb::from_config(&config)?)
@@ -1,5 +1,5 @@
 use super::logger::Logger;
-use crate::iohashing::{InputHashBundle, OutputHashBundle};
+use crate::iohashing::{InputHashBundle, InputsDiff, OutputHashBundle};
 use anyhow::Result;
 use async_trait::async_trait;
 
@@ -13,6 +13,7 @@ impl Logger for Dummy {
         _output_bundle: &OutputHashBundle,
         _result_from_cache: bool,
         _non_determinism: bool,
+        _miss_explanation: Option<&InputsDiff>,
     ) -> Result<()> {
         Ok(())
     }
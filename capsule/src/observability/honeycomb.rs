@@ -1,6 +1,6 @@
 use crate::{
     config::Config,
-    iohashing::{Input, InputHashBundle, Output, OutputHashBundle},
+    iohashing::{Input, InputHashBundle, InputsDiff, Output, OutputHashBundle},
 };
 use anyhow::anyhow;
 use anyhow::Result;
@@ -63,9 +63,12 @@ const MAX_JSON_ENTRIES: usize = 200;
 fn hash_details_to_json(bundle: &InputHashBundle) -> serde_json::Value {
     let mut file_map = serde_json::Map::<String, serde_json::Value>::new();
     let mut tool_tag_map = serde_json::Map::<String, serde_json::Value>::new();
+    let mut env_map = serde_json::Map::<String, serde_json::Value>::new();
+    let mut command = None;
+    let mut stdin = None;
     for (input, hash) in bundle.hash_details.iter() {
         // Cap the size of the resulting JSON.
-        if file_map.len() + tool_tag_map.len() > MAX_JSON_ENTRIES {
+        if file_map.len() + tool_tag_map.len() + env_map.len() > MAX_JSON_ENTRIES {
             break;
         }
         let value = serde_json::Value::String(hash.to_string());
@@ -76,6 +79,18 @@ fn hash_details_to_json(bundle: &InputHashBundle) -> serde_json::Value {
             Input::ToolTag(tool_tag) => {
                 tool_tag_map.insert(tool_tag.to_string(), value);
             }
+            Input::Command { args, cwd } => {
+                command = Some((shell_words::join(args), cwd.to_string()));
+            }
+            Input::EnvVar(name, env_value) => {
+                env_map.insert(
+                    name.to_string(),
+                    env_value.clone().map_or(serde_json::Value::Null, Into::into),
+                );
+            }
+            Input::Stdin => {
+                stdin = Some(hash.to_string());
+            }
         }
     }
     let mut json_map = serde_json::Map::<String, serde_json::Value>::new();
@@ -85,6 +100,16 @@ fn hash_details_to_json(bundle: &InputHashBundle) -> serde_json::Value {
     if !tool_tag_map.is_empty() {
         json_map.insert("tool_tag".into(), serde_json::Value::Object(tool_tag_map));
     }
+    if !env_map.is_empty() {
+        json_map.insert("env".into(), serde_json::Value::Object(env_map));
+    }
+    if let Some(stdin) = stdin {
+        json_map.insert("stdin".into(), stdin.into());
+    }
+    if let Some((args, cwd)) = command {
+        json_map.insert("command".into(), args.into());
+        json_map.insert("cwd".into(), cwd.into());
+    }
     serde_json::Value::Object(json_map)
 }
 
@@ -118,6 +143,26 @@ fn output_hash_details_to_json(bundle: &OutputHashBundle) -> serde_json::Value {
     serde_json::Value::Object(json_map)
 }
 
+/// Convert the explanation of a cache miss to JSON.
+fn miss_explanation_to_json(diff: &InputsDiff) -> serde_json::Value {
+    // Cap the size of the resulting JSON.
+    let inputs = |inputs: &[Input]| {
+        serde_json::Value::Array(
+            inputs
+                .iter()
+                .take(MAX_JSON_ENTRIES)
+                .map(|input| input.to_string().into())
+                .collect(),
+        )
+    };
+    let mut json_map = serde_json::Map::<String, serde_json::Value>::new();
+    json_map.insert("previous_inputs_hash".into(), diff.previous_hash.clone().into());
+    json_map.insert("changed".into(), inputs(&diff.changed));
+    json_map.insert("added".into(), inputs(&diff.added));
+    json_map.insert("removed".into(), inputs(&diff.removed));
+    serde_json::Value::Object(json_map)
+}
+
 #[async_trait]
 impl Logger for Honeycomb {
     async fn log(
@@ -126,6 +171,7 @@ impl Logger for Honeycomb {
         output_bundle: &OutputHashBundle,
         result_from_cache: bool,
         non_determinism: bool,
+        miss_explanation: Option<&InputsDiff>,
     ) -> Result<()> {
         let mut map = serde_json::Map::new();
         map.insert("trace.trace_id".into(), self.trace_id.clone().into());
@@ -142,6 +188,9 @@ impl Logger for Honeycomb {
             output_hash_details_to_json(output_bundle),
         );
         map.insert("outputs_hash".into(), output_bundle.hash.clone().into());
+        if let Some(diff) = miss_explanation {
+            map.insert("miss_explanation".into(), miss_explanation_to_json(diff));
+        }
         for (key, value) in &self.extra_kv {
             map.insert(key.to_owned(), value.to_owned().into());
         }
@@ -1,4 +1,4 @@
-use crate::iohashing::{InputHashBundle, OutputHashBundle};
+use crate::iohashing::{InputHashBundle, InputsDiff, OutputHashBundle};
 use anyhow::Result;
 use async_trait::async_trait;
 
@@ -10,5 +10,6 @@ pub trait Logger {
         output_bundle: &OutputHashBundle,
         result_from_cache: bool,
         non_determinism: bool,
+        miss_explanation: Option<&InputsDiff>,
     ) -> Result<()>;
 }
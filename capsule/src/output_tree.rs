@@ -0,0 +1,358 @@
+//! Manifests of directory outputs, which are restored exactly as the command left them, with empty
+//! subdirectories, the modes of directories, and without stale files from earlier runs.
+use anyhow::{bail, Context, Result};
+use log::warn;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::ErrorKind;
+use std::os::unix::fs::{symlink, PermissionsExt};
+use std::path::{Path, PathBuf};
+
+use crate::iohashing::{hash_files, HashFunction};
+
+/// Contents of a directory, by the names of its entries.
+#[derive(PartialOrd, Ord, PartialEq, Eq, Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Directory {
+    pub mode: u32,
+    pub entries: BTreeMap<String, Entry>,
+}
+
+#[derive(PartialOrd, Ord, PartialEq, Eq, Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Entry {
+    /// Regular file, whose contents are an object in the content addressable storage.
+    File {
+        hash: String,
+        mode: u32,
+    },
+    Symlink {
+        target: String,
+    },
+    Directory(Directory),
+}
+
+impl Directory {
+    /// Returns the Merkle hash of the directory, covering the names, modes and hashes of all of its entries,
+    /// so that e.g. an added empty directory or a changed mode changes the hash of every directory above it.
+    pub fn hash(&self, hash_function: HashFunction) -> String {
+        let mut acc = format!("directory\0{:o}\0", self.mode);
+        for (name, entry) in &self.entries {
+            let (kind, hash) = match entry {
+                Entry::File { hash, mode } => (format!("file {:o}", mode), hash.clone()),
+                Entry::Symlink { target } => ("symlink".to_string(), hash_function.symlink_hash(target)),
+                Entry::Directory(directory) => ("directory".to_string(), directory.hash(hash_function)),
+            };
+            acc.push_str(&format!("{}\0{}\0{}\0", kind, hash, name));
+        }
+        hash_function.string_hash(&acc)
+    }
+
+    /// Returns the files in the tree, as paths relative to it, with their hashes and modes.
+    pub fn files(&self) -> Vec<(PathBuf, &str, u32)> {
+        let mut files = Vec::new();
+        for (name, entry) in &self.entries {
+            match entry {
+                Entry::File { hash, mode } => files.push((PathBuf::from(name), hash.as_str(), *mode)),
+                Entry::Directory(directory) => files.extend(
+                    directory
+                        .files()
+                        .into_iter()
+                        .map(|(path, hash, mode)| (Path::new(name).join(path), hash, mode)),
+                ),
+                Entry::Symlink { .. } => {}
+            }
+        }
+        files
+    }
+
+    /// Hashes of the files, in the same order as `files()`.
+    fn file_hashes_mut(&mut self) -> Vec<&mut String> {
+        let mut hashes = Vec::new();
+        for entry in self.entries.values_mut() {
+            match entry {
+                Entry::File { hash, .. } => hashes.push(hash),
+                Entry::Directory(directory) => hashes.extend(directory.file_hashes_mut()),
+                Entry::Symlink { .. } => {}
+            }
+        }
+        hashes
+    }
+}
+
+fn mode(metadata: &fs::Metadata) -> u32 {
+    metadata.permissions().mode() & 0o7777
+}
+
+fn entry_name(path: &Path) -> Result<String> {
+    path.file_name()
+        .and_then(|name| name.to_str())
+        .map(String::from)
+        .with_context(|| format!("Name of '{}' isn't valid UTF-8", path.to_string_lossy()))
+}
+
+/// Lists the directory recursively, leaving the hashes of the files empty.
+fn list_directory(path: &Path) -> Result<Directory> {
+    let metadata = fs::metadata(path).with_context(|| format!("Reading '{}'", path.to_string_lossy()))?;
+    let mut directory = Directory {
+        mode: mode(&metadata),
+        entries: BTreeMap::new(),
+    };
+    for dir_entry in fs::read_dir(path).with_context(|| format!("Listing '{}'", path.to_string_lossy()))? {
+        let entry_path = dir_entry?.path();
+        let metadata = fs::symlink_metadata(&entry_path)?;
+        let entry = if metadata.file_type().is_symlink() {
+            let target = fs::read_link(&entry_path)?;
+            let target = target
+                .to_str()
+                .with_context(|| format!("Target of symlink '{}' isn't valid UTF-8", entry_path.to_string_lossy()))?;
+            Entry::Symlink {
+                target: target.to_string(),
+            }
+        } else if metadata.is_dir() {
+            Entry::Directory(list_directory(&entry_path)?)
+        } else if metadata.is_file() {
+            Entry::File {
+                hash: String::new(),
+                mode: mode(&metadata),
+            }
+        } else {
+            warn!(
+                "Skipping '{}', which is neither a file, a symlink nor a directory",
+                entry_path.to_string_lossy()
+            );
+            continue;
+        };
+        directory.entries.insert(entry_name(&entry_path)?, entry);
+    }
+    Ok(directory)
+}
+
+/// Reads the manifest of the directory, hashing its files on up to `concurrent_hash_max` threads.
+/// Returns None if there is no directory at the path.
+pub fn read_tree(path: &Path, hash_function: HashFunction, concurrent_hash_max: usize) -> Result<Option<Directory>> {
+    if !path.is_dir() {
+        return Ok(None);
+    }
+    let mut directory = list_directory(path)?;
+    let paths: Vec<PathBuf> = directory
+        .files()
+        .into_iter()
+        .map(|(file, _, _)| path.join(file))
+        .collect();
+    let hashes = hash_files(&paths, concurrent_hash_max, |path| hash_function.file_hash(path))?;
+    for (hash, file_hash) in directory.file_hashes_mut().into_iter().zip(hashes) {
+        *hash = file_hash;
+    }
+    Ok(Some(directory))
+}
+
+/// Makes the directory and its subdirectories writable by the owner, as `remove_dir_all()` can't delete
+/// the entries of read-only ones, e.g. of a tree restored with its modes earlier.
+fn make_writable(path: &Path) -> std::io::Result<()> {
+    fs::set_permissions(path, fs::Permissions::from_mode(0o700))?;
+    for dir_entry in fs::read_dir(path)? {
+        let dir_entry = dir_entry?;
+        if dir_entry.file_type()?.is_dir() {
+            make_writable(&dir_entry.path())?;
+        }
+    }
+    Ok(())
+}
+
+fn remove(path: &Path, file_type: fs::FileType) -> Result<()> {
+    if file_type.is_dir() {
+        make_writable(path).and_then(|()| fs::remove_dir_all(path))
+    } else {
+        fs::remove_file(path)
+    }
+    .with_context(|| format!("Removing '{}'", path.to_string_lossy()))
+}
+
+/// Prepares the directory for downloading the files of the manifest into it: removes everything not in the
+/// manifest, and creates the subdirectories and symlinks. The modes of directories are set by `set_modes()`
+/// once the files are in place, as they might not be writable.
+pub fn prepare_tree(path: &Path, directory: &Directory) -> Result<()> {
+    // The manifest comes from the cache, so it mustn't point outside of the directory.
+    if let Some(name) = directory
+        .entries
+        .keys()
+        .find(|name| name.is_empty() || *name == "." || *name == ".." || name.contains('/'))
+    {
+        bail!(
+            "Invalid entry '{}' in the manifest of '{}'",
+            name,
+            path.to_string_lossy()
+        );
+    }
+    match fs::symlink_metadata(path) {
+        // Let the owner add files to the directory while it's restored.
+        Ok(metadata) if metadata.is_dir() => fs::set_permissions(path, fs::Permissions::from_mode(0o700))?,
+        Ok(metadata) => {
+            remove(path, metadata.file_type())?;
+            fs::create_dir(path)?;
+        }
+        Err(err) if err.kind() == ErrorKind::NotFound => fs::create_dir_all(path)?,
+        Err(err) => return Err(err).with_context(|| format!("Reading '{}'", path.to_string_lossy())),
+    }
+    for dir_entry in fs::read_dir(path)? {
+        let dir_entry = dir_entry?;
+        let file_type = dir_entry.file_type()?;
+        let expected = dir_entry
+            .file_name()
+            .to_str()
+            .and_then(|name| directory.entries.get(name));
+        // Symlinks are always recreated, and files are replaced by the downloaded ones.
+        let keep = match expected {
+            Some(Entry::File { .. }) => file_type.is_file(),
+            Some(Entry::Directory(_)) => file_type.is_dir(),
+            Some(Entry::Symlink { .. }) | None => false,
+        };
+        if !keep {
+            remove(&dir_entry.path(), file_type)?;
+        }
+    }
+    for (name, entry) in &directory.entries {
+        let entry_path = path.join(name);
+        match entry {
+            Entry::Directory(subdirectory) => prepare_tree(&entry_path, subdirectory)?,
+            Entry::Symlink { target } => symlink(target, &entry_path)?,
+            Entry::File { .. } => {}
+        }
+    }
+    Ok(())
+}
+
+/// Removes the directory, if there is one, as the command of the cache entry didn't create it.
+pub fn remove_tree(path: &Path) -> Result<()> {
+    match fs::symlink_metadata(path) {
+        Ok(metadata) if metadata.is_dir() => remove(path, metadata.file_type()),
+        Ok(_) => Ok(()),
+        Err(err) if err.kind() == ErrorKind::NotFound => Ok(()),
+        Err(err) => Err(err).with_context(|| format!("Reading '{}'", path.to_string_lossy())),
+    }
+}
+
+/// Sets the modes of the directory and its subdirectories, the deepest ones first.
+pub fn set_modes(path: &Path, directory: &Directory) -> Result<()> {
+    for (name, entry) in &directory.entries {
+        if let Entry::Directory(subdirectory) = entry {
+            set_modes(&path.join(name), subdirectory)?;
+        }
+    }
+    fs::set_permissions(path, fs::Permissions::from_mode(directory.mode))
+        .with_context(|| format!("Setting the mode of '{}'", path.to_string_lossy()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::iohashing::string_hash;
+
+    fn create_tree(root: &Path) {
+        fs::create_dir_all(root.join("sub/empty")).unwrap();
+        fs::write(root.join("a.txt"), "a").unwrap();
+        fs::write(root.join("sub/b.sh"), "b").unwrap();
+        fs::set_permissions(root.join("sub/b.sh"), fs::Permissions::from_mode(0o755)).unwrap();
+        fs::set_permissions(root.join("sub"), fs::Permissions::from_mode(0o750)).unwrap();
+        symlink("a.txt", root.join("link")).unwrap();
+    }
+
+    #[test]
+    fn test_read_tree() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().join("out");
+        assert_eq!(read_tree(&root, HashFunction::Sha256, 1).unwrap(), None);
+        create_tree(&root);
+        let tree = read_tree(&root, HashFunction::Sha256, 4).unwrap().unwrap();
+        assert_eq!(
+            tree.files(),
+            vec![
+                (PathBuf::from("a.txt"), string_hash("a").as_str(), 0o644),
+                (PathBuf::from("sub/b.sh"), string_hash("b").as_str(), 0o755),
+            ]
+        );
+        assert_eq!(tree.entries["link"], Entry::Symlink { target: "a.txt".into() });
+        match tree.entries["sub"] {
+            Entry::Directory(ref sub) => {
+                assert_eq!(sub.mode, 0o750);
+                assert!(matches!(sub.entries["empty"], Entry::Directory(ref empty) if empty.entries.is_empty()));
+            }
+            _ => panic!("Not a directory"),
+        }
+
+        // Empty directories and modes of directories are a part of the hash.
+        let hash = tree.hash(HashFunction::Sha256);
+        fs::remove_dir(root.join("sub/empty")).unwrap();
+        let without_empty = read_tree(&root, HashFunction::Sha256, 1).unwrap().unwrap();
+        assert_ne!(without_empty.hash(HashFunction::Sha256), hash);
+        fs::create_dir(root.join("sub/empty")).unwrap();
+        assert_eq!(
+            read_tree(&root, HashFunction::Sha256, 1)
+                .unwrap()
+                .unwrap()
+                .hash(HashFunction::Sha256),
+            hash
+        );
+        fs::set_permissions(root.join("sub"), fs::Permissions::from_mode(0o755)).unwrap();
+        assert_ne!(
+            read_tree(&root, HashFunction::Sha256, 1)
+                .unwrap()
+                .unwrap()
+                .hash(HashFunction::Sha256),
+            hash
+        );
+    }
+
+    #[test]
+    fn test_prepare_tree() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().join("out");
+        create_tree(&root);
+        let tree = read_tree(&root, HashFunction::Sha256, 1).unwrap().unwrap();
+
+        // Stale files and directories are removed, and the entries of the wrong kind replaced.
+        fs::write(root.join("stale.txt"), "stale").unwrap();
+        fs::create_dir_all(root.join("stale/read_only")).unwrap();
+        fs::write(root.join("stale/read_only/file"), "stale").unwrap();
+        fs::set_permissions(root.join("stale/read_only"), fs::Permissions::from_mode(0o500)).unwrap();
+        fs::remove_dir(root.join("sub/empty")).unwrap();
+        fs::write(root.join("sub/empty"), "not a directory").unwrap();
+        fs::remove_file(root.join("link")).unwrap();
+        symlink("stale.txt", root.join("link")).unwrap();
+        prepare_tree(&root, &tree).unwrap();
+        set_modes(&root, &tree).unwrap();
+        assert!(!root.join("stale.txt").exists());
+        assert!(!root.join("stale").exists());
+        assert!(root.join("sub/empty").is_dir());
+        assert_eq!(fs::read_link(root.join("link")).unwrap(), Path::new("a.txt"));
+        assert_eq!(read_tree(&root, HashFunction::Sha256, 1).unwrap().unwrap(), tree);
+
+        // A file in place of the directory is replaced too.
+        fs::remove_dir_all(&root).unwrap();
+        fs::write(&root, "not a directory").unwrap();
+        prepare_tree(&root, &tree).unwrap();
+        assert!(root.join("sub/empty").is_dir());
+
+        let mut invalid = tree;
+        invalid
+            .entries
+            .insert("..".into(), Entry::Directory(Directory::default()));
+        assert!(prepare_tree(&root, &invalid).is_err());
+    }
+
+    #[test]
+    fn test_remove_tree() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().join("out");
+        create_tree(&root);
+        fs::set_permissions(root.join("sub"), fs::Permissions::from_mode(0o500)).unwrap();
+        remove_tree(&root).unwrap();
+        assert!(!root.exists());
+        // Nothing to remove is fine, and files are left alone.
+        remove_tree(&root).unwrap();
+        fs::write(&root, "not a directory").unwrap();
+        remove_tree(&root).unwrap();
+        assert!(root.is_file());
+    }
+}
@@ -0,0 +1,105 @@
+//! Tool tags describing the host platform, so that artifacts built on one platform aren't restored
+//! onto an incompatible one, e.g. binaries linked against a newer glibc, or compiled for newer CPUs.
+use std::env;
+
+/// Environment variable with the digest of the container image capsule runs in, e.g. set by the CI runner.
+pub const CONTAINER_IMAGE_VAR: &str = "CAPSULE_CONTAINER_IMAGE";
+
+/// Returns the platform tool tags, e.g. `platform arch: x86_64`.
+pub fn platform_tags() -> Vec<String> {
+    let mut tags = vec![("arch", env::consts::ARCH.to_string())];
+    tags.push(("kernel", format!("{} {}", env::consts::OS, kernel_version())));
+    if let Some(libc) = libc_version() {
+        tags.push(("libc", libc));
+    }
+    if let Some(level) = cpu_level() {
+        tags.push(("cpu", level.to_string()));
+    }
+    if let Some(image) = env::var(CONTAINER_IMAGE_VAR).ok().filter(|image| !image.is_empty()) {
+        tags.push(("image", image));
+    }
+    tags.into_iter()
+        .map(|(name, value)| format!("platform {}: {}", name, value))
+        .collect()
+}
+
+/// The major and minor version of the kernel, which is what its ABI changes with, without the patch level
+/// and distribution suffixes which differ between otherwise identical runners.
+fn kernel_version() -> String {
+    let uname = nix::sys::utsname::uname();
+    uname.release().split(['.', '-']).take(2).collect::<Vec<_>>().join(".")
+}
+
+#[cfg(target_env = "gnu")]
+fn libc_version() -> Option<String> {
+    // Safe, as it returns a pointer to a static string.
+    let version = unsafe { std::ffi::CStr::from_ptr(nix::libc::gnu_get_libc_version()) };
+    Some(format!("glibc {}", version.to_string_lossy()))
+}
+
+#[cfg(not(target_env = "gnu"))]
+fn libc_version() -> Option<String> {
+    // Other libcs are linked statically.
+    None
+}
+
+/// The x86-64 microarchitecture level supported by the CPU, as defined in the x86-64 psABI.
+#[cfg(target_arch = "x86_64")]
+fn cpu_level() -> Option<&'static str> {
+    let v2 = is_x86_feature_detected!("cmpxchg16b")
+        && is_x86_feature_detected!("popcnt")
+        && is_x86_feature_detected!("sse3")
+        && is_x86_feature_detected!("sse4.1")
+        && is_x86_feature_detected!("sse4.2")
+        && is_x86_feature_detected!("ssse3");
+    let v3 = v2
+        && is_x86_feature_detected!("avx")
+        && is_x86_feature_detected!("avx2")
+        && is_x86_feature_detected!("bmi1")
+        && is_x86_feature_detected!("bmi2")
+        && is_x86_feature_detected!("f16c")
+        && is_x86_feature_detected!("fma")
+        && is_x86_feature_detected!("lzcnt")
+        && is_x86_feature_detected!("movbe")
+        && is_x86_feature_detected!("xsave");
+    let v4 = v3
+        && is_x86_feature_detected!("avx512f")
+        && is_x86_feature_detected!("avx512bw")
+        && is_x86_feature_detected!("avx512cd")
+        && is_x86_feature_detected!("avx512dq")
+        && is_x86_feature_detected!("avx512vl");
+    Some(match (v2, v3, v4) {
+        (_, _, true) => "x86-64-v4",
+        (_, true, _) => "x86-64-v3",
+        (true, _, _) => "x86-64-v2",
+        _ => "x86-64",
+    })
+}
+
+#[cfg(not(target_arch = "x86_64"))]
+fn cpu_level() -> Option<&'static str> {
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serial_test::serial;
+
+    #[test]
+    #[serial]
+    fn test_platform_tags() {
+        env::remove_var(CONTAINER_IMAGE_VAR);
+        let tags = platform_tags();
+        assert_eq!(tags[0], format!("platform arch: {}", env::consts::ARCH));
+        assert!(tags[1].starts_with("platform kernel: linux "));
+        // Only the major and minor version.
+        assert_eq!(tags[1].matches('.').count(), 1);
+        assert!(!tags.iter().any(|tag| tag.starts_with("platform image:")));
+
+        env::set_var(CONTAINER_IMAGE_VAR, "sha256:1234");
+        let tags = platform_tags();
+        env::remove_var(CONTAINER_IMAGE_VAR);
+        assert_eq!(tags.last().unwrap(), "platform image: sha256:1234");
+    }
+}
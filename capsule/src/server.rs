@@ -0,0 +1,737 @@
+use anyhow::{Context, Result};
+use hyper::body::HttpBody;
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{header, Body, Method, Request, Response, Server, StatusCode};
+use log::{error, info, warn};
+use serde::Serialize;
+use std::collections::{BTreeMap, HashMap};
+use std::convert::Infallible;
+use std::fs;
+use std::io;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use tempfile::NamedTempFile;
+use tokio::io::AsyncWriteExt;
+use tokio::task;
+use tokio_util::io::ReaderStream;
+
+use crate::iohashing::{string_hash, HashFunction, InputOutputBundle};
+
+/// Subdirectory of a namespace with the action entries.
+const ENTRIES_DIR: &str = "ac";
+
+/// Subdirectory of a namespace with the content addressed objects.
+const OBJECTS_DIR: &str = "cas";
+
+/// Namespace of the requests that don't specify any.
+const DEFAULT_NAMESPACE: &str = "default";
+
+/// Default maximum size of a cache entry. Entries are received into memory to be validated, so larger ones
+/// are rejected before they are received.
+pub const DEFAULT_MAX_ENTRY_SIZE: u64 = 64 * 1024 * 1024;
+
+/// Usage and traffic statistics of a single namespace.
+#[derive(Debug, Default, Clone, Serialize)]
+pub struct NamespaceStats {
+    pub bytes: u64,
+    pub quota: Option<u64>,
+    pub entries: u64,
+    pub objects: u64,
+    pub hits: u64,
+    pub misses: u64,
+    pub uploads: u64,
+    pub rejected: u64,
+}
+
+/// A shared cache server, storing data on local disk, and speaking the same protocol as the `http` backend:
+/// entries are stored under `/<namespace>/ac/<key>`, and objects under `/<namespace>/cas/<hash>`.
+/// The namespace can be omitted from the path, in which case it's `default`. A `DELETE` of `/<namespace>`
+/// removes everything stored in the namespace.
+pub struct CacheServer {
+    /// Root directory of the stored data.
+    pub root: PathBuf,
+
+    /// Accepted bearer tokens. If empty, all requests are accepted.
+    pub tokens: Vec<String>,
+
+    /// Quota in bytes for namespaces not listed in `quotas`.
+    pub default_quota: Option<u64>,
+
+    /// Quotas in bytes of individual namespaces.
+    pub quotas: HashMap<String, u64>,
+
+    /// Maximum size of a cache entry in bytes.
+    pub max_entry_size: u64,
+
+    stats: Mutex<BTreeMap<String, NamespaceStats>>,
+}
+
+/// What a request path refers to.
+#[derive(Debug, PartialEq)]
+enum Resource<'a> {
+    Stats,
+    Namespace { namespace: &'a str },
+    Entry { namespace: &'a str, key: &'a str },
+    Object { namespace: &'a str, hash: &'a str },
+}
+
+fn is_hex_hash(s: &str) -> bool {
+    s.len() == 64 && s.bytes().all(|c| matches!(c, b'0'..=b'9' | b'a'..=b'f'))
+}
+
+fn is_namespace(s: &str) -> bool {
+    !s.is_empty()
+        && s != ENTRIES_DIR
+        && s != OBJECTS_DIR
+        && s.bytes().all(|c| c.is_ascii_alphanumeric() || c == b'-' || c == b'_')
+}
+
+// Only well formed hashes and namespaces are accepted, so paths can't escape the root directory.
+fn parse_path(path: &str) -> Option<Resource<'_>> {
+    let segments: Vec<&str> = path.trim_start_matches('/').split('/').collect();
+    let (namespace, kind, key) = match segments[..] {
+        ["stats"] => return Some(Resource::Stats),
+        [namespace] if is_namespace(namespace) => return Some(Resource::Namespace { namespace }),
+        [kind, key] => (DEFAULT_NAMESPACE, kind, key),
+        [namespace, kind, key] if is_namespace(namespace) => (namespace, kind, key),
+        _ => return None,
+    };
+    if !is_hex_hash(key) {
+        return None;
+    }
+    match kind {
+        ENTRIES_DIR => Some(Resource::Entry { namespace, key }),
+        OBJECTS_DIR => Some(Resource::Object { namespace, hash: key }),
+        _ => None,
+    }
+}
+
+fn status(code: StatusCode) -> Response<Body> {
+    Response::builder().status(code).body(Body::empty()).unwrap()
+}
+
+fn content_length(req: &Request<Body>) -> Option<u64> {
+    req.headers()
+        .get(header::CONTENT_LENGTH)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse::<u64>().ok())
+}
+
+// Receive the body into memory, or return None as soon as it turns out to be larger than the limit.
+async fn read_limited(mut body: Body, limit: u64) -> Result<Option<Vec<u8>>> {
+    let mut data = Vec::new();
+    while let Some(chunk) = body.data().await {
+        let chunk = chunk?;
+        if (data.len() + chunk.len()) as u64 > limit {
+            return Ok(None);
+        }
+        data.extend_from_slice(&chunk);
+    }
+    Ok(Some(data))
+}
+
+// Compare the hashes of tokens, so that the time taken doesn't tell how much of a token was guessed right.
+fn token_matches(token: &str, accepted: &str) -> bool {
+    let (token, accepted) = (string_hash(token), string_hash(accepted));
+    token
+        .bytes()
+        .zip(accepted.bytes())
+        .fold(0, |difference, (a, b)| difference | (a ^ b))
+        == 0
+}
+
+// Recursively add up sizes and number of regular files below the given directory.
+fn scan_usage(dir: &Path) -> Result<(u64, u64)> {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok((0, 0)),
+        Err(err) => return Err(err.into()),
+    };
+    let (mut bytes, mut count) = (0, 0);
+    for entry in entries {
+        let entry = entry?;
+        let file_type = entry.file_type()?;
+        if file_type.is_dir() {
+            let (dir_bytes, dir_count) = scan_usage(&entry.path())?;
+            bytes += dir_bytes;
+            count += dir_count;
+        } else if file_type.is_file() {
+            bytes += entry.metadata()?.len();
+            count += 1;
+        }
+    }
+    Ok((bytes, count))
+}
+
+impl CacheServer {
+    /// Create the server, accounting for the data already stored under the root directory.
+    pub fn new(
+        root: PathBuf,
+        tokens: Vec<String>,
+        default_quota: Option<u64>,
+        quotas: HashMap<String, u64>,
+    ) -> Result<Self> {
+        fs::create_dir_all(&root).with_context(|| format!("Creating cache directory '{}'", root.display()))?;
+        let mut stats = BTreeMap::new();
+        for entry in fs::read_dir(&root)? {
+            let entry = entry?;
+            let namespace = entry.file_name().to_string_lossy().to_string();
+            if !entry.file_type()?.is_dir() || !is_namespace(&namespace) {
+                continue;
+            }
+            let (entries_bytes, entries) = scan_usage(&entry.path().join(ENTRIES_DIR))?;
+            let (objects_bytes, objects) = scan_usage(&entry.path().join(OBJECTS_DIR))?;
+            info!(
+                "Namespace '{}': {} entries, {} objects, {} bytes",
+                namespace,
+                entries,
+                objects,
+                entries_bytes + objects_bytes
+            );
+            stats.insert(
+                namespace,
+                NamespaceStats {
+                    bytes: entries_bytes + objects_bytes,
+                    entries,
+                    objects,
+                    ..Default::default()
+                },
+            );
+        }
+        Ok(Self {
+            root,
+            tokens,
+            default_quota,
+            quotas,
+            max_entry_size: DEFAULT_MAX_ENTRY_SIZE,
+            stats: Mutex::new(stats),
+        })
+    }
+
+    /// Return a snapshot of statistics of all namespaces.
+    pub fn stats(&self) -> BTreeMap<String, NamespaceStats> {
+        let mut stats = self.stats.lock().unwrap().clone();
+        for (namespace, namespace_stats) in stats.iter_mut() {
+            namespace_stats.quota = self.quota(namespace);
+        }
+        stats
+    }
+
+    fn quota(&self, namespace: &str) -> Option<u64> {
+        self.quotas.get(namespace).copied().or(self.default_quota)
+    }
+
+    fn path(&self, namespace: &str, dir: &str, key: &str) -> PathBuf {
+        self.root.join(namespace).join(dir).join(&key[..2]).join(key)
+    }
+
+    fn authorized(&self, req: &Request<Body>) -> bool {
+        if self.tokens.is_empty() {
+            return true;
+        }
+        let value = match req.headers().get(header::AUTHORIZATION).and_then(|v| v.to_str().ok()) {
+            Some(value) => value,
+            None => return false,
+        };
+        // Basic authentication is accepted with the token as the password, for clients which can't send bearer tokens.
+        let token = if let Some(token) = value.strip_prefix("Bearer ") {
+            token.to_string()
+        } else if let Some(credentials) = value.strip_prefix("Basic ") {
+            let decoded = base64::decode(credentials).unwrap_or_default();
+            let decoded = String::from_utf8_lossy(&decoded);
+            decoded
+                .split_once(':')
+                .map(|(_, password)| password.to_string())
+                .unwrap_or_default()
+        } else {
+            return false;
+        };
+        // Every token is compared, so that the time taken doesn't tell which one matched.
+        self.tokens
+            .iter()
+            .fold(false, |matched, accepted| token_matches(&token, accepted) | matched)
+    }
+
+    fn update_stats<F: FnOnce(&mut NamespaceStats)>(&self, namespace: &str, f: F) {
+        f(self.stats.lock().unwrap().entry(namespace.to_string()).or_default())
+    }
+
+    /// Check that `size` bytes replacing `replaced` bytes fit into the namespace quota, and account for them if so.
+    fn reserve(&self, namespace: &str, size: u64, replaced: u64) -> bool {
+        let quota = self.quota(namespace);
+        let mut stats = self.stats.lock().unwrap();
+        let namespace_stats = stats.entry(namespace.to_string()).or_default();
+        let bytes = namespace_stats.bytes.saturating_sub(replaced) + size;
+        if size > replaced && matches!(quota, Some(quota) if bytes > quota) {
+            namespace_stats.rejected += 1;
+            return false;
+        }
+        namespace_stats.bytes = bytes;
+        true
+    }
+
+    /// Receive the request body into a temporary file next to its destination, returning the file,
+    /// the hashes of the contents with every supported hash function, and the size.
+    async fn receive(&self, dest: &Path, mut body: Body) -> Result<(NamedTempFile, Vec<String>, u64)> {
+        let dir = dest.parent().context("No parent directory")?.to_path_buf();
+        tokio::fs::create_dir_all(&dir).await?;
+        let (file, writer) = task::spawn_blocking(move || -> io::Result<_> {
+            let file = NamedTempFile::new_in(dir)?;
+            let writer = file.reopen()?;
+            Ok((file, writer))
+        })
+        .await??;
+        let mut writer = tokio::fs::File::from_std(writer);
+        // Clients may use any of the hash functions, and the server doesn't know which one.
+        let mut hashers: Vec<_> = HashFunction::ALL.iter().map(|f| f.hasher()).collect();
+        let mut size = 0;
+        while let Some(chunk) = body.data().await {
+            let chunk = chunk?;
+            for hasher in &mut hashers {
+                hasher.update(&chunk);
+            }
+            writer.write_all(&chunk).await?;
+            size += chunk.len() as u64;
+        }
+        writer.flush().await?;
+        let hashes = hashers.into_iter().map(|hasher| hasher.finalize()).collect();
+        Ok((file, hashes, size))
+    }
+
+    async fn get(&self, path: &Path, head: bool) -> Result<Response<Body>> {
+        let file = match tokio::fs::File::open(path).await {
+            Ok(file) => file,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(status(StatusCode::NOT_FOUND)),
+            Err(err) => return Err(err.into()),
+        };
+        let content_length = file.metadata().await?.len();
+        let body = if head {
+            Body::empty()
+        } else {
+            Body::wrap_stream(ReaderStream::new(file))
+        };
+        Ok(Response::builder()
+            .header(header::CONTENT_LENGTH, content_length)
+            .body(body)?)
+    }
+
+    async fn put_entry(&self, namespace: &str, key: &str, req: Request<Body>) -> Result<Response<Body>> {
+        let too_large = || {
+            self.update_stats(namespace, |stats| stats.rejected += 1);
+            Ok(status(StatusCode::PAYLOAD_TOO_LARGE))
+        };
+        if matches!(content_length(&req), Some(length) if length > self.max_entry_size) {
+            return too_large();
+        }
+        let data = match read_limited(req.into_body(), self.max_entry_size).await? {
+            Some(data) => data,
+            None => return too_large(),
+        };
+        if serde_json::from_slice::<InputOutputBundle>(&data).is_err() {
+            return Ok(status(StatusCode::BAD_REQUEST));
+        }
+        let path = self.path(namespace, ENTRIES_DIR, key);
+        let old_size = tokio::fs::metadata(&path).await.map(|m| m.len()).ok();
+        if !self.reserve(namespace, data.len() as u64, old_size.unwrap_or_default()) {
+            return Ok(status(StatusCode::INSUFFICIENT_STORAGE));
+        }
+        let dir = path.parent().context("No parent directory")?.to_path_buf();
+        tokio::fs::create_dir_all(&dir).await?;
+        task::spawn_blocking(move || -> Result<()> {
+            let mut file = NamedTempFile::new_in(dir)?;
+            io::Write::write_all(&mut file, &data)?;
+            file.persist(&path)?;
+            Ok(())
+        })
+        .await??;
+        self.update_stats(namespace, |stats| {
+            if old_size.is_none() {
+                stats.entries += 1;
+            }
+            stats.uploads += 1;
+        });
+        Ok(status(StatusCode::OK))
+    }
+
+    async fn put_object(&self, namespace: &str, hash: &str, req: Request<Body>) -> Result<Response<Body>> {
+        let path = self.path(namespace, OBJECTS_DIR, hash);
+        // Objects are immutable, so a duplicate upload can be dropped.
+        if tokio::fs::metadata(&path).await.is_ok() {
+            return Ok(status(StatusCode::OK));
+        }
+        // Reject what doesn't fit into the quota before receiving it, if the size is known in advance.
+        if let (Some(content_length), Some(quota)) = (content_length(&req), self.quota(namespace)) {
+            let used = self.stats.lock().unwrap().get(namespace).map_or(0, |stats| stats.bytes);
+            if used + content_length > quota {
+                self.update_stats(namespace, |stats| stats.rejected += 1);
+                return Ok(status(StatusCode::INSUFFICIENT_STORAGE));
+            }
+        }
+        let (file, received_hashes, size) = self.receive(&path, req.into_body()).await?;
+        if !received_hashes.iter().any(|received_hash| received_hash == hash) {
+            warn!(
+                "Rejecting object '{}' in '{}' with hashes '{}'",
+                hash,
+                namespace,
+                received_hashes.join("', '")
+            );
+            return Ok(status(StatusCode::BAD_REQUEST));
+        }
+        if !self.reserve(namespace, size, 0) {
+            return Ok(status(StatusCode::INSUFFICIENT_STORAGE));
+        }
+        // Concurrent uploads of the same object race to create it, and only the one which does keeps its share
+        // of the quota.
+        let created = task::spawn_blocking(move || match file.persist_noclobber(path) {
+            Ok(_) => Ok(true),
+            Err(err) if err.error.kind() == io::ErrorKind::AlreadyExists => Ok(false),
+            Err(err) => Err(err.error),
+        })
+        .await??;
+        if !created {
+            self.reserve(namespace, 0, size);
+            return Ok(status(StatusCode::OK));
+        }
+        self.update_stats(namespace, |stats| {
+            stats.objects += 1;
+            stats.uploads += 1;
+        });
+        Ok(status(StatusCode::OK))
+    }
+
+    /// Remove everything stored in the namespace, and reset its usage.
+    async fn delete_namespace(&self, namespace: &str) -> Result<Response<Body>> {
+        match tokio::fs::remove_dir_all(self.root.join(namespace)).await {
+            Ok(()) => {}
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {}
+            Err(err) => return Err(err.into()),
+        }
+        info!("Namespace '{}' deleted", namespace);
+        self.update_stats(namespace, |stats| {
+            stats.bytes = 0;
+            stats.entries = 0;
+            stats.objects = 0;
+        });
+        Ok(status(StatusCode::OK))
+    }
+
+    async fn route(&self, req: Request<Body>) -> Result<Response<Body>> {
+        if !self.authorized(&req) {
+            return Ok(status(StatusCode::UNAUTHORIZED));
+        }
+        let resource = match parse_path(req.uri().path()) {
+            Some(resource) => resource,
+            None => return Ok(status(StatusCode::NOT_FOUND)),
+        };
+        let method = req.method().clone();
+        match (resource, method) {
+            (Resource::Stats, Method::GET) => Ok(Response::builder()
+                .header(header::CONTENT_TYPE, "application/json")
+                .body(Body::from(serde_json::to_vec(&self.stats())?))?),
+            (Resource::Namespace { namespace }, Method::DELETE) => {
+                let namespace = namespace.to_string();
+                self.delete_namespace(&namespace).await
+            }
+            (Resource::Entry { namespace, key }, method @ (Method::GET | Method::HEAD)) => {
+                let response = self
+                    .get(&self.path(namespace, ENTRIES_DIR, key), method == Method::HEAD)
+                    .await?;
+                let hit = response.status().is_success();
+                self.update_stats(namespace, |stats| if hit { stats.hits += 1 } else { stats.misses += 1 });
+                Ok(response)
+            }
+            (Resource::Object { namespace, hash }, method @ (Method::GET | Method::HEAD)) => {
+                self.get(&self.path(namespace, OBJECTS_DIR, hash), method == Method::HEAD)
+                    .await
+            }
+            (Resource::Entry { namespace, key }, Method::PUT) => {
+                let (namespace, key) = (namespace.to_string(), key.to_string());
+                self.put_entry(&namespace, &key, req).await
+            }
+            (Resource::Object { namespace, hash }, Method::PUT) => {
+                let (namespace, hash) = (namespace.to_string(), hash.to_string());
+                self.put_object(&namespace, &hash, req).await
+            }
+            _ => Ok(status(StatusCode::METHOD_NOT_ALLOWED)),
+        }
+    }
+
+    /// Handle a single request. Errors are logged, and reported to the client as internal server errors.
+    pub async fn handle(self: Arc<Self>, req: Request<Body>) -> Result<Response<Body>, Infallible> {
+        let description = format!("{} {}", req.method(), req.uri().path());
+        Ok(self.route(req).await.unwrap_or_else(|err| {
+            error!("{}: {:#}", description, err);
+            status(StatusCode::INTERNAL_SERVER_ERROR)
+        }))
+    }
+
+    /// Serve requests on the given address until the process is terminated.
+    pub async fn serve(self: Arc<Self>, addr: SocketAddr) -> Result<()> {
+        let server = Server::try_bind(&addr)?.serve(make_service_fn(move |_| {
+            let server = self.clone();
+            async move { Ok::<_, Infallible>(service_fn(move |req| server.clone().handle(req))) }
+        }));
+        info!("Serving the capsule cache on {}", server.local_addr());
+        server.await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::caching::backend::CachingBackend;
+    use crate::caching::http::{HttpAuth, HttpBackend};
+    use crate::iohashing::{string_hash, FileOutput, InputHashBundle, Output, OutputHashBundle};
+    use tokio::io::AsyncReadExt;
+
+    fn start_server(dir: &Path, default_quota: Option<u64>) -> (SocketAddr, Arc<CacheServer>) {
+        serve(
+            CacheServer::new(
+                dir.to_path_buf(),
+                vec!["secret".to_string()],
+                default_quota,
+                HashMap::new(),
+            )
+            .unwrap(),
+        )
+    }
+
+    fn serve(server: CacheServer) -> (SocketAddr, Arc<CacheServer>) {
+        let server = Arc::new(server);
+        let handler = server.clone();
+        let make_service = make_service_fn(move |_| {
+            let handler = handler.clone();
+            async move { Ok::<_, Infallible>(service_fn(move |req| handler.clone().handle(req))) }
+        });
+        let http_server = Server::bind(&([127, 0, 0, 1], 0).into()).serve(make_service);
+        let addr = http_server.local_addr();
+        tokio::spawn(http_server);
+        (addr, server)
+    }
+
+    fn backend(addr: SocketAddr, namespace: &str, token: &str) -> HttpBackend {
+        HttpBackend {
+            url: format!("http://{}/{}", addr, namespace),
+            auth: Some(HttpAuth::Bearer(token.to_string())),
+            client: reqwest::Client::new(),
+            capsule_id: "wtf".to_string(),
+        }
+    }
+
+    fn inputs() -> InputHashBundle {
+        InputHashBundle {
+            hash: "1234".to_string(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_parse_path() {
+        let hash = string_hash("");
+        assert_eq!(parse_path("/stats"), Some(Resource::Stats));
+        assert_eq!(
+            parse_path(&format!("/ac/{}", hash)),
+            Some(Resource::Entry {
+                namespace: DEFAULT_NAMESPACE,
+                key: &hash
+            })
+        );
+        assert_eq!(
+            parse_path(&format!("/team/cas/{}", hash)),
+            Some(Resource::Object {
+                namespace: "team",
+                hash: &hash
+            })
+        );
+        assert_eq!(parse_path("/team"), Some(Resource::Namespace { namespace: "team" }));
+        assert_eq!(parse_path("/ac/../../etc/passwd"), None);
+        assert_eq!(parse_path(&format!("/../cas/{}", hash)), None);
+        assert_eq!(parse_path("/cas/1234"), None);
+    }
+
+    #[tokio::test]
+    async fn test_server_roundtrip() {
+        let dir = tempfile::tempdir().unwrap();
+        let (addr, server) = start_server(dir.path(), None);
+        let backend = backend(addr, "team", "secret");
+        assert!(backend.lookup(&inputs()).await.unwrap().is_none());
+
+        let contents = b"file contents".to_vec();
+        let object_hash = string_hash("file contents");
+        backend
+            .upload_object_file(
+                "out".to_string(),
+                &object_hash,
+                Box::pin(std::io::Cursor::new(contents.clone())),
+                contents.len() as u64,
+            )
+            .await
+            .unwrap();
+        let outputs = OutputHashBundle {
+            hash: "5678".to_string(),
+            hash_details: vec![(
+                Output::File(FileOutput {
+                    filename: "out".into(),
+                    present: true,
+                    mode: 0o644,
+                    symlink: None,
+                }),
+                object_hash.clone(),
+            )],
+            ..Default::default()
+        };
+        backend.write(&inputs(), &outputs, "job".to_string()).await.unwrap();
+        let bundle = backend.lookup(&inputs()).await.unwrap().unwrap();
+        assert_eq!(bundle.source, "job");
+        let mut downloaded = Vec::new();
+        backend
+            .download_object_file(&object_hash)
+            .await
+            .unwrap()
+            .read_to_end(&mut downloaded)
+            .await
+            .unwrap();
+        assert_eq!(downloaded, contents);
+
+        let stats = server.stats();
+        let team = &stats["team"];
+        // The client stores the latest entry of the capsule as a separate entry.
+        assert_eq!((team.entries, team.objects), (2, 1));
+        assert_eq!((team.hits, team.misses), (1, 1));
+
+        // Concurrent uploads of the same object only account for it once.
+        let other = b"other contents".to_vec();
+        let other_hash = string_hash("other contents");
+        let upload = || {
+            backend.upload_object_file(
+                "other".to_string(),
+                &other_hash,
+                Box::pin(std::io::Cursor::new(other.clone())),
+                other.len() as u64,
+            )
+        };
+        let (first, second) = tokio::join!(upload(), upload());
+        first.unwrap();
+        second.unwrap();
+        let stats = server.stats();
+        assert_eq!(stats["team"].objects, 2);
+        assert_eq!(stats["team"].bytes, team.bytes + other.len() as u64);
+        let team = &stats["team"];
+
+        // Overwriting an entry only accounts for the difference in size.
+        backend.write(&inputs(), &outputs, "job".to_string()).await.unwrap();
+        assert_eq!(server.stats()["team"].bytes, team.bytes);
+
+        // Usage is recovered after a restart.
+        let restarted = CacheServer::new(dir.path().to_path_buf(), vec![], None, HashMap::new()).unwrap();
+        assert_eq!(restarted.stats()["team"].bytes, team.bytes);
+
+        // Deleting the namespace removes its data and resets its usage.
+        let response = reqwest::Client::new()
+            .delete(format!("http://{}/team", addr))
+            .bearer_auth("secret")
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let stats = server.stats();
+        assert_eq!(
+            (stats["team"].bytes, stats["team"].entries, stats["team"].objects),
+            (0, 0, 0)
+        );
+        assert!(backend.lookup(&inputs()).await.unwrap().is_none());
+        assert!(!dir.path().join("team").exists());
+    }
+
+    #[tokio::test]
+    async fn test_server_rejects() {
+        let dir = tempfile::tempdir().unwrap();
+        let (addr, server) = start_server(dir.path(), Some(20));
+        let client = reqwest::Client::new();
+        let url = |path: &str| format!("http://{}/{}", addr, path);
+
+        // Wrong token.
+        assert!(backend(addr, "team", "wrong").lookup(&inputs()).await.is_err());
+
+        // Hash mismatch.
+        let response = client
+            .put(url(&format!("cas/{}", string_hash("something else"))))
+            .bearer_auth("secret")
+            .body("contents")
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+        // Objects hashed with any of the hash functions are accepted.
+        let blake3 = HashFunction::Blake3.string_hash("contents");
+        let response = client
+            .put(url(&format!("other/cas/{}", blake3)))
+            .bearer_auth("secret")
+            .body("contents")
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+
+        // Not a cache entry.
+        let response = client
+            .put(url(&format!("ac/{}", string_hash("key"))))
+            .bearer_auth("secret")
+            .body("not json")
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+        // Over the quota.
+        let contents = "more than twenty bytes of contents";
+        let response = client
+            .put(url(&format!("cas/{}", string_hash(contents))))
+            .bearer_auth("secret")
+            .body(contents)
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::INSUFFICIENT_STORAGE);
+        let stats = server.stats();
+        assert_eq!(stats[DEFAULT_NAMESPACE].rejected, 1);
+        assert_eq!(stats[DEFAULT_NAMESPACE].bytes, 0);
+    }
+
+    #[tokio::test]
+    async fn test_server_entry_size() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut server = CacheServer::new(dir.path().to_path_buf(), vec![], None, HashMap::new()).unwrap();
+        server.max_entry_size = 1000;
+        let (addr, server) = serve(server);
+        let put = |body: Vec<u8>| {
+            reqwest::Client::new()
+                .put(format!("http://{}/ac/{}", addr, string_hash("key")))
+                .body(body)
+                .send()
+        };
+        let entry = serde_json::to_vec(&InputOutputBundle {
+            inputs: inputs(),
+            outputs: OutputHashBundle::default(),
+            source: String::new(),
+        })
+        .unwrap();
+        assert!(entry.len() <= 1000);
+        assert_eq!(put(entry).await.unwrap().status(), StatusCode::OK);
+        let response = put(vec![b' '; 1001]).await.unwrap();
+        assert_eq!(response.status(), StatusCode::PAYLOAD_TOO_LARGE);
+        assert_eq!(server.stats()[DEFAULT_NAMESPACE].rejected, 1);
+        // Entries sent in chunks, without the size in advance, are limited as well.
+        let chunks = futures::stream::iter((0..2).map(|_| Ok::<_, io::Error>(vec![b' '; 600])));
+        let response = reqwest::Client::new()
+            .put(format!("http://{}/ac/{}", addr, string_hash("key")))
+            .body(reqwest::Body::wrap_stream(chunks))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::PAYLOAD_TOO_LARGE);
+    }
+}
@@ -0,0 +1,150 @@
+//! Fingerprints of the tools a command runs, to be added to its inputs as tool tags, so that upgrading a
+//! compiler is a cache miss without computing a tool tag by hand.
+use anyhow::{anyhow, bail, Context, Result};
+use std::env;
+use std::fs;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+
+use crate::hash_cache::HashCache;
+use crate::iohashing::HashFunction;
+use crate::workspace_path::WorkspacePath;
+
+fn is_executable(path: &Path) -> bool {
+    match fs::metadata(path) {
+        Ok(metadata) => metadata.is_file() && metadata.permissions().mode() & 0o111 != 0,
+        Err(_) => false,
+    }
+}
+
+/// Finds the executable like the shell does, searching PATH for names without a slash, and resolves symlinks,
+/// so that e.g. rustup or alternatives symlinks stand for the binary they point to.
+pub fn find_executable(name: &str) -> Result<PathBuf> {
+    let path = if name.contains('/') {
+        PathBuf::from(name)
+    } else {
+        let path_var = env::var_os("PATH").unwrap_or_default();
+        env::split_paths(&path_var)
+            .map(|dir| dir.join(name))
+            .find(|path| is_executable(path))
+            .ok_or_else(|| anyhow!("Tool binary '{}' not found in PATH", name))?
+    };
+    if !is_executable(&path) {
+        bail!("Tool binary '{}' is not an executable file", path.to_string_lossy());
+    }
+    fs::canonicalize(&path).with_context(|| format!("Resolving tool binary '{}'", path.to_string_lossy()))
+}
+
+/// Returns the name of the binary for its tool tag: names looked up in PATH as they are, paths in the workspace
+/// relative to its root, and other paths as their file name, since the host paths differ between machines.
+fn tag_name(name: &str, workspace_root: &Option<String>) -> Result<String> {
+    if !name.contains('/') {
+        return Ok(name.to_string());
+    }
+    let cwd = env::current_dir().context("Reading the current directory")?;
+    Ok(match WorkspacePath::from_full_path(&cwd.join(name), workspace_root) {
+        path @ WorkspacePath::Workspace(_) => path.to_string(),
+        WorkspacePath::NonWorkspace(path) => path.file_name().map_or_else(
+            || name.to_string(),
+            |file_name| file_name.to_string_lossy().into_owned(),
+        ),
+    })
+}
+
+/// Returns the tool tag of the binary, with the hash of its contents.
+pub fn binary_tool_tag(
+    name: &str,
+    workspace_root: &Option<String>,
+    hash_function: HashFunction,
+    hash_cache: Option<&HashCache>,
+) -> Result<String> {
+    let path = find_executable(name)?;
+    let hash = match hash_cache {
+        Some(cache) => cache.file_hash(&path, hash_function)?,
+        None => hash_function.file_hash(&path)?,
+    };
+    Ok(format!("binary {}: {}", tag_name(name, workspace_root)?, hash))
+}
+
+/// Returns the tool tag of the command (e.g. `rustc -vV`), with the hash of its standard output.
+pub fn command_tool_tag(command: &str, hash_function: HashFunction) -> Result<String> {
+    let args = shell_words::split(command).with_context(|| format!("Parsing tool command '{}'", command))?;
+    let (program, args) = args.split_first().ok_or_else(|| anyhow!("Empty tool command"))?;
+    let output = Command::new(program)
+        .args(args)
+        .stdin(Stdio::null())
+        .stderr(Stdio::inherit())
+        .output()
+        .with_context(|| format!("Running tool command '{}'", command))?;
+    if !output.status.success() {
+        bail!("Tool command '{}' failed with {}", command, output.status);
+    }
+    Ok(format!(
+        "command {}: {}",
+        command,
+        hash_function.bytes_hash(&output.stdout)
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::iohashing::file_hash;
+    use std::os::unix::fs::symlink;
+
+    #[test]
+    fn test_find_executable() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let tool = dir.path().join("tool");
+        fs::write(&tool, "#!/bin/sh\n")?;
+        fs::set_permissions(&tool, fs::Permissions::from_mode(0o755))?;
+        symlink(&tool, dir.path().join("link"))?;
+        fs::write(dir.path().join("not_executable"), "")?;
+        let tool = fs::canonicalize(tool)?;
+
+        let link = dir.path().join("link");
+        assert_eq!(find_executable(link.to_str().unwrap())?, tool);
+        assert!(find_executable(dir.path().join("not_executable").to_str().unwrap()).is_err());
+        assert!(find_executable(dir.path().join("nonexistent").to_str().unwrap()).is_err());
+        assert!(find_executable("capsule-nonexistent-tool").is_err());
+        assert!(find_executable("sh")?.is_absolute());
+        Ok(())
+    }
+
+    #[test]
+    fn test_tool_tags() -> Result<()> {
+        let sh = find_executable("sh")?;
+        assert_eq!(
+            binary_tool_tag("sh", &None, HashFunction::Sha256, None)?,
+            format!("binary sh: {}", file_hash(&sh)?)
+        );
+        // Host paths are left out of the tag, except for the part in the workspace.
+        let dir = tempfile::tempdir()?;
+        let tool = dir.path().join("bin").join("tool");
+        fs::create_dir(dir.path().join("bin"))?;
+        fs::write(&tool, "#!/bin/sh\n")?;
+        fs::set_permissions(&tool, fs::Permissions::from_mode(0o755))?;
+        let (tool_name, hash) = (tool.to_str().unwrap(), file_hash(&tool)?);
+        assert_eq!(
+            binary_tool_tag(tool_name, &None, HashFunction::Sha256, None)?,
+            format!("binary tool: {}", hash)
+        );
+        let root = Some(dir.path().to_str().unwrap().to_string());
+        assert_eq!(
+            binary_tool_tag(tool_name, &root, HashFunction::Sha256, None)?,
+            format!("binary //bin/tool: {}", hash)
+        );
+        assert_eq!(
+            command_tool_tag("echo 'version 1'", HashFunction::Sha256)?,
+            format!(
+                "command echo 'version 1': {}",
+                HashFunction::Sha256.string_hash("version 1\n")
+            )
+        );
+        assert!(command_tool_tag("false", HashFunction::Sha256).is_err());
+        assert!(command_tool_tag("", HashFunction::Sha256).is_err());
+        assert!(command_tool_tag("capsule-nonexistent-tool -V", HashFunction::Sha256).is_err());
+        Ok(())
+    }
+}
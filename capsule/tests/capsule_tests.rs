@@ -46,6 +46,7 @@ fn test_s3_cache_hit() {
         &[
             "-c",
             "wtf",
+            "--ignore_command",
             "-b",
             "s3",
             "-i",
@@ -69,6 +70,7 @@ fn test_s3_cache_hit() {
         &[
             "-c",
             "wtf",
+            "--ignore_command",
             "-b",
             "s3",
             "-i",
@@ -100,6 +102,7 @@ fn test_s3_cache_hit_ws_root() {
         &[
             "-c",
             "wtf",
+            "--ignore_command",
             "--workspace_root",
             workspace_root,
             "-b",
@@ -125,6 +128,7 @@ fn test_s3_cache_hit_ws_root() {
         &[
             "-c",
             "wtf",
+            "--ignore_command",
             "-w",
             workspace_root,
             "-b",
@@ -151,7 +155,17 @@ fn test_cache_expiration() {
     // Run it first time.
     common::capsule(
         setup_data.port,
-        &["-c", "wtf", "-t", "foo", "--", "/bin/bash", "-c", &command],
+        &[
+            "-c",
+            "wtf",
+            "--ignore_command",
+            "-t",
+            "foo",
+            "--",
+            "/bin/bash",
+            "-c",
+            &command,
+        ],
     );
     // Creating
     println!("Checking file {:?}", side_effect);
@@ -165,7 +179,17 @@ fn test_cache_expiration() {
     let command = format!("echo 'wtf' > {}", side_effect.to_str().unwrap());
     common::capsule(
         setup_data.port,
-        &["-c", "wtf", "-t", "foo", "--", "/bin/bash", "-c", &command],
+        &[
+            "-c",
+            "wtf",
+            "--ignore_command",
+            "-t",
+            "foo",
+            "--",
+            "/bin/bash",
+            "-c",
+            &command,
+        ],
     );
     println!("Checking file {:?}", side_effect);
     // Verify that the second time the side effect is present
@@ -179,14 +203,21 @@ fn test_inputs_hash() {
     std::fs::write(&input, "input data").unwrap();
     let output = assert_cmd::Command::cargo_bin("capsule")
         .expect("Couldn't find capsule target")
-        .args(["--inputs_hash", "-t", "foo", "-i", input.to_str().unwrap()])
+        .args([
+            "--inputs_hash",
+            "--ignore_command",
+            "-t",
+            "foo",
+            "-i",
+            input.to_str().unwrap(),
+        ])
         .output()
         .expect("Couldn't execute capsule");
 
     assert!(output.status.success());
     assert_eq!(
         output.stdout,
-        b"6683fee73b2d88cd8414b00fdc6ea103e6e3d47f23dd8d67379b5be41fc72273"
+        b"a3dbdae4e96ac6f501d9f113991ce3b9b22871d6e21e411ba13e2b20d8c07c0a"
     );
 }
 
@@ -267,3 +298,268 @@ fn test_cas_optimization() {
         common::get_object(setup_data.port, "capsule-objects", &key).unwrap()
     );
 }
+
+#[test]
+fn test_gc() {
+    let setup_data = common::setup(); // RAII - clean up on destruction.
+    let output = setup_data.path("output.txt");
+    let command = format!("echo 'foo' > {}", output.to_str().unwrap());
+    // Two cache entries of the same capsule, sharing the output object.
+    for tool_tag in ["xxx", "yyy"] {
+        common::capsule(
+            setup_data.port,
+            &[
+                "-c",
+                "wtf",
+                "-b",
+                "s3",
+                "-t",
+                tool_tag,
+                "-o",
+                output.to_str().unwrap(),
+                "--",
+                "/bin/bash",
+                "-c",
+                &command,
+            ],
+        );
+    }
+    let hash = file_hash(&output).unwrap();
+    // An object no cache entry refers to.
+    common::put_object(setup_data.port, "capsule-objects", "ab/abcdef", b"orphan");
+    // S3 timestamps have a resolution of a second, so make sure everything is past the grace period.
+    std::thread::sleep(std::time::Duration::from_secs(2));
+
+    // Dry run only reports.
+    let gc_args = ["gc", "-b", "s3", "--keep", "1", "--grace_period", "0"];
+    let dry_run_args: Vec<&str> = gc_args.iter().copied().chain(["--dry_run"]).collect();
+    assert_eq!(common::capsule(setup_data.port, &dry_run_args), 0);
+    assert_eq!(common::list_keys(setup_data.port, "capsule-test").len(), 2);
+    assert_eq!(common::list_keys(setup_data.port, "capsule-objects").len(), 2);
+
+    assert_eq!(common::capsule(setup_data.port, &gc_args), 0);
+    assert_eq!(common::list_keys(setup_data.port, "capsule-test").len(), 1);
+    assert_eq!(
+        common::list_keys(setup_data.port, "capsule-objects"),
+        vec![format!("{}/{}", &hash[0..2], hash)]
+    );
+}
+
+#[test]
+fn test_inspect() {
+    let setup_data = common::setup(); // RAII - clean up on destruction.
+    let output = setup_data.path("output.txt");
+    let command = format!("echo 'foo' > {}", output.to_str().unwrap());
+    for tool_tag in ["xxx", "yyy"] {
+        common::capsule(
+            setup_data.port,
+            &[
+                "-c",
+                "wtf",
+                "-b",
+                "s3",
+                "-t",
+                tool_tag,
+                "-o",
+                output.to_str().unwrap(),
+                "--",
+                "/bin/bash",
+                "-c",
+                &command,
+            ],
+        );
+    }
+    // Entries of a capsule whose ID merely starts with ours are not listed.
+    common::capsule(
+        setup_data.port,
+        &["-c", "wtf/other", "-b", "s3", "--", "/bin/bash", "-c", "true"],
+    );
+
+    let (code, listing) = common::capsule_output(setup_data.port, &["ls", "-c", "wtf", "-b", "s3"]);
+    assert_eq!(code, 0);
+    let hashes: Vec<&str> = listing
+        .lines()
+        .map(|line| line.split_whitespace().next().unwrap())
+        .collect();
+    assert_eq!(hashes.len(), 2);
+
+    let (code, show) = common::capsule_output(setup_data.port, &["show", "-c", "wtf", "-b", "s3", hashes[0]]);
+    assert_eq!(code, 0);
+    assert!(show.contains(&format!("Inputs hash:  {}", hashes[0])));
+    assert!(show.contains("Exit code:    0"));
+
+    let (code, diff) = common::capsule_output(
+        setup_data.port,
+        &["diff", "-c", "wtf", "-b", "s3", hashes[0], hashes[1]],
+    );
+    assert_eq!(code, 0);
+    assert!(diff.contains("tool tag 'xxx'"));
+    assert!(diff.contains("tool tag 'yyy'"));
+}
+
+#[test]
+fn test_command_line_miss() {
+    let setup_data = common::setup(); // RAII - clean up on destruction.
+    let side_effect = setup_data.path("side_effect.txt");
+    for word in ["hello", "world"] {
+        let command = format!("echo '{}' >> {}", word, side_effect.to_str().unwrap());
+        common::capsule(
+            setup_data.port,
+            &["-c", "wtf", "-b", "s3", "--", "/bin/bash", "-c", &command],
+        );
+    }
+    // Different command lines never share a cache entry, so both commands ran.
+    assert_eq!(fs::read_to_string(&side_effect).unwrap(), "hello\nworld\n");
+}
+
+#[test]
+fn test_stdin_input() {
+    let directory = tempfile::tempdir().unwrap();
+    let inputs_hash = |stdin: &str| {
+        let output = assert_cmd::Command::cargo_bin("capsule")
+            .expect("Couldn't find capsule target")
+            .args(["--inputs_hash", "--ignore_command", "--stdin_input", "-t", "foo"])
+            .write_stdin(stdin)
+            .output()
+            .expect("Couldn't execute capsule");
+        assert!(output.status.success());
+        output.stdout
+    };
+    assert_eq!(inputs_hash("hello"), inputs_hash("hello"));
+    assert_ne!(inputs_hash("hello"), inputs_hash("world"));
+
+    // On a cache miss, the command still reads all of the standard input.
+    let output = directory.path().join("output.txt");
+    let command = format!("cat > {}", output.to_str().unwrap());
+    assert_cmd::Command::cargo_bin("capsule")
+        .expect("Couldn't find capsule target")
+        .args(["-c", "wtf", "--stdin_input", "--", "/bin/bash", "-c", &command])
+        .write_stdin("hello")
+        .assert()
+        .success();
+    assert_eq!(fs::read_to_string(&output).unwrap(), "hello");
+}
+
+#[test]
+fn test_capture_replay() {
+    let directory = tempfile::tempdir().unwrap();
+    let cache_dir = directory.path().join("cache");
+    let side_effect = directory.path().join("side_effect.txt");
+    let command = format!("echo out; echo err >&2; echo run >> {}", side_effect.to_str().unwrap());
+    let run = || {
+        assert_cmd::Command::cargo_bin("capsule")
+            .expect("Couldn't find capsule target")
+            .env("CAPSULE_LOG", "off")
+            .args([
+                "-c",
+                "wtf",
+                "-b",
+                "local",
+                "--local_cache_dir",
+                cache_dir.to_str().unwrap(),
+                "--capture_stdout",
+                "--capture_stderr",
+                "--",
+                "/bin/bash",
+                "-c",
+                &command,
+            ])
+            .output()
+            .expect("Couldn't execute capsule")
+    };
+    let first = run();
+    let second = run();
+    // The command ran only once, and the cache hit looks just like the run.
+    assert_eq!(fs::read_to_string(&side_effect).unwrap(), "run\n");
+    assert_eq!(first.stdout, b"out\n");
+    assert_eq!(first.stderr, b"err\n");
+    assert_eq!(second.stdout, first.stdout);
+    assert_eq!(second.stderr, first.stderr);
+}
+
+#[test]
+fn test_capture_combined_replay() {
+    let directory = tempfile::tempdir().unwrap();
+    let cache_dir = directory.path().join("cache");
+    let side_effect = directory.path().join("side_effect.txt");
+    let command = format!(
+        "echo 1; sleep 0.2; echo 2 >&2; sleep 0.2; echo 3; echo run >> {}",
+        side_effect.to_str().unwrap()
+    );
+    let capsule = assert_cmd::cargo::cargo_bin("capsule");
+    // Run capsule with its stdout and stderr going to the same pipe, to see the interleaving.
+    let run = || {
+        assert_cmd::Command::new("/bin/bash")
+            .env("CAPSULE_LOG", "off")
+            .args([
+                "-c",
+                "\"$0\" \"$@\" 2>&1",
+                capsule.to_str().unwrap(),
+                "-c",
+                "wtf",
+                "-b",
+                "local",
+                "--local_cache_dir",
+                cache_dir.to_str().unwrap(),
+                "--capture_combined",
+                "--",
+                "/bin/bash",
+                "-c",
+                &command,
+            ])
+            .output()
+            .expect("Couldn't execute capsule")
+            .stdout
+    };
+    assert_eq!(run(), b"1\n2\n3\n");
+    assert_eq!(run(), b"1\n2\n3\n");
+    assert_eq!(fs::read_to_string(&side_effect).unwrap(), "run\n");
+}
+
+#[test]
+fn test_hash_function() {
+    let directory = tempfile::tempdir().unwrap();
+    let cache_dir = directory.path().join("cache");
+    let side_effect = directory.path().join("side_effect.txt");
+    let output = directory.path().join("output.txt");
+    let command = format!(
+        "echo contents > {}; echo run >> {}",
+        output.to_str().unwrap(),
+        side_effect.to_str().unwrap()
+    );
+    let run = |hash_function: &str| {
+        assert_cmd::Command::cargo_bin("capsule")
+            .expect("Couldn't find capsule target")
+            .env("CAPSULE_LOG", "off")
+            .args([
+                "-c",
+                "wtf",
+                "-b",
+                "local",
+                "--local_cache_dir",
+                cache_dir.to_str().unwrap(),
+                "--hash_function",
+                hash_function,
+                "-o",
+                output.to_str().unwrap(),
+                "--",
+                "/bin/bash",
+                "-c",
+                &command,
+            ])
+            .assert()
+            .success();
+    };
+    run("blake3");
+    fs::remove_file(&output).unwrap();
+    // The output is downloaded and verified with the hash function of the entry.
+    run("blake3");
+    assert_eq!(fs::read_to_string(&output).unwrap(), "contents\n");
+    assert_eq!(fs::read_to_string(&side_effect).unwrap(), "run\n");
+    // Entries of one hash function are never used by another.
+    run("sha256");
+    assert_eq!(fs::read_to_string(&side_effect).unwrap(), "run\nrun\n");
+    run("blake3");
+    run("sha256");
+    assert_eq!(fs::read_to_string(&side_effect).unwrap(), "run\nrun\n");
+}
@@ -13,7 +13,7 @@ use nix::unistd;
 use rand::Rng;
 
 use rusoto_core::region::Region;
-use rusoto_s3::{DeleteBucketRequest, GetObjectRequest, PutObjectRequest, S3Client, S3 as _};
+use rusoto_s3::{DeleteBucketRequest, GetObjectRequest, ListObjectsV2Request, PutObjectRequest, S3Client, S3 as _};
 
 use tokio::io::AsyncReadExt;
 use tokio::runtime::Runtime;
@@ -120,6 +120,11 @@ pub fn setup() -> SetupData {
 }
 
 pub fn capsule(port: u16, args: &[&str]) -> i32 {
+    capsule_output(port, args).0
+}
+
+// Run capsule, and return its exit code together with its standard output.
+pub fn capsule_output(port: u16, args: &[&str]) -> (i32, String) {
     let output = assert_cmd::Command::cargo_bin("capsule")
         .expect("Couldn't find capsule target")
         .env("AWS_ACCESS_KEY_ID", "minioadmin")
@@ -136,7 +141,10 @@ pub fn capsule(port: u16, args: &[&str]) -> i32 {
         .expect("Couldn't execute capsule");
     io::stdout().write_all(&output.stdout).unwrap();
     io::stderr().write_all(&output.stderr).unwrap();
-    output.status.code().unwrap_or(1)
+    (
+        output.status.code().unwrap_or(1),
+        String::from_utf8_lossy(&output.stdout).to_string(),
+    )
 }
 
 // A utility to remove a bucket in integration tests.
@@ -211,3 +219,29 @@ pub fn get_object(port: u16, bucket: &str, key: &str) -> Result<Vec<u8>> {
     })?;
     Ok(body)
 }
+
+// A utility to list keys in an s3 bucket in integration tests.
+pub fn list_keys(port: u16, bucket: &str) -> Vec<String> {
+    std::env::set_var("AWS_ACCESS_KEY_ID", "minioadmin");
+    std::env::set_var("AWS_SECRET_ACCESS_KEY", "minioadmin");
+
+    let req = ListObjectsV2Request {
+        bucket: bucket.to_string(),
+        ..Default::default()
+    };
+    let client = S3Client::new(Region::Custom {
+        name: "eu-central-1".to_string(),
+        endpoint: format!("http://127.0.0.1:{}", port),
+    });
+
+    let rt = Runtime::new().unwrap();
+    let response = rt.block_on(async move { client.list_objects_v2(req).await.unwrap() });
+    let mut keys: Vec<String> = response
+        .contents
+        .unwrap_or_default()
+        .into_iter()
+        .filter_map(|object| object.key)
+        .collect();
+    keys.sort();
+    keys
+}